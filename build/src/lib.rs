@@ -405,9 +405,12 @@ impl TwirpServiceGenerator {
                         write!(buf, "{}", method.input_type)?;
                     }
                     if self.request_extractors.is_empty() {
-                        write!(buf, ", _: ::twurst_server::codegen::RequestParts")?;
+                        write!(buf, ", _: ::twurst_server::codegen::RequestParts, _: ()")?;
                     } else {
-                        write!(buf, ", mut parts: ::twurst_server::codegen::RequestParts")?;
+                        write!(
+                            buf,
+                            ", mut parts: ::twurst_server::codegen::RequestParts, _: ()"
+                        )?;
                     }
                     write!(buf, "| {{")?;
                     write!(buf, "                async move {{")?;
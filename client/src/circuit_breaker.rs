@@ -0,0 +1,424 @@
+use http::{Request, Response};
+use pin_project_lite::pin_project;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll, ready};
+use std::time::{Duration, Instant};
+use tower_layer::Layer;
+use tower_service::Service;
+use twurst_error::TwirpError;
+
+/// When a [`CircuitBreakerLayer`] opens the circuit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailureThreshold {
+    /// After this number of failed calls in the window
+    Count(u32),
+    /// When this percentage (between 0 and 100) of the calls in the window failed,
+    /// once the window has the minimum number of calls set with [`CircuitBreakerLayer::with_min_calls`]
+    Percentage(u8),
+}
+
+/// State of the circuit of a [`CircuitBreakerLayer`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    /// The calls are passed to the inner service
+    Closed,
+    /// The calls fail without calling the inner service
+    Open,
+    /// A single probe call is passed to the inner service to decide if the circuit should be closed
+    HalfOpen,
+}
+
+/// [`Layer`] failing the calls without calling the inner service after too many failures,
+/// preventing failures from cascading.
+///
+/// A call fails if the inner service returns an error or a `5xx` response.
+/// When the [`FailureThreshold`] is reached in the window (1 minute by default), the circuit opens:
+/// the calls immediately get a Twirp `unavailable` "Circuit breaker open" response.
+/// After the probe interval (30 seconds by default), the circuit is half-open:
+/// one call is passed to the inner service, if it succeeds the circuit closes, else it stays open.
+///
+/// The clones of the layer share the same circuit.
+/// It can be used on the server side (e.g. with `TwirpRouter::layer`) to protect the handlers
+/// or on the client side with [`TwirpHttpClient::with_layer`](crate::TwirpHttpClient::with_layer):
+///
+/// ```
+/// use http::{Request, Response};
+/// use std::convert::Infallible;
+/// use std::time::Duration;
+/// use tower::service_fn;
+/// use twurst_client::{CircuitBreakerLayer, FailureThreshold, TwirpHttpClient, TwirpRequestBody};
+///
+/// let breaker = CircuitBreakerLayer::new(FailureThreshold::Count(5))
+///     .with_window(Duration::from_secs(10))
+///     .with_probe_interval(Duration::from_secs(5));
+/// let _client = TwirpHttpClient::new(service_fn(|_: Request<TwirpRequestBody>| async {
+///     Ok::<_, Infallible>(Response::new(String::new()))
+/// }))
+/// .with_layer(breaker);
+/// ```
+#[derive(Clone, Debug)]
+pub struct CircuitBreakerLayer {
+    threshold: FailureThreshold,
+    window: Duration,
+    probe_interval: Duration,
+    min_calls: u32,
+    circuit: Arc<Mutex<Circuit>>,
+}
+
+impl CircuitBreakerLayer {
+    pub fn new(threshold: FailureThreshold) -> Self {
+        Self {
+            threshold,
+            window: Duration::from_secs(60),
+            probe_interval: Duration::from_secs(30),
+            min_calls: 5,
+            circuit: Arc::new(Mutex::new(Circuit::Closed {
+                window_start: Instant::now(),
+                calls: 0,
+                failures: 0,
+            })),
+        }
+    }
+
+    /// Sets the duration of the windows in which the failures are counted
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Sets how long the circuit stays open before a probe call is allowed
+    pub fn with_probe_interval(mut self, probe_interval: Duration) -> Self {
+        self.probe_interval = probe_interval;
+        self
+    }
+
+    /// Sets how many calls a window must have before a [`FailureThreshold::Percentage`] applies (5 by default),
+    /// so that a few failures at the start of a window do not open the circuit.
+    pub fn with_min_calls(mut self, min_calls: u32) -> Self {
+        self.min_calls = min_calls;
+        self
+    }
+
+    /// Current state of the circuit
+    pub fn state(&self) -> CircuitState {
+        match *self.circuit.lock().unwrap_or_else(PoisonError::into_inner) {
+            Circuit::Closed { .. } => CircuitState::Closed,
+            Circuit::Open { since } if since.elapsed() < self.probe_interval => CircuitState::Open,
+            Circuit::Open { .. } | Circuit::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Returns `None` if the call is not allowed, else if the call is a probe
+    fn acquire(&self) -> Option<bool> {
+        let mut circuit = self.circuit.lock().unwrap_or_else(PoisonError::into_inner);
+        match *circuit {
+            Circuit::Closed {
+                ref mut window_start,
+                ref mut calls,
+                ref mut failures,
+            } => {
+                if window_start.elapsed() >= self.window {
+                    *window_start = Instant::now();
+                    *calls = 0;
+                    *failures = 0;
+                }
+                Some(false)
+            }
+            // A probe that did not complete (e.g. was cancelled) does not block the circuit forever
+            Circuit::Open { since } | Circuit::HalfOpen { since }
+                if since.elapsed() >= self.probe_interval =>
+            {
+                *circuit = Circuit::HalfOpen {
+                    since: Instant::now(),
+                };
+                Some(true)
+            }
+            Circuit::Open { .. } | Circuit::HalfOpen { .. } => None,
+        }
+    }
+
+    fn record(&self, probe: bool, failed: bool) {
+        let mut circuit = self.circuit.lock().unwrap_or_else(PoisonError::into_inner);
+        match *circuit {
+            Circuit::HalfOpen { .. } if probe => {
+                *circuit = if failed {
+                    Circuit::Open {
+                        since: Instant::now(),
+                    }
+                } else {
+                    Circuit::Closed {
+                        window_start: Instant::now(),
+                        calls: 0,
+                        failures: 0,
+                    }
+                };
+            }
+            Circuit::Closed {
+                ref mut calls,
+                ref mut failures,
+                ..
+            } if !probe => {
+                *calls += 1;
+                if failed {
+                    *failures += 1;
+                }
+                let tripped = match self.threshold {
+                    FailureThreshold::Count(count) => *failures >= count,
+                    FailureThreshold::Percentage(percentage) => {
+                        *calls >= self.min_calls
+                            && *failures > 0
+                            && *failures * 100 >= u32::from(percentage) * *calls
+                    }
+                };
+                if tripped {
+                    *circuit = Circuit::Open {
+                        since: Instant::now(),
+                    };
+                }
+            }
+            // The circuit changed while the call was running
+            _ => (),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Circuit {
+    Closed {
+        window_start: Instant,
+        calls: u32,
+        failures: u32,
+    },
+    Open {
+        since: Instant,
+    },
+    HalfOpen {
+        since: Instant,
+    },
+}
+
+impl<S> Layer<S> for CircuitBreakerLayer {
+    type Service = CircuitBreakerService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CircuitBreakerService {
+            inner,
+            breaker: self.clone(),
+        }
+    }
+}
+
+/// Service built by [`CircuitBreakerLayer`]
+#[derive(Clone, Debug)]
+pub struct CircuitBreakerService<S> {
+    inner: S,
+    breaker: CircuitBreakerLayer,
+}
+
+impl<S: Service<Request<B>, Response = Response<RB>>, B, RB: From<String>> Service<Request<B>>
+    for CircuitBreakerService<S>
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = CircuitBreakerFuture<S::Future>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        match self.breaker.acquire() {
+            Some(probe) => CircuitBreakerFuture::Called {
+                inner: self.inner.call(request),
+                breaker: self.breaker.clone(),
+                probe,
+            },
+            None => CircuitBreakerFuture::Rejected,
+        }
+    }
+}
+
+pin_project! {
+    /// Future returned by [`CircuitBreakerService`]
+    #[project = CircuitBreakerFutureProj]
+    pub enum CircuitBreakerFuture<F> {
+        Called {
+            #[pin]
+            inner: F,
+            breaker: CircuitBreakerLayer,
+            probe: bool,
+        },
+        Rejected,
+    }
+}
+
+impl<F: Future<Output = Result<Response<B>, E>>, B: From<String>, E> Future
+    for CircuitBreakerFuture<F>
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project() {
+            CircuitBreakerFutureProj::Called {
+                inner,
+                breaker,
+                probe,
+            } => {
+                let result = ready!(inner.poll(cx));
+                let failed = match &result {
+                    Ok(response) => response.status().is_server_error(),
+                    Err(_) => true,
+                };
+                breaker.record(*probe, failed);
+                Poll::Ready(result)
+            }
+            CircuitBreakerFutureProj::Rejected => {
+                Poll::Ready(Ok(TwirpError::unavailable("Circuit breaker open").into()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::StatusCode;
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use tower::{ServiceExt, service_fn};
+
+    /// Service failing while `failing` is set and counting its calls
+    fn test_service(
+        failing: Arc<AtomicBool>,
+        calls: Arc<AtomicUsize>,
+    ) -> impl Service<Request<String>, Response = Response<String>, Error = Infallible, Future: Send>
+    + Clone {
+        service_fn(move |_: Request<String>| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            let response = if failing.load(Ordering::Relaxed) {
+                TwirpError::internal("Failed").into()
+            } else {
+                Response::new(String::new())
+            };
+            async move { Ok(response) }
+        })
+    }
+
+    async fn call(
+        service: impl Service<Request<String>, Response = Response<String>, Error = Infallible>,
+    ) -> StatusCode {
+        service
+            .oneshot(Request::new(String::new()))
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_transitions() {
+        let failing = Arc::new(AtomicBool::new(true));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let breaker = CircuitBreakerLayer::new(FailureThreshold::Count(2))
+            .with_probe_interval(Duration::from_millis(10));
+        let service = breaker.layer(test_service(failing.clone(), calls.clone()));
+
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert_eq!(
+            call(service.clone()).await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert_eq!(
+            call(service.clone()).await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        // The inner service is not called while the circuit is open
+        let response = service
+            .clone()
+            .oneshot(Request::new(String::new()))
+            .await
+            .unwrap();
+        assert_eq!(
+            TwirpError::from(response),
+            TwirpError::unavailable("Circuit breaker open")
+        );
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+        // A failed probe keeps the circuit open
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert_eq!(
+            call(service.clone()).await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert_eq!(call(service.clone()).await, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+
+        // A successful probe closes the circuit
+        failing.store(false, Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(call(service.clone()).await, StatusCode::OK);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert_eq!(call(service).await, StatusCode::OK);
+        assert_eq!(calls.load(Ordering::Relaxed), 5);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_single_probe() {
+        let failing = Arc::new(AtomicBool::new(true));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let breaker = CircuitBreakerLayer::new(FailureThreshold::Count(1))
+            .with_probe_interval(Duration::from_millis(10));
+        let service = breaker.layer(test_service(failing, calls.clone()));
+        call(service.clone()).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // Only the first call after the probe interval is passed to the inner service
+        let probe = service.clone().call(Request::new(String::new()));
+        assert_eq!(call(service).await, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            probe.await.unwrap().status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_percentage() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let breaker = CircuitBreakerLayer::new(FailureThreshold::Percentage(50));
+        let service = breaker.layer(test_service(failing.clone(), calls));
+        for _ in 0..3 {
+            call(service.clone()).await;
+        }
+        failing.store(true, Ordering::Relaxed);
+        for _ in 0..2 {
+            call(service.clone()).await;
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        call(service).await;
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_percentage_min_calls() {
+        let failing = Arc::new(AtomicBool::new(true));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let breaker = CircuitBreakerLayer::new(FailureThreshold::Percentage(50)).with_min_calls(3);
+        let service = breaker.layer(test_service(failing, calls));
+        // A single failure in a new window is 100% of the calls but not enough calls
+        call(service.clone()).await;
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        call(service.clone()).await;
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        call(service).await;
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+}
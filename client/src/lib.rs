@@ -6,12 +6,22 @@
 )]
 #![cfg_attr(docsrs, feature(doc_auto_cfg))]
 
+mod circuit_breaker;
+#[cfg(feature = "retry")]
+mod retry;
+
+pub use circuit_breaker::{
+    CircuitBreakerFuture, CircuitBreakerLayer, CircuitBreakerService, CircuitState,
+    FailureThreshold,
+};
 use http::header::CONTENT_TYPE;
 use http::{HeaderValue, Method, Request, Response, StatusCode};
 use http_body::{Body, Frame, SizeHint};
 use http_body_util::BodyExt;
 use prost_reflect::bytes::{Buf, Bytes, BytesMut};
 use prost_reflect::{DynamicMessage, ReflectMessage};
+#[cfg(feature = "retry")]
+pub use retry::{RetryLayer, RetryService, TwirpRetryPolicy};
 use serde::Serialize;
 use std::convert::Infallible;
 use std::error::Error;
@@ -20,7 +30,9 @@ use std::future::Future;
 use std::future::poll_fn;
 use std::mem::take;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use tower_layer::Layer;
 use tower_service::Service;
 pub use twurst_error::{TwirpError, TwirpErrorCode};
 
@@ -43,8 +55,12 @@ pub struct TwirpHttpClient<S: TwirpHttpService> {
     service: S,
     base_url: Option<String>,
     use_json: bool,
+    interceptors: Vec<Interceptor>,
 }
 
+/// Function set with [`TwirpHttpClient::with_interceptor`]
+type Interceptor = Arc<dyn Fn(&mut Request<Bytes>) -> Result<(), TwirpError> + Send + Sync>;
+
 #[cfg(feature = "reqwest-012")]
 impl TwirpHttpClient<Reqwest012Service> {
     /// Builds a new client using [`reqwest 0.12`](reqwest_012).
@@ -105,6 +121,7 @@ impl<S: TwirpHttpService> TwirpHttpClient<S> {
             service,
             base_url: Some(base_url),
             use_json: false,
+            interceptors: Vec::new(),
         }
     }
 
@@ -125,6 +142,7 @@ impl<S: TwirpHttpService> TwirpHttpClient<S> {
             service,
             base_url: None,
             use_json: false,
+            interceptors: Vec::new(),
         }
     }
 
@@ -138,6 +156,75 @@ impl<S: TwirpHttpService> TwirpHttpClient<S> {
         self.use_json = false;
     }
 
+    /// Builder version of [`use_json`](Self::use_json)
+    ///
+    /// ```
+    /// use http::Response;
+    /// use std::convert::Infallible;
+    /// use twurst_client::TwirpHttpClient;
+    /// use twurst_error::TwirpError;
+    ///
+    /// let _client = TwirpHttpClient::new(tower::service_fn(|_request| async {
+    ///     Ok::<Response<String>, Infallible>(TwirpError::unimplemented("not implemented").into())
+    /// }))
+    /// .with_json();
+    /// ```
+    pub fn with_json(mut self) -> Self {
+        self.use_json();
+        self
+    }
+
+    /// Builder version of [`use_binary_protobuf`](Self::use_binary_protobuf)
+    pub fn with_binary_protobuf(mut self) -> Self {
+        self.use_binary_protobuf();
+        self
+    }
+
+    /// Calls `interceptor` on each request before sending it (e.g. to add an `Authorization` header).
+    ///
+    /// The interceptors are called in the order they are added.
+    /// If one fails, the request is not sent and its error is returned.
+    ///
+    /// ```
+    /// use http::Response;
+    /// use http::header::AUTHORIZATION;
+    /// use std::convert::Infallible;
+    /// use twurst_client::TwirpHttpClient;
+    /// use twurst_error::TwirpError;
+    ///
+    /// let _client = TwirpHttpClient::new(tower::service_fn(|_request| async {
+    ///     Ok::<Response<String>, Infallible>(TwirpError::unimplemented("not implemented").into())
+    /// }))
+    /// .with_interceptor(|request| {
+    ///     request
+    ///         .headers_mut()
+    ///         .insert(AUTHORIZATION, "Bearer token".parse().unwrap());
+    ///     Ok(())
+    /// });
+    /// ```
+    pub fn with_interceptor(
+        mut self,
+        interceptor: impl Fn(&mut Request<Bytes>) -> Result<(), TwirpError> + Send + Sync + 'static,
+    ) -> Self {
+        self.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    /// Wraps the underlying service with a [`tower` layer](Layer) (e.g. from [`tower-http`](https://docs.rs/tower-http)).
+    ///
+    /// The layer sees the requests after the interceptors set with [`with_interceptor`](Self::with_interceptor).
+    pub fn with_layer<L: Layer<S>>(self, layer: L) -> TwirpHttpClient<L::Service>
+    where
+        L::Service: TwirpHttpService,
+    {
+        TwirpHttpClient {
+            service: layer.layer(self.service),
+            base_url: self.base_url,
+            use_json: self.use_json,
+            interceptors: self.interceptors,
+        }
+    }
+
     /// Send a Twirp request and get a response.
     ///
     /// Used internally by the generated code.
@@ -154,7 +241,7 @@ impl<S: TwirpHttpService> TwirpHttpClient<S> {
                 e,
             )
         })?;
-        let request = self.build_request(path, request)?;
+        let request = self.intercept(self.build_request(path, request)?)?;
         let response = self.service.call(request).await.map_err(|e| {
             TwirpError::wrap(
                 TwirpErrorCode::Unknown,
@@ -202,6 +289,20 @@ impl<S: TwirpHttpService> TwirpHttpClient<S> {
         })
     }
 
+    fn intercept(
+        &self,
+        request: Request<TwirpRequestBody>,
+    ) -> Result<Request<TwirpRequestBody>, TwirpError> {
+        if self.interceptors.is_empty() {
+            return Ok(request);
+        }
+        let mut request = request.map(Bytes::from);
+        for interceptor in &self.interceptors {
+            interceptor(&mut request)?;
+        }
+        Ok(request.map(TwirpRequestBody::from))
+    }
+
     async fn extract_response<T: ReflectMessage + Default>(
         &self,
         response: Response<S::ResponseBody>,
@@ -471,6 +572,94 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn with_json_and_binary_protobuf() -> Result<(), Box<dyn Error>> {
+        let service = service_fn(|request: Request<TwirpRequestBody>| async move {
+            let content_type = request
+                .headers()
+                .get(CONTENT_TYPE)
+                .unwrap()
+                .to_str()
+                .unwrap();
+            Ok::<Response<String>, TwirpError>(Response::from(TwirpError::not_found(content_type)))
+        });
+        let client = TwirpHttpClient::new(service).with_json();
+        assert_eq!(
+            client
+                .call::<_, Timestamp>("/foo", &Timestamp::default())
+                .await
+                .unwrap_err(),
+            TwirpError::not_found("application/json")
+        );
+        let client = client.with_binary_protobuf();
+        assert_eq!(
+            client
+                .call::<_, Timestamp>("/foo", &Timestamp::default())
+                .await
+                .unwrap_err(),
+            TwirpError::not_found("application/protobuf")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn interceptors_and_layers() -> Result<(), Box<dyn Error>> {
+        let service = service_fn(|request: Request<TwirpRequestBody>| async move {
+            let header = |name| {
+                request
+                    .headers()
+                    .get_all(name)
+                    .iter()
+                    .map(|v| v.to_str().unwrap().to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            };
+            Ok::<Response<String>, TwirpError>(Response::from(TwirpError::not_found(format!(
+                "{} {}",
+                header("x-intercepted"),
+                header("x-layer")
+            ))))
+        });
+        let client = TwirpHttpClient::new(service)
+            .with_interceptor(|request| {
+                request
+                    .headers_mut()
+                    .append("x-intercepted", HeaderValue::from_static("first"));
+                Ok(())
+            })
+            .with_interceptor(|request| {
+                request
+                    .headers_mut()
+                    .append("x-intercepted", HeaderValue::from_static("second"));
+                Ok(())
+            })
+            .with_layer(tower::layer::layer_fn(|inner| {
+                tower::ServiceExt::map_request(inner, |mut request: Request<TwirpRequestBody>| {
+                    request
+                        .headers_mut()
+                        .insert("x-layer", HeaderValue::from_static("layer"));
+                    request
+                })
+            }));
+        assert_eq!(
+            client
+                .call::<_, Timestamp>("/foo", &Timestamp::default())
+                .await
+                .unwrap_err(),
+            TwirpError::not_found("first,second layer")
+        );
+
+        let client = client.with_interceptor(|_| Err(TwirpError::unauthenticated("No token")));
+        assert_eq!(
+            client
+                .call::<_, Timestamp>("/foo", &Timestamp::default())
+                .await
+                .unwrap_err(),
+            TwirpError::unauthenticated("No token")
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     async fn json_request_without_base_ok() -> Result<(), Box<dyn Error>> {
         let service = service_fn(|request: Request<TwirpRequestBody>| async move {
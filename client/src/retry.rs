@@ -0,0 +1,502 @@
+use crate::TwirpRequestBody;
+use http::{Request, Response};
+use http_body::Body;
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyExt, Full};
+use prost_reflect::bytes::{Buf, Bytes};
+use std::collections::hash_map::RandomState;
+use std::error::Error;
+use std::future::{Future, poll_fn};
+use std::hash::BuildHasher;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{Sleep, sleep};
+use tower::retry::Policy;
+use tower_layer::Layer;
+use tower_service::Service;
+use twurst_error::{TwirpError, TwirpErrorCode};
+
+/// [`Policy`] for [`tower::retry::Retry`] retrying the requests failing with a retryable [`TwirpError`].
+///
+/// The delay between attempts grows exponentially from `initial_backoff` (100ms by default),
+/// it is multiplied by `multiplier` (2 by default) after each attempt and capped at `max_backoff`.
+///
+/// To retry the HTTP requests of a [`TwirpHttpClient`](crate::TwirpHttpClient) use [`RetryLayer`].
+///
+/// ```
+/// use tower::retry::Retry;
+/// use twurst_client::{TwirpError, TwirpRetryPolicy};
+///
+/// let _service = Retry::new(
+///     TwirpRetryPolicy::new(3, TwirpRetryPolicy::is_transient),
+///     tower::service_fn(|_request: ()| async { Err::<(), _>(TwirpError::unavailable("down")) }),
+/// );
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct TwirpRetryPolicy {
+    max_attempts: u32,
+    retryable: fn(&TwirpError) -> bool,
+    initial_backoff: Duration,
+    multiplier: f64,
+    max_backoff: Duration,
+    jitter: bool,
+    respect_retry_after: bool,
+    attempts: u32,
+}
+
+impl TwirpRetryPolicy {
+    /// Sends each request at most `max_attempts` times (including the first one)
+    /// and retries only if `retryable` returns `true` for the returned error.
+    pub fn new(max_attempts: u32, retryable: fn(&TwirpError) -> bool) -> Self {
+        Self {
+            max_attempts,
+            retryable,
+            initial_backoff: Duration::from_millis(100),
+            multiplier: 2.,
+            max_backoff: Duration::MAX,
+            jitter: false,
+            respect_retry_after: false,
+            attempts: 0,
+        }
+    }
+
+    /// Sets the delay before the first retry.
+    pub fn with_initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    /// Sets by how much the delay is multiplied after each retry.
+    pub fn with_multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    /// Sets the maximal delay between two attempts.
+    ///
+    /// It does not apply to the delays sent by the server if [`respect_retry_after`](Self::respect_retry_after) is set.
+    pub fn with_max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Waits for a random delay between half the backoff and the full backoff
+    /// to avoid all the clients retrying at the same time.
+    pub fn with_jitter(mut self) -> Self {
+        self.jitter = true;
+        self
+    }
+
+    /// Waits for the delay sent by the server (see [`TwirpError::retry_after`]) instead of the backoff when there is one.
+    pub fn respect_retry_after(mut self) -> Self {
+        self.respect_retry_after = true;
+        self
+    }
+
+    /// Retryable predicate matching the errors that are usually transient:
+    /// `unavailable`, `resource_exhausted`, `aborted` and `deadline_exceeded`.
+    pub fn is_transient(error: &TwirpError) -> bool {
+        matches!(
+            error.code(),
+            TwirpErrorCode::Unavailable
+                | TwirpErrorCode::ResourceExhausted
+                | TwirpErrorCode::Aborted
+                | TwirpErrorCode::DeadlineExceeded
+        )
+    }
+
+    /// Records a failed attempt and returns how long to wait before the next one, if any.
+    fn backoff(&mut self, error: &TwirpError) -> Option<Duration> {
+        self.attempts += 1;
+        if self.attempts >= self.max_attempts || !(self.retryable)(error) {
+            return None;
+        }
+        if self.respect_retry_after {
+            if let Some(retry_after) = error.retry_after() {
+                return Some(retry_after);
+            }
+        }
+        let exponent = i32::try_from(self.attempts - 1).unwrap_or(i32::MAX);
+        let backoff = Duration::try_from_secs_f64(
+            self.initial_backoff.as_secs_f64() * self.multiplier.powi(exponent),
+        )
+        .unwrap_or(Duration::MAX)
+        .min(self.max_backoff);
+        if !self.jitter {
+            return Some(backoff);
+        }
+        // We do not need a good random number generator, the hasher random keys are enough
+        let random = RandomState::new().hash_one(self.attempts) as f64 / u64::MAX as f64;
+        Some(backoff.mul_f64(0.5 + random / 2.))
+    }
+}
+
+impl<Req: Clone, Res> Policy<Req, Res, TwirpError> for TwirpRetryPolicy {
+    type Future = Sleep;
+
+    fn retry(&mut self, _: &mut Req, result: &mut Result<Res, TwirpError>) -> Option<Sleep> {
+        Some(sleep(self.backoff(result.as_ref().err()?)?))
+    }
+
+    fn clone_request(&mut self, request: &Req) -> Option<Req> {
+        Some(request.clone())
+    }
+}
+
+/// [`Layer`] retrying the HTTP requests of a [`TwirpHttpClient`](crate::TwirpHttpClient)
+/// following a [`TwirpRetryPolicy`].
+///
+/// The bodies of the error responses are buffered to get the returned [`TwirpError`],
+/// the successful responses are streamed without buffering.
+/// Transport errors are not retried.
+///
+/// ```
+/// use http::Response;
+/// use std::convert::Infallible;
+/// use std::time::Duration;
+/// use twurst_client::{RetryLayer, TwirpError, TwirpHttpClient, TwirpRetryPolicy};
+///
+/// let _client = TwirpHttpClient::new(tower::service_fn(|_request| async {
+///     Ok::<Response<String>, Infallible>(TwirpError::unavailable("down").into())
+/// }))
+/// .with_layer(RetryLayer::new(
+///     TwirpRetryPolicy::new(5, TwirpRetryPolicy::is_transient)
+///         .with_initial_backoff(Duration::from_millis(50))
+///         .with_multiplier(1.5)
+///         .with_max_backoff(Duration::from_secs(2))
+///         .with_jitter()
+///         .respect_retry_after(),
+/// ));
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct RetryLayer {
+    policy: TwirpRetryPolicy,
+}
+
+impl RetryLayer {
+    pub fn new(policy: TwirpRetryPolicy) -> Self {
+        Self { policy }
+    }
+}
+
+impl<S> Layer<S> for RetryLayer {
+    type Service = RetryService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RetryService {
+            inner,
+            policy: self.policy,
+        }
+    }
+}
+
+/// Service built by [`RetryLayer`]
+#[derive(Clone, Copy, Debug)]
+pub struct RetryService<S> {
+    inner: S,
+    policy: TwirpRetryPolicy,
+}
+
+impl<S, B> Service<Request<TwirpRequestBody>> for RetryService<S>
+where
+    S: Service<Request<TwirpRequestBody>, Response = Response<B>> + Clone + Send + 'static,
+    S::Error: Error + Send + Sync + 'static,
+    S::Future: Send,
+    B: Body<Error: Error + Send + Sync + 'static> + Send + 'static,
+    B::Data: Send,
+{
+    type Response = Response<UnsyncBoxBody<Bytes, TwirpError>>;
+    type Error = TwirpError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, TwirpError>> + Send>>;
+
+    #[inline]
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // The inner service readiness is checked before each attempt
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<TwirpRequestBody>) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let mut policy = self.policy;
+        let request = request.map(Bytes::from);
+        Box::pin(async move {
+            loop {
+                poll_fn(|cx| inner.poll_ready(cx))
+                    .await
+                    .map_err(transport_error)?;
+                let response = inner
+                    .call(request.clone().map(TwirpRequestBody::from))
+                    .await
+                    .map_err(transport_error)?;
+                if response.status().is_success() {
+                    return Ok(response.map(|body| {
+                        body.map_frame(|frame| {
+                            frame.map_data(|mut data| data.copy_to_bytes(data.remaining()))
+                        })
+                        .map_err(transport_error)
+                        .boxed_unsync()
+                    }));
+                }
+                // Only the error responses are buffered, to get their Twirp error
+                let (parts, body) = response.into_parts();
+                let body = body.collect().await.map_err(transport_error)?.to_bytes();
+                let response = Response::from_parts(parts, body);
+                match policy.backoff(&response.clone().into()) {
+                    Some(backoff) => sleep(backoff).await,
+                    None => {
+                        return Ok(response
+                            .map(|body| Full::new(body).map_err(|e| match e {}).boxed_unsync()));
+                    }
+                }
+            }
+        })
+    }
+}
+
+fn transport_error(error: impl Error + Send + Sync + 'static) -> TwirpError {
+    TwirpError::wrap(TwirpErrorCode::Unknown, error.to_string(), error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TwirpHttpClient;
+    use http::header::CONTENT_TYPE;
+    use prost_reflect::prost_types::Timestamp;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use tokio::time::Instant;
+    use tower::ServiceExt;
+    use tower::retry::Retry;
+
+    async fn call_failing(policy: TwirpRetryPolicy, error: TwirpError) -> (bool, u32) {
+        call_failing_with_backoff(policy, Duration::from_millis(1), error).await
+    }
+
+    async fn call_failing_with_backoff(
+        policy: TwirpRetryPolicy,
+        initial_backoff: Duration,
+        error: TwirpError,
+    ) -> (bool, u32) {
+        let calls = Arc::new(AtomicU32::new(0));
+        let service = Retry::new(
+            policy.with_initial_backoff(initial_backoff),
+            tower::service_fn({
+                let calls = calls.clone();
+                move |()| {
+                    let calls = calls.clone();
+                    let error = error.clone();
+                    async move {
+                        if calls.fetch_add(1, Ordering::Relaxed) < 2 {
+                            Err(error)
+                        } else {
+                            Ok(())
+                        }
+                    }
+                }
+            }),
+        );
+        let result = service.oneshot(()).await;
+        (result.is_ok(), calls.load(Ordering::Relaxed))
+    }
+
+    #[tokio::test]
+    async fn retries_until_success() {
+        let policy = TwirpRetryPolicy::new(3, TwirpRetryPolicy::is_transient);
+        assert_eq!(
+            call_failing(policy, TwirpError::unavailable("down")).await,
+            (true, 3)
+        );
+    }
+
+    #[tokio::test]
+    async fn stops_after_max_attempts() {
+        let policy = TwirpRetryPolicy::new(2, TwirpRetryPolicy::is_transient);
+        assert_eq!(
+            call_failing(policy, TwirpError::unavailable("down")).await,
+            (false, 2)
+        );
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_non_retryable() {
+        let policy = TwirpRetryPolicy::new(3, TwirpRetryPolicy::is_transient);
+        assert_eq!(
+            call_failing(policy, TwirpError::invalid_argument("bad")).await,
+            (false, 1)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn respects_retry_after() {
+        let policy = TwirpRetryPolicy::new(3, TwirpRetryPolicy::is_transient).respect_retry_after();
+        let error =
+            TwirpError::resource_exhausted("slow down").with_retry_after(Duration::from_secs(5));
+        let start = Instant::now();
+        assert_eq!(
+            call_failing_with_backoff(policy, Duration::from_secs(3600), error).await,
+            (true, 3)
+        );
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ignores_retry_after_by_default() {
+        let policy = TwirpRetryPolicy::new(2, TwirpRetryPolicy::is_transient);
+        let error =
+            TwirpError::resource_exhausted("slow down").with_retry_after(Duration::from_secs(5));
+        let start = Instant::now();
+        call_failing_with_backoff(policy, Duration::from_secs(1), error).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let mut policy = TwirpRetryPolicy::new(6, TwirpRetryPolicy::is_transient)
+            .with_initial_backoff(Duration::from_secs(1))
+            .with_multiplier(3.)
+            .with_max_backoff(Duration::from_secs(20));
+        let error = TwirpError::unavailable("down");
+        let backoffs = (0..6).map(|_| policy.backoff(&error)).collect::<Vec<_>>();
+        assert_eq!(
+            backoffs,
+            [
+                Some(Duration::from_secs(1)),
+                Some(Duration::from_secs(3)),
+                Some(Duration::from_secs(9)),
+                Some(Duration::from_secs(20)),
+                Some(Duration::from_secs(20)),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn jitter() {
+        let mut policy = TwirpRetryPolicy::new(10, TwirpRetryPolicy::is_transient)
+            .with_initial_backoff(Duration::from_secs(8))
+            .with_multiplier(1.)
+            .with_jitter();
+        for _ in 0..9 {
+            let backoff = policy.backoff(&TwirpError::unavailable("down")).unwrap();
+            assert!(
+                Duration::from_secs(4) <= backoff && backoff <= Duration::from_secs(8),
+                "{backoff:?}"
+            );
+        }
+    }
+
+    async fn call_client_failing(
+        policy: TwirpRetryPolicy,
+        failures: u32,
+        error: TwirpError,
+    ) -> (Result<Timestamp, TwirpError>, u32) {
+        let calls = Arc::new(AtomicU32::new(0));
+        let service = tower::service_fn({
+            let calls = calls.clone();
+            move |request: Request<TwirpRequestBody>| {
+                let calls = calls.clone();
+                let error = error.clone();
+                async move {
+                    assert_eq!(request.uri(), "/foo");
+                    let response = if calls.fetch_add(1, Ordering::Relaxed) < failures {
+                        error.into()
+                    } else {
+                        Response::builder()
+                            .header(CONTENT_TYPE, "application/json")
+                            .body("\"1970-01-01T00:00:10Z\"".to_string())
+                            .unwrap()
+                    };
+                    Ok::<Response<String>, TwirpError>(response)
+                }
+            }
+        });
+        let client = TwirpHttpClient::new(service)
+            .with_json()
+            .with_layer(RetryLayer::new(policy));
+        let result = client
+            .call::<_, Timestamp>("/foo", &Timestamp::default())
+            .await;
+        (result, calls.load(Ordering::Relaxed))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn layer_retries_until_success() {
+        let policy = TwirpRetryPolicy::new(4, TwirpRetryPolicy::is_transient)
+            .with_initial_backoff(Duration::from_secs(1));
+        let start = Instant::now();
+        let (result, calls) =
+            call_client_failing(policy, 3, TwirpError::deadline_exceeded("too slow")).await;
+        assert_eq!(
+            result.unwrap(),
+            Timestamp {
+                seconds: 10,
+                nanos: 0
+            }
+        );
+        assert_eq!(calls, 4);
+        assert_eq!(start.elapsed(), Duration::from_secs(7));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn layer_stops_after_max_attempts() {
+        let policy = TwirpRetryPolicy::new(3, TwirpRetryPolicy::is_transient);
+        let (result, calls) = call_client_failing(policy, 5, TwirpError::unavailable("down")).await;
+        assert_eq!(result.unwrap_err(), TwirpError::unavailable("down"));
+        assert_eq!(calls, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn layer_does_not_retry_non_retryable() {
+        let policy = TwirpRetryPolicy::new(3, TwirpRetryPolicy::is_transient);
+        let (result, calls) = call_client_failing(policy, 1, TwirpError::not_found("nope")).await;
+        assert_eq!(result.unwrap_err(), TwirpError::not_found("nope"));
+        assert_eq!(calls, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn layer_respects_retry_after_header() {
+        let policy = TwirpRetryPolicy::new(3, TwirpRetryPolicy::is_transient)
+            .with_initial_backoff(Duration::from_secs(3600))
+            .respect_retry_after();
+        let error =
+            TwirpError::resource_exhausted("slow down").with_retry_after(Duration::from_secs(5));
+        let start = Instant::now();
+        let (result, calls) = call_client_failing(policy, 2, error).await;
+        assert!(result.is_ok());
+        assert_eq!(calls, 3);
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn layer_does_not_buffer_successful_responses() {
+        /// Body of a response that never ends, e.g. a long stream
+        struct EndlessBody;
+
+        impl Body for EndlessBody {
+            type Data = Bytes;
+            type Error = TwirpError;
+
+            fn poll_frame(
+                self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+            ) -> Poll<Option<Result<http_body::Frame<Bytes>, TwirpError>>> {
+                Poll::Pending
+            }
+        }
+
+        let service = RetryLayer::new(TwirpRetryPolicy::new(3, TwirpRetryPolicy::is_transient))
+            .layer(tower::service_fn(|_: Request<TwirpRequestBody>| async {
+                Ok::<_, TwirpError>(Response::new(EndlessBody))
+            }));
+        let response = tokio::time::timeout(
+            Duration::from_secs(1),
+            service.oneshot(Request::new(TwirpRequestBody::from(Bytes::new()))),
+        )
+        .await
+        .expect("the response body must not be collected")
+        .unwrap();
+        assert!(response.status().is_success());
+    }
+}
@@ -9,7 +9,23 @@
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
+use std::str::FromStr;
+use std::string::FromUtf8Error;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+const RETRY_AFTER_META: &str = "retry_after";
+/// gRPC metadata holding the Twirp error code, for the codes without an exact gRPC equivalent
+#[cfg(feature = "tonic-014")]
+const TWIRP_CODE_METADATA: &str = "twirp-code";
+/// Prefix of the gRPC metadata holding the Twirp error metadata
+#[cfg(feature = "tonic-014")]
+const TWIRP_META_METADATA_PREFIX: &str = "twirp-meta-";
+/// Binary gRPC metadata holding as a JSON object the Twirp error metadata
+/// that can't be sent unchanged in `twirp-meta-<key>` metadata
+#[cfg(feature = "tonic-014")]
+const TWIRP_META_BIN_METADATA: &str = "twirp-meta-bin";
 
 /// A Twirp [error](https://twitchtv.github.io/twirp/docs/spec_v7.html#errors)
 ///
@@ -25,7 +41,7 @@ use std::sync::Arc;
 /// assert_eq!(error.message(), "Object foo not found");
 /// assert_eq!(error.meta("id"), Some("foo"));
 /// ```
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TwirpError {
     /// The [error code](https://twitchtv.github.io/twirp/docs/spec_v7.html#error-codes)
@@ -40,6 +56,10 @@ pub struct TwirpError {
     meta: HashMap<String, String>,
     #[cfg_attr(feature = "serde", serde(default, skip))]
     source: Option<Arc<dyn Error + Send + Sync>>,
+    /// The full `google.rpc.Status` to send to gRPC clients
+    #[cfg(feature = "tonic-014")]
+    #[cfg_attr(feature = "serde", serde(default, skip))]
+    grpc_status_details: Option<Box<tonic_types_014::Status>>,
 }
 
 impl TwirpError {
@@ -48,6 +68,17 @@ impl TwirpError {
         self.code
     }
 
+    /// Checks if both errors have the same code, ignoring the message and metadata.
+    ///
+    /// ```
+    /// # use twurst_error::TwirpError;
+    /// assert!(TwirpError::not_found("foo").code_eq(&TwirpError::not_found("bar")));
+    /// ```
+    #[inline]
+    pub fn code_eq(&self, other: &TwirpError) -> bool {
+        self.code == other.code
+    }
+
     #[inline]
     pub fn message(&self) -> &str {
         &self.msg
@@ -77,6 +108,8 @@ impl TwirpError {
             msg: msg.into(),
             meta: HashMap::new(),
             source: None,
+            #[cfg(feature = "tonic-014")]
+            grpc_status_details: None,
         }
     }
 
@@ -91,6 +124,8 @@ impl TwirpError {
             msg: msg.into(),
             meta: HashMap::new(),
             source: Some(Arc::new(e)),
+            #[cfg(feature = "tonic-014")]
+            grpc_status_details: None,
         }
     }
 
@@ -101,6 +136,189 @@ impl TwirpError {
         self
     }
 
+    /// Sets after how long the client might retry the request, stored in the `retry_after` metadata as seconds.
+    ///
+    /// Sub-second durations are rounded up.
+    /// It is also sent in the `Retry-After` HTTP header if the error is converted to an HTTP response.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # use twurst_error::TwirpError;
+    /// let error = TwirpError::resource_exhausted("Too many requests")
+    ///     .with_retry_after(Duration::from_secs(10));
+    /// assert_eq!(error.meta("retry_after"), Some("10"));
+    /// assert_eq!(error.retry_after(), Some(Duration::from_secs(10)));
+    /// ```
+    #[inline]
+    pub fn with_retry_after(self, retry_after: Duration) -> Self {
+        let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        self.with_meta(RETRY_AFTER_META, seconds.to_string())
+    }
+
+    /// After how long the client might retry the request, read from the `retry_after` metadata
+    #[inline]
+    pub fn retry_after(&self) -> Option<Duration> {
+        Some(Duration::from_secs(
+            self.meta(RETRY_AFTER_META)?.parse().ok()?,
+        ))
+    }
+
+    /// Error describing the failed items of a batch, given with their index in the batch.
+    ///
+    /// The code is the most frequent one among the failures (the first one to occur in case of a tie).
+    /// The indexes of the failed items are stored in the `failed_items` metadata as a comma-separated list
+    /// and each failure is stored in the `item.<index>` metadata as `<code>: <message>`.
+    /// If there is no failure, the code is `unknown`.
+    ///
+    /// ```
+    /// # use twurst_error::{TwirpError, TwirpErrorCode};
+    /// let error = TwirpError::batch_errors([
+    ///     (0, &TwirpError::not_found("foo not found")),
+    ///     (2, &TwirpError::invalid_argument("bar is invalid")),
+    ///     (3, &TwirpError::invalid_argument("baz is invalid")),
+    /// ]);
+    /// assert_eq!(error.code(), TwirpErrorCode::InvalidArgument);
+    /// assert_eq!(error.message(), "3 batch items failed");
+    /// assert_eq!(error.meta("failed_items"), Some("0,2,3"));
+    /// assert_eq!(error.meta("item.0"), Some("not_found: foo not found"));
+    /// ```
+    pub fn batch_errors<'a>(errors: impl IntoIterator<Item = (usize, &'a TwirpError)>) -> Self {
+        let mut counts = Vec::<(TwirpErrorCode, usize)>::new();
+        let mut indexes = Vec::new();
+        let mut meta = HashMap::new();
+        for (index, error) in errors {
+            match counts.iter_mut().find(|(code, _)| *code == error.code) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((error.code, 1)),
+            }
+            indexes.push(index.to_string());
+            meta.insert(
+                format!("item.{index}"),
+                format!("{}: {}", error.code, error.msg),
+            );
+        }
+        // max_by_key returns the last maximum, we want the first one
+        let code = counts
+            .iter()
+            .rev()
+            .max_by_key(|(_, count)| *count)
+            .map_or(TwirpErrorCode::Unknown, |(code, _)| *code);
+        meta.insert("failed_items".into(), indexes.join(","));
+        let mut error = Self::new(code, format!("{} batch items failed", indexes.len()));
+        error.meta = meta;
+        error
+    }
+
+    /// Returns [`batch_errors`](Self::batch_errors) describing the failed results if there are some.
+    ///
+    /// ```
+    /// # use twurst_error::{TwirpError, TwirpErrorCode};
+    /// let results = [Ok(1), Err(TwirpError::not_found("2 not found")), Ok(3)];
+    /// let error = TwirpError::from_batch_results(&results).unwrap();
+    /// assert_eq!(error.code(), TwirpErrorCode::NotFound);
+    /// assert_eq!(error.meta("failed_items"), Some("1"));
+    /// assert!(TwirpError::from_batch_results(&[Ok::<_, TwirpError>(1)]).is_none());
+    /// ```
+    pub fn from_batch_results<O>(results: &[Result<O, TwirpError>]) -> Option<TwirpError> {
+        let mut errors = results
+            .iter()
+            .enumerate()
+            .filter_map(|(index, result)| Some((index, result.as_ref().err()?)))
+            .peekable();
+        errors.peek()?;
+        Some(Self::batch_errors(errors))
+    }
+
+    /// [JSON Schema](https://json-schema.org/) (draft-07) of the serialized Twirp error object.
+    ///
+    /// Useful to document the error format in API documentations.
+    #[cfg(feature = "schema")]
+    pub fn json_schema() -> serde_json::Value {
+        use TwirpErrorCode::*;
+        let codes = [
+            Canceled,
+            Unknown,
+            InvalidArgument,
+            Malformed,
+            DeadlineExceeded,
+            NotFound,
+            BadRoute,
+            AlreadyExists,
+            PermissionDenied,
+            Unauthenticated,
+            ResourceExhausted,
+            FailedPrecondition,
+            Aborted,
+            OutOfRange,
+            Unimplemented,
+            Internal,
+            Unavailable,
+            Dataloss,
+        ];
+        serde_json::json!({
+            "$schema": "http://json-schema.org/draft-07/schema#",
+            "title": "TwirpError",
+            "description": "A Twirp error, see https://twitchtv.github.io/twirp/docs/spec_v7.html#error-codes",
+            "type": "object",
+            "properties": {
+                "code": {
+                    "description": "The error code",
+                    "type": "string",
+                    "enum": codes.iter().map(|code| code.to_string()).collect::<Vec<_>>(),
+                },
+                "msg": {
+                    "description": "The human readable error message",
+                    "type": "string",
+                },
+                "meta": {
+                    "description": "Some metadata describing the error",
+                    "type": "object",
+                    "additionalProperties": { "type": "string" },
+                },
+            },
+            "required": ["code", "msg"],
+            "additionalProperties": false,
+        })
+    }
+
+    /// Serializes the error to its Twirp JSON object, e.g. to embed it in a larger JSON document.
+    ///
+    /// ```
+    /// use twurst_error::TwirpError;
+    ///
+    /// assert_eq!(
+    ///     TwirpError::not_found("No user").to_json_value(),
+    ///     serde_json::json!({"code": "not_found", "msg": "No user"})
+    /// );
+    /// ```
+    #[cfg(feature = "json")]
+    pub fn to_json_value(&self) -> serde_json::Value {
+        serde_json::to_value(self).expect("Twirp errors are always serializable to JSON")
+    }
+
+    /// Deserializes an error from its Twirp JSON object, the inverse of [`to_json_value`](Self::to_json_value).
+    #[cfg(feature = "json")]
+    pub fn from_json_value(value: serde_json::Value) -> Result<Self, serde_json::Error> {
+        serde_json::from_value(value)
+    }
+
+    /// Attaches a structured [`google.rpc.Status`](tonic_types_014::Status) to the error.
+    ///
+    /// It is sent to gRPC clients in the `grpc-status-details-bin` trailer.
+    #[cfg(feature = "tonic-014")]
+    #[inline]
+    pub fn with_grpc_status_details(mut self, status: tonic_types_014::Status) -> Self {
+        self.grpc_status_details = Some(Box::new(status));
+        self
+    }
+
+    /// The structured [`google.rpc.Status`](tonic_types_014::Status) attached to the error, if any
+    #[cfg(feature = "tonic-014")]
+    #[inline]
+    pub fn grpc_status_details(&self) -> Option<&tonic_types_014::Status> {
+        self.grpc_status_details.as_deref()
+    }
+
     #[inline]
     pub fn aborted(msg: impl Into<String>) -> Self {
         Self::new(TwirpErrorCode::Aborted, msg)
@@ -185,6 +403,124 @@ impl TwirpError {
     pub fn unimplemented(msg: impl Into<String>) -> Self {
         Self::new(TwirpErrorCode::Unimplemented, msg)
     }
+
+    #[inline]
+    pub fn wrap_aborted(msg: impl Into<String>, e: impl Error + Send + Sync + 'static) -> Self {
+        Self::wrap(TwirpErrorCode::Aborted, msg, e)
+    }
+
+    #[inline]
+    pub fn wrap_dataloss(msg: impl Into<String>, e: impl Error + Send + Sync + 'static) -> Self {
+        Self::wrap(TwirpErrorCode::Dataloss, msg, e)
+    }
+
+    #[inline]
+    pub fn wrap_failed_precondition(
+        msg: impl Into<String>,
+        e: impl Error + Send + Sync + 'static,
+    ) -> Self {
+        Self::wrap(TwirpErrorCode::FailedPrecondition, msg, e)
+    }
+
+    #[inline]
+    pub fn wrap_out_of_range(
+        msg: impl Into<String>,
+        e: impl Error + Send + Sync + 'static,
+    ) -> Self {
+        Self::wrap(TwirpErrorCode::OutOfRange, msg, e)
+    }
+
+    #[inline]
+    pub fn wrap_permission_denied(
+        msg: impl Into<String>,
+        e: impl Error + Send + Sync + 'static,
+    ) -> Self {
+        Self::wrap(TwirpErrorCode::PermissionDenied, msg, e)
+    }
+
+    #[inline]
+    pub fn wrap_resource_exhausted(
+        msg: impl Into<String>,
+        e: impl Error + Send + Sync + 'static,
+    ) -> Self {
+        Self::wrap(TwirpErrorCode::ResourceExhausted, msg, e)
+    }
+
+    #[inline]
+    pub fn wrap_unauthenticated(
+        msg: impl Into<String>,
+        e: impl Error + Send + Sync + 'static,
+    ) -> Self {
+        Self::wrap(TwirpErrorCode::Unauthenticated, msg, e)
+    }
+
+    #[inline]
+    pub fn wrap_unavailable(msg: impl Into<String>, e: impl Error + Send + Sync + 'static) -> Self {
+        Self::wrap(TwirpErrorCode::Unavailable, msg, e)
+    }
+}
+
+/// Set with [`TwirpError::set_suppress_source`]
+static SUPPRESS_SOURCE: AtomicBool = AtomicBool::new(false);
+
+impl TwirpError {
+    /// Hides the source errors of all the Twirp errors from [`Error::source`] and [`Debug`](fmt::Debug)
+    /// so that logging the errors does not leak internal details (e.g. a database error).
+    ///
+    /// [`Display`](fmt::Display) only writes the code and the message in all cases.
+    ///
+    /// ```
+    /// # use twurst_error::TwirpError;
+    /// use std::error::Error;
+    ///
+    /// TwirpError::set_suppress_source(true);
+    /// let error = TwirpError::wrap(
+    ///     twurst_error::TwirpErrorCode::Internal,
+    ///     "Failed to load the user",
+    ///     std::io::Error::other("connection to db.internal:5432 failed"),
+    /// );
+    /// assert!(error.source().is_none());
+    /// assert!(!format!("{error:?}").contains("db.internal"));
+    /// # TwirpError::set_suppress_source(false);
+    /// ```
+    pub fn set_suppress_source(suppress: bool) {
+        SUPPRESS_SOURCE.store(suppress, Ordering::Relaxed);
+    }
+
+    /// Removes the source error and, for the `internal`, `unknown` and `dataloss` errors,
+    /// replaces the message with a generic one as it might contain internal details.
+    ///
+    /// The code and the metadata are kept.
+    pub fn suppress_source(mut self) -> Self {
+        self.source = None;
+        #[cfg(feature = "tonic-014")]
+        {
+            self.grpc_status_details = None;
+        }
+        if matches!(
+            self.code,
+            TwirpErrorCode::Internal | TwirpErrorCode::Unknown | TwirpErrorCode::Dataloss
+        ) {
+            self.msg = "Internal error".into();
+        }
+        self
+    }
+}
+
+impl fmt::Debug for TwirpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("TwirpError");
+        debug
+            .field("code", &self.code)
+            .field("msg", &self.msg)
+            .field("meta", &self.meta);
+        if !SUPPRESS_SOURCE.load(Ordering::Relaxed) {
+            debug.field("source", &self.source);
+            #[cfg(feature = "tonic-014")]
+            debug.field("grpc_status_details", &self.grpc_status_details);
+        }
+        debug.finish()
+    }
 }
 
 impl fmt::Display for TwirpError {
@@ -197,6 +533,9 @@ impl fmt::Display for TwirpError {
 impl Error for TwirpError {
     #[inline]
     fn source(&self) -> Option<&(dyn Error + 'static)> {
+        if SUPPRESS_SOURCE.load(Ordering::Relaxed) {
+            return None;
+        }
         Some(self.source.as_ref()?)
     }
 }
@@ -211,7 +550,7 @@ impl PartialEq for TwirpError {
 impl Eq for TwirpError {}
 
 /// A Twirp [error code](https://twitchtv.github.io/twirp/docs/spec_v7.html#error-codes)
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum TwirpErrorCode {
@@ -253,31 +592,207 @@ pub enum TwirpErrorCode {
     Dataloss,
 }
 
-/// Applies the mapping defined in [Twirp spec](https://twitchtv.github.io/twirp/docs/spec_v7.html#error-codes)
+impl TwirpErrorCode {
+    /// How serious an error with this code is, e.g. to choose the level to log it at.
+    ///
+    /// Errors caused by the client (e.g. `not_found` or `invalid_argument`) are [`Severity::Info`],
+    /// errors that might be worth investigating (e.g. `permission_denied` or `deadline_exceeded`) [`Severity::Warn`],
+    /// server failures (`internal`, `unknown` and `unavailable`) [`Severity::Error`]
+    /// and `dataloss` [`Severity::Critical`].
+    ///
+    /// ```
+    /// # use twurst_error::{Severity, TwirpErrorCode};
+    /// assert_eq!(TwirpErrorCode::NotFound.severity(), Severity::Info);
+    /// assert_eq!(TwirpErrorCode::Dataloss.severity(), Severity::Critical);
+    /// ```
+    pub fn severity(self) -> Severity {
+        match self {
+            Self::Canceled => Severity::Debug,
+            Self::InvalidArgument
+            | Self::Malformed
+            | Self::NotFound
+            | Self::BadRoute
+            | Self::AlreadyExists
+            | Self::FailedPrecondition
+            | Self::Aborted
+            | Self::OutOfRange => Severity::Info,
+            Self::DeadlineExceeded
+            | Self::PermissionDenied
+            | Self::Unauthenticated
+            | Self::ResourceExhausted
+            | Self::Unimplemented => Severity::Warn,
+            Self::Unknown | Self::Internal | Self::Unavailable => Severity::Error,
+            Self::Dataloss => Severity::Critical,
+        }
+    }
+
+    /// HTTP status code of the responses with this error code,
+    /// following the mapping defined in the [Twirp spec](https://twitchtv.github.io/twirp/docs/spec_v7.html#error-codes).
+    ///
+    /// ```
+    /// # use twurst_error::TwirpErrorCode;
+    /// assert_eq!(TwirpErrorCode::NotFound.to_http_status(), 404);
+    /// ```
+    pub fn to_http_status(self) -> u16 {
+        match self {
+            Self::Canceled => 408,
+            Self::Unknown => 500,
+            Self::InvalidArgument => 400,
+            Self::Malformed => 400,
+            Self::DeadlineExceeded => 408,
+            Self::NotFound => 404,
+            Self::BadRoute => 404,
+            Self::AlreadyExists => 409,
+            Self::PermissionDenied => 403,
+            Self::Unauthenticated => 401,
+            Self::ResourceExhausted => 429,
+            Self::FailedPrecondition => 412,
+            Self::Aborted => 409,
+            Self::OutOfRange => 400,
+            Self::Unimplemented => 501,
+            Self::Internal => 500,
+            Self::Unavailable => 503,
+            Self::Dataloss => 500,
+        }
+    }
+
+    /// Error code for an HTTP status code, the reverse of [`to_http_status`](Self::to_http_status).
+    ///
+    /// When several codes share the same status, the first one of the Twirp spec table is returned
+    /// (e.g. `invalid_argument` for `400`), except for `500` that gives `internal` like the statuses
+    /// that are not in the table and for `408` that gives `deadline_exceeded`.
+    ///
+    /// It is not used for the responses that are not Twirp errors (e.g. returned by a proxy),
+    /// their codes are only guessed from the status class (e.g. `malformed` for `400`).
+    ///
+    /// ```
+    /// # use twurst_error::TwirpErrorCode;
+    /// assert_eq!(TwirpErrorCode::from_http_status(404), TwirpErrorCode::NotFound);
+    /// assert_eq!(TwirpErrorCode::from_http_status(408), TwirpErrorCode::DeadlineExceeded);
+    /// assert_eq!(TwirpErrorCode::from_http_status(418), TwirpErrorCode::Internal);
+    /// ```
+    pub fn from_http_status(status: u16) -> Self {
+        match status {
+            408 => Self::DeadlineExceeded,
+            400 => Self::InvalidArgument,
+            404 => Self::NotFound,
+            409 => Self::AlreadyExists,
+            403 => Self::PermissionDenied,
+            401 => Self::Unauthenticated,
+            429 => Self::ResourceExhausted,
+            412 => Self::FailedPrecondition,
+            501 => Self::Unimplemented,
+            503 => Self::Unavailable,
+            _ => Self::Internal,
+        }
+    }
+}
+
+/// Parses the code from the Twirp wire format (e.g. `not_found`)
+///
+/// ```
+/// # use twurst_error::TwirpErrorCode;
+/// assert_eq!("not_found".parse(), Ok(TwirpErrorCode::NotFound));
+/// assert!("NotFound".parse::<TwirpErrorCode>().is_err());
+/// ```
+impl FromStr for TwirpErrorCode {
+    type Err = ParseTwirpErrorCodeError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Ok(match name {
+            "canceled" => Self::Canceled,
+            "unknown" => Self::Unknown,
+            "invalid_argument" => Self::InvalidArgument,
+            "malformed" => Self::Malformed,
+            "deadline_exceeded" => Self::DeadlineExceeded,
+            "not_found" => Self::NotFound,
+            "bad_route" => Self::BadRoute,
+            "already_exists" => Self::AlreadyExists,
+            "permission_denied" => Self::PermissionDenied,
+            "unauthenticated" => Self::Unauthenticated,
+            "resource_exhausted" => Self::ResourceExhausted,
+            "failed_precondition" => Self::FailedPrecondition,
+            "aborted" => Self::Aborted,
+            "out_of_range" => Self::OutOfRange,
+            "unimplemented" => Self::Unimplemented,
+            "internal" => Self::Internal,
+            "unavailable" => Self::Unavailable,
+            "dataloss" => Self::Dataloss,
+            _ => return Err(ParseTwirpErrorCodeError(name.into())),
+        })
+    }
+}
+
+/// Error returned when parsing a string that is not a [`TwirpErrorCode`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseTwirpErrorCodeError(String);
+
+impl fmt::Display for ParseTwirpErrorCodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is not a Twirp error code", self.0)
+    }
+}
+
+impl Error for ParseTwirpErrorCodeError {}
+
+/// Severity of an error, see [`TwirpErrorCode::severity`]
+///
+/// The levels are ordered from the least to the most serious.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Severity {
+    Debug,
+    Info,
+    Warn,
+    Error,
+    Critical,
+}
+
+/// Writes the level in uppercase (e.g. `WARN`)
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Debug => "DEBUG",
+            Self::Info => "INFO",
+            Self::Warn => "WARN",
+            Self::Error => "ERROR",
+            Self::Critical => "CRITICAL",
+        })
+    }
+}
+
+/// Writes the code as in the Twirp wire format (e.g. `not_found`)
+impl fmt::Display for TwirpErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Canceled => "canceled",
+            Self::Unknown => "unknown",
+            Self::InvalidArgument => "invalid_argument",
+            Self::Malformed => "malformed",
+            Self::DeadlineExceeded => "deadline_exceeded",
+            Self::NotFound => "not_found",
+            Self::BadRoute => "bad_route",
+            Self::AlreadyExists => "already_exists",
+            Self::PermissionDenied => "permission_denied",
+            Self::Unauthenticated => "unauthenticated",
+            Self::ResourceExhausted => "resource_exhausted",
+            Self::FailedPrecondition => "failed_precondition",
+            Self::Aborted => "aborted",
+            Self::OutOfRange => "out_of_range",
+            Self::Unimplemented => "unimplemented",
+            Self::Internal => "internal",
+            Self::Unavailable => "unavailable",
+            Self::Dataloss => "dataloss",
+        })
+    }
+}
+
+/// Applies the mapping defined in [Twirp spec](https://twitchtv.github.io/twirp/docs/spec_v7.html#error-codes),
+/// see [`TwirpErrorCode::to_http_status`]
 #[cfg(feature = "http")]
 impl From<TwirpErrorCode> for http::StatusCode {
     #[inline]
     fn from(code: TwirpErrorCode) -> Self {
-        match code {
-            TwirpErrorCode::Canceled => Self::REQUEST_TIMEOUT,
-            TwirpErrorCode::Unknown => Self::INTERNAL_SERVER_ERROR,
-            TwirpErrorCode::InvalidArgument => Self::BAD_REQUEST,
-            TwirpErrorCode::Malformed => Self::BAD_REQUEST,
-            TwirpErrorCode::DeadlineExceeded => Self::REQUEST_TIMEOUT,
-            TwirpErrorCode::NotFound => Self::NOT_FOUND,
-            TwirpErrorCode::BadRoute => Self::NOT_FOUND,
-            TwirpErrorCode::AlreadyExists => Self::CONFLICT,
-            TwirpErrorCode::PermissionDenied => Self::FORBIDDEN,
-            TwirpErrorCode::Unauthenticated => Self::UNAUTHORIZED,
-            TwirpErrorCode::ResourceExhausted => Self::TOO_MANY_REQUESTS,
-            TwirpErrorCode::FailedPrecondition => Self::PRECONDITION_FAILED,
-            TwirpErrorCode::Aborted => Self::CONFLICT,
-            TwirpErrorCode::OutOfRange => Self::BAD_REQUEST,
-            TwirpErrorCode::Unimplemented => Self::NOT_IMPLEMENTED,
-            TwirpErrorCode::Internal => Self::INTERNAL_SERVER_ERROR,
-            TwirpErrorCode::Unavailable => Self::SERVICE_UNAVAILABLE,
-            TwirpErrorCode::Dataloss => Self::SERVICE_UNAVAILABLE,
-        }
+        Self::from_u16(code.to_http_status()).expect("Twirp status codes are valid")
     }
 }
 
@@ -285,12 +800,13 @@ impl From<TwirpErrorCode> for http::StatusCode {
 impl<B: From<String>> From<TwirpError> for http::Response<B> {
     fn from(error: TwirpError) -> Self {
         let json = serde_json::to_string(&error).unwrap();
-        http::Response::builder()
+        let mut builder = http::Response::builder()
             .status(error.code)
-            .header(http::header::CONTENT_TYPE, "application/json")
-            .extension(error)
-            .body(json.into())
-            .unwrap()
+            .header(http::header::CONTENT_TYPE, "application/json");
+        if let Some(retry_after) = error.retry_after() {
+            builder = builder.header(http::header::RETRY_AFTER, retry_after.as_secs());
+        }
+        builder.extension(error).body(json.into()).unwrap()
     }
 }
 
@@ -303,7 +819,24 @@ impl<B: AsRef<[u8]>> From<http::Response<B>> for TwirpError {
         }
         // We are lenient here, a bad error is better than no error at all
         let status = response.status();
-        let body = response.into_body();
+        // Only the delay-seconds form of Retry-After is supported
+        let retry_after = response
+            .headers()
+            .get(http::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok()?.trim().parse::<u64>().ok());
+        let error = TwirpError::from_response_body(status, response.into_body());
+        match retry_after {
+            Some(seconds) if error.retry_after().is_none() => {
+                error.with_retry_after(Duration::from_secs(seconds))
+            }
+            _ => error,
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+impl TwirpError {
+    fn from_response_body(status: http::StatusCode, body: impl AsRef<[u8]>) -> Self {
         if let Ok(error) = serde_json::from_slice::<TwirpError>(body.as_ref()) {
             // The body is an error, we use it
             return error;
@@ -375,19 +908,72 @@ impl From<TwirpErrorCode> for tonic_014::Code {
     }
 }
 
+/// The Twirp code and metadata are sent in the `twirp-code` and `twirp-meta-<key>` gRPC metadata
+/// so that the conversion back to a Twirp error is lossless.
+/// The metadata whose key is not a lowercase gRPC metadata key or whose value is not printable ASCII
+/// are sent together as a JSON object in the binary `twirp-meta-bin` metadata.
 #[cfg(feature = "tonic-014")]
 impl From<TwirpError> for tonic_014::Status {
     #[inline]
     fn from(error: TwirpError) -> Self {
-        if let Some(source) = &error.source {
-            if let Some(status) = source.downcast_ref::<tonic_014::Status>() {
-                if status.code() == error.code().into() && status.message() == error.message() {
+        let mut status = if let Some(details) = &error.grpc_status_details {
+            Self::with_details(
+                error.code().into(),
+                error.message(),
+                prost::Message::encode_to_vec(details.as_ref()).into(),
+            )
+        } else {
+            match error
+                .source
+                .as_ref()
+                .and_then(|source| source.downcast_ref::<tonic_014::Status>())
+            {
+                Some(status)
+                    if status.code() == error.code().into()
+                        && status.message() == error.message() =>
+                {
                     // This is a status wrapped as a Twirp error, we reuse the status to keep the details
-                    return status.clone();
+                    status.clone()
                 }
+                _ => Self::new(error.code().into(), error.message()),
+            }
+        };
+        let metadata = status.metadata_mut();
+        if TwirpErrorCode::from(tonic_014::Code::from(error.code)) != error.code {
+            if let Ok(code) = error.code.to_string().parse() {
+                metadata.insert(TWIRP_CODE_METADATA, code);
             }
         }
-        Self::new(error.code().into(), error.into_message())
+        let mut binary_meta = HashMap::new();
+        for (key, value) in &error.meta {
+            let metadata_key = format!("{TWIRP_META_METADATA_PREFIX}{key}");
+            let entry = tonic_014::metadata::AsciiMetadataKey::from_bytes(metadata_key.as_bytes())
+                .ok()
+                .filter(|ascii_key| ascii_key.as_str() == metadata_key)
+                .zip(
+                    value
+                        .parse::<tonic_014::metadata::AsciiMetadataValue>()
+                        .ok()
+                        .filter(|ascii_value| ascii_value.to_str().ok() == Some(value.as_str())),
+                );
+            match entry {
+                Some((key, value)) => {
+                    metadata.insert(key, value);
+                }
+                None => {
+                    binary_meta.insert(key, value);
+                }
+            }
+        }
+        if !binary_meta.is_empty() {
+            if let Ok(json) = serde_json::to_vec(&binary_meta) {
+                metadata.insert_bin(
+                    TWIRP_META_BIN_METADATA,
+                    tonic_014::metadata::BinaryMetadataValue::from_bytes(&json),
+                );
+            }
+        }
+        status
     }
 }
 
@@ -421,15 +1007,342 @@ impl From<tonic_014::Code> for TwirpErrorCode {
 impl From<tonic_014::Status> for TwirpError {
     #[inline]
     fn from(status: tonic_014::Status) -> TwirpError {
-        Self::wrap(status.code().into(), status.message().to_string(), status)
+        Self::from_tonic_status(status)
+    }
+}
+
+#[cfg(feature = "tonic-014")]
+impl TwirpError {
+    /// Converts a gRPC status to a Twirp error, the reverse of the `From<TwirpError> for Status` conversion.
+    ///
+    /// The structured [`google.rpc.Status`](tonic_types_014::Status) from the status details
+    /// (or from its `grpc-status-details-bin` metadata) is kept in [`grpc_status_details`](Self::grpc_status_details).
+    pub fn from_tonic_status(status: tonic_014::Status) -> TwirpError {
+        let details = if status.details().is_empty() {
+            status
+                .metadata()
+                .get_bin("grpc-status-details-bin")
+                .and_then(|value| value.to_bytes().ok())
+        } else {
+            Some(status.details().to_vec().into())
+        };
+        let details = details.and_then(|details| {
+            <tonic_types_014::Status as prost::Message>::decode(details.as_ref()).ok()
+        });
+        let metadata = status.metadata();
+        let code = metadata
+            .get(TWIRP_CODE_METADATA)
+            .and_then(|code| code.to_str().ok()?.parse().ok())
+            .filter(|code| tonic_014::Code::from(*code) == status.code())
+            .unwrap_or_else(|| status.code().into());
+        let mut meta: HashMap<String, String> = metadata
+            .iter()
+            .filter_map(|entry| {
+                let tonic_014::metadata::KeyAndValueRef::Ascii(key, value) = entry else {
+                    return None;
+                };
+                let key = key.as_str().strip_prefix(TWIRP_META_METADATA_PREFIX)?;
+                Some((key.to_string(), value.to_str().ok()?.to_string()))
+            })
+            .collect();
+        if let Some(binary_meta) = metadata
+            .get_bin(TWIRP_META_BIN_METADATA)
+            .and_then(|value| value.to_bytes().ok())
+            .and_then(|json| serde_json::from_slice::<HashMap<String, String>>(&json).ok())
+        {
+            meta.extend(binary_meta);
+        }
+        let mut error = Self::wrap(code, status.message().to_string(), status);
+        error.grpc_status_details = details.map(Box::new);
+        error.meta = meta;
+        error
+    }
+
+    /// Reads the error from the trailers of a failed gRPC response:
+    /// the `grpc-status` code, the percent-encoded `grpc-message`
+    /// and the base64-encoded `google.rpc.Status` in `grpc-status-details-bin`.
+    ///
+    /// The other trailers are handled like the metadata in [`from_tonic_status`](Self::from_tonic_status).
+    /// If there is no `grpc-status`, an `unknown` error is returned.
+    ///
+    /// ```
+    /// # use http::{HeaderMap, HeaderValue};
+    /// # use twurst_error::TwirpError;
+    /// let mut trailers = HeaderMap::new();
+    /// trailers.insert("grpc-status", HeaderValue::from_static("5"));
+    /// trailers.insert("grpc-message", HeaderValue::from_static("foo%20not%20found"));
+    /// assert_eq!(
+    ///     TwirpError::from_grpc_trailers(&trailers),
+    ///     TwirpError::not_found("foo not found")
+    /// );
+    /// ```
+    pub fn from_grpc_trailers(headers: &http::HeaderMap) -> TwirpError {
+        match tonic_014::Status::from_header_map(headers) {
+            Some(status) => Self::from_tonic_status(status),
+            None => Self::new(
+                TwirpErrorCode::Unknown,
+                "No grpc-status in the gRPC response",
+            ),
+        }
+    }
+}
+
+/// Converts to an `internal` error, formatting only fails if a [`fmt::Display`] implementation is broken.
+impl From<fmt::Error> for TwirpError {
+    #[inline]
+    fn from(error: fmt::Error) -> TwirpError {
+        Self::wrap(TwirpErrorCode::Internal, "Failed to format a string", error)
+    }
+}
+
+/// Converts to a `malformed` error with the invalid byte sequence in the `invalid_utf8_sequence` metadata
+/// as lowercase hexadecimal.
+///
+/// ```
+/// # use twurst_error::{TwirpError, TwirpErrorCode};
+/// let error = TwirpError::from(String::from_utf8(b"a\xff\xfeb".to_vec()).unwrap_err());
+/// assert_eq!(error.code(), TwirpErrorCode::Malformed);
+/// assert_eq!(error.meta("invalid_utf8_sequence"), Some("ff"));
+/// ```
+impl From<FromUtf8Error> for TwirpError {
+    fn from(error: FromUtf8Error) -> TwirpError {
+        let utf8_error = error.utf8_error();
+        let start = utf8_error.valid_up_to();
+        let end = utf8_error
+            .error_len()
+            .map_or(error.as_bytes().len(), |len| start + len);
+        let sequence = error.as_bytes()[start..end]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        Self::wrap(
+            TwirpErrorCode::Malformed,
+            "invalid UTF-8 in request data",
+            error,
+        )
+        .with_meta("invalid_utf8_sequence", sequence)
+    }
+}
+
+/// Converts a boxed error to an `internal` error keeping it as the error source
+impl From<Box<dyn Error + Send + Sync>> for TwirpError {
+    fn from(error: Box<dyn Error + Send + Sync>) -> TwirpError {
+        Self {
+            source: Some(Arc::from(error)),
+            ..Self::internal("Internal error")
+        }
+    }
+}
+
+/// Converts an [`anyhow`](https://docs.rs/anyhow/1) error to an `internal` error keeping it as the error source
+#[cfg(feature = "anyhow-1")]
+impl From<anyhow_1::Error> for TwirpError {
+    #[inline]
+    fn from(error: anyhow_1::Error) -> TwirpError {
+        Box::<dyn Error + Send + Sync>::from(error).into()
+    }
+}
+
+#[cfg(feature = "csv-1")]
+impl TwirpError {
+    /// Converts a CSV error: I/O errors become `internal` errors and the other ones `malformed` errors.
+    ///
+    /// ```
+    /// # use twurst_error::{TwirpError, TwirpErrorCode};
+    /// let mut reader = csv_1::Reader::from_reader("a,b\n1\n".as_bytes());
+    /// let error = reader.records().next().unwrap().unwrap_err();
+    /// assert_eq!(TwirpError::from_csv_error(error).code(), TwirpErrorCode::Malformed);
+    /// ```
+    pub fn from_csv_error(error: csv_1::Error) -> Self {
+        let (code, message) = if error.is_io_error() {
+            (TwirpErrorCode::Internal, "I/O error while reading CSV")
+        } else {
+            (TwirpErrorCode::Malformed, "Invalid CSV")
+        };
+        Self::wrap(code, format!("{message}: {error}"), error)
+    }
+}
+
+#[cfg(feature = "csv-1")]
+impl From<csv_1::Error> for TwirpError {
+    #[inline]
+    fn from(error: csv_1::Error) -> TwirpError {
+        Self::from_csv_error(error)
+    }
+}
+
+#[cfg(feature = "serde-yaml-09")]
+impl TwirpError {
+    /// Converts a YAML error to a `malformed` error.
+    ///
+    /// `serde_yaml` does not tell apart the I/O errors so they are `malformed` errors too.
+    pub fn from_yaml_error(error: serde_yaml_09::Error) -> Self {
+        Self::wrap(
+            TwirpErrorCode::Malformed,
+            format!("Invalid YAML: {error}"),
+            error,
+        )
+    }
+}
+
+#[cfg(feature = "serde-yaml-09")]
+impl From<serde_yaml_09::Error> for TwirpError {
+    #[inline]
+    fn from(error: serde_yaml_09::Error) -> TwirpError {
+        Self::from_yaml_error(error)
+    }
+}
+
+#[cfg(feature = "sqlx-08")]
+impl TwirpError {
+    /// Converts a [`sqlx`](https://docs.rs/sqlx/0.8) error:
+    /// - `RowNotFound` becomes a `not_found` error,
+    /// - unique constraint violations become `already_exists` errors,
+    /// - foreign key constraint violations become `failed_precondition` errors,
+    /// - check constraint violations become `invalid_argument` errors,
+    /// - connection errors (I/O, TLS, pool timeout or closed) become `unavailable` errors,
+    /// - the other errors become `internal` errors.
+    ///
+    /// The database error message is not sent to the client, it is only kept as the error source.
+    ///
+    /// ```
+    /// # use twurst_error::{TwirpError, TwirpErrorCode};
+    /// let error = TwirpError::from_sqlx_error(sqlx_08::Error::RowNotFound);
+    /// assert_eq!(error.code(), TwirpErrorCode::NotFound);
+    /// ```
+    pub fn from_sqlx_error(error: sqlx_08::Error) -> Self {
+        let (code, message) = match &error {
+            sqlx_08::Error::RowNotFound => (TwirpErrorCode::NotFound, "Not found"),
+            sqlx_08::Error::Database(e) if e.is_unique_violation() => {
+                (TwirpErrorCode::AlreadyExists, "Already exists")
+            }
+            sqlx_08::Error::Database(e) if e.is_foreign_key_violation() => (
+                TwirpErrorCode::FailedPrecondition,
+                "A referenced entity does not exist",
+            ),
+            sqlx_08::Error::Database(e) if e.is_check_violation() => {
+                (TwirpErrorCode::InvalidArgument, "Invalid value")
+            }
+            sqlx_08::Error::Io(_)
+            | sqlx_08::Error::Tls(_)
+            | sqlx_08::Error::PoolTimedOut
+            | sqlx_08::Error::PoolClosed => {
+                (TwirpErrorCode::Unavailable, "The database is unavailable")
+            }
+            _ => (TwirpErrorCode::Internal, "Database error"),
+        };
+        Self::wrap(code, message, error)
+    }
+}
+
+#[cfg(feature = "sqlx-08")]
+impl From<sqlx_08::Error> for TwirpError {
+    #[inline]
+    fn from(error: sqlx_08::Error) -> TwirpError {
+        Self::from_sqlx_error(error)
+    }
+}
+
+#[cfg(feature = "redis-1")]
+impl TwirpError {
+    /// Converts a [`redis`](https://docs.rs/redis/1) error:
+    /// - I/O errors become `unavailable` errors,
+    /// - authentication failures become `permission_denied` errors,
+    /// - the other errors become `internal` errors.
+    ///
+    /// The Redis error message is not sent to the client, it is only kept as the error source.
+    ///
+    /// ```
+    /// # use twurst_error::{TwirpError, TwirpErrorCode};
+    /// let error = redis_1::RedisError::from(std::io::Error::other("connection reset"));
+    /// assert_eq!(TwirpError::from_redis_error(error).code(), TwirpErrorCode::Unavailable);
+    /// ```
+    pub fn from_redis_error(error: redis_1::RedisError) -> Self {
+        let (code, message) = match error.kind() {
+            redis_1::ErrorKind::Io => (TwirpErrorCode::Unavailable, "Redis is unavailable"),
+            redis_1::ErrorKind::AuthenticationFailed => (
+                TwirpErrorCode::PermissionDenied,
+                "Redis authentication failed",
+            ),
+            _ => (TwirpErrorCode::Internal, "Redis error"),
+        };
+        Self::wrap(code, message, error)
+    }
+}
+
+#[cfg(feature = "redis-1")]
+impl From<redis_1::RedisError> for TwirpError {
+    #[inline]
+    fn from(error: redis_1::RedisError) -> TwirpError {
+        Self::from_redis_error(error)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashSet;
     #[cfg(feature = "http")]
     use std::error::Error;
+    use std::fmt::Write;
+
+    const ALL_CODES: [TwirpErrorCode; 18] = [
+        TwirpErrorCode::Canceled,
+        TwirpErrorCode::Unknown,
+        TwirpErrorCode::InvalidArgument,
+        TwirpErrorCode::Malformed,
+        TwirpErrorCode::DeadlineExceeded,
+        TwirpErrorCode::NotFound,
+        TwirpErrorCode::BadRoute,
+        TwirpErrorCode::AlreadyExists,
+        TwirpErrorCode::PermissionDenied,
+        TwirpErrorCode::Unauthenticated,
+        TwirpErrorCode::ResourceExhausted,
+        TwirpErrorCode::FailedPrecondition,
+        TwirpErrorCode::Aborted,
+        TwirpErrorCode::OutOfRange,
+        TwirpErrorCode::Unimplemented,
+        TwirpErrorCode::Internal,
+        TwirpErrorCode::Unavailable,
+        TwirpErrorCode::Dataloss,
+    ];
+
+    #[test]
+    fn test_severity() {
+        assert_eq!(TwirpErrorCode::Canceled.severity(), Severity::Debug);
+        assert_eq!(TwirpErrorCode::NotFound.severity(), Severity::Info);
+        assert_eq!(TwirpErrorCode::PermissionDenied.severity(), Severity::Warn);
+        assert_eq!(TwirpErrorCode::Internal.severity(), Severity::Error);
+        assert_eq!(TwirpErrorCode::Dataloss.severity(), Severity::Critical);
+        assert!(Severity::Warn < Severity::Error);
+        assert_eq!(Severity::Critical.to_string(), "CRITICAL");
+    }
+
+    #[test]
+    fn test_from_fmt_error() {
+        struct Broken;
+
+        impl fmt::Display for Broken {
+            fn fmt(&self, _: &mut fmt::Formatter<'_>) -> fmt::Result {
+                Err(fmt::Error)
+            }
+        }
+
+        let error = TwirpError::from(write!(String::new(), "{Broken}").unwrap_err());
+        assert_eq!(error.code(), TwirpErrorCode::Internal);
+        assert!(error.source().is_some());
+    }
+
+    #[test]
+    fn test_from_utf8_error() {
+        let error = TwirpError::from(String::from_utf8(b"ab\xe2\x82".to_vec()).unwrap_err());
+        assert_eq!(
+            error,
+            TwirpError::malformed("invalid UTF-8 in request data")
+                .with_meta("invalid_utf8_sequence", "e282")
+        );
+        assert!(error.source().is_some());
+    }
 
     #[test]
     fn test_accessors() {
@@ -439,6 +1352,409 @@ mod tests {
         assert_eq!(error.meta("foo"), Some("bar"));
     }
 
+    #[test]
+    fn test_batch_errors_dominant_code() {
+        let results = [
+            Err(TwirpError::not_found("a")),
+            Ok(()),
+            Err(TwirpError::invalid_argument("b")),
+            Err(TwirpError::invalid_argument("c")),
+            Err(TwirpError::not_found("d")),
+            Err(TwirpError::internal("e")),
+        ];
+        let error = TwirpError::from_batch_results(&results).unwrap();
+        // Tie between not_found and invalid_argument: the first one wins
+        assert_eq!(error.code(), TwirpErrorCode::NotFound);
+        assert_eq!(error.message(), "5 batch items failed");
+        assert_eq!(error.meta("failed_items"), Some("0,2,3,4,5"));
+        assert_eq!(error.meta("item.1"), None);
+        assert_eq!(error.meta("item.3"), Some("invalid_argument: c"));
+
+        let error = TwirpError::from_batch_results(&results[1..]).unwrap();
+        assert_eq!(error.code(), TwirpErrorCode::InvalidArgument);
+        assert_eq!(error.meta("failed_items"), Some("1,2,3,4"));
+
+        assert!(TwirpError::from_batch_results::<()>(&[]).is_none());
+        assert_eq!(TwirpError::batch_errors([]).code(), TwirpErrorCode::Unknown);
+    }
+
+    #[test]
+    fn test_from_boxed_error() {
+        let error = TwirpError::from(Box::<dyn Error + Send + Sync>::from("oops"));
+        assert_eq!(error, TwirpError::internal("Internal error"));
+        assert_eq!(error.source().unwrap().to_string(), "oops");
+    }
+
+    #[cfg(feature = "anyhow-1")]
+    #[test]
+    fn test_from_anyhow_error() {
+        fn handler() -> Result<(), TwirpError> {
+            Err(anyhow_1::anyhow!("oops").context("while handling"))?;
+            Ok(())
+        }
+
+        let error = handler().unwrap_err();
+        assert_eq!(error.code(), TwirpErrorCode::Internal);
+        assert_eq!(error.message(), "Internal error");
+        let source = error.source().unwrap();
+        assert_eq!(source.to_string(), "while handling");
+        assert_eq!(source.source().unwrap().to_string(), "oops");
+    }
+
+    #[cfg(feature = "csv-1")]
+    #[test]
+    fn test_from_csv_error() {
+        fn parse(input: &[u8]) -> Result<Vec<(String, u32)>, TwirpError> {
+            let mut reader = csv_1::Reader::from_reader(input);
+            Ok(reader.deserialize().collect::<Result<_, _>>()?)
+        }
+
+        assert_eq!(parse(b"name,age\nfoo,1\n").unwrap(), [("foo".into(), 1)]);
+        let error = parse(b"name,age\nfoo,bar\n").unwrap_err();
+        assert_eq!(error.code(), TwirpErrorCode::Malformed);
+        assert!(error.message().starts_with("Invalid CSV: "));
+
+        let error = TwirpError::from(csv_1::Error::from(std::io::Error::other("broken pipe")));
+        assert_eq!(error.code(), TwirpErrorCode::Internal);
+        assert_eq!(error.message(), "I/O error while reading CSV: broken pipe");
+    }
+
+    #[cfg(feature = "serde-yaml-09")]
+    #[test]
+    fn test_from_yaml_error() {
+        fn parse(input: &str) -> Result<HashMap<String, u32>, TwirpError> {
+            Ok(serde_yaml_09::from_str(input)?)
+        }
+
+        assert_eq!(parse("foo: 1").unwrap(), HashMap::from([("foo".into(), 1)]));
+        let error = parse("foo: bar").unwrap_err();
+        assert_eq!(error.code(), TwirpErrorCode::Malformed);
+        assert!(error.message().starts_with("Invalid YAML: "));
+        assert!(error.source().is_some());
+    }
+
+    #[cfg(feature = "redis-1")]
+    #[test]
+    fn test_from_redis_error() {
+        for (error, code) in [
+            (
+                redis_1::RedisError::from(std::io::Error::other("connection reset")),
+                TwirpErrorCode::Unavailable,
+            ),
+            (
+                redis_1::RedisError::from((
+                    redis_1::ErrorKind::AuthenticationFailed,
+                    "Password authentication failed",
+                )),
+                TwirpErrorCode::PermissionDenied,
+            ),
+            (
+                redis_1::RedisError::from((
+                    redis_1::ErrorKind::UnexpectedReturnType,
+                    "Response type not string compatible",
+                )),
+                TwirpErrorCode::Internal,
+            ),
+        ] {
+            let error = TwirpError::from(error);
+            assert_eq!(error.code(), code);
+            assert!(!error.message().contains("connection reset"));
+            assert!(error.source().is_some());
+        }
+    }
+
+    #[cfg(feature = "sqlx-08")]
+    #[test]
+    fn test_from_sqlx_error() {
+        #[derive(Debug)]
+        struct TestDatabaseError(sqlx_08::error::ErrorKind);
+
+        impl fmt::Display for TestDatabaseError {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{:?}", self.0)
+            }
+        }
+
+        impl Error for TestDatabaseError {}
+
+        impl sqlx_08::error::DatabaseError for TestDatabaseError {
+            fn message(&self) -> &str {
+                "constraint violated"
+            }
+
+            fn as_error(&self) -> &(dyn Error + Send + Sync + 'static) {
+                self
+            }
+
+            fn as_error_mut(&mut self) -> &mut (dyn Error + Send + Sync + 'static) {
+                self
+            }
+
+            fn into_error(self: Box<Self>) -> Box<dyn Error + Send + Sync + 'static> {
+                self
+            }
+
+            fn kind(&self) -> sqlx_08::error::ErrorKind {
+                use sqlx_08::error::ErrorKind;
+
+                match self.0 {
+                    ErrorKind::UniqueViolation => ErrorKind::UniqueViolation,
+                    ErrorKind::ForeignKeyViolation => ErrorKind::ForeignKeyViolation,
+                    ErrorKind::CheckViolation => ErrorKind::CheckViolation,
+                    _ => ErrorKind::Other,
+                }
+            }
+        }
+
+        fn query(error: sqlx_08::Error) -> Result<(), TwirpError> {
+            Err(error)?
+        }
+
+        let database_error = |kind| sqlx_08::Error::Database(Box::new(TestDatabaseError(kind)));
+        for (error, code) in [
+            (sqlx_08::Error::RowNotFound, TwirpErrorCode::NotFound),
+            (
+                database_error(sqlx_08::error::ErrorKind::UniqueViolation),
+                TwirpErrorCode::AlreadyExists,
+            ),
+            (
+                database_error(sqlx_08::error::ErrorKind::ForeignKeyViolation),
+                TwirpErrorCode::FailedPrecondition,
+            ),
+            (
+                database_error(sqlx_08::error::ErrorKind::CheckViolation),
+                TwirpErrorCode::InvalidArgument,
+            ),
+            (
+                database_error(sqlx_08::error::ErrorKind::Other),
+                TwirpErrorCode::Internal,
+            ),
+            (
+                std::io::Error::other("connection reset").into(),
+                TwirpErrorCode::Unavailable,
+            ),
+            (sqlx_08::Error::PoolTimedOut, TwirpErrorCode::Unavailable),
+            (
+                sqlx_08::Error::ColumnNotFound("id".into()),
+                TwirpErrorCode::Internal,
+            ),
+        ] {
+            let error = query(error).unwrap_err();
+            assert_eq!(error.code(), code);
+            assert!(error.source().is_some());
+        }
+    }
+
+    #[test]
+    fn test_suppress_source() {
+        let error = TwirpError::wrap(
+            TwirpErrorCode::Internal,
+            "Query failed: relation \"users\" does not exist",
+            std::io::Error::other("relation \"users\" does not exist"),
+        )
+        .with_meta("id", "foo")
+        .suppress_source();
+        assert!(error.source().is_none());
+        assert!(!format!("{error:?}").contains("users"));
+        assert_eq!(
+            error,
+            TwirpError::internal("Internal error").with_meta("id", "foo")
+        );
+
+        // Client errors messages are meant to be seen by the client
+        let error = TwirpError::wrap(
+            TwirpErrorCode::Malformed,
+            "Invalid YAML",
+            std::io::Error::other("bad"),
+        )
+        .suppress_source();
+        assert!(error.source().is_none());
+        assert_eq!(error.message(), "Invalid YAML");
+    }
+
+    #[test]
+    fn test_code_eq() {
+        for (i, left) in ALL_CODES.into_iter().enumerate() {
+            for (j, right) in ALL_CODES.into_iter().enumerate() {
+                assert_eq!(left == right, i == j);
+                assert_eq!(
+                    TwirpError::new(left, "foo").code_eq(&TwirpError::new(right, "bar")),
+                    i == j
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_code_hash() {
+        let set = ALL_CODES.into_iter().collect::<HashSet<_>>();
+        assert_eq!(set.len(), ALL_CODES.len());
+        for code in ALL_CODES {
+            assert!(set.contains(&code));
+        }
+    }
+
+    #[test]
+    fn test_code_display() {
+        let names = ALL_CODES
+            .into_iter()
+            .map(|code| code.to_string())
+            .collect::<HashSet<_>>();
+        assert_eq!(names.len(), ALL_CODES.len());
+        assert_eq!(TwirpErrorCode::NotFound.to_string(), "not_found");
+        assert_eq!(TwirpErrorCode::Dataloss.to_string(), "dataloss");
+    }
+
+    /// The table of https://twitchtv.github.io/twirp/docs/spec_v7.html#error-codes
+    const SPEC_HTTP_STATUSES: [(&str, u16); 18] = [
+        ("canceled", 408),
+        ("unknown", 500),
+        ("invalid_argument", 400),
+        ("malformed", 400),
+        ("deadline_exceeded", 408),
+        ("not_found", 404),
+        ("bad_route", 404),
+        ("already_exists", 409),
+        ("permission_denied", 403),
+        ("unauthenticated", 401),
+        ("resource_exhausted", 429),
+        ("failed_precondition", 412),
+        ("aborted", 409),
+        ("out_of_range", 400),
+        ("unimplemented", 501),
+        ("internal", 500),
+        ("unavailable", 503),
+        ("dataloss", 500),
+    ];
+
+    #[test]
+    fn test_http_status_mapping() {
+        for (name, status) in SPEC_HTTP_STATUSES {
+            let code = name.parse::<TwirpErrorCode>().unwrap();
+            assert_eq!(code.to_string(), name);
+            assert_eq!(code.to_http_status(), status, "{code}");
+            // The reverse mapping returns the first code of the table with the same status
+            let (first_name, _) = SPEC_HTTP_STATUSES
+                .into_iter()
+                .find(|(_, s)| *s == status)
+                .unwrap();
+            let expected = match status {
+                500 => "internal",
+                408 => "deadline_exceeded",
+                _ => first_name,
+            };
+            assert_eq!(
+                TwirpErrorCode::from_http_status(status).to_string(),
+                expected,
+                "{status}"
+            );
+        }
+        assert_eq!(
+            ALL_CODES.map(|code| code.to_string()),
+            SPEC_HTTP_STATUSES.map(|(name, _)| name.to_string())
+        );
+        for status in [200, 302, 418, 502] {
+            assert_eq!(
+                TwirpErrorCode::from_http_status(status),
+                TwirpErrorCode::Internal
+            );
+        }
+        assert_eq!(
+            "Internal".parse::<TwirpErrorCode>(),
+            Err(ParseTwirpErrorCodeError("Internal".into()))
+        );
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_code_display_matches_serde() {
+        for code in ALL_CODES {
+            assert_eq!(serde_json::to_string(&code).unwrap(), format!("\"{code}\""));
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json_value() -> Result<(), Box<dyn Error>> {
+        let error = TwirpError::invalid_argument("Invalid name")
+            .with_meta("field", "name")
+            .with_retry_after(Duration::from_secs(5));
+        let value = error.to_json_value();
+        assert_eq!(value["code"], "invalid_argument");
+        assert_eq!(value["meta"]["field"], "name");
+        assert_eq!(
+            serde_json::from_str::<TwirpError>(&value.to_string())?,
+            error
+        );
+        assert_eq!(TwirpError::from_json_value(value)?, error);
+        assert!(TwirpError::from_json_value(serde_json::json!({"code": "foo"})).is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_constructors_json_code() {
+        for (error, wrapped, code) in [
+            (
+                TwirpError::aborted("error"),
+                TwirpError::wrap_aborted("error", std::io::Error::other("cause")),
+                "aborted",
+            ),
+            (
+                TwirpError::dataloss("error"),
+                TwirpError::wrap_dataloss("error", std::io::Error::other("cause")),
+                "dataloss",
+            ),
+            (
+                TwirpError::failed_precondition("error"),
+                TwirpError::wrap_failed_precondition("error", std::io::Error::other("cause")),
+                "failed_precondition",
+            ),
+            (
+                TwirpError::out_of_range("error"),
+                TwirpError::wrap_out_of_range("error", std::io::Error::other("cause")),
+                "out_of_range",
+            ),
+            (
+                TwirpError::permission_denied("error"),
+                TwirpError::wrap_permission_denied("error", std::io::Error::other("cause")),
+                "permission_denied",
+            ),
+            (
+                TwirpError::resource_exhausted("error"),
+                TwirpError::wrap_resource_exhausted("error", std::io::Error::other("cause")),
+                "resource_exhausted",
+            ),
+            (
+                TwirpError::unauthenticated("error"),
+                TwirpError::wrap_unauthenticated("error", std::io::Error::other("cause")),
+                "unauthenticated",
+            ),
+            (
+                TwirpError::unavailable("error"),
+                TwirpError::wrap_unavailable("error", std::io::Error::other("cause")),
+                "unavailable",
+            ),
+        ] {
+            assert_eq!(error.to_json_value()["code"], code);
+            assert_eq!(wrapped.to_json_value()["code"], code);
+            assert_eq!(wrapped, error);
+            assert!(wrapped.source().is_some());
+        }
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn test_json_schema() {
+        let schema = TwirpError::json_schema();
+        let codes = schema["properties"]["code"]["enum"].as_array().unwrap();
+        assert_eq!(codes.len(), ALL_CODES.len());
+        for code in ALL_CODES {
+            assert!(codes.contains(&code.to_string().into()), "{code}");
+        }
+        assert_eq!(schema["required"], serde_json::json!(["code", "msg"]));
+    }
+
     #[cfg(feature = "http")]
     #[test]
     fn test_to_response() -> Result<(), Box<dyn Error>> {
@@ -469,6 +1785,33 @@ mod tests {
         Ok(())
     }
 
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_meta_response_round_trip() -> Result<(), Box<dyn Error>> {
+        let error = TwirpError::not_found("Object not found")
+            .with_meta("id", "foo")
+            .with_meta("kind", "bar");
+        let response = http::Response::<Vec<u8>>::from(error.clone());
+        // We drop the extensions to force the parsing of the body
+        let response = http::Response::builder()
+            .status(response.status())
+            .body(response.into_body())?;
+        let parsed = TwirpError::from(response);
+        assert_eq!(parsed, error);
+        assert_eq!(
+            parsed.meta_iter().collect::<HashMap<_, _>>(),
+            HashMap::from([("id", "foo"), ("kind", "bar")])
+        );
+
+        // No meta object if empty
+        let response = http::Response::<Vec<u8>>::from(TwirpError::not_found("Object not found"));
+        assert_eq!(
+            response.into_body(),
+            b"{\"code\":\"not_found\",\"msg\":\"Object not found\"}"
+        );
+        Ok(())
+    }
+
     #[cfg(feature = "http")]
     #[test]
     fn test_from_plain_response() -> Result<(), Box<dyn Error>> {
@@ -479,6 +1822,48 @@ mod tests {
             TwirpError::from(response),
             TwirpError::permission_denied("Thou shall not pass")
         );
+        // The codes are not the ones of the Twirp spec table, the response is not from a Twirp server
+        for status in [http::StatusCode::BAD_REQUEST, http::StatusCode::CONFLICT] {
+            let response = http::Response::builder().status(status).body("Nope")?;
+            assert_eq!(TwirpError::from(response), TwirpError::malformed("Nope"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let error = TwirpError::resource_exhausted("Too many requests");
+        assert_eq!(error.retry_after(), None);
+        assert_eq!(
+            error
+                .clone()
+                .with_retry_after(Duration::from_millis(1500))
+                .retry_after(),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(error.with_meta("retry_after", "soon").retry_after(), None);
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_retry_after_response_round_trip() -> Result<(), Box<dyn Error>> {
+        let response = http::Response::<Vec<u8>>::from(
+            TwirpError::resource_exhausted("Too many requests")
+                .with_retry_after(Duration::from_secs(3)),
+        );
+        assert_eq!(
+            response.headers().get(http::header::RETRY_AFTER),
+            Some(&http::HeaderValue::from_static("3"))
+        );
+
+        let response = http::Response::builder()
+            .status(http::StatusCode::TOO_MANY_REQUESTS)
+            .header(http::header::RETRY_AFTER, "5")
+            .body("{\"code\":\"resource_exhausted\",\"msg\":\"Too many requests\"}")?;
+        assert_eq!(
+            TwirpError::from(response).retry_after(),
+            Some(Duration::from_secs(5))
+        );
         Ok(())
     }
 
@@ -500,6 +1885,79 @@ mod tests {
         assert_eq!(status.message(), "Not found");
     }
 
+    #[cfg(feature = "tonic-014")]
+    #[test]
+    fn test_all_codes_tonic_014_status_roundtrip() {
+        for code in ALL_CODES {
+            let error = TwirpError::new(code, "Failed").with_meta("id", "foo");
+            let status = tonic_014::Status::from(error.clone());
+            assert_eq!(TwirpError::from(status), error, "{code}");
+        }
+    }
+
+    #[cfg(feature = "tonic-014")]
+    #[test]
+    fn test_tonic_014_status_metadata() {
+        let error = TwirpError::malformed("Bad")
+            .with_meta("resource", "foo")
+            .with_meta("Invalid Key", "bar")
+            .with_meta("unicode", "é");
+        let status = tonic_014::Status::from(error);
+        assert_eq!(status.code(), tonic_014::Code::InvalidArgument);
+        assert_eq!(status.metadata().get("twirp-code").unwrap(), "malformed");
+        assert_eq!(status.metadata().get("twirp-meta-resource").unwrap(), "foo");
+        assert!(status.metadata().get_bin("twirp-meta-bin").is_some());
+        assert_eq!(
+            TwirpError::from(status),
+            TwirpError::malformed("Bad")
+                .with_meta("resource", "foo")
+                .with_meta("Invalid Key", "bar")
+                .with_meta("unicode", "é")
+        );
+
+        // The keys are not lowercased, the values not restricted to ASCII
+        let error = TwirpError::not_found("Missing")
+            .with_meta("resourceId", "42")
+            .with_meta("name", "Zoë");
+        assert_eq!(
+            TwirpError::from(tonic_014::Status::from(error.clone())),
+            error
+        );
+
+        // A Twirp code not matching the gRPC code is ignored
+        let mut status = tonic_014::Status::not_found("Missing");
+        status
+            .metadata_mut()
+            .insert("twirp-code", "malformed".parse().unwrap());
+        assert_eq!(TwirpError::from(status), TwirpError::not_found("Missing"));
+    }
+
+    #[cfg(feature = "tonic-014")]
+    #[test]
+    fn test_from_grpc_trailers() {
+        let details = tonic_types_014::Status {
+            code: tonic_014::Code::Unavailable as i32,
+            message: "Down for maintenance".into(),
+            details: Vec::new(),
+        };
+        let error = TwirpError::unavailable("Down for maintenance")
+            .with_meta("region", "eu")
+            .with_grpc_status_details(details.clone());
+        // Trailers-only response: the status is in the headers
+        let trailers = tonic_014::Status::from(error.clone())
+            .into_http::<String>()
+            .headers()
+            .clone();
+        let parsed = TwirpError::from_grpc_trailers(&trailers);
+        assert_eq!(parsed, error);
+        assert_eq!(parsed.grpc_status_details(), Some(&details));
+
+        assert_eq!(
+            TwirpError::from_grpc_trailers(&http::HeaderMap::new()).code(),
+            TwirpErrorCode::Unknown
+        );
+    }
+
     #[cfg(feature = "tonic-014")]
     #[test]
     fn test_from_to_tonic_014_status_roundtrip() {
@@ -513,4 +1971,54 @@ mod tests {
         assert_eq!(status.message(), new_status.message());
         assert_eq!(status.details(), new_status.details());
     }
+
+    #[cfg(feature = "tonic-014")]
+    #[test]
+    fn test_grpc_status_details_roundtrip() {
+        let details = tonic_types_014::Status {
+            code: tonic_014::Code::NotFound as i32,
+            message: "Not found".into(),
+            details: vec![prost_types::Any {
+                type_url: "type.googleapis.com/google.rpc.ResourceInfo".into(),
+                value: b"foo".to_vec(),
+            }],
+        };
+        let error = TwirpError::not_found("Not found").with_grpc_status_details(details.clone());
+        assert_eq!(error.grpc_status_details(), Some(&details));
+        let status = tonic_014::Status::from(error);
+        assert_eq!(status.code(), tonic_014::Code::NotFound);
+        assert_eq!(
+            status.details(),
+            prost::Message::encode_to_vec(&details).as_slice()
+        );
+        assert_eq!(
+            TwirpError::from(status).grpc_status_details(),
+            Some(&details)
+        );
+    }
+
+    #[cfg(feature = "tonic-014")]
+    #[test]
+    fn test_from_tonic_status_with_details_metadata() {
+        let details = tonic_types_014::Status {
+            code: tonic_014::Code::Unavailable as i32,
+            message: "Down".into(),
+            details: Vec::new(),
+        };
+        let mut status = tonic_014::Status::unavailable("Down");
+        status.metadata_mut().insert_bin(
+            "grpc-status-details-bin",
+            tonic_014::metadata::MetadataValue::from_bytes(&prost::Message::encode_to_vec(
+                &details,
+            )),
+        );
+        let error = TwirpError::from_tonic_status(status);
+        assert_eq!(error, TwirpError::unavailable("Down"));
+        assert_eq!(error.grpc_status_details(), Some(&details));
+        assert_eq!(
+            TwirpError::from_tonic_status(tonic_014::Status::unavailable("Down"))
+                .grpc_status_details(),
+            None
+        );
+    }
 }
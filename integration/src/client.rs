@@ -127,6 +127,7 @@ impl TryFrom<Data> for TestRequest {
             duration: Some(data.duration.try_into()?),
             any: Some(Any::from_msg(&Int { value: data.any })?),
             value: Some(Value::from(data.value)),
+            field_mask: None,
             option: Some(test_request::Option::Right(data.option)),
         })
     }
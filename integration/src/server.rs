@@ -77,6 +77,7 @@ fn response_from_request(request: TestRequest) -> TestResponse {
         duration: request.duration,
         any: request.any,
         value: request.value,
+        field_mask: request.field_mask,
         option: request.option.map(|o| match o {
             test_request::Option::Left(l) => test_response::Option::Left(l),
             test_request::Option::Right(r) => test_response::Option::Right(r),
@@ -96,6 +96,7 @@ fn dummy_request() -> TestRequest {
         any: None,
         option: Some(test_request::Option::Right(1.2)),
         value: Some(Value::from("foo".to_string())),
+        field_mask: None,
     }
 }
 
@@ -110,5 +111,6 @@ fn dummy_response() -> TestResponse {
         any: None,
         option: Some(test_response::Option::Right(1.2)),
         value: Some(Value::from("foo".to_string())),
+        field_mask: None,
     }
 }
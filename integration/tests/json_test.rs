@@ -1,13 +1,15 @@
-use axum::body::Bytes;
+use axum::body::{Body, Bytes, to_bytes};
 use axum::http::Request;
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
 use axum::response::IntoResponse;
 use eyre::Result;
 use std::str;
 use std::time::{Duration, UNIX_EPOCH};
-use tower::service_fn;
+use tower::{ServiceExt, service_fn};
 use twurst_client::{TwirpHttpClient, TwirpRequestBody};
 use twurst_integration::client::{Choice, Data};
-use twurst_integration::proto::IntegrationServiceClient;
+use twurst_integration::proto::{IntegrationService, IntegrationServiceClient};
+use twurst_integration::server::IntegrationServiceServicer;
 use twurst_server::TwirpError;
 
 #[tokio::test]
@@ -41,3 +43,44 @@ async fn test_json_serialization() -> Result<()> {
     assert_eq!(error, TwirpError::unimplemented(""));
     Ok(())
 }
+
+/// Sends `request` as JSON to the echo server and returns its JSON response
+async fn echo_json(request: &'static str) -> Result<String> {
+    let response = IntegrationServiceServicer {}
+        .into_router::<()>()
+        .oneshot(
+            Request::post("/integration.IntegrationService/Test")
+                .header(CONTENT_TYPE, "application/json")
+                .header(AUTHORIZATION, "Bearer password")
+                .body(Body::from(request))?,
+        )
+        .await?;
+    assert!(response.status().is_success());
+    Ok(String::from_utf8(
+        to_bytes(response.into_body(), usize::MAX).await?.to_vec(),
+    )?)
+}
+
+#[tokio::test]
+async fn test_json_well_known_types() -> Result<()> {
+    for (request, response) in [
+        (
+            "{\"time\":\"2024-01-15T10:30:00Z\",\"duration\":\"1.5s\",\"fieldMask\":\"fieldA,fieldB.nestedC\"}",
+            "{\"time\":\"2024-01-15T10:30:00Z\",\"duration\":\"1.500s\",\"fieldMask\":\"fieldA,fieldB.nestedC\"}",
+        ),
+        // Sub-second timestamp and negative durations
+        (
+            "{\"time\":\"2024-01-15T10:30:00.120Z\",\"duration\":\"-1.5s\"}",
+            "{\"time\":\"2024-01-15T10:30:00.120Z\",\"duration\":\"-1.500s\"}",
+        ),
+        (
+            "{\"time\":\"2024-01-15T11:30:00.000000001+01:00\",\"duration\":\"-0.000001s\"}",
+            "{\"time\":\"2024-01-15T10:30:00.000000001Z\",\"duration\":\"-0.000001s\"}",
+        ),
+        // Empty field mask
+        ("{\"fieldMask\":\"\"}", "{\"fieldMask\":\"\"}"),
+    ] {
+        assert_eq!(echo_json(request).await?, response);
+    }
+    Ok(())
+}
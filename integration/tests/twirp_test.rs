@@ -2,12 +2,15 @@ use eyre::Result;
 use std::time::{Duration, SystemTime};
 use tower::ServiceBuilder;
 use tower_http::auth::AddAuthorizationLayer;
-use twurst_client::TwirpHttpClient;
+use twurst_client::{CircuitBreakerLayer, CircuitState, FailureThreshold, TwirpHttpClient};
 use twurst_integration::client::{Choice, Data, IntegrationClient};
-use twurst_integration::proto::{IntegrationService, IntegrationServiceClient};
+use twurst_integration::proto::{
+    IntegrationService, IntegrationServiceClient, TestRequest, TestResponse,
+};
 use twurst_integration::server;
 use twurst_integration::server::IntegrationServiceServicer;
 use twurst_server::TwirpError;
+use twurst_server::codegen::TwirpRouter;
 
 #[tokio::test]
 async fn test_simple_twirp_echo_protobuf() -> Result<()> {
@@ -69,6 +72,32 @@ async fn test_wrong_password() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_server_circuit_breaker() -> Result<()> {
+    let breaker = CircuitBreakerLayer::new(FailureThreshold::Count(1));
+    let router = TwirpRouter::new(())
+        .route(
+            "/integration.IntegrationService/Test",
+            |(), _: TestRequest, _, _| async move {
+                Err::<TestResponse, _>(TwirpError::internal("Failed"))
+            },
+        )
+        .layer(breaker.clone())
+        .build();
+    let client = IntegrationServiceClient::new(TwirpHttpClient::new(router));
+    let request = example_data().try_into()?;
+    assert_eq!(
+        client.test(&request).await.unwrap_err(),
+        TwirpError::internal("Failed")
+    );
+    assert_eq!(breaker.state(), CircuitState::Open);
+    assert_eq!(
+        client.test(&request).await.unwrap_err(),
+        TwirpError::unavailable("Circuit breaker open")
+    );
+    Ok(())
+}
+
 fn example_data() -> Data {
     Data {
         string: "test_simple_twirp_echo".to_string(),
@@ -0,0 +1,314 @@
+//! Measures the memory allocated by a `TwirpRouter`.
+//!
+//! Run with `cargo bench -p twurst-server --bench allocations`.
+//!
+//! The JSON request lines give the peak memory allocated and the time taken while parsing a large JSON request:
+//! `reader` decodes it with a reader over the received chunks, `buffered` copies the body into a single buffer
+//! before decoding it like the `TwirpRouter` does, and `router` goes through a `TwirpRouter`.
+//! The reader saves a copy of the body but serde_json is much slower with it than with a slice.
+//! The protobuf request lines give the same for the binary protobuf encoding of the same message.
+//!
+//! The protobuf response lines give the number of allocations done to encode a response body,
+//! the former way with a `BytesMut` and the current one with `encode_to_vec`,
+//! and to handle a whole protobuf request.
+//!
+//! With the `grpc` feature, the gRPC unary lines give the number of allocations done
+//! to run a unary handler future, the former way boxing it and the current one polling it in place,
+//! and to handle a whole unary gRPC request.
+
+use axum::Router;
+use axum::body::{Body, HttpBody};
+use axum::http::Request;
+use axum::http::header::CONTENT_TYPE;
+use http_body_util::BodyExt;
+use prost::Message;
+use prost_reflect::bytes::{Buf, Bytes, BytesMut};
+use prost_reflect::{DynamicMessage, ReflectMessage};
+use prost_types::value::Kind;
+use prost_types::{ListValue, Timestamp, Value};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::convert::Infallible;
+#[cfg(feature = "grpc")]
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tower::ServiceExt;
+#[cfg(feature = "grpc")]
+use twurst_server::TwirpError;
+#[cfg(feature = "grpc")]
+use twurst_server::codegen::GrpcRouter;
+use twurst_server::codegen::TwirpRouter;
+
+const STRING_SIZE: usize = 1024;
+const ITEM_COUNT: usize = 16 * 1024;
+const BODY_SIZE: usize = ITEM_COUNT * (STRING_SIZE + 3) + 1;
+const CHUNK_SIZE: usize = 64 * 1024;
+const ITERATIONS: usize = 10_000;
+
+struct CountingAllocator {
+    current: AtomicUsize,
+    peak: AtomicUsize,
+    count: AtomicUsize,
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let current = self.current.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        self.peak.fetch_max(current, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.current.fetch_sub(layout.size(), Ordering::Relaxed);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator {
+    current: AtomicUsize::new(0),
+    peak: AtomicUsize::new(0),
+    count: AtomicUsize::new(0),
+};
+
+/// Returns the peak of the memory allocated while running `f` on top of what was allocated before
+async fn peak_allocation(f: impl Future<Output = ()>) -> usize {
+    let start = ALLOCATOR.current.load(Ordering::Relaxed);
+    ALLOCATOR.peak.store(start, Ordering::Relaxed);
+    f.await;
+    ALLOCATOR.peak.load(Ordering::Relaxed) - start
+}
+
+/// Returns the peak of the memory allocated while running `f` and its duration
+async fn measure(f: impl Future<Output = ()>) -> (usize, Duration) {
+    let start = Instant::now();
+    let peak = peak_allocation(f).await;
+    (peak, start.elapsed())
+}
+
+/// Returns the average number of allocations done by `f` over `ITERATIONS` runs
+async fn allocation_count<F: Future<Output = ()>>(mut f: impl FnMut() -> F) -> f64 {
+    let start = ALLOCATOR.count.load(Ordering::Relaxed);
+    for _ in 0..ITERATIONS {
+        f().await;
+    }
+    (ALLOCATOR.count.load(Ordering::Relaxed) - start) as f64 / ITERATIONS as f64
+}
+
+/// Splits the body in chunks like received from the network
+fn chunks(body: &[u8]) -> Vec<Bytes> {
+    body.chunks(CHUNK_SIZE)
+        .map(Bytes::copy_from_slice)
+        .collect()
+}
+
+/// JSON encoding of a `google.protobuf.ListValue` of 1 KiB strings
+fn json_chunks() -> Vec<Bytes> {
+    let item = format!("\"{}\"", "a".repeat(STRING_SIZE));
+    chunks(format!("[{}]", vec![item; ITEM_COUNT].join(",")).as_bytes())
+}
+
+/// Protobuf encoding of the same `google.protobuf.ListValue`
+fn protobuf_chunks() -> Vec<Bytes> {
+    let item = Value {
+        kind: Some(Kind::StringValue("a".repeat(STRING_SIZE))),
+    };
+    chunks(
+        &ListValue {
+            values: vec![item; ITEM_COUNT],
+        }
+        .encode_to_vec(),
+    )
+}
+
+/// The chunks are allocated before the measurement, only the parsing allocations are counted
+fn chunked_body(chunks: Vec<Bytes>) -> Body {
+    Body::from_stream(tokio_stream::iter(
+        chunks.into_iter().map(Ok::<_, Infallible>),
+    ))
+}
+
+async fn json_reader(chunks: Vec<Bytes>) {
+    let body = chunked_body(chunks).collect().await.unwrap().aggregate();
+    let mut deserializer = serde_json::Deserializer::from_reader(body.reader());
+    let message =
+        DynamicMessage::deserialize(ListValue::default().descriptor(), &mut deserializer).unwrap();
+    let message: ListValue = message.transcode_to().unwrap();
+    assert_eq!(message.values.len(), ITEM_COUNT);
+}
+
+async fn json_buffered(chunks: Vec<Bytes>) {
+    let body = chunked_body(chunks).collect().await.unwrap().to_bytes();
+    let mut deserializer = serde_json::Deserializer::from_slice(&body);
+    let message =
+        DynamicMessage::deserialize(ListValue::default().descriptor(), &mut deserializer).unwrap();
+    let message: ListValue = message.transcode_to().unwrap();
+    assert_eq!(message.values.len(), ITEM_COUNT);
+}
+
+async fn protobuf_buffered(chunks: Vec<Bytes>) {
+    let body = chunked_body(chunks).collect().await.unwrap().to_bytes();
+    let message = ListValue::decode(body).unwrap();
+    assert_eq!(message.values.len(), ITEM_COUNT);
+}
+
+async fn request_router(chunks: Vec<Bytes>, content_type: &'static str) {
+    let router = TwirpRouter::new(())
+        .route(
+            "/google.protobuf.Echo/Echo",
+            |(), request: ListValue, _, _| async move {
+                assert_eq!(request.values.len(), ITEM_COUNT);
+                Ok(())
+            },
+        )
+        .with_body_limit(2 * BODY_SIZE)
+        .build();
+    let response = router
+        .oneshot(
+            Request::post("/google.protobuf.Echo/Echo")
+                .header(CONTENT_TYPE, content_type)
+                .body(chunked_body(chunks))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+}
+
+fn response() -> Timestamp {
+    Timestamp {
+        seconds: 1_700_000_000,
+        nanos: 123_456_789,
+    }
+}
+
+/// Former encoding of the response body
+async fn encode_response_bytes_mut() {
+    let response = response();
+    let mut buffer = BytesMut::with_capacity(response.encoded_len());
+    response.encode(&mut buffer).unwrap();
+    let body = Body::from(buffer.freeze());
+    assert_eq!(body.size_hint().exact(), Some(11));
+}
+
+/// Current encoding of the response body
+async fn encode_response_vec() {
+    let body = Body::from(response().encode_to_vec());
+    assert_eq!(body.size_hint().exact(), Some(11));
+}
+
+async fn protobuf_router(router: Router) {
+    let response = router
+        .oneshot(
+            Request::post("/google.protobuf.Echo/Echo")
+                .header(CONTENT_TYPE, "application/protobuf")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+}
+
+#[cfg(feature = "grpc")]
+type GrpcUnaryResult = Result<tonic::Response<Timestamp>, tonic::Status>;
+
+#[cfg(feature = "grpc")]
+async fn grpc_handler() -> Result<Timestamp, TwirpError> {
+    Ok(response())
+}
+
+/// Former future of the unary gRPC methods
+#[cfg(feature = "grpc")]
+async fn grpc_unary_boxed() {
+    let future: Pin<Box<dyn Future<Output = GrpcUnaryResult> + Send>> =
+        Box::pin(async move { Ok(tonic::Response::new(grpc_handler().await?)) });
+    assert!(future.await.is_ok());
+}
+
+/// Current future of the unary gRPC methods
+#[cfg(feature = "grpc")]
+async fn grpc_unary_unboxed() {
+    let future = async move { Ok(tonic::Response::new(grpc_handler().await?)) };
+    let result: GrpcUnaryResult = future.await;
+    assert!(result.is_ok());
+}
+
+#[cfg(feature = "grpc")]
+async fn grpc_router(router: Router) {
+    // An empty message without compression
+    let body = Body::from(Bytes::from_static(&[0, 0, 0, 0, 0]));
+    let response = router
+        .oneshot(
+            Request::post("/google.protobuf.Echo/Echo")
+                .header(CONTENT_TYPE, "application/grpc")
+                .body(body)
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = response.into_body().collect().await.unwrap();
+    assert_eq!(body.trailers().unwrap()["grpc-status"], "0");
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let chunks = json_chunks();
+    let reader = measure(json_reader(chunks.clone())).await;
+    let buffered = measure(json_buffered(chunks.clone())).await;
+    let router = measure(request_router(chunks, "application/json")).await;
+    for (name, (peak, duration)) in [
+        ("reader", reader),
+        ("buffered", buffered),
+        ("router", router),
+    ] {
+        println!(
+            "JSON request {name}: peak of {} MiB for a {} MiB body ({:.2}x) in {duration:?}",
+            peak / 1024 / 1024,
+            BODY_SIZE / 1024 / 1024,
+            peak as f64 / BODY_SIZE as f64
+        );
+    }
+    let chunks = protobuf_chunks();
+    let body_size = chunks.iter().map(Bytes::len).sum::<usize>();
+    let buffered = peak_allocation(protobuf_buffered(chunks.clone())).await;
+    let router = peak_allocation(request_router(chunks, "application/protobuf")).await;
+    for (name, peak) in [("buffered", buffered), ("router", router)] {
+        println!(
+            "protobuf request {name}: peak of {} MiB for a {} MiB body ({:.2}x)",
+            peak / 1024 / 1024,
+            body_size / 1024 / 1024,
+            peak as f64 / body_size as f64
+        );
+    }
+
+    let bytes_mut = allocation_count(encode_response_bytes_mut).await;
+    let vec = allocation_count(encode_response_vec).await;
+    for (name, count) in [("BytesMut", bytes_mut), ("encode_to_vec", vec)] {
+        println!("protobuf response encoding with {name}: {count:.2} allocations");
+    }
+    let router = TwirpRouter::new(())
+        .route("/google.protobuf.Echo/Echo", |(), (), _, _| async move {
+            Ok(response())
+        })
+        .build();
+    let request = allocation_count(|| protobuf_router(router.clone())).await;
+    println!("protobuf request: {request:.2} allocations");
+
+    #[cfg(feature = "grpc")]
+    {
+        let boxed = allocation_count(grpc_unary_boxed).await;
+        let unboxed = allocation_count(grpc_unary_unboxed).await;
+        for (name, count) in [("boxed", boxed), ("unboxed", unboxed)] {
+            println!("gRPC unary future {name}: {count:.2} allocations");
+        }
+        let router = GrpcRouter::new(())
+            .route("/google.protobuf.Echo/Echo", |(), _: Timestamp, _, ()| {
+                grpc_handler()
+            })
+            .build();
+        let request = allocation_count(|| grpc_router(router.clone())).await;
+        println!("gRPC unary request: {request:.2} allocations");
+    }
+}
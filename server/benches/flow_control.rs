@@ -0,0 +1,93 @@
+//! Measures the throughput of large gRPC requests depending on the HTTP/2 flow control settings.
+//!
+//! Run with `cargo bench -p twurst-server --features grpc,serve --bench flow_control`.
+//!
+//! Each line gives the time to upload `REQUEST_COUNT` messages of `MESSAGE_SIZE` bytes
+//! one after the other on a single connection to a local server with:
+//! - the HTTP/2 specification defaults: a 65,535 bytes window and 16 KiB frames,
+//! - the server defaults (the hyper ones): a 1 MiB window and 16 KiB frames,
+//! - a window larger than the messages: a 8 MiB window and 1 MiB frames.
+//!
+//! The larger windows save waiting for the server to update them,
+//! so the gain grows with the network latency.
+
+use axum::http::Request;
+use axum::http::header::CONTENT_TYPE;
+use http_body_util::{BodyExt, Full};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use prost::Message;
+use prost_reflect::bytes::Bytes;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use twurst_server::codegen::GrpcRouter;
+
+const MESSAGE_SIZE: usize = 1024 * 1024;
+const REQUEST_COUNT: usize = 20;
+
+/// Returns the time taken to upload the messages to a server configured by `configure`
+async fn upload(configure: impl FnOnce(GrpcRouter<()>) -> GrpcRouter<()>) -> Duration {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
+    let router = GrpcRouter::new(()).route(
+        "/google.protobuf.Echo/Echo",
+        |(), request: Vec<u8>, _, ()| async move {
+            assert_eq!(request.len(), MESSAGE_SIZE);
+            Ok(())
+        },
+    );
+    let server = tokio::spawn(configure(router).serve_with_graceful_shutdown(
+        listener,
+        async move {
+            shutdown_receiver.await.unwrap();
+        },
+        Some(Duration::from_secs(1)),
+    ));
+
+    // An uncompressed gRPC message
+    let message = vec![1; MESSAGE_SIZE].encode_to_vec();
+    let mut frame = vec![0];
+    frame.extend_from_slice(&u32::try_from(message.len()).unwrap().to_be_bytes());
+    frame.extend_from_slice(&message);
+    let frame = Bytes::from(frame);
+
+    let client = Client::builder(TokioExecutor::new())
+        .http2_only(true)
+        .build_http::<Full<Bytes>>();
+    let start = Instant::now();
+    for _ in 0..REQUEST_COUNT {
+        let request = Request::post(format!("http://{address}/google.protobuf.Echo/Echo"))
+            .header(CONTENT_TYPE, "application/grpc")
+            .body(Full::new(frame.clone()))
+            .unwrap();
+        let response = client.request(request).await.unwrap();
+        let body = response.into_body().collect().await.unwrap();
+        assert_eq!(body.trailers().unwrap()["grpc-status"], "0");
+    }
+    let elapsed = start.elapsed();
+
+    drop(client);
+    shutdown_sender.send(()).unwrap();
+    server.await.unwrap().unwrap();
+    elapsed
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let spec = upload(|router| router.with_flow_control(65_535, 16_384)).await;
+    let default = upload(|router| router).await;
+    let tuned = upload(|router| router.with_flow_control(8 << 20, 1 << 20)).await;
+    for (name, elapsed) in [
+        ("the HTTP/2 defaults", spec),
+        ("the server defaults", default),
+        ("a 8 MiB window", tuned),
+    ] {
+        println!(
+            "gRPC upload with {name}: {} ms, {:.0} MiB/s",
+            elapsed.as_millis(),
+            (REQUEST_COUNT * MESSAGE_SIZE) as f64 / 1024. / 1024. / elapsed.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,398 @@
+use crate::TwirpError;
+use axum::extract::Request;
+use axum::http::HeaderName;
+use axum::http::header::AUTHORIZATION;
+use axum::response::{IntoResponse, Response};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+
+pub(crate) const X_API_KEY: HeaderName = HeaderName::from_static("x-api-key");
+
+/// Identity (e.g. a user or a tenant identifier) resolved from an API key by [`ApiKeyLayer`].
+///
+/// It is available in the request extensions, so handlers can get it from their `RequestParts`
+/// with `parts.extensions.get::<Principal>()`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Principal(pub String);
+
+/// Store resolving the API keys checked by [`ApiKeyLayer`] to their [`Principal`].
+pub trait KeyStore: Send + Sync + 'static {
+    /// Returns the principal owning `key`, `None` if the key is not valid.
+    fn lookup(&self, key: &str) -> impl Future<Output = Option<Principal>> + Send;
+}
+
+/// In-memory [`KeyStore`] mapping each valid key to its principal
+#[derive(Clone, Debug)]
+pub struct InMemoryKeyStore {
+    keys: InMemoryKeys,
+}
+
+#[derive(Clone, Debug)]
+enum InMemoryKeys {
+    Principals(HashMap<String, Principal>),
+    /// Set with `TwirpRouter::with_shared_api_key_auth`, the principal of the keys is [`key_principal`]
+    Shared(Arc<RwLock<HashSet<String>>>),
+}
+
+/// Principal of the keys without an identity of their own, derived from the SHA-256 hash of the key
+/// so that the callers can be told apart without exposing the secret key to the handlers and logs.
+pub(crate) fn key_principal(key: &str) -> Principal {
+    let hash = Sha256::digest(key.as_bytes());
+    let mut principal = String::with_capacity(8 + 2 * hash.len());
+    principal.push_str("api-key-");
+    for byte in hash {
+        let _ = write!(principal, "{byte:02x}");
+    }
+    Principal(principal)
+}
+
+impl Default for InMemoryKeyStore {
+    fn default() -> Self {
+        Self::new(HashMap::new())
+    }
+}
+
+impl InMemoryKeyStore {
+    pub fn new(keys: HashMap<String, Principal>) -> Self {
+        Self {
+            keys: InMemoryKeys::Principals(keys),
+        }
+    }
+
+    pub(crate) fn shared(keys: Arc<RwLock<HashSet<String>>>) -> Self {
+        Self {
+            keys: InMemoryKeys::Shared(keys),
+        }
+    }
+}
+
+impl KeyStore for InMemoryKeyStore {
+    async fn lookup(&self, key: &str) -> Option<Principal> {
+        match &self.keys {
+            InMemoryKeys::Principals(keys) => keys.get(key).cloned(),
+            InMemoryKeys::Shared(keys) => keys
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .contains(key)
+                .then(|| key_principal(key)),
+        }
+    }
+}
+
+/// [`KeyStore`] calling an async function to resolve the keys, e.g. to query a database.
+///
+/// ```
+/// use twurst_server::{AsyncKeyStore, Principal};
+///
+/// let _store = AsyncKeyStore::new(|key: String| async move {
+///     (key == "secret").then(|| Principal("admin".into()))
+/// });
+/// ```
+#[derive(Clone, Debug)]
+pub struct AsyncKeyStore<F> {
+    lookup: F,
+}
+
+impl<F: Fn(String) -> Fut + Send + Sync + 'static, Fut: Future<Output = Option<Principal>> + Send>
+    AsyncKeyStore<F>
+{
+    pub fn new(lookup: F) -> Self {
+        Self { lookup }
+    }
+}
+
+impl<F: Fn(String) -> Fut + Send + Sync + 'static, Fut: Future<Output = Option<Principal>> + Send>
+    KeyStore for AsyncKeyStore<F>
+{
+    fn lookup(&self, key: &str) -> impl Future<Output = Option<Principal>> + Send {
+        (self.lookup)(key.into())
+    }
+}
+
+/// Where [`ApiKeyLayer`] reads the API key from
+#[derive(Clone, Debug)]
+enum ApiKeySource {
+    Header(HeaderName),
+    BearerToken,
+}
+
+/// [`Layer`] rejecting with a Twirp `unauthenticated` error the requests without a valid API key.
+///
+/// The key is read from the `X-Api-Key` header by default and resolved with a [`KeyStore`].
+/// The resolved [`Principal`] is added to the request extensions.
+///
+/// It can be added to a router with `TwirpRouter::layer`:
+/// ```
+/// use std::collections::HashMap;
+/// use twurst_server::codegen::TwirpRouter;
+/// use twurst_server::{ApiKeyLayer, InMemoryKeyStore, Principal};
+///
+/// let store = InMemoryKeyStore::new(HashMap::from([(
+///     "secret".to_string(),
+///     Principal("tenant-1".into()),
+/// )]));
+/// let _router: axum::Router = TwirpRouter::new(())
+///     .layer(ApiKeyLayer::new(store).with_bearer_token())
+///     .build();
+/// ```
+pub struct ApiKeyLayer<K> {
+    store: Arc<K>,
+    source: ApiKeySource,
+}
+
+impl<K> Clone for ApiKeyLayer<K> {
+    fn clone(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+            source: self.source.clone(),
+        }
+    }
+}
+
+impl<K: KeyStore> ApiKeyLayer<K> {
+    pub fn new(store: K) -> Self {
+        Self {
+            store: Arc::new(store),
+            source: ApiKeySource::Header(X_API_KEY),
+        }
+    }
+
+    /// Reads the key from the `key_header` header instead of `X-Api-Key`.
+    ///
+    /// # Panics
+    ///
+    /// If `key_header` is not a valid header name.
+    pub fn with_header(mut self, key_header: &str) -> Self {
+        self.source = ApiKeySource::Header(
+            HeaderName::try_from(key_header).expect("invalid API key header name"),
+        );
+        self
+    }
+
+    /// Reads the key from the `Authorization: Bearer <key>` header instead of `X-Api-Key`.
+    pub fn with_bearer_token(mut self) -> Self {
+        self.source = ApiKeySource::BearerToken;
+        self
+    }
+}
+
+impl<S, K> Layer<S> for ApiKeyLayer<K> {
+    type Service = ApiKeyService<S, K>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ApiKeyService {
+            inner,
+            store: self.store.clone(),
+            source: self.source.clone(),
+        }
+    }
+}
+
+/// Service built by [`ApiKeyLayer`]
+pub struct ApiKeyService<S, K> {
+    inner: S,
+    store: Arc<K>,
+    source: ApiKeySource,
+}
+
+impl<S: Clone, K> Clone for ApiKeyService<S, K> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            store: self.store.clone(),
+            source: self.source.clone(),
+        }
+    }
+}
+
+impl<S, K> Service<Request> for ApiKeyService<S, K>
+where
+    S: Service<Request, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+    K: KeyStore,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request) -> Self::Future {
+        let key = match &self.source {
+            ApiKeySource::Header(header) => request.headers().get(header),
+            ApiKeySource::BearerToken => request.headers().get(AUTHORIZATION),
+        }
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| match &self.source {
+            ApiKeySource::Header(_) => Some(value),
+            ApiKeySource::BearerToken => value.strip_prefix("Bearer "),
+        })
+        .map(str::to_owned);
+        // The ready service is the one to call, we keep the clone for the next calls
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let store = self.store.clone();
+        Box::pin(async move {
+            let principal = match key {
+                Some(key) => store.lookup(&key).await,
+                None => None,
+            };
+            let Some(principal) = principal else {
+                return Ok(TwirpError::unauthenticated("Invalid API key").into_response());
+            };
+            request.extensions_mut().insert(principal);
+            inner.call(request).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::TwirpRouter;
+    use crate::codegen::tests::MyMessage;
+    use axum::body::Body;
+    use axum::http::header::CONTENT_TYPE;
+    use axum::http::{Method, StatusCode};
+    use http_body_util::BodyExt;
+    use std::sync::Mutex;
+    use tower::ServiceExt;
+
+    async fn call<K: KeyStore>(
+        layer: ApiKeyLayer<K>,
+        header: Option<(HeaderName, &'static str)>,
+    ) -> (StatusCode, String, Option<Principal>) {
+        let principal = Arc::new(Mutex::new(None));
+        let handler_principal = principal.clone();
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                move |(), request: MyMessage, parts, _| {
+                    *handler_principal.lock().unwrap() =
+                        parts.extensions.get::<Principal>().cloned();
+                    async move { Ok(request) }
+                },
+            )
+            .layer(layer)
+            .build();
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri("/package.MyService/MyMethod")
+            .header(CONTENT_TYPE, "application/json");
+        if let Some((name, value)) = header {
+            request = request.header(name, value);
+        }
+        let response = router
+            .oneshot(request.body(Body::from("{}")).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let principal = principal.lock().unwrap().take();
+        (status, String::from_utf8(body.into()).unwrap(), principal)
+    }
+
+    fn store() -> InMemoryKeyStore {
+        InMemoryKeyStore::new(HashMap::from([(
+            "secret".into(),
+            Principal("tenant-1".into()),
+        )]))
+    }
+
+    const INVALID_API_KEY: &str = "{\"code\":\"unauthenticated\",\"msg\":\"Invalid API key\"}";
+
+    #[tokio::test]
+    async fn test_api_key_layer() {
+        assert_eq!(
+            call(ApiKeyLayer::new(store()), Some((X_API_KEY, "secret"))).await,
+            (
+                StatusCode::OK,
+                "{}".into(),
+                Some(Principal("tenant-1".into()))
+            )
+        );
+        assert_eq!(
+            call(ApiKeyLayer::new(store()), Some((X_API_KEY, "other"))).await,
+            (StatusCode::UNAUTHORIZED, INVALID_API_KEY.into(), None)
+        );
+        assert_eq!(
+            call(ApiKeyLayer::new(store()), None).await,
+            (StatusCode::UNAUTHORIZED, INVALID_API_KEY.into(), None)
+        );
+        // The key is only read from the configured header
+        assert_eq!(
+            call(
+                ApiKeyLayer::new(store()).with_header("x-custom-key"),
+                Some((X_API_KEY, "secret"))
+            )
+            .await
+            .0,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            call(
+                ApiKeyLayer::new(store()).with_header("x-custom-key"),
+                Some((HeaderName::from_static("x-custom-key"), "secret"))
+            )
+            .await
+            .0,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn test_api_key_layer_bearer_token() {
+        let layer = ApiKeyLayer::new(AsyncKeyStore::new(|key: String| async move {
+            (key == "secret").then(|| Principal("tenant-2".into()))
+        }))
+        .with_bearer_token();
+        assert_eq!(
+            call(layer.clone(), Some((AUTHORIZATION, "Bearer secret"))).await,
+            (
+                StatusCode::OK,
+                "{}".into(),
+                Some(Principal("tenant-2".into()))
+            )
+        );
+        assert_eq!(
+            call(layer.clone(), Some((AUTHORIZATION, "Bearer other"))).await,
+            (StatusCode::UNAUTHORIZED, INVALID_API_KEY.into(), None)
+        );
+        assert_eq!(
+            call(layer.clone(), Some((AUTHORIZATION, "Basic secret"))).await,
+            (StatusCode::UNAUTHORIZED, INVALID_API_KEY.into(), None)
+        );
+        assert_eq!(
+            call(layer, None).await,
+            (StatusCode::UNAUTHORIZED, INVALID_API_KEY.into(), None)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shared_key_principal() {
+        let keys = Arc::new(RwLock::new(HashSet::from(["foo".to_string()])));
+        let (status, _, principal) = call(
+            ApiKeyLayer::new(InMemoryKeyStore::shared(keys)),
+            Some((X_API_KEY, "foo")),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        // The SHA-256 hash of the key, stable across builds and instances
+        assert_eq!(
+            principal,
+            Some(Principal(
+                "api-key-2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae".into()
+            ))
+        );
+    }
+}
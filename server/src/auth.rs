@@ -0,0 +1,131 @@
+use crate::TwirpError;
+use crate::codegen::RequestParts;
+use axum::body::Body;
+use axum::http::{Request, Response};
+use tower_http::validate_request::{ValidateRequest, ValidateRequestHeader};
+use tower_layer::Layer;
+
+/// [`Layer`] rejecting the requests for which `validator` fails with the returned Twirp error.
+///
+/// It bridges [`tower_http`]'s request validation with Twirp errors:
+/// the error is returned as a Twirp JSON response with the matching HTTP status.
+///
+/// ```
+/// use twurst_server::{TwirpAuthorizationLayer, TwirpError};
+///
+/// let _layer = TwirpAuthorizationLayer::new(|parts: &twurst_server::codegen::RequestParts| {
+///     if parts.headers.contains_key("authorization") {
+///         Ok(())
+///     } else {
+///         Err(TwirpError::unauthenticated("Missing authorization header"))
+///     }
+/// });
+/// ```
+#[derive(Clone, Debug)]
+pub struct TwirpAuthorizationLayer<F> {
+    validator: F,
+}
+
+impl<F: Fn(&RequestParts) -> Result<(), TwirpError> + Clone> TwirpAuthorizationLayer<F> {
+    pub fn new(validator: F) -> Self {
+        Self { validator }
+    }
+}
+
+impl<S, F: Clone> Layer<S> for TwirpAuthorizationLayer<F> {
+    type Service = ValidateRequestHeader<S, TwirpAuthorization<F>>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ValidateRequestHeader::custom(
+            inner,
+            TwirpAuthorization {
+                validator: self.validator.clone(),
+            },
+        )
+    }
+}
+
+/// [`ValidateRequest`] implementation used by [`TwirpAuthorizationLayer`]
+#[derive(Clone, Debug)]
+pub struct TwirpAuthorization<F> {
+    validator: F,
+}
+
+impl<B: Default, F: Fn(&RequestParts) -> Result<(), TwirpError>> ValidateRequest<B>
+    for TwirpAuthorization<F>
+{
+    type ResponseBody = Body;
+
+    fn validate(&mut self, request: &mut Request<B>) -> Result<(), Response<Body>> {
+        // We temporarily move the request out to give its parts to the validator
+        let (parts, body) = std::mem::take(request).into_parts();
+        let result = (self.validator)(&parts);
+        *request = Request::from_parts(parts, body);
+        result.map_err(Response::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::TwirpRouter;
+    use crate::codegen::tests::MyMessage;
+    use axum::http::header::CONTENT_TYPE;
+    use axum::http::{Method, StatusCode};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_authorization_layer() {
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .layer(TwirpAuthorizationLayer::new(
+                |parts: &RequestParts| match parts
+                    .headers
+                    .get("authorization")
+                    .map(|v| v.as_bytes())
+                {
+                    Some(b"Bearer admin") => Ok(()),
+                    Some(_) => Err(TwirpError::permission_denied("Not an admin")),
+                    None => Err(TwirpError::unauthenticated("No credentials")),
+                },
+            ))
+            .build();
+        let call = |authorization: Option<&'static str>| {
+            let mut request = Request::builder()
+                .method(Method::POST)
+                .uri("/package.MyService/MyMethod")
+                .header(CONTENT_TYPE, "application/json");
+            if let Some(authorization) = authorization {
+                request = request.header("authorization", authorization);
+            }
+            router
+                .clone()
+                .oneshot(request.body(Body::from("{}")).unwrap())
+        };
+
+        let response = call(Some("Bearer admin")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            "{}"
+        );
+
+        let response = call(Some("Bearer user")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            "{\"code\":\"permission_denied\",\"msg\":\"Not an admin\"}"
+        );
+
+        let response = call(None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            "{\"code\":\"unauthenticated\",\"msg\":\"No credentials\"}"
+        );
+    }
+}
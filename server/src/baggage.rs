@@ -0,0 +1,175 @@
+use axum::extract::Request;
+use axum::http::{self, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use percent_encoding::{AsciiSet, CONTROLS, percent_decode_str, utf8_percent_encode};
+use std::collections::HashMap;
+use std::future::Future;
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+
+const BAGGAGE: &str = "baggage";
+
+/// Characters that must be percent-encoded in a baggage value
+const BAGGAGE_VALUE: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b',')
+    .add(b';')
+    .add(b'\\')
+    .add(b'%');
+
+tokio::task_local! {
+    static CURRENT_BAGGAGE: OtelBaggage;
+}
+
+/// [W3C baggage](https://www.w3.org/TR/baggage/) key-value pairs of the request.
+///
+/// It is extracted from the `baggage` header if [`TwirpRouter::with_baggage_propagation`](crate::codegen::TwirpRouter::with_baggage_propagation) is enabled
+/// and is available in the request extensions and with [`OtelBaggage::current`] while the handler is running.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OtelBaggage(pub HashMap<String, String>);
+
+impl OtelBaggage {
+    /// Parses the value of a `baggage` header, ignoring the invalid list members and the properties.
+    pub fn parse(header: &str) -> Self {
+        Self(
+            header
+                .split(',')
+                .filter_map(|member| {
+                    let (key_value, _properties) = member.split_once(';').unwrap_or((member, ""));
+                    let (key, value) = key_value.split_once('=')?;
+                    let key = key.trim();
+                    if key.is_empty() {
+                        return None;
+                    }
+                    let value = percent_decode_str(value.trim()).decode_utf8().ok()?;
+                    Some((key.into(), value.into()))
+                })
+                .collect(),
+        )
+    }
+
+    /// Serializes the baggage into a `baggage` header value.
+    pub fn to_header_value(&self) -> Option<HeaderValue> {
+        let value = self
+            .0
+            .iter()
+            .map(|(key, value)| format!("{key}={}", utf8_percent_encode(value, BAGGAGE_VALUE)))
+            .collect::<Vec<_>>()
+            .join(",");
+        HeaderValue::try_from(value).ok()
+    }
+
+    /// The baggage of the Twirp request currently being handled by this task, if any.
+    pub fn current() -> Option<Self> {
+        CURRENT_BAGGAGE.try_with(Clone::clone).ok()
+    }
+
+    /// Runs `future` with this baggage as [`OtelBaggage::current`].
+    ///
+    /// Useful to propagate the baggage to spawned tasks.
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT_BAGGAGE.scope(self, future).await
+    }
+}
+
+pub(crate) async fn extract_baggage(mut request: Request, next: Next) -> Response {
+    let baggage = request
+        .headers()
+        .get_all(BAGGAGE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(OtelBaggage::parse)
+        .fold(OtelBaggage::default(), |mut all, baggage| {
+            all.0.extend(baggage.0);
+            all
+        });
+    request.extensions_mut().insert(baggage.clone());
+    baggage.scope(next.run(request)).await
+}
+
+/// [`Layer`] adding the [current baggage](OtelBaggage::current) to the outgoing HTTP requests.
+///
+/// To be used with the service given to `TwirpHttpClient` to propagate the baggage
+/// of the Twirp request being served to the Twirp requests made while serving it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BaggagePropagationLayer;
+
+impl<S> Layer<S> for BaggagePropagationLayer {
+    type Service = BaggagePropagation<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        BaggagePropagation { inner }
+    }
+}
+
+/// Service built by [`BaggagePropagationLayer`]
+#[derive(Clone, Debug)]
+pub struct BaggagePropagation<S> {
+    inner: S,
+}
+
+impl<S: Service<http::Request<B>>, B> Service<http::Request<B>> for BaggagePropagation<S> {
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: http::Request<B>) -> Self::Future {
+        if let Some(value) = OtelBaggage::current().and_then(|b| b.to_header_value()) {
+            request.headers_mut().insert(BAGGAGE, value);
+        }
+        self.inner.call(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            OtelBaggage::parse("userId=alice, serverNode = DF%2028 ,isProduction=false;p=1,foo"),
+            OtelBaggage(HashMap::from([
+                ("userId".into(), "alice".into()),
+                ("serverNode".into(), "DF 28".into()),
+                ("isProduction".into(), "false".into()),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_to_header_value() {
+        let baggage = OtelBaggage(HashMap::from([("serverNode".into(), "DF 28".into())]));
+        assert_eq!(
+            baggage.to_header_value(),
+            Some(HeaderValue::from_static("serverNode=DF%2028"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_propagation_layer() {
+        let mut service = BaggagePropagationLayer.layer(tower::service_fn(
+            |request: http::Request<()>| async move {
+                Ok::<_, Infallible>(request.headers().get(BAGGAGE).cloned())
+            },
+        ));
+        assert_eq!(service.call(http::Request::new(())).await.unwrap(), None);
+        let baggage = OtelBaggage(HashMap::from([("userId".into(), "alice".into())]));
+        assert_eq!(
+            baggage
+                .scope(async { service.call(http::Request::new(())).await })
+                .await
+                .unwrap(),
+            Some(HeaderValue::from_static("userId=alice"))
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use crate::codegen::{RequestParts, collect_request_body};
+#[cfg(feature = "body-encryption")]
+use aes_gcm::aead::{Aead, Generate};
+#[cfg(feature = "body-encryption")]
+use aes_gcm::{Aes256Gcm, Nonce};
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use prost_reflect::bytes::Bytes;
+use std::sync::Arc;
+#[cfg(feature = "body-encryption")]
+use tracing::error;
+
+/// Function set with `TwirpRouter::with_request_body_logger`
+pub(crate) type RequestBodyLogger = Arc<dyn Fn(&RequestParts, Bytes) + Send + Sync>;
+
+/// Configuration set with `TwirpRouter::with_request_body_logger`
+#[derive(Clone)]
+pub(crate) struct BodyLog {
+    pub(crate) logger: RequestBodyLogger,
+    /// Set with `TwirpRouter::with_body_encryption`
+    #[cfg(feature = "body-encryption")]
+    pub(crate) cipher: Option<Aes256Gcm>,
+    pub(crate) body_limit: Option<usize>,
+}
+
+pub(crate) async fn log_request_body(
+    State(log): State<BodyLog>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let (parts, body) = match collect_request_body(request, log.body_limit).await {
+        Ok(request) => request,
+        Err(e) => return e.into_response(),
+    };
+    #[cfg(feature = "body-encryption")]
+    let logged = match &log.cipher {
+        Some(cipher) => encrypt(cipher, &body),
+        None => Some(body.clone()),
+    };
+    #[cfg(not(feature = "body-encryption"))]
+    let logged = Some(body.clone());
+    if let Some(logged) = logged {
+        (log.logger)(&parts, logged);
+    }
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
+
+/// Encrypts `body` with a random nonce, returned before the ciphertext
+#[cfg(feature = "body-encryption")]
+fn encrypt(cipher: &Aes256Gcm, body: &[u8]) -> Option<Bytes> {
+    let nonce = Nonce::generate();
+    let ciphertext = cipher
+        .encrypt(&nonce, body)
+        .inspect_err(|e| error!("Failed to encrypt the request body: {e}"))
+        .ok()?;
+    let mut encrypted = Vec::with_capacity(nonce.len() + ciphertext.len());
+    encrypted.extend_from_slice(&nonce);
+    encrypted.extend_from_slice(&ciphertext);
+    Some(encrypted.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen::TwirpRouter;
+    use crate::codegen::tests::MyMessage;
+    use axum::Router;
+    use axum::body::Body;
+    use axum::extract::Request;
+    use axum::http::header::CONTENT_TYPE;
+    use axum::http::{Method, StatusCode};
+    use http_body_util::BodyExt;
+    use prost_reflect::bytes::Bytes;
+    use std::sync::{Arc, Mutex};
+    use tower::ServiceExt;
+
+    type Logged = Arc<Mutex<Vec<(String, Bytes)>>>;
+
+    fn router(configure: impl FnOnce(TwirpRouter<()>) -> TwirpRouter<()>) -> (Router, Logged) {
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let router = configure(
+            TwirpRouter::new(())
+                .route(
+                    "/package.MyService/MyMethod",
+                    |(), request: MyMessage, _, _| async move { Ok(request) },
+                )
+                .with_request_body_logger({
+                    let logged = logged.clone();
+                    move |parts, body| {
+                        logged
+                            .lock()
+                            .unwrap()
+                            .push((parts.uri.path().to_string(), body));
+                    }
+                }),
+        )
+        .build();
+        (router, logged)
+    }
+
+    async fn call(router: Router) -> (StatusCode, Bytes) {
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/package.MyService/MyMethod")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from("{}"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, body)
+    }
+
+    #[tokio::test]
+    async fn test_request_body_logger() {
+        let (router, logged) = router(|router| router);
+        assert_eq!(call(router).await, (StatusCode::OK, Bytes::from("{}")));
+        assert_eq!(
+            *logged.lock().unwrap(),
+            [("/package.MyService/MyMethod".to_string(), Bytes::from("{}"))]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_body_logger_limit() {
+        let (router, logged) = router(|router| router.with_body_limit(1));
+        assert_eq!(call(router).await.0, StatusCode::TOO_MANY_REQUESTS);
+        assert!(logged.lock().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "body-encryption")]
+    #[tokio::test]
+    async fn test_body_encryption() {
+        use aes_gcm::aead::{Aead, KeyInit};
+        use aes_gcm::{Aes256Gcm, Nonce};
+
+        let key = [7; 32];
+        let (router, logged) = router(|router| router.with_body_encryption(key));
+        // The handler gets the plain body
+        assert_eq!(call(router).await, (StatusCode::OK, Bytes::from("{}")));
+        let (_, encrypted) = logged.lock().unwrap().pop().unwrap();
+        assert_ne!(encrypted, "{}");
+        let (nonce, ciphertext) = encrypted.split_at(12);
+        let plain = Aes256Gcm::new(&key.into())
+            .decrypt(&Nonce::try_from(nonce).unwrap(), ciphertext)
+            .unwrap();
+        assert_eq!(plain, b"{}");
+    }
+}
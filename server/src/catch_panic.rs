@@ -0,0 +1,87 @@
+use crate::TwirpError;
+use axum::body::Body;
+use axum::http::Response;
+use std::any::Any;
+use tower_http::catch_panic::{CatchPanic, ResponseForPanic};
+use tower_layer::Layer;
+use tracing::error;
+
+/// [`Layer`] converting the panics of the inner service into Twirp `internal` errors.
+///
+/// The panic message is logged and the client gets a generic "Internal server error" message.
+/// The inner service does not need to be [`UnwindSafe`](std::panic::UnwindSafe).
+///
+/// It can be added to a router with `TwirpRouter::layer`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PanicRecoveryLayer;
+
+impl<S> Layer<S> for PanicRecoveryLayer {
+    type Service = CatchPanic<S, TwirpPanicHandler>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CatchPanic::custom(inner, TwirpPanicHandler)
+    }
+}
+
+/// [`ResponseForPanic`] implementation used by [`PanicRecoveryLayer`]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TwirpPanicHandler;
+
+impl ResponseForPanic for TwirpPanicHandler {
+    type ResponseBody = Body;
+
+    fn response_for_panic(&mut self, panic: Box<dyn Any + Send + 'static>) -> Response<Body> {
+        let message = if let Some(message) = panic.downcast_ref::<&str>() {
+            message
+        } else if let Some(message) = panic.downcast_ref::<String>() {
+            message.as_str()
+        } else {
+            "unknown panic payload"
+        };
+        error!("The request handler panicked: {message}");
+        TwirpError::internal("Internal server error").into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::TwirpRouter;
+    use crate::codegen::tests::MyMessage;
+    use axum::http::header::CONTENT_TYPE;
+    use axum::http::{Method, Request, StatusCode};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_panic_recovery() {
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), _: MyMessage, _, _| async move {
+                    if true {
+                        panic!("Something went wrong");
+                    }
+                    Ok(MyMessage {})
+                },
+            )
+            .layer(PanicRecoveryLayer)
+            .build();
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/package.MyService/MyMethod")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from("{}"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            "{\"code\":\"internal\",\"msg\":\"Internal server error\"}"
+        );
+    }
+}
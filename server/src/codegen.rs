@@ -1,32 +1,90 @@
-use crate::TwirpError;
+use crate::api_key::{ApiKeyLayer, InMemoryKeyStore, key_principal};
+#[cfg(feature = "baggage")]
+use crate::baggage::extract_baggage;
+use crate::body_log::{BodyLog, RequestBodyLogger, log_request_body};
+#[cfg(feature = "prometheus")]
+use crate::concurrency::ConcurrencyMetrics;
+#[cfg(feature = "grpc")]
+use crate::concurrency::limit_grpc_concurrency;
+use crate::concurrency::{acquire_permit, limit_concurrency};
+use crate::cors::{CorsConfig, apply_cors};
+#[cfg(feature = "dedup")]
+use crate::dedup::{ContentDedup, DedupStore, deduplicate_content};
+use crate::early_data::{EarlyDataPolicy, check_early_data};
+#[cfg(feature = "grpc")]
+use crate::health::{GRPC_HEALTH_CHECK_PATH, HealthCheckRequest, grpc_health_check_response};
+use crate::health::{HealthCheck, health_check_response};
+use crate::json::{TwirpJsonOptions, make_bytes_url_safe, write_in_field_order};
+#[cfg(feature = "grpc")]
+use crate::mtls::check_channel_credentials;
+#[cfg(feature = "grpc")]
+use crate::reflection::{GRPC_REFLECTION_PATH, GRPC_REFLECTION_V1ALPHA_PATH, ReflectionService};
+use crate::schema::schema_registry_router;
+#[cfg(feature = "serve")]
+use crate::serve::ConnectionSettings;
+use crate::timeout::run_with_timeout;
+#[cfg(feature = "grpc")]
+use crate::timeout::{grpc_deadline, grpc_timeout};
+use crate::{TwirpError, twirp_fallback};
+#[cfg(feature = "body-encryption")]
+use aes_gcm::{Aes256Gcm, KeyInit};
+use axum::Extension;
 use axum::RequestExt;
 pub use axum::Router;
 use axum::body::Body;
 pub use axum::extract::FromRequestParts;
 use axum::extract::{Request, State};
 #[cfg(feature = "grpc")]
-use axum::http::Method;
-use axum::http::header::CONTENT_TYPE;
+use axum::http::Extensions;
+use axum::http::header::{ACCEPT, ALLOW, CONTENT_SECURITY_POLICY, CONTENT_TYPE};
 pub use axum::http::request::Parts as RequestParts;
-use axum::http::{HeaderMap, HeaderValue};
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode, Uri};
+use axum::middleware::{self, Next};
 pub use axum::response::IntoResponse;
 use axum::response::Response;
-use axum::routing::post;
-use http_body_util::BodyExt;
+use axum::routing::{MethodFilter, Route, get, on, post};
+use base64::Engine;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use percent_encoding::percent_decode_str;
 #[cfg(feature = "grpc")]
 use pin_project_lite::pin_project;
-use prost_reflect::bytes::{Bytes, BytesMut};
+#[cfg(feature = "grpc")]
+use prost_reflect::DescriptorError;
+use prost_reflect::DescriptorPool;
+#[cfg(feature = "grpc")]
+use prost_reflect::MessageDescriptor;
+use prost_reflect::bytes::Bytes;
 use prost_reflect::{DynamicMessage, ReflectMessage};
-use serde::Serialize;
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::error::Error;
 use std::future::Future;
-#[cfg(feature = "grpc")]
+#[cfg(any(feature = "grpc", feature = "serve"))]
+use std::io;
+use std::marker::PhantomData;
 use std::pin::Pin;
+use std::sync::{Arc, RwLock};
 #[cfg(feature = "grpc")]
-use std::task::{Context, Poll};
+use std::sync::{Mutex, OnceLock, PoisonError};
+#[cfg(feature = "grpc")]
+use std::task::{Context, Poll, Waker, ready};
+use std::time::Duration;
+#[cfg(feature = "grpc")]
+use tokio::io::{AsyncRead, ReadBuf};
+#[cfg(feature = "serve")]
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
 #[cfg(feature = "grpc")]
 pub use tokio_stream::Stream;
 #[cfg(feature = "grpc")]
 use tokio_stream::StreamExt;
+#[cfg(feature = "compression")]
+use tower_http::compression::CompressionLayer;
+#[cfg(feature = "compression")]
+use tower_http::decompression::RequestDecompressionLayer;
+use tower_layer::Layer;
+use tower_service::Service;
 use tracing::error;
 pub use trait_variant::make as trait_variant_make;
 use twurst_error::TwirpErrorCode;
@@ -34,527 +92,5106 @@ use twurst_error::TwirpErrorCode;
 const APPLICATION_JSON: HeaderValue = HeaderValue::from_static("application/json");
 const APPLICATION_PROTOBUF: HeaderValue = HeaderValue::from_static("application/protobuf");
 
-pub struct TwirpRouter<S, RS = ()> {
-    router: Router<RS>,
+/// Builder of an axum [`Router`] serving Twirp methods.
+///
+/// `RS` is the state given to the handlers. `St` is the state provided by the router itself
+/// (e.g. with [`with_arc_state`](TwirpRouter::with_arc_state)), `()` if it is provided after [`build`](TwirpRouter::build).
+pub struct TwirpRouter<S, RS = (), St: ProvidedState<RS> = ()> {
+    router: Router<St::RouterState>,
     service: S,
+    state: St,
+    method_validation: bool,
+    #[cfg(feature = "validate")]
+    validation: bool,
+    #[cfg(feature = "baggage")]
+    baggage_propagation: bool,
+    early_data: Option<EarlyDataPolicy>,
+    api_key_auth: Option<ApiKeyLayer<InMemoryKeyStore>>,
+    body_limit: Option<usize>,
+    max_header_count: Option<usize>,
+    concurrency_limit: Option<usize>,
+    timeout: Option<Duration>,
+    #[cfg(feature = "dedup")]
+    content_dedup: Option<(Duration, Arc<dyn DedupStore>)>,
+    request_body_logger: Option<RequestBodyLogger>,
+    #[cfg(feature = "body-encryption")]
+    body_encryption: Option<Aes256Gcm>,
+    json_options: Option<TwirpJsonOptions>,
+    route_listing: bool,
+    fallback: bool,
+    routes: Vec<ListedRoute>,
+    prefix: String,
+    health_check: Option<(String, HealthCheck)>,
+    schema_registry: Option<DescriptorPool>,
+    response_headers: Option<HeaderMap>,
+    cors: Option<CorsConfig>,
+    #[cfg(feature = "compression")]
+    response_compression: bool,
+    #[cfg(feature = "compression")]
+    request_decompression: bool,
+    #[cfg(feature = "prometheus")]
+    concurrency_metrics: Option<ConcurrencyMetrics>,
 }
 
-impl<S: Clone + Send + Sync + 'static, RS: Clone + Send + Sync + 'static> TwirpRouter<S, RS> {
-    pub fn new(service: S) -> Self {
-        Self {
-            router: Router::new(),
-            service,
-        }
+impl<S: Clone + Send + Sync + 'static, RS: Send + Sync + 'static> TwirpRouter<S, Arc<RS>, Arc<RS>> {
+    /// Builds a router whose handlers share `state`.
+    ///
+    /// The state is reference-counted, not cloned, for each request,
+    /// and several routers can share it by being given clones of the same [`Arc`].
+    /// Use [`build_with_state`](Self::build_with_state) to get a router with the state already provided.
+    pub fn with_arc_state(service: S, state: Arc<RS>) -> Self {
+        Self::new_with_state(service, state)
     }
 
-    pub fn route<
-        I: ReflectMessage + Default,
-        O: ReflectMessage,
-        F: Future<Output = Result<O, TwirpError>> + Send,
-    >(
-        mut self,
-        path: &str,
-        call: impl (Fn(S, I, RequestParts, RS) -> F) + Clone + Send + Sync + 'static,
-    ) -> Self {
-        let service = self.service.clone();
-        self.router = self.router.route(
-            path,
-            post(
-                move |State(state): State<RS>, request: Request| async move {
-                    let (parts, body) = request.with_limited_body().into_parts();
-                    let content_type = ContentType::from_headers(&parts.headers)?;
-                    let request = parse_request(content_type, body).await?;
-                    let response = call(service, request, parts, state).await?;
-                    serialize_response(content_type, response)
-                },
-            ),
-        );
-        self
+    /// Same as [`build`](TwirpRouter::build) but provides the state given to [`with_arc_state`](Self::with_arc_state).
+    pub fn build_with_state<RS2>(self) -> Router<RS2> {
+        let (router, state) = self.build_router();
+        router.with_state(state)
     }
+}
 
-    pub fn route_streaming(mut self, path: &str) -> Self {
-        self.router = self.router.route(
-            path,
-            post(move || async move {
-                TwirpError::unimplemented("Streaming is not supported by Twirp")
-            }),
-        );
-        self
+impl<S: Clone + Send + Sync + 'static, RS: Clone + Send + Sync + 'static> TwirpRouter<S, RS> {
+    pub fn new(service: S) -> Self {
+        Self::new_with_state(service, ())
     }
 
     pub fn build(self) -> Router<RS> {
-        self.router
-    }
-}
-
-#[derive(Clone, Copy)]
-enum ContentType {
-    Protobuf,
-    Json,
-}
-
-impl ContentType {
-    fn from_headers(headers: &HeaderMap) -> Result<Self, TwirpError> {
-        let content_type = headers
-            .get(CONTENT_TYPE)
-            .ok_or_else(|| TwirpError::malformed("No content-type header"))?;
-        if content_type == APPLICATION_PROTOBUF {
-            Ok(ContentType::Protobuf)
-        } else if content_type == APPLICATION_JSON {
-            Ok(ContentType::Json)
-        } else {
-            Err(TwirpError::malformed(format!(
-                "Unsupported content type: {}",
-                String::from_utf8_lossy(content_type.as_bytes())
-            )))
-        }
+        self.build_router().0
     }
 }
 
-async fn parse_request<I: ReflectMessage + Default>(
-    content_type: ContentType,
-    body: Body,
-) -> Result<I, TwirpError> {
-    let body = body.collect().await.map_err(|e| {
-        TwirpError::wrap(
-            TwirpErrorCode::Internal,
-            "Failed to read the request body",
-            e,
+impl<S: Clone + Send + Sync + 'static, RS: Clone + Send + Sync + 'static>
+    TwirpRouter<S, RS, AsyncStateFactory<RS>>
+{
+    /// Builds a router whose handlers get a new state for each request, created by `factory`
+    /// (e.g. to open a database transaction at the start of each call).
+    ///
+    /// If `factory` fails, the request fails with an `internal` error without calling the handler.
+    /// Use [`build_with_state_factory`](Self::build_with_state_factory) to build the router.
+    pub fn new_with_async_state_factory<
+        F: Future<Output = Result<RS, TwirpError>> + Send + 'static,
+    >(
+        service: S,
+        factory: impl Fn() -> F + Send + Sync + 'static,
+    ) -> Self {
+        Self::new_with_state(
+            service,
+            AsyncStateFactory(Arc::new(move || Box::pin(factory()))),
         )
-    })?;
-    match content_type {
-        ContentType::Protobuf => I::decode(body.aggregate()).map_err(|e| {
-            TwirpError::wrap(
-                TwirpErrorCode::Malformed,
-                format!("Invalid binary protobuf request: {e}"),
-                e,
-            )
-        }),
-        ContentType::Json => json_decode(&body.to_bytes()), // TODO: avoid to_bytes?
     }
-}
 
-fn serialize_response<O: ReflectMessage>(
-    content_type: ContentType,
-    response: O,
-) -> Result<Response, TwirpError> {
-    let (content_type, body) = match content_type {
-        ContentType::Protobuf => {
-            let mut buffer = BytesMut::with_capacity(response.encoded_len());
-            response.encode(&mut buffer).map_err(|e| {
+    /// Same as [`build`](TwirpRouter::build) but provides to each request the state created
+    /// by the factory given to [`new_with_async_state_factory`](Self::new_with_async_state_factory).
+    pub fn build_with_state_factory<RS2: Clone + Send + Sync + 'static>(self) -> Router<RS2> {
+        let (router, AsyncStateFactory(factory)) = self.build_router();
+        Router::new().fallback(move |mut request: Request| async move {
+            let state = factory().await.map_err(|e| {
                 TwirpError::wrap(
                     TwirpErrorCode::Internal,
-                    format!("Failed to serialize to protobuf: {e}"),
+                    "Failed to create request state",
                     e,
                 )
             })?;
-            (APPLICATION_PROTOBUF, buffer.into())
-        }
-        ContentType::Json => (APPLICATION_JSON, json_encode(&response)?),
-    };
-    Response::builder()
-        .header(CONTENT_TYPE, content_type)
-        .body(Body::from(body))
-        .map_err(|e| {
-            error!("Failed to build the response: {e}");
-            TwirpError::internal("Failed to build the response")
+            request.extensions_mut().insert(RequestState(state));
+            let response = router.clone().call(request).await;
+            Ok::<_, TwirpError>(response.unwrap_or_else(|e| match e {}))
         })
+    }
 }
 
-fn json_encode<T: ReflectMessage>(message: &T) -> Result<Bytes, TwirpError> {
-    let mut serializer = serde_json::Serializer::new(Vec::new());
-    message
-        .transcode_to_dynamic()
-        .serialize(&mut serializer)
-        .map_err(|e| {
-            error!("Failed to serialize the JSON response: {e}");
-            TwirpError::internal("Failed to build the response")
-        })?;
-    Ok(serializer.into_inner().into())
-}
-
-fn json_decode<T: ReflectMessage + Default>(message: &[u8]) -> Result<T, TwirpError> {
-    let dynamic_message = dynamic_json_decode::<T>(message).map_err(|e| {
-        TwirpError::wrap(
-            TwirpErrorCode::Malformed,
-            format!("Invalid JSON protobuf request: {e}"),
-            e,
-        )
-    })?;
-    dynamic_message.transcode_to().map_err(|e| {
-        error!("Failed to cast input message: {e}");
-        TwirpError::internal("Internal error while parsing the JSON request")
-    })
-}
-
-fn dynamic_json_decode<T: ReflectMessage + Default>(
-    message: &[u8],
-) -> Result<DynamicMessage, serde_json::Error> {
-    let mut deserializer = serde_json::Deserializer::from_slice(message);
-    let dynamic_message =
-        DynamicMessage::deserialize(T::default().descriptor(), &mut deserializer)?;
-    deserializer.end()?;
-    Ok(dynamic_message)
-}
-
-#[cfg(feature = "grpc")]
-pub struct GrpcRouter<S> {
-    router: Router,
-    service: S,
-}
-
-#[cfg(feature = "grpc")]
-impl<S: Clone + Send + Sync + 'static> GrpcRouter<S> {
-    pub fn new(service: S) -> Self {
+impl<S: Clone + Send + Sync + 'static, RS: Clone + Send + Sync + 'static, St: ProvidedState<RS>>
+    TwirpRouter<S, RS, St>
+{
+    fn new_with_state(service: S, state: St) -> Self {
         Self {
             router: Router::new(),
             service,
+            state,
+            method_validation: false,
+            #[cfg(feature = "validate")]
+            validation: false,
+            #[cfg(feature = "baggage")]
+            baggage_propagation: false,
+            early_data: None,
+            api_key_auth: None,
+            body_limit: None,
+            max_header_count: None,
+            concurrency_limit: None,
+            timeout: None,
+            #[cfg(feature = "dedup")]
+            content_dedup: None,
+            request_body_logger: None,
+            #[cfg(feature = "body-encryption")]
+            body_encryption: None,
+            json_options: None,
+            route_listing: false,
+            fallback: false,
+            routes: Vec::new(),
+            prefix: String::new(),
+            health_check: None,
+            schema_registry: None,
+            response_headers: None,
+            cors: None,
+            #[cfg(feature = "compression")]
+            response_compression: false,
+            #[cfg(feature = "compression")]
+            request_decompression: false,
+            #[cfg(feature = "prometheus")]
+            concurrency_metrics: None,
         }
     }
 
-    pub fn route<
-        I: ReflectMessage + Default + 'static,
-        O: ReflectMessage + 'static,
-        C: (Fn(S, I, RequestParts) -> F) + Clone + Send + Sync + 'static,
-        F: Future<Output = Result<O, TwirpError>> + Send + 'static,
-    >(
-        mut self,
-        path: &str,
-        callback: C,
-    ) -> Self {
-        let service = self.service.clone();
-        self.router = self.router.route(
-            path,
-            post(move |request: Request| async move {
-                let method = GrpcService { service, callback };
-                let codec = tonic_prost::ProstCodec::default();
-                let mut grpc = tonic::server::Grpc::new(codec);
-                grpc.unary(method, request).await
-            }),
+    /// Rejects all requests that are not `POST` (or `OPTIONS` for CORS preflight) with the same 405 `bad_route`
+    /// error the routes return, before they reach the other layers and also on paths without a route.
+    pub fn with_method_validation(mut self) -> Self {
+        self.method_validation = true;
+        self
+    }
+
+    /// Validates the request messages using their [`protoc-gen-validate`](https://github.com/bufbuild/protoc-gen-validate)
+    /// annotations before calling the handlers.
+    ///
+    /// Invalid messages are rejected with a Twirp `malformed` error.
+    #[cfg(feature = "validate")]
+    pub fn with_validation(mut self) -> Self {
+        self.validation = true;
+        self
+    }
+
+    /// Extracts the [W3C baggage](https://www.w3.org/TR/baggage/) from the `baggage` request header.
+    ///
+    /// It is available as an [`OtelBaggage`](crate::OtelBaggage) in the [`RequestParts`] extensions
+    /// and with [`OtelBaggage::current`](crate::OtelBaggage::current).
+    /// Use [`BaggagePropagationLayer`](crate::BaggagePropagationLayer) on the clients to forward it.
+    #[cfg(feature = "baggage")]
+    pub fn with_baggage_propagation(mut self) -> Self {
+        self.baggage_propagation = true;
+        self
+    }
+
+    /// Rejects with a Twirp `unauthenticated` error the requests without one of the `valid_keys` in the `key_header` header.
+    ///
+    /// The [`Principal`](crate::Principal) added to the request extensions is `api-key-` followed by the hexadecimal
+    /// SHA-256 hash of the key, not the key itself that must stay secret.
+    /// Use [`with_shared_api_key_auth`](Self::with_shared_api_key_auth) to be able to update the valid keys later
+    /// and [`ApiKeyLayer`](crate::ApiKeyLayer) to resolve the keys to other principals.
+    ///
+    /// # Panics
+    ///
+    /// If `key_header` is not a valid header name.
+    pub fn with_api_key_auth(mut self, key_header: &str, valid_keys: HashSet<String>) -> Self {
+        let store = InMemoryKeyStore::new(
+            valid_keys
+                .into_iter()
+                .map(|key| {
+                    let principal = key_principal(&key);
+                    (key, principal)
+                })
+                .collect(),
         );
+        self.api_key_auth = Some(ApiKeyLayer::new(store).with_header(key_header));
         self
     }
 
-    pub fn route_server_streaming<
-        I: ReflectMessage + Default + 'static,
-        O: ReflectMessage + 'static,
-        C: (Fn(S, I, RequestParts) -> F) + Clone + Send + Sync + 'static,
-        F: Future<Output = Result<OS, TwirpError>> + Send + 'static,
-        OS: Stream<Item = Result<O, TwirpError>> + Send + 'static,
-    >(
+    /// Same as [`with_api_key_auth`](Self::with_api_key_auth) but the valid keys are shared with the caller
+    /// that can update them (e.g. to reload them) while the server is running.
+    ///
+    /// # Panics
+    ///
+    /// If `key_header` is not a valid header name.
+    pub fn with_shared_api_key_auth(
         mut self,
-        path: &str,
-        callback: C,
+        key_header: &str,
+        valid_keys: Arc<RwLock<HashSet<String>>>,
     ) -> Self {
-        let service = self.service.clone();
-        self.router = self.router.route(
-            path,
-            post(move |request: Request| async move {
-                let method = GrpcService { service, callback };
-                let codec = tonic_prost::ProstCodec::default();
-                let mut grpc = tonic::server::Grpc::new(codec);
-                grpc.server_streaming(method, request).await
-            }),
-        );
+        self.api_key_auth =
+            Some(ApiKeyLayer::new(InMemoryKeyStore::shared(valid_keys)).with_header(key_header));
         self
     }
 
-    pub fn route_client_streaming<
-        I: ReflectMessage + Default + 'static,
-        O: ReflectMessage + 'static,
-        C: (Fn(S, GrpcClientStream<I>, RequestParts) -> F) + Clone + Send + Sync + 'static,
-        F: Future<Output = Result<O, TwirpError>> + Send + 'static,
-    >(
+    /// Rejects with a Twirp `resource_exhausted` error the requests whose body is larger than `limit` bytes.
+    ///
+    /// By default, [axum's default limit](axum::extract::DefaultBodyLimit) is used.
+    /// Use [`route_with_limit`](Self::route_with_limit) to set a different limit on a specific route.
+    pub fn with_body_limit(mut self, limit: usize) -> Self {
+        self.body_limit = Some(limit);
+        self
+    }
+
+    /// Detects the requests whose content (path and raw body) was already received,
+    /// e.g. to avoid running twice expensive idempotent operations.
+    ///
+    /// The requests are identified by the SHA-256 hash of their raw body together with their path and query,
+    /// so that the same body sent to two different methods is not considered a duplicate.
+    /// The hash also covers the caller: the [`Principal`](crate::Principal) set by the API key authentication
+    /// if any, the `Authorization` and `X-Api-Key` headers otherwise. Two callers sending the same body
+    /// are not duplicates of each other.
+    /// The successful responses are kept in `store` for `window`, their duplicates get them back
+    /// without calling the handler. The duplicates of a request still being handled wait for it
+    /// for at most 30 seconds before failing with an `aborted` error.
+    /// Failed requests are forgotten so that they can be retried.
+    #[cfg(feature = "dedup")]
+    pub fn with_content_dedup(mut self, window: Duration, store: Arc<dyn DedupStore>) -> Self {
+        self.content_dedup = Some((window, store));
+        self
+    }
+
+    /// Calls `logger` with the raw body of each request before it is handled,
+    /// e.g. to keep an audit log of the received requests.
+    ///
+    /// The body is read within the limit set by [`with_body_limit`](Self::with_body_limit),
+    /// the handler gets it unchanged.
+    pub fn with_request_body_logger(
         mut self,
-        path: &str,
-        callback: C,
+        logger: impl Fn(&RequestParts, Bytes) + Send + Sync + 'static,
     ) -> Self {
-        let service = self.service.clone();
-        self.router = self.router.route(
-            path,
-            post(move |request: Request| async move {
-                let method = GrpcService { service, callback };
-                let codec = tonic_prost::ProstCodec::default();
-                let mut grpc = tonic::server::Grpc::new(codec);
-                grpc.client_streaming(method, request).await
-            }),
-        );
+        self.request_body_logger = Some(Arc::new(logger));
         self
     }
 
-    pub fn route_streaming<
-        I: ReflectMessage + Default + 'static,
-        O: ReflectMessage + 'static,
-        C: (Fn(S, GrpcClientStream<I>, RequestParts) -> F) + Clone + Send + Sync + 'static,
-        F: Future<Output = Result<OS, TwirpError>> + Send + 'static,
-        OS: Stream<Item = Result<O, TwirpError>> + Send + 'static,
-    >(
+    /// Encrypts with AES-256-GCM and `key` the bodies given to the logger set with
+    /// [`with_request_body_logger`](Self::with_request_body_logger), e.g. to store them encrypted at rest.
+    ///
+    /// Each body is encrypted with a new random 12 bytes nonce, written before the ciphertext.
+    /// The handlers still get the plain bodies.
+    #[cfg(feature = "body-encryption")]
+    pub fn with_body_encryption(mut self, key: [u8; 32]) -> Self {
+        self.body_encryption = Some(Aes256Gcm::new(&key.into()));
+        self
+    }
+
+    /// Rejects with a Twirp `malformed` error the requests with more than `max` headers,
+    /// before their body is read.
+    pub fn with_max_header_count(mut self, max: usize) -> Self {
+        self.max_header_count = Some(max);
+        self
+    }
+
+    /// Rejects with a Twirp `resource_exhausted` error the requests received while `limit` requests are already being handled.
+    ///
+    /// Unlike [`route_with_concurrency_limit`](Self::route_with_concurrency_limit), the requests do not wait:
+    /// the router sheds the load instead of queueing it.
+    pub fn with_concurrency_limit(mut self, limit: usize) -> Self {
+        self.concurrency_limit = Some(limit);
+        self
+    }
+
+    /// Fails with a Twirp `deadline_exceeded` error the calls whose handler takes more than `timeout`.
+    ///
+    /// Use [`route_with_timeout`](Self::route_with_timeout) to set a different timeout on a specific route.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Answers the requests to unknown paths with a Twirp `bad_route` error,
+    /// like [`twirp_fallback`](crate::twirp_fallback) does, instead of an empty `404` response.
+    ///
+    /// When merging several built routers, the fallback must only be set on one of them.
+    pub fn with_fallback(mut self) -> Self {
+        self.fallback = true;
+        self
+    }
+
+    /// Adds a `GET /.well-known/twirp-routes` endpoint returning the registered routes as a JSON array
+    /// of `{"method": "POST", "path": "...", "enabled": true}` objects.
+    ///
+    /// Streaming routes are listed as not enabled because Twirp does not support them.
+    ///
+    /// This endpoint, the [health check](Self::with_health_check) and the [schema registry](Self::with_schema_registry)
+    /// are served outside of the middlewares of the Twirp routes: authentication, method validation, limits, timeouts,
+    /// compression and the layers added with [`layer`](Self::layer) do not apply to them.
+    /// The [CORS](Self::with_cors) and [response headers](Self::with_response_headers) options do.
+    pub fn with_route_listing(mut self) -> Self {
+        self.route_listing = true;
+        self
+    }
+
+    /// Adds a `GET` endpoint at `path` (e.g. `/healthz`) for liveness or readiness probes.
+    ///
+    /// It responds `200 OK` with an `ok` body if `check` returns `true` and `503 Service Unavailable` otherwise.
+    /// See [`with_route_listing`](Self::with_route_listing) for the options applying to it.
+    pub fn with_health_check(
         mut self,
         path: &str,
-        callback: C,
+        check: impl Fn() -> bool + Clone + Send + Sync + 'static,
     ) -> Self {
-        let service = self.service.clone();
-        self.router = self.router.route(
-            path,
-            post(move |request: Request| async move {
-                let method = GrpcService { service, callback };
-                let codec = tonic_prost::ProstCodec::default();
-                let mut grpc = tonic::server::Grpc::new(codec);
-                grpc.streaming(method, request).await
-            }),
-        );
+        self.health_check = Some((path.into(), Arc::new(check)));
         self
     }
 
-    pub fn build(self) -> Router {
-        self.router
+    /// Serves the proto files of `pool` for schema registries:
+    /// - `GET /.well-known/proto` returns the file names (e.g. `["package/service.proto"]`) as a JSON array,
+    /// - `GET /.well-known/proto/<file name>` returns the file as an encoded `google.protobuf.FileDescriptorProto`.
+    ///
+    /// See [`with_route_listing`](Self::with_route_listing) for the options applying to them.
+    pub fn with_schema_registry(mut self, pool: DescriptorPool) -> Self {
+        self.schema_registry = Some(pool);
+        self
     }
-}
 
-#[cfg(feature = "grpc")]
-struct GrpcService<S, C> {
-    service: S,
-    callback: C,
-}
+    /// Adds `headers` to all the responses of the router (e.g. `Strict-Transport-Security`).
+    ///
+    /// The headers already set in a response are kept: `headers` only provides default values.
+    /// Calling this method several times merges the headers.
+    pub fn with_response_headers(mut self, headers: HeaderMap) -> Self {
+        self.response_headers
+            .get_or_insert_with(HeaderMap::new)
+            .extend(headers);
+        self
+    }
 
-#[cfg(feature = "grpc")]
-impl<
-    S: Clone + Send + Sync + 'static,
-    I: ReflectMessage + Default + 'static,
-    O: ReflectMessage + 'static,
-    C: (Fn(S, I, RequestParts) -> F) + Clone + Send + 'static,
-    F: Future<Output = Result<O, TwirpError>> + Send + 'static,
-> tonic::server::UnaryService<I> for GrpcService<S, C>
-{
-    type Response = O;
-    type Future = TonicResponseFuture<O>;
+    /// Adds the `Content-Security-Policy: <policy>` header to all the responses of the router.
+    ///
+    /// [`ContentSecurityPolicy::default_twirp`](crate::ContentSecurityPolicy::default_twirp) provides a strict policy for Twirp endpoints.
+    /// Like with [`with_response_headers`](Self::with_response_headers), a policy set by a handler takes precedence.
+    ///
+    /// # Panics
+    ///
+    /// If `policy` is not a valid header value.
+    pub fn with_content_security_policy(self, policy: &str) -> Self {
+        let policy = HeaderValue::try_from(policy).expect("invalid Content-Security-Policy");
+        self.with_response_headers(HeaderMap::from_iter([(CONTENT_SECURITY_POLICY, policy)]))
+    }
 
-    fn call(&mut self, request: tonic::Request<I>) -> Self::Future {
-        let (request, parts) = grpc_to_twirp_request(request);
-        let result_future = (self.callback)(self.service.clone(), request, parts);
-        Box::pin(async move { Ok(tonic::Response::new(result_future.await?)) })
+    /// Handles [CORS](https://developer.mozilla.org/en-US/docs/Web/HTTP/CORS) following `config`.
+    ///
+    /// The preflight `OPTIONS` requests are answered directly and the other responses get the `Access-Control-Allow-Origin` header.
+    /// The requests from an origin that is not allowed fail with a `permission_denied` error.
+    pub fn with_cors(mut self, config: CorsConfig) -> Self {
+        self.cors = Some(config);
+        self
     }
-}
 
-#[cfg(feature = "grpc")]
-impl<
-    S: Clone + Send + Sync + 'static,
-    I: ReflectMessage + Default + 'static,
-    O: ReflectMessage + 'static,
-    C: (Fn(S, I, RequestParts) -> F) + Clone + Send + 'static,
-    F: Future<Output = Result<OS, TwirpError>> + Send + 'static,
-    OS: Stream<Item = Result<O, TwirpError>> + Send + 'static,
-> tonic::server::ServerStreamingService<I> for GrpcService<S, C>
-{
-    type Response = O;
-    type ResponseStream = Pin<Box<dyn Stream<Item = Result<O, tonic::Status>> + Send>>;
-    type Future = TonicResponseFuture<Self::ResponseStream>;
+    /// Compresses the responses with [`CompressionLayer`].
+    ///
+    /// The encoding is chosen from the `Accept-Encoding` request header following its quality values
+    /// (e.g. `deflate` is chosen for `gzip;q=0.5, deflate`).
+    #[cfg(feature = "compression")]
+    pub fn with_response_compression(mut self) -> Self {
+        self.response_compression = true;
+        self
+    }
 
-    fn call(&mut self, request: tonic::Request<I>) -> Self::Future {
-        let (request, parts) = grpc_to_twirp_request(request);
-        let result_future = (self.callback)(self.service.clone(), request, parts);
-        Box::pin(async move {
-            Ok(tonic::Response::new(
-                Box::pin(result_future.await?.map(|item| Ok(item?))) as Self::ResponseStream,
-            ))
+    /// Decompresses the request bodies following their `Content-Encoding` header.
+    ///
+    /// `gzip` and `deflate` are supported, and `zstd` too if the `zstd` feature is enabled.
+    /// The body limit set with [`with_body_limit`](Self::with_body_limit) applies to the decompressed body,
+    /// protecting against decompression bombs, and invalid compressed bodies are rejected with a `malformed` error.
+    #[cfg(feature = "compression")]
+    pub fn with_request_decompression(mut self) -> Self {
+        self.request_decompression = true;
+        self
+    }
+
+    /// Sets the options of the JSON encoding of the responses.
+    pub fn with_json_options(mut self, options: TwirpJsonOptions) -> Self {
+        self.json_options = Some(options);
+        self
+    }
+
+    /// Sets how the requests sent in TLS 1.3 0-RTT early data are handled.
+    ///
+    /// By default, the router does not look at early data.
+    pub fn with_early_data(mut self, policy: EarlyDataPolicy) -> Self {
+        self.early_data = Some(policy);
+        self
+    }
+
+    /// Records in `registry` the metrics of the routes registered with [`route_with_concurrency_limit`](Self::route_with_concurrency_limit).
+    ///
+    /// The `twirp_concurrency_queue_duration_seconds` histogram measures how long the requests waited for the limit
+    /// and the `twirp_concurrency_rejected_total` counter the requests abandoned while waiting (e.g. client disconnected).
+    /// Both have a `route` label.
+    ///
+    /// Fails if they are already registered in `registry`, e.g. by another router.
+    #[cfg(feature = "prometheus")]
+    pub fn with_concurrency_metrics(
+        mut self,
+        registry: prometheus::Registry,
+    ) -> prometheus::Result<Self> {
+        self.concurrency_metrics = Some(ConcurrencyMetrics::register(&registry)?);
+        Ok(self)
+    }
+
+    pub fn route<
+        I: ReflectMessage + Default,
+        O: ReflectMessage,
+        F: Future<Output = Result<O, TwirpError>> + Send,
+    >(
+        self,
+        path: &str,
+        call: impl (Fn(S, I, RequestParts, RS) -> F) + Clone + Send + Sync + 'static,
+    ) -> Self {
+        self.route_with_options(path, RouteOptions::default(), call)
+    }
+
+    /// Same as [`route`](Self::route) but the requests whose body is larger than `limit` bytes
+    /// are rejected with a Twirp `resource_exhausted` error.
+    ///
+    /// It overrides the limit set with [`with_body_limit`](Self::with_body_limit).
+    pub fn route_with_limit<
+        I: ReflectMessage + Default,
+        O: ReflectMessage,
+        F: Future<Output = Result<O, TwirpError>> + Send,
+    >(
+        self,
+        path: &str,
+        limit: usize,
+        call: impl (Fn(S, I, RequestParts, RS) -> F) + Clone + Send + Sync + 'static,
+    ) -> Self {
+        let options = RouteOptions {
+            body_limit: Some(limit),
+            ..RouteOptions::default()
+        };
+        self.route_with_options(path, options, call)
+    }
+
+    /// Same as [`route`](Self::route) but the calls whose handler takes more than `timeout`
+    /// fail with a Twirp `deadline_exceeded` error.
+    ///
+    /// It overrides the timeout set with [`with_timeout`](Self::with_timeout).
+    pub fn route_with_timeout<
+        I: ReflectMessage + Default,
+        O: ReflectMessage,
+        F: Future<Output = Result<O, TwirpError>> + Send,
+    >(
+        self,
+        path: &str,
+        timeout: Duration,
+        call: impl (Fn(S, I, RequestParts, RS) -> F) + Clone + Send + Sync + 'static,
+    ) -> Self {
+        let options = RouteOptions {
+            timeout: Some(timeout),
+            ..RouteOptions::default()
+        };
+        self.route_with_options(path, options, call)
+    }
+
+    /// Same as [`route`](Self::route) but also accepts `GET` requests, for methods without side effects.
+    ///
+    /// The request message of the `GET` requests is encoded in binary protobuf then in base64url
+    /// in the `twirp_request` (or `request`) query parameter.
+    /// Their response is in JSON unless the `Accept` header is `application/protobuf`.
+    pub fn route_idempotent<
+        I: ReflectMessage + Default,
+        O: ReflectMessage,
+        F: Future<Output = Result<O, TwirpError>> + Send,
+    >(
+        self,
+        path: &str,
+        call: impl (Fn(S, I, RequestParts, RS) -> F) + Clone + Send + Sync + 'static,
+    ) -> Self {
+        let options = RouteOptions {
+            get: true,
+            ..RouteOptions::default()
+        };
+        self.route_with_options(path, options, call)
+    }
+
+    fn route_with_options<
+        I: ReflectMessage + Default,
+        O: ReflectMessage,
+        F: Future<Output = Result<O, TwirpError>> + Send,
+    >(
+        mut self,
+        path: &str,
+        options: RouteOptions,
+        call: impl (Fn(S, I, RequestParts, RS) -> F) + Clone + Send + Sync + 'static,
+    ) -> Self {
+        let path = format!("{}{path}", self.prefix);
+        self.routes.push(ListedRoute {
+            path: path.clone(),
+            enabled: true,
+            get: options.get,
+        });
+        let methods = if options.get {
+            MethodFilter::POST.or(MethodFilter::GET)
+        } else {
+            MethodFilter::POST
+        };
+        let service = self.service.clone();
+        self.router = self.router.route(
+            &path,
+            on(
+                methods,
+                move |HandlerState(state, _): HandlerState<RS, St>, request: Request| async move {
+                    let limit = options.body_limit.or_else(|| {
+                        request
+                            .extensions()
+                            .get::<RequestBodyLimit>()
+                            .map(|limit| limit.0)
+                    });
+                    let timeout = options.timeout.or_else(|| {
+                        request
+                            .extensions()
+                            .get::<RequestTimeout>()
+                            .map(|timeout| timeout.0)
+                    });
+                    let (parts, body) = match limit {
+                        Some(limit) => {
+                            let (parts, body) = request.into_parts();
+                            (parts, Body::new(Limited::new(body, limit)))
+                        }
+                        None => request.with_limited_body().into_parts(),
+                    };
+                    let (request, response_content_type) = if parts.method == Method::GET {
+                        let response_content_type =
+                            response_content_type(&parts.headers, ContentType::Json);
+                        (parse_query_request(&parts.uri)?, response_content_type)
+                    } else {
+                        let (content_type, response_content_type) =
+                            content_type_from_request_headers(&parts.headers)?;
+                        (
+                            parse_request(content_type, body).await?,
+                            response_content_type,
+                        )
+                    };
+                    #[cfg(feature = "validate")]
+                    if parts.extensions.get::<TwirpRequestValidation>().is_some() {
+                        prost_reflect_validate::validate(&request)
+                            .map_err(|e| TwirpError::malformed(e.to_string()))?;
+                    }
+                    let json_options = parts
+                        .extensions
+                        .get::<TwirpJsonOptions>()
+                        .copied()
+                        .unwrap_or_default();
+                    let response = call(service, request, parts, state);
+                    let response = match timeout {
+                        Some(timeout) => run_with_timeout(timeout, response).await??,
+                        None => response.await?,
+                    };
+                    serialize_response(response_content_type, response, json_options)
+                },
+            )
+            .fallback(move || async move { twirp_method_not_allowed(options.get) }),
+        );
+        self
+    }
+
+    /// Same as [`route`](Self::route) but at most `limit` requests are handled at the same time.
+    ///
+    /// The other requests wait for a running one to finish.
+    pub fn route_with_concurrency_limit<
+        I: ReflectMessage + Default,
+        O: ReflectMessage,
+        F: Future<Output = Result<O, TwirpError>> + Send,
+    >(
+        self,
+        path: &str,
+        limit: usize,
+        call: impl (Fn(S, I, RequestParts, RS) -> F) + Clone + Send + Sync + 'static,
+    ) -> Self {
+        let semaphore = Arc::new(Semaphore::new(limit));
+        let route: Arc<str> = path.into();
+        self.route(path, move |service, request, parts, state| {
+            let semaphore = semaphore.clone();
+            let route = route.clone();
+            let call = call.clone();
+            async move {
+                let _permit = acquire_permit(&semaphore, &route, &parts.extensions).await?;
+                call(service, request, parts, state).await
+            }
         })
     }
+
+    pub fn route_streaming(mut self, path: &str) -> Self {
+        let path = format!("{}{path}", self.prefix);
+        self.routes.push(ListedRoute {
+            path: path.clone(),
+            enabled: false,
+            get: false,
+        });
+        self.router = self.router.route(
+            &path,
+            post(move || async move {
+                TwirpError::unimplemented("Streaming is not supported by Twirp")
+            })
+            .fallback(|| async { twirp_method_not_allowed(false) }),
+        );
+        self
+    }
+
+    /// Adds the routes of `other` (e.g. built in another module) to this router.
+    ///
+    /// Only the routes and the layers already applied to them are taken from `other`,
+    /// the options (e.g. [`with_timeout`](Self::with_timeout)) are the ones of this router.
+    ///
+    /// # Panics
+    ///
+    /// If both routers have a route with the same path, like [`Router::merge`].
+    pub fn merge<St2: ProvidedState<RS, RouterState = St::RouterState>>(
+        mut self,
+        other: TwirpRouter<S, RS, St2>,
+    ) -> Self {
+        self.router = self.router.merge(other.router);
+        self.routes.extend(other.routes);
+        self
+    }
+
+    /// Returns the paths of the registered routes, including their prefix and the ones added by
+    /// [`merge`](Self::merge) or [`nest`](Self::nest), in registration order.
+    ///
+    /// The endpoints added by options like [`with_health_check`](Self::with_health_check) are not listed.
+    pub fn registered_paths(&self) -> Vec<String> {
+        self.routes.iter().map(|route| route.path.clone()).collect()
+    }
+
+    /// Prepends `path_prefix` (e.g. `/twirp`) to the paths of the routes registered after this call.
+    ///
+    /// It replaces the prefix set by a previous call.
+    ///
+    /// # Panics
+    ///
+    /// If `path_prefix` does not start with `/` or ends with `/`.
+    pub fn prefix(mut self, path_prefix: &str) -> Self {
+        check_path_prefix(path_prefix);
+        self.prefix = path_prefix.into();
+        self
+    }
+
+    /// Mounts the routes of `other` under `path_prefix`, appended to the current [`prefix`](Self::prefix).
+    ///
+    /// Like with [`merge`](Self::merge), the options of `other` are ignored.
+    ///
+    /// # Panics
+    ///
+    /// If `path_prefix` does not start with `/` or ends with `/`.
+    pub fn nest<St2: ProvidedState<RS, RouterState = St::RouterState>>(
+        mut self,
+        path_prefix: &str,
+        other: TwirpRouter<S, RS, St2>,
+    ) -> Self {
+        check_path_prefix(path_prefix);
+        let path_prefix = format!("{}{path_prefix}", self.prefix);
+        self.router = self.router.nest(&path_prefix, other.router);
+        self.routes
+            .extend(other.routes.into_iter().map(|route| ListedRoute {
+                path: format!("{path_prefix}{}", route.path),
+                ..route
+            }));
+        self
+    }
+
+    /// Applies `layer` to the routes registered so far, like [`Router::layer`].
+    ///
+    /// The routes registered after this call are not affected.
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<Route> + Clone + Send + Sync + 'static,
+        L::Service: Service<Request> + Clone + Send + Sync + 'static,
+        <L::Service as Service<Request>>::Response: IntoResponse + 'static,
+        <L::Service as Service<Request>>::Error: Into<Infallible> + 'static,
+        <L::Service as Service<Request>>::Future: Send + 'static,
+    {
+        self.router = self.router.layer(layer);
+        self
+    }
+
+    fn build_router(self) -> (Router<St::RouterState>, St) {
+        let mut router = self.router;
+        if self.fallback {
+            router = router.fallback(twirp_fallback);
+        }
+        #[cfg(feature = "dedup")]
+        if let Some((window, store)) = self.content_dedup {
+            router = router.layer(middleware::from_fn_with_state(
+                ContentDedup::new(window, store, self.body_limit),
+                deduplicate_content,
+            ));
+        }
+        if let Some(logger) = self.request_body_logger {
+            router = router.layer(middleware::from_fn_with_state(
+                BodyLog {
+                    logger,
+                    #[cfg(feature = "body-encryption")]
+                    cipher: self.body_encryption,
+                    body_limit: self.body_limit,
+                },
+                log_request_body,
+            ));
+        }
+        #[cfg(feature = "prometheus")]
+        if let Some(metrics) = self.concurrency_metrics {
+            router = router.layer(Extension(metrics));
+        }
+        if let Some(limit) = self.body_limit {
+            router = router.layer(Extension(RequestBodyLimit(limit)));
+        }
+        if let Some(timeout) = self.timeout {
+            router = router.layer(Extension(RequestTimeout(timeout)));
+        }
+        if let Some(options) = self.json_options {
+            router = router.layer(Extension(options));
+        }
+        #[cfg(feature = "validate")]
+        if self.validation {
+            router = router.layer(Extension(TwirpRequestValidation));
+        }
+        #[cfg(feature = "baggage")]
+        if self.baggage_propagation {
+            router = router.layer(middleware::from_fn(extract_baggage));
+        }
+        if let Some(policy) = self.early_data {
+            router = router.layer(middleware::from_fn_with_state(
+                Arc::new(policy),
+                check_early_data,
+            ));
+        }
+        if let Some(auth) = self.api_key_auth {
+            router = router.layer(auth);
+        }
+        if self.method_validation {
+            let get_paths = self
+                .routes
+                .iter()
+                .filter(|route| route.get)
+                .map(|route| route.path.clone())
+                .collect::<HashSet<_>>();
+            router = router.layer(middleware::from_fn_with_state(
+                Arc::new(get_paths),
+                validate_twirp_method,
+            ));
+        }
+        if let Some(max) = self.max_header_count {
+            router = router.layer(middleware::from_fn_with_state(max, check_header_count));
+        }
+        if let Some(limit) = self.concurrency_limit {
+            router = router.layer(middleware::from_fn_with_state(
+                Arc::new(Semaphore::new(limit)),
+                limit_concurrency,
+            ));
+        }
+        #[cfg(feature = "compression")]
+        if self.response_compression {
+            router = router.layer(CompressionLayer::new());
+        }
+        #[cfg(feature = "compression")]
+        if self.request_decompression {
+            router = router.layer(RequestDecompressionLayer::new().pass_through_unaccepted(true));
+        }
+        if self.route_listing {
+            let listing = Bytes::from(
+                serde_json::Value::from_iter(self.routes.iter().map(|route| {
+                    serde_json::json!({
+                        "method": "POST",
+                        "path": route.path,
+                        "enabled": route.enabled,
+                    })
+                }))
+                .to_string(),
+            );
+            router = router.route(
+                "/.well-known/twirp-routes",
+                get(move || async move { ([(CONTENT_TYPE, APPLICATION_JSON)], listing) }),
+            );
+        }
+        if let Some((path, check)) = self.health_check {
+            router = router.route(
+                &path,
+                get(move || async move { health_check_response(&check) }),
+            );
+        }
+        if let Some(pool) = &self.schema_registry {
+            router = router.merge(schema_registry_router(pool));
+        }
+        if let Some(config) = self.cors {
+            router = router.layer(middleware::from_fn_with_state(Arc::new(config), apply_cors));
+        }
+        if let Some(headers) = self.response_headers {
+            router = router.layer(middleware::from_fn_with_state(
+                Arc::new(headers),
+                add_response_headers,
+            ));
+        }
+        (router, self.state)
+    }
 }
 
-#[cfg(feature = "grpc")]
-impl<
-    S: Clone + Send + Sync + 'static,
-    I: ReflectMessage + Default + 'static,
-    O: ReflectMessage + 'static,
-    C: (Fn(S, GrpcClientStream<I>, RequestParts) -> F) + Clone + Send + 'static,
-    F: Future<Output = Result<O, TwirpError>> + Send + 'static,
-> tonic::server::ClientStreamingService<I> for GrpcService<S, C>
-{
-    type Response = O;
-    type Future = TonicResponseFuture<Self::Response>;
+async fn add_response_headers(
+    State(headers): State<Arc<HeaderMap>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    for name in headers.keys() {
+        if !response.headers().contains_key(name) {
+            for value in headers.get_all(name) {
+                response.headers_mut().append(name, value.clone());
+            }
+        }
+    }
+    response
+}
 
-    fn call(&mut self, request: tonic::Request<tonic::Streaming<I>>) -> Self::Future {
-        let (request, parts) = grpc_to_twirp_request(request);
-        let request = GrpcClientStream { stream: request };
-        let result_future = (self.callback)(self.service.clone(), request, parts);
-        Box::pin(async move { Ok(tonic::Response::new(result_future.await?)) })
+/// State provided by a [`TwirpRouter`] itself to its handlers, its `St` type parameter.
+///
+/// It is `()` for the routers created with [`TwirpRouter::new`], whose state is provided after [`TwirpRouter::build`],
+/// `Arc<RS>` for [`TwirpRouter::with_arc_state`]
+/// and [`AsyncStateFactory`] for [`TwirpRouter::new_with_async_state_factory`].
+pub trait ProvidedState<RS>: 'static {
+    /// State of the axum router the routes are registered on
+    type RouterState: Clone + Send + Sync + 'static;
+
+    /// Returns the state given to the handlers from the state of the axum router,
+    /// `None` if each request gets its own state.
+    fn handler_state(router_state: &Self::RouterState) -> Option<RS>;
+}
+
+impl<RS: Clone + Send + Sync + 'static> ProvidedState<RS> for () {
+    type RouterState = RS;
+
+    #[inline]
+    fn handler_state(router_state: &RS) -> Option<RS> {
+        Some(router_state.clone())
     }
 }
 
-#[cfg(feature = "grpc")]
-impl<
-    S: Clone + Send + Sync + 'static,
-    I: ReflectMessage + Default + 'static,
-    O: ReflectMessage + 'static,
-    C: (Fn(S, GrpcClientStream<I>, RequestParts) -> F) + Clone + Send + 'static,
-    F: Future<Output = Result<OS, TwirpError>> + Send + 'static,
-    OS: Stream<Item = Result<O, TwirpError>> + Send + 'static,
-> tonic::server::StreamingService<I> for GrpcService<S, C>
+impl<RS: Send + Sync + 'static> ProvidedState<Arc<RS>> for Arc<RS> {
+    type RouterState = Arc<RS>;
+
+    #[inline]
+    fn handler_state(router_state: &Arc<RS>) -> Option<Arc<RS>> {
+        Some(router_state.clone())
+    }
+}
+
+/// Per-request state factory set with [`TwirpRouter::new_with_async_state_factory`]
+pub struct AsyncStateFactory<RS>(StateFactory<RS>);
+
+impl<RS: 'static> ProvidedState<RS> for AsyncStateFactory<RS> {
+    /// The routes get the state of each request from its extensions
+    type RouterState = ();
+
+    #[inline]
+    fn handler_state((): &()) -> Option<RS> {
+        None
+    }
+}
+
+type StateFactory<RS> =
+    Arc<dyn Fn() -> Pin<Box<dyn Future<Output = Result<RS, TwirpError>> + Send>> + Send + Sync>;
+
+/// State of a single request, given to the handlers instead of the router state
+#[derive(Clone)]
+struct RequestState<RS>(RS);
+
+/// Extracts the [`RequestState`] of the request if set, else the state provided by the router
+struct HandlerState<RS, St = ()>(RS, PhantomData<fn() -> St>);
+
+impl<RS: Clone + Send + Sync + 'static, St: ProvidedState<RS>> FromRequestParts<St::RouterState>
+    for HandlerState<RS, St>
 {
-    type Response = O;
-    type ResponseStream = Pin<Box<dyn Stream<Item = Result<O, tonic::Status>> + Send>>;
-    type Future = TonicResponseFuture<Self::ResponseStream>;
+    type Rejection = TwirpError;
 
-    fn call(&mut self, request: tonic::Request<tonic::Streaming<I>>) -> Self::Future {
-        let (request, parts) = grpc_to_twirp_request(request);
-        let request = GrpcClientStream { stream: request };
-        let result_future = (self.callback)(self.service.clone(), request, parts);
-        Box::pin(async move {
-            Ok(tonic::Response::new(
-                Box::pin(result_future.await?.map(|item| Ok(item?))) as Self::ResponseStream,
-            ))
-        })
+    async fn from_request_parts(
+        parts: &mut RequestParts,
+        state: &St::RouterState,
+    ) -> Result<Self, TwirpError> {
+        let state = match parts.extensions.remove::<RequestState<RS>>() {
+            Some(RequestState(state)) => state,
+            None => St::handler_state(state)
+                .ok_or_else(|| TwirpError::internal("The request has no state"))?,
+        };
+        Ok(Self(state, PhantomData))
     }
 }
 
-#[cfg(feature = "grpc")]
-type TonicResponseFuture<R> =
-    Pin<Box<dyn Future<Output = Result<tonic::Response<R>, tonic::Status>> + Send + 'static>>;
+/// A route registered on a [`TwirpRouter`], for [`TwirpRouter::with_route_listing`]
+struct ListedRoute {
+    path: String,
+    enabled: bool,
+    /// Registered with [`TwirpRouter::route_idempotent`]
+    get: bool,
+}
 
-#[cfg(feature = "grpc")]
-fn grpc_to_twirp_request<T>(request: tonic::Request<T>) -> (T, RequestParts) {
-    let (metadata, extensions, request) = request.into_parts();
-    let mut request_builder = Request::builder().method(Method::POST);
-    *request_builder.headers_mut().unwrap() = metadata.into_headers();
-    *request_builder.extensions_mut().unwrap() = extensions;
-    let (parts, ()) = request_builder.body(()).unwrap().into_parts();
-    (request, parts)
+/// Options overriding the router-wide ones on a specific route
+#[derive(Clone, Copy, Default)]
+struct RouteOptions {
+    body_limit: Option<usize>,
+    timeout: Option<Duration>,
+    get: bool,
 }
 
-#[cfg(feature = "grpc")]
-pin_project! {
-    pub struct GrpcClientStream<O> {
-        #[pin]
-        stream: tonic::Streaming<O>,
+/// Body size limit set with [`TwirpRouter::with_body_limit`]
+#[derive(Clone, Copy)]
+struct RequestBodyLimit(usize);
+
+/// Handler timeout set with [`TwirpRouter::with_timeout`]
+#[derive(Clone, Copy)]
+struct RequestTimeout(Duration);
+
+async fn validate_twirp_method(
+    State(get_paths): State<Arc<HashSet<String>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method();
+    let get = get_paths.contains(request.uri().path());
+    if method == Method::POST || method == Method::OPTIONS || (method == Method::GET && get) {
+        return next.run(request).await;
     }
+    twirp_method_not_allowed(get)
 }
 
-#[cfg(feature = "grpc")]
-impl<O> Stream for GrpcClientStream<O> {
-    type Item = Result<O, TwirpError>;
+/// Twirp only allows `POST` (and `GET` on the idempotent routes if `get` is set),
+/// it is still a `bad_route` error but with a 405 status
+fn twirp_method_not_allowed(get: bool) -> Response {
+    let (allow, message) = if get {
+        ("GET, POST", "Only GET and POST methods are allowed")
+    } else {
+        ("POST", "Only POST method is allowed")
+    };
+    let mut response = TwirpError::new(TwirpErrorCode::BadRoute, message).into_response();
+    *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+    response
+        .headers_mut()
+        .insert(ALLOW, HeaderValue::from_static(allow));
+    response
+}
 
-    fn poll_next(
-        mut self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-    ) -> Poll<Option<Result<O, TwirpError>>> {
-        self.as_mut()
-            .project()
-            .stream
-            .poll_next(cx)
-            .map(|opt| opt.map(|r| Ok(r?)))
+fn check_path_prefix(path_prefix: &str) {
+    assert!(
+        path_prefix.starts_with('/') && !path_prefix.ends_with('/'),
+        "the path prefix {path_prefix:?} must start with '/' and not end with '/'"
+    );
+}
+
+async fn check_header_count(State(max): State<usize>, request: Request, next: Next) -> Response {
+    if request.headers().len() > max {
+        return TwirpError::malformed("Too many request headers").into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(feature = "serve")]
+impl<S: Clone + Send + Sync + 'static> TwirpRouter<S> {
+    /// Serves the router on `listener` until `signal` resolves.
+    ///
+    /// When `signal` resolves, no new connection is accepted but in-flight requests are still served.
+    /// If `drain_timeout` is set, the requests still running after it are aborted.
+    pub async fn serve_with_graceful_shutdown(
+        self,
+        listener: TcpListener,
+        signal: impl Future<Output = ()>,
+        drain_timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        crate::serve::serve_with_graceful_shutdown(
+            listener,
+            self.build(),
+            signal,
+            drain_timeout,
+            ConnectionSettings::default(),
+        )
+        .await
     }
+}
 
-    fn size_hint(&self) -> (usize, Option<usize>) {
-        self.stream.size_hint()
+#[derive(Clone, Copy)]
+enum ContentType {
+    Protobuf,
+    Json,
+}
+
+/// Returns the formats of the request and of the response
+///
+/// The response uses the format of the `Accept` header if it is one of the Twirp ones
+/// and the format of the request otherwise.
+fn content_type_from_request_headers(
+    headers: &HeaderMap,
+) -> Result<(ContentType, ContentType), TwirpError> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .ok_or_else(|| TwirpError::malformed("No content-type header"))?;
+    // The parameters (e.g. `; charset=utf-8`) are ignored
+    let Some(request_format) = twirp_media_type(content_type.as_bytes()).0 else {
+        return Err(TwirpError::malformed(format!(
+            "Unsupported content type: {}",
+            String::from_utf8_lossy(content_type.as_bytes())
+        )));
+    };
+    Ok((
+        request_format,
+        response_content_type(headers, request_format),
+    ))
+}
+
+/// Returns the format of the response from the `Accept` header, `default` if it has no Twirp format.
+///
+/// The header can be a list of media types with parameters, the Twirp one with the highest `q` parameter is picked.
+fn response_content_type(headers: &HeaderMap, default: ContentType) -> ContentType {
+    let mut best: Option<(ContentType, f32)> = None;
+    for media_range in headers
+        .get_all(ACCEPT)
+        .iter()
+        .flat_map(|accept| accept.as_bytes().split(|b| *b == b','))
+    {
+        let (Some(format), parameters) = twirp_media_type(media_range) else {
+            continue;
+        };
+        let quality = parameters
+            .filter_map(|parameter| {
+                let (name, value) = parameter.split_once('=')?;
+                name.trim()
+                    .eq_ignore_ascii_case("q")
+                    .then(|| value.trim().parse().ok())?
+            })
+            .next()
+            .unwrap_or(1.);
+        if quality > 0. && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+            best = Some((format, quality));
+        }
     }
+    best.map_or(default, |(format, _)| format)
 }
 
-pub async fn twirp_error_from_response(response: impl IntoResponse) -> TwirpError {
-    let (parts, body) = response.into_response().into_parts();
-    let body = match body.collect().await {
-        Ok(body) => body.to_bytes(),
-        Err(e) => {
-            error!(
-                "Failed to load the body of the HTTP payload when building a TwirpError from a generic HTTP response: {e}"
-            );
-            return TwirpError::wrap(
-                TwirpErrorCode::Internal,
-                "Failed to map an internal error",
-                e,
+/// Parses a media type with its parameters (e.g. `application/json; charset=utf-8`),
+/// returns its Twirp format if any and its parameters
+fn twirp_media_type(media_type: &[u8]) -> (Option<ContentType>, impl Iterator<Item = &str>) {
+    let mut parts = media_type.split(|b| *b == b';');
+    let essence = parts.next().unwrap_or_default().trim_ascii();
+    let format = if essence.eq_ignore_ascii_case(APPLICATION_PROTOBUF.as_bytes()) {
+        Some(ContentType::Protobuf)
+    } else if essence.eq_ignore_ascii_case(APPLICATION_JSON.as_bytes()) {
+        Some(ContentType::Json)
+    } else {
+        None
+    };
+    (
+        format,
+        parts.filter_map(|parameter| std::str::from_utf8(parameter).ok()),
+    )
+}
+
+/// Extracts the service and method name from a Twirp path `[prefix]/[package.]Service/Method`
+#[cfg(any(feature = "trace", feature = "trace-context"))]
+pub(crate) fn rpc_service_and_method(path: &str) -> (&str, &str) {
+    let mut segments = path.rsplit('/');
+    let method = segments.next().unwrap_or_default();
+    let service = segments.next().unwrap_or_default();
+    (service, method)
+}
+
+/// Parses the request message of a `GET` request from the `twirp_request` or `request` query parameter
+fn parse_query_request<I: ReflectMessage + Default>(uri: &Uri) -> Result<I, TwirpError> {
+    let encoded = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (key == "twirp_request" || key == "request").then_some(value)
+        })
+        .ok_or_else(|| TwirpError::malformed("No request query parameter"))?;
+    let encoded = percent_decode_str(encoded).decode_utf8().map_err(|e| {
+        TwirpError::wrap(
+            TwirpErrorCode::Malformed,
+            "Invalid request query parameter encoding",
+            e,
+        )
+    })?;
+    let binary = BASE64_URL_SAFE_NO_PAD
+        .decode(encoded.trim_end_matches('='))
+        .map_err(|e| {
+            TwirpError::wrap(
+                TwirpErrorCode::Malformed,
+                format!("Invalid base64url request query parameter: {e}"),
+                e,
+            )
+        })?;
+    I::decode(binary.as_slice()).map_err(|e| {
+        TwirpError::wrap(
+            TwirpErrorCode::Malformed,
+            format!("Invalid binary protobuf request: {e}"),
+            e,
+        )
+    })
+}
+
+async fn parse_request<I: ReflectMessage + Default>(
+    content_type: ContentType,
+    body: Body,
+) -> Result<I, TwirpError> {
+    let body = body.collect().await.map_err(|e| {
+        if is_length_limit_error(&e) {
+            return TwirpError::resource_exhausted("Request body too large");
+        }
+        #[cfg(feature = "compression")]
+        if is_io_error(&e) {
+            // Only the decompression of the body produces I/O errors
+            return TwirpError::wrap(
+                TwirpErrorCode::Malformed,
+                "Failed to decompress the request body",
+                e,
+            );
+        }
+        TwirpError::wrap(
+            TwirpErrorCode::Internal,
+            "Failed to read the request body",
+            e,
+        )
+    })?;
+    match content_type {
+        // aggregate() chains the received chunks without copying them
+        ContentType::Protobuf => I::decode(body.aggregate()).map_err(|e| {
+            TwirpError::wrap(
+                TwirpErrorCode::Malformed,
+                format!("Invalid binary protobuf request: {e}"),
+                e,
+            )
+        }),
+        // serde_json is much faster on a contiguous slice than with a reader over the chunks
+        // (see the allocations bench), to_bytes() only copies the bodies received in several chunks
+        ContentType::Json => json_decode(&body.to_bytes()),
+    }
+}
+
+/// Reads the whole body of `request` within `body_limit`, axum's default limit if not set
+pub(crate) async fn collect_request_body(
+    request: Request,
+    body_limit: Option<usize>,
+) -> Result<(RequestParts, Bytes), TwirpError> {
+    let (parts, body) = match body_limit {
+        Some(limit) => {
+            let (parts, body) = request.into_parts();
+            (parts, Body::new(Limited::new(body, limit)))
+        }
+        None => request.with_limited_body().into_parts(),
+    };
+    match body.collect().await {
+        Ok(body) => Ok((parts, body.to_bytes())),
+        Err(e) if is_length_limit_error(&e) => {
+            Err(TwirpError::resource_exhausted("Request body too large"))
+        }
+        Err(e) => Err(TwirpError::internal(format!(
+            "Failed to read the request body: {e}"
+        ))),
+    }
+}
+
+pub(crate) fn is_length_limit_error(error: &(dyn Error + 'static)) -> bool {
+    let mut error = Some(error);
+    while let Some(e) = error {
+        if e.is::<LengthLimitError>() {
+            return true;
+        }
+        error = e.source();
+    }
+    false
+}
+
+#[cfg(feature = "compression")]
+fn is_io_error(error: &(dyn Error + 'static)) -> bool {
+    let mut error = Some(error);
+    while let Some(e) = error {
+        if e.is::<std::io::Error>() {
+            return true;
+        }
+        error = e.source();
+    }
+    false
+}
+
+fn serialize_response<O: ReflectMessage>(
+    content_type: ContentType,
+    response: O,
+    json_options: TwirpJsonOptions,
+) -> Result<Response, TwirpError> {
+    let (content_type, body) = match content_type {
+        // The vector is allocated with the exact encoded length and moved into the body
+        ContentType::Protobuf => (APPLICATION_PROTOBUF, response.encode_to_vec().into()),
+        ContentType::Json => (APPLICATION_JSON, json_encode(&response, json_options)?),
+    };
+    Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .map_err(|e| {
+            error!("Failed to build the response: {e}");
+            TwirpError::internal("Failed to build the response")
+        })
+}
+
+pub(crate) fn json_encode<T: ReflectMessage>(
+    message: &T,
+    options: TwirpJsonOptions,
+) -> Result<Bytes, TwirpError> {
+    let message = message.transcode_to_dynamic();
+    let serialize_options = options.serialize_options();
+    let serialized = if options.base64_url_safe || options.preserve_field_order {
+        message
+            .serialize_with_options(serde_json::value::Serializer, &serialize_options)
+            .and_then(|mut json| {
+                if options.base64_url_safe {
+                    make_bytes_url_safe(&message, &mut json, options.preserve_proto_field_names);
+                }
+                if options.preserve_field_order {
+                    let mut out = Vec::new();
+                    write_in_field_order(
+                        &message,
+                        &json,
+                        options.preserve_proto_field_names,
+                        &mut out,
+                    )?;
+                    Ok(out)
+                } else {
+                    serde_json::to_vec(&json)
+                }
+            })
+    } else {
+        let mut serializer = serde_json::Serializer::new(Vec::new());
+        message
+            .serialize_with_options(&mut serializer, &serialize_options)
+            .map(|()| serializer.into_inner())
+    };
+    let serialized = serialized.map_err(|e| {
+        error!("Failed to serialize the JSON response: {e}");
+        TwirpError::internal("Failed to build the response")
+    })?;
+    Ok(serialized.into())
+}
+
+pub(crate) fn json_decode<T: ReflectMessage + Default>(message: &[u8]) -> Result<T, TwirpError> {
+    let dynamic_message = dynamic_json_decode::<T>(message).map_err(|e| {
+        TwirpError::wrap(
+            TwirpErrorCode::Malformed,
+            format!("Invalid JSON protobuf request: {e}"),
+            e,
+        )
+    })?;
+    dynamic_message.transcode_to().map_err(|e| {
+        error!("Failed to cast input message: {e}");
+        TwirpError::internal("Internal error while parsing the JSON request")
+    })
+}
+
+fn dynamic_json_decode<T: ReflectMessage + Default>(
+    message: &[u8],
+) -> Result<DynamicMessage, serde_json::Error> {
+    let mut deserializer = serde_json::Deserializer::from_slice(message);
+    let dynamic_message =
+        DynamicMessage::deserialize(T::default().descriptor(), &mut deserializer)?;
+    deserializer.end()?;
+    Ok(dynamic_message)
+}
+
+#[cfg(feature = "grpc")]
+pub struct GrpcRouter<S, RS = ()> {
+    router: Router<RS>,
+    service: S,
+    paths: Vec<String>,
+    #[cfg(feature = "validate")]
+    validation: bool,
+    #[cfg(feature = "compression")]
+    response_compression: bool,
+    #[cfg(feature = "serve")]
+    connection_settings: ConnectionSettings,
+    timeout: Option<Duration>,
+    method_allowlist: Option<Arc<HashSet<String>>>,
+    concurrency_limit: Option<usize>,
+    channel_credentials: Option<Arc<HashSet<String>>>,
+    field_constraint_checker: Option<Arc<dyn FieldConstraintChecker>>,
+    metadata_router: Option<MetadataRouter>,
+}
+
+/// Function set with [`GrpcRouter::with_metadata_router`]
+#[cfg(feature = "grpc")]
+type MetadataRouter = Arc<dyn Fn(&tonic::metadata::MetadataMap) -> String + Send + Sync>;
+
+#[cfg(feature = "grpc")]
+impl<S: Clone + Send + Sync + 'static, RS: Clone + Send + Sync + 'static> GrpcRouter<S, RS> {
+    pub fn new(service: S) -> Self {
+        Self {
+            router: Router::new(),
+            service,
+            paths: Vec::new(),
+            #[cfg(feature = "validate")]
+            validation: false,
+            #[cfg(feature = "compression")]
+            response_compression: false,
+            #[cfg(feature = "serve")]
+            connection_settings: ConnectionSettings::default(),
+            timeout: None,
+            method_allowlist: None,
+            concurrency_limit: None,
+            channel_credentials: None,
+            field_constraint_checker: None,
+            metadata_router: None,
+        }
+    }
+
+    /// Only serves the methods whose path (e.g. `/package.MyService/MyMethod`) is in `allowed_paths`.
+    ///
+    /// The calls to the other methods fail with an `UNIMPLEMENTED` status.
+    pub fn with_method_allowlist(mut self, allowed_paths: HashSet<String>) -> Self {
+        self.method_allowlist = Some(Arc::new(allowed_paths));
+        self
+    }
+
+    /// Fails with a `RESOURCE_EXHAUSTED` status the calls received while `limit` calls are already being handled.
+    ///
+    /// A call is handled until its handler returns the response (or the response stream).
+    pub fn with_concurrency_limit(mut self, limit: usize) -> Self {
+        self.concurrency_limit = Some(limit);
+        self
+    }
+
+    /// Only serves the clients whose TLS certificate has a subject common name
+    /// or a subject alternative name (DNS, URI or e-mail) in `allowed_subjects`.
+    ///
+    /// The leaf certificate is read from the [`PeerCertificates`](crate::PeerCertificates) request extension.
+    /// The calls without a client certificate fail with an `UNAUTHENTICATED` status
+    /// and the calls from the other clients with a `PERMISSION_DENIED` status.
+    pub fn with_channel_credentials(mut self, allowed_subjects: Vec<String>) -> Self {
+        self.channel_credentials = Some(Arc::new(allowed_subjects.into_iter().collect()));
+        self
+    }
+
+    /// Fails with a `DEADLINE_EXCEEDED` status the calls whose handler takes more than `timeout`
+    /// to return the response (or the response stream).
+    ///
+    /// The timeout is sent back in the `grpc-timeout` header of the failed responses.
+    /// The deadline set by the client in the `grpc-timeout` request header is always enforced,
+    /// see [`GrpcDeadline`](crate::GrpcDeadline).
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the HTTP/2 `SETTINGS_MAX_CONCURRENT_STREAMS` sent to the clients,
+    /// i.e. how many concurrent streams (calls) a client can open on a single connection.
+    ///
+    /// Only used by [`serve_with_graceful_shutdown`](Self::serve_with_graceful_shutdown).
+    #[cfg(feature = "serve")]
+    pub fn with_max_concurrent_streams(mut self, max: u32) -> Self {
+        self.connection_settings.max_concurrent_streams = Some(max);
+        self
+    }
+
+    /// Sets the HTTP/2 flow control window of the streams and of the connections and the maximum frame size.
+    ///
+    /// By default, the server uses a 1 MiB window and 16,384 bytes frames
+    /// (the HTTP/2 specification defaults are a 65,535 bytes window and 16,384 bytes frames).
+    /// A window larger than the messages (e.g. 8 MiB) and larger frames (e.g. 1 MiB) improve
+    /// the throughput of large or streaming calls, especially on high latency networks,
+    /// at the cost of more memory per connection.
+    /// See the `flow_control` benchmark for a comparison.
+    ///
+    /// Only used by [`serve_with_graceful_shutdown`](Self::serve_with_graceful_shutdown).
+    ///
+    /// # Panics
+    ///
+    /// If `max_frame_size` is not between 16,384 and 16,777,215 bytes, the bounds allowed by HTTP/2.
+    #[cfg(feature = "serve")]
+    pub fn with_flow_control(mut self, initial_window_size: u32, max_frame_size: u32) -> Self {
+        assert!(
+            (16_384..=16_777_215).contains(&max_frame_size),
+            "the HTTP/2 max frame size {max_frame_size} must be between 16384 and 16777215"
+        );
+        self.connection_settings.initial_window_size = Some(initial_window_size);
+        self.connection_settings.max_frame_size = Some(max_frame_size);
+        self
+    }
+
+    /// Closes the connections on which nothing has been sent or received during `idle_timeout`,
+    /// e.g. because the client has disconnected without closing them.
+    ///
+    /// Only used by [`serve_with_graceful_shutdown`](Self::serve_with_graceful_shutdown).
+    #[cfg(feature = "serve")]
+    pub fn with_connection_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.connection_settings.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// Routes each request to the path returned by `router_fn` from the request metadata
+    /// (e.g. to choose a backend shard) instead of the path sent by the client.
+    ///
+    /// The method allowlist and the other options apply to the returned path.
+    /// If it is not a valid path, the request fails with an `internal` status.
+    pub fn with_metadata_router(
+        mut self,
+        router_fn: impl Fn(&tonic::metadata::MetadataMap) -> String + Clone + Send + Sync + 'static,
+    ) -> Self {
+        self.metadata_router = Some(Arc::new(router_fn));
+        self
+    }
+
+    /// Serves the `Check` method of the [gRPC health checking protocol](https://github.com/grpc/grpc/blob/master/doc/health-checking.md).
+    ///
+    /// It returns `SERVING` if `check` returns `true` and `NOT_SERVING` otherwise, whatever the requested service.
+    /// The route is registered like the other ones: if a method allowlist is set,
+    /// `/grpc.health.v1.Health/Check` must be in it.
+    pub fn with_health_check(
+        self,
+        check: impl Fn() -> bool + Clone + Send + Sync + 'static,
+    ) -> Self {
+        let check: HealthCheck = Arc::new(check);
+        self.route_unary(
+            GRPC_HEALTH_CHECK_PATH,
+            tonic_prost::ProstCodec::default,
+            move |_, _: HealthCheckRequest, _, _| {
+                let response = grpc_health_check_response(&check);
+                async move { Ok(response) }
+            },
+        )
+    }
+
+    /// Serves the [gRPC server reflection](https://github.com/grpc/grpc/blob/master/doc/server-reflection.md) v1 service
+    /// (`grpc.reflection.v1.ServerReflection`) used by tools like `grpcurl` to discover the services.
+    ///
+    /// `file_descriptor_sets` are serialized `FileDescriptorSet`s (e.g. written by `protoc --descriptor_set_out`)
+    /// describing the services of the router.
+    /// The `file_by_filename`, `file_containing_symbol`, `file_containing_extension`,
+    /// `all_extension_numbers_of_type` and `list_services` requests are supported.
+    ///
+    /// Does nothing if the v1 service is already served by [`with_reflection_v1alpha`](Self::with_reflection_v1alpha).
+    ///
+    /// Fails if one of `file_descriptor_sets` is not a valid serialized `FileDescriptorSet`.
+    pub fn with_reflection(self, file_descriptor_sets: &[&[u8]]) -> Result<Self, DescriptorError> {
+        let mut pool = DescriptorPool::new();
+        for file_descriptor_set in file_descriptor_sets {
+            pool.decode_file_descriptor_set(*file_descriptor_set)?;
+        }
+        Ok(self.route_reflection(GRPC_REFLECTION_PATH, pool))
+    }
+
+    /// Serves the gRPC server reflection service in its `grpc.reflection.v1alpha.ServerReflection` version,
+    /// still used by older tools (e.g. older `grpcurl` versions), in addition to its v1 version.
+    ///
+    /// Both are served from `pool`, except the v1 version if it is already served by
+    /// [`with_reflection`](Self::with_reflection).
+    pub fn with_reflection_v1alpha(self, pool: DescriptorPool) -> Self {
+        self.route_reflection(GRPC_REFLECTION_V1ALPHA_PATH, pool.clone())
+            .route_reflection(GRPC_REFLECTION_PATH, pool)
+    }
+
+    /// Serves the reflection service at `path` if it is not already served
+    fn route_reflection(mut self, path: &str, pool: DescriptorPool) -> Self {
+        if self.paths.iter().any(|registered| registered == path) {
+            return self;
+        }
+        let reflection = ReflectionService::new(pool);
+        self.paths.push(path.to_string());
+        self.router = self.router.route(
+            path,
+            post(move |request: Request| async move {
+                let codec = tonic_prost::ProstCodec::default();
+                let mut grpc = tonic::server::Grpc::new(codec);
+                grpc.streaming(reflection, request).await
+            }),
+        );
+        self
+    }
+
+    /// Compresses the responses that are not gRPC ones (e.g. health checks) with [`CompressionLayer`].
+    ///
+    /// gRPC responses are left untouched because gRPC has its own message compression.
+    #[cfg(feature = "compression")]
+    pub fn with_response_compression(mut self) -> Self {
+        self.response_compression = true;
+        self
+    }
+
+    /// Validates the request messages using their [`protoc-gen-validate`](https://github.com/bufbuild/protoc-gen-validate)
+    /// annotations before calling the handlers.
+    ///
+    /// Invalid messages are rejected with an `invalid_argument` status.
+    /// Routes registered with a custom codec (e.g. [`route_with_codec`](Self::route_with_codec)) are not validated.
+    #[cfg(feature = "validate")]
+    pub fn with_validation(mut self) -> Self {
+        self.validation = true;
+        self
+    }
+
+    /// Checks the request messages with `checker` before calling the handlers.
+    ///
+    /// Messages violating some constraints are rejected with an `invalid_argument` status
+    /// whose message is the list of the violations separated by `; `.
+    /// Routes registered with a custom codec (e.g. [`route_with_codec`](Self::route_with_codec)) are not checked.
+    pub fn with_field_constraint_checker(
+        mut self,
+        checker: Arc<dyn FieldConstraintChecker>,
+    ) -> Self {
+        self.field_constraint_checker = Some(checker);
+        self
+    }
+
+    pub fn route<
+        I: ReflectMessage + Default + 'static,
+        O: ReflectMessage + 'static,
+        C: (Fn(S, I, RequestParts, RS) -> F) + Clone + Send + Sync + 'static,
+        F: Future<Output = Result<O, TwirpError>> + Send + 'static,
+    >(
+        self,
+        path: &str,
+        callback: C,
+    ) -> Self {
+        let callback = move |service: S, request: I, parts: RequestParts, state: RS| {
+            let callback = callback.clone();
+            async move {
+                check_grpc_message(&parts.extensions, &request)?;
+                callback(service, request, parts, state).await
+            }
+        };
+        self.route_unary(path, tonic_prost::ProstCodec::default, callback)
+    }
+
+    /// Same as [`route`](Self::route) but also sends a copy of each request to `shadow_callback`
+    /// (e.g. a new implementation to compare with the current one).
+    ///
+    /// `shadow_callback` runs concurrently in a spawned task and its result is ignored:
+    /// the response is always the one of `primary_callback` and the shadow errors are only logged.
+    pub fn route_with_shadow<
+        I: ReflectMessage + Default + Clone + 'static,
+        O: ReflectMessage + 'static,
+        C: (Fn(S, I, RequestParts, RS) -> F) + Clone + Send + Sync + 'static,
+        F: Future<Output = Result<O, TwirpError>> + Send + 'static,
+        SC: (Fn(S, I, RequestParts, RS) -> SF) + Clone + Send + Sync + 'static,
+        SF: Future<Output = Result<SO, TwirpError>> + Send + 'static,
+        SO: Send + 'static,
+    >(
+        self,
+        path: &str,
+        primary_callback: C,
+        shadow_callback: SC,
+    ) -> Self {
+        let shadow_path = path.to_string();
+        let callback = move |service: S, request: I, parts: RequestParts, state: RS| {
+            let primary_callback = primary_callback.clone();
+            let shadow_callback = shadow_callback.clone();
+            let path = shadow_path.clone();
+            async move {
+                // Only the requests passing the checks are sent to the shadow
+                check_grpc_message(&parts.extensions, &request)?;
+                let shadow = shadow_callback(
+                    service.clone(),
+                    request.clone(),
+                    parts.clone(),
+                    state.clone(),
+                );
+                tokio::spawn(async move {
+                    if let Err(e) = shadow.await {
+                        error!("The shadow handler of {path} failed: {e}");
+                    }
+                });
+                primary_callback(service, request, parts, state).await
+            }
+        };
+        self.route_unary(path, tonic_prost::ProstCodec::default, callback)
+    }
+
+    /// Same as [`route`](Self::route) but with a custom [`Codec`](tonic::codec::Codec)
+    /// to serialize the messages (e.g. JSON or MessagePack) instead of protobuf.
+    pub fn route_with_codec<
+        I: Send + 'static,
+        O: Send + 'static,
+        CO: tonic::codec::Codec<Encode = O, Decode = I> + Clone + Send + Sync + 'static,
+        C: (Fn(S, I, RequestParts, RS) -> F) + Clone + Send + Sync + 'static,
+        F: Future<Output = Result<O, TwirpError>> + Send + 'static,
+    >(
+        self,
+        path: &str,
+        codec: CO,
+        callback: C,
+    ) -> Self {
+        self.route_unary(path, move || codec.clone(), callback)
+    }
+
+    fn route_unary<
+        I: Send + 'static,
+        O: Send + 'static,
+        CO: tonic::codec::Codec<Encode = O, Decode = I> + Send + 'static,
+        C: (Fn(S, I, RequestParts, RS) -> F) + Clone + Send + Sync + 'static,
+        F: Future<Output = Result<O, TwirpError>> + Send + 'static,
+    >(
+        mut self,
+        path: &str,
+        make_codec: impl (Fn() -> CO) + Clone + Send + Sync + 'static,
+        callback: C,
+    ) -> Self {
+        let service = self.service.clone();
+        self.paths.push(path.to_string());
+        self.router = self.router.route(
+            path,
+            post(
+                move |HandlerState(state, _): HandlerState<RS>, request: Request| async move {
+                    let method = grpc_service_with_state(service, callback, state);
+                    let mut grpc = tonic::server::Grpc::new(make_codec());
+                    grpc.unary(method, request).await
+                },
+            ),
+        );
+        self
+    }
+
+    /// Same as [`route`](Self::route) but the callback gets the full [`tonic::Request`]
+    /// including its metadata and extensions.
+    pub fn route_tonic_request<
+        I: ReflectMessage + Default + 'static,
+        O: ReflectMessage + 'static,
+        C: (Fn(S, tonic::Request<I>, RS) -> F) + Clone + Send + Sync + 'static,
+        F: Future<Output = Result<O, TwirpError>> + Send + 'static,
+    >(
+        mut self,
+        path: &str,
+        callback: C,
+    ) -> Self {
+        let service = self.service.clone();
+        self.paths.push(path.to_string());
+        self.router = self.router.route(
+            path,
+            post(
+                move |HandlerState(state, _): HandlerState<RS>, request: Request| async move {
+                    let callback = move |service: S, request: tonic::Request<I>| {
+                        let callback = callback.clone();
+                        let state = state.clone();
+                        async move {
+                            check_grpc_message(request.extensions(), request.get_ref())?;
+                            callback(service, request, state).await
+                        }
+                    };
+                    let method = GrpcTonicRequestService { service, callback };
+                    let codec = tonic_prost::ProstCodec::default();
+                    let mut grpc = tonic::server::Grpc::new(codec);
+                    grpc.unary(method, request).await
+                },
+            ),
+        );
+        self
+    }
+
+    /// Same as [`route`](Self::route) but the callback also returns gRPC trailing metadata
+    /// added to the trailers of the response (next to `grpc-status`).
+    pub fn route_with_trailers<
+        I: ReflectMessage + Default + 'static,
+        O: ReflectMessage + 'static,
+        C: (Fn(S, I, RequestParts, RS) -> F) + Clone + Send + Sync + 'static,
+        F: Future<Output = Result<(O, tonic::metadata::MetadataMap), TwirpError>> + Send + 'static,
+    >(
+        mut self,
+        path: &str,
+        callback: C,
+    ) -> Self {
+        let service = self.service.clone();
+        self.paths.push(path.to_string());
+        self.router = self.router.route(
+            path,
+            post(
+                move |HandlerState(state, _): HandlerState<RS>, request: Request| async move {
+                    let trailers = Arc::new(Mutex::new(None));
+                    let callback = {
+                        let trailers = trailers.clone();
+                        move |service: S, request: I, parts: RequestParts, state: RS| {
+                            let callback = callback.clone();
+                            let trailers = trailers.clone();
+                            async move {
+                                check_grpc_message(&parts.extensions, &request)?;
+                                let (response, metadata) =
+                                    callback(service, request, parts, state).await?;
+                                *trailers.lock().unwrap_or_else(PoisonError::into_inner) =
+                                    Some(metadata.into_headers());
+                                Ok(response)
+                            }
+                        }
+                    };
+                    let method = grpc_service_with_state(service, callback, state);
+                    let codec = tonic_prost::ProstCodec::default();
+                    let mut grpc = tonic::server::Grpc::new(codec);
+                    let response = grpc.unary(method, request).await;
+                    // The unary handler is done when the response is returned
+                    let mut extra_trailers = trailers
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .take();
+                    response.map(|body| {
+                        Body::new(body.map_frame(move |mut frame| {
+                            if let Some(trailers) = frame.trailers_mut() {
+                                trailers.extend(extra_trailers.take().unwrap_or_default());
+                            }
+                            frame
+                        }))
+                    })
+                },
+            ),
+        );
+        self
+    }
+
+    /// Registers a method returning a stream of messages.
+    ///
+    /// The stream is dropped when the client cancels the call or disconnects,
+    /// so the resources it holds are released without explicit cancellation handling.
+    pub fn route_server_streaming<
+        I: ReflectMessage + Default + 'static,
+        O: ReflectMessage + 'static,
+        C: (Fn(S, I, RequestParts, RS) -> F) + Clone + Send + Sync + 'static,
+        F: Future<Output = Result<OS, TwirpError>> + Send + 'static,
+        OS: Stream<Item = Result<O, TwirpError>> + Send + 'static,
+    >(
+        self,
+        path: &str,
+        callback: C,
+    ) -> Self {
+        let callback = move |service: S, request: I, parts: RequestParts, state: RS| {
+            let callback = callback.clone();
+            async move {
+                check_grpc_message(&parts.extensions, &request)?;
+                callback(service, request, parts, state).await
+            }
+        };
+        self.route_server_streaming_codec(path, tonic_prost::ProstCodec::default, callback)
+    }
+
+    /// Same as [`route_server_streaming`](Self::route_server_streaming) but with a custom [`Codec`](tonic::codec::Codec)
+    /// like [`route_with_codec`](Self::route_with_codec).
+    pub fn route_server_streaming_with_codec<
+        I: Send + 'static,
+        O: Send + 'static,
+        CO: tonic::codec::Codec<Encode = O, Decode = I> + Clone + Send + Sync + 'static,
+        C: (Fn(S, I, RequestParts, RS) -> F) + Clone + Send + Sync + 'static,
+        F: Future<Output = Result<OS, TwirpError>> + Send + 'static,
+        OS: Stream<Item = Result<O, TwirpError>> + Send + 'static,
+    >(
+        self,
+        path: &str,
+        codec: CO,
+        callback: C,
+    ) -> Self {
+        self.route_server_streaming_codec(path, move || codec.clone(), callback)
+    }
+
+    fn route_server_streaming_codec<
+        I: Send + 'static,
+        O: Send + 'static,
+        CO: tonic::codec::Codec<Encode = O, Decode = I> + Send + 'static,
+        C: (Fn(S, I, RequestParts, RS) -> F) + Clone + Send + Sync + 'static,
+        F: Future<Output = Result<OS, TwirpError>> + Send + 'static,
+        OS: Stream<Item = Result<O, TwirpError>> + Send + 'static,
+    >(
+        mut self,
+        path: &str,
+        make_codec: impl (Fn() -> CO) + Clone + Send + Sync + 'static,
+        callback: C,
+    ) -> Self {
+        let service = self.service.clone();
+        self.paths.push(path.to_string());
+        self.router = self.router.route(
+            path,
+            post(
+                move |HandlerState(state, _): HandlerState<RS>, request: Request| async move {
+                    let method = grpc_service_with_state(service, callback, state);
+                    let mut grpc = tonic::server::Grpc::new(make_codec());
+                    grpc.server_streaming(method, request).await
+                },
+            ),
+        );
+        self
+    }
+
+    pub fn route_client_streaming<
+        I: ReflectMessage + Default + 'static,
+        O: ReflectMessage + 'static,
+        C: (Fn(S, GrpcClientStream<I>, RequestParts, RS) -> F) + Clone + Send + Sync + 'static,
+        F: Future<Output = Result<O, TwirpError>> + Send + 'static,
+    >(
+        self,
+        path: &str,
+        callback: C,
+    ) -> Self {
+        let callback =
+            move |service: S, request: GrpcClientStream<I>, parts: RequestParts, state: RS| {
+                let request = request.checked(&parts.extensions);
+                callback(service, request, parts, state)
+            };
+        self.route_client_streaming_codec(path, tonic_prost::ProstCodec::default, callback)
+    }
+
+    /// Same as [`route_client_streaming`](Self::route_client_streaming) but with a custom [`Codec`](tonic::codec::Codec)
+    /// like [`route_with_codec`](Self::route_with_codec).
+    pub fn route_client_streaming_with_codec<
+        I: Send + 'static,
+        O: Send + 'static,
+        CO: tonic::codec::Codec<Encode = O, Decode = I> + Clone + Send + Sync + 'static,
+        C: (Fn(S, GrpcClientStream<I>, RequestParts, RS) -> F) + Clone + Send + Sync + 'static,
+        F: Future<Output = Result<O, TwirpError>> + Send + 'static,
+    >(
+        self,
+        path: &str,
+        codec: CO,
+        callback: C,
+    ) -> Self {
+        self.route_client_streaming_codec(path, move || codec.clone(), callback)
+    }
+
+    fn route_client_streaming_codec<
+        I: Send + 'static,
+        O: Send + 'static,
+        CO: tonic::codec::Codec<Encode = O, Decode = I> + Send + 'static,
+        C: (Fn(S, GrpcClientStream<I>, RequestParts, RS) -> F) + Clone + Send + Sync + 'static,
+        F: Future<Output = Result<O, TwirpError>> + Send + 'static,
+    >(
+        mut self,
+        path: &str,
+        make_codec: impl (Fn() -> CO) + Clone + Send + Sync + 'static,
+        callback: C,
+    ) -> Self {
+        let service = self.service.clone();
+        self.paths.push(path.to_string());
+        self.router = self.router.route(
+            path,
+            post(
+                move |HandlerState(state, _): HandlerState<RS>, request: Request| async move {
+                    let method = grpc_service_with_state(service, callback, state);
+                    let mut grpc = tonic::server::Grpc::new(make_codec());
+                    grpc.client_streaming(method, request).await
+                },
+            ),
+        );
+        self
+    }
+
+    pub fn route_streaming<
+        I: ReflectMessage + Default + 'static,
+        O: ReflectMessage + 'static,
+        C: (Fn(S, GrpcClientStream<I>, RequestParts, RS) -> F) + Clone + Send + Sync + 'static,
+        F: Future<Output = Result<OS, TwirpError>> + Send + 'static,
+        OS: Stream<Item = Result<O, TwirpError>> + Send + 'static,
+    >(
+        self,
+        path: &str,
+        callback: C,
+    ) -> Self {
+        let callback =
+            move |service: S, request: GrpcClientStream<I>, parts: RequestParts, state: RS| {
+                let request = request.checked(&parts.extensions);
+                callback(service, request, parts, state)
+            };
+        self.route_streaming_codec(path, tonic_prost::ProstCodec::default, callback)
+    }
+
+    /// Same as [`route_streaming`](Self::route_streaming) but with a custom [`Codec`](tonic::codec::Codec)
+    /// like [`route_with_codec`](Self::route_with_codec).
+    pub fn route_streaming_with_codec<
+        I: Send + 'static,
+        O: Send + 'static,
+        CO: tonic::codec::Codec<Encode = O, Decode = I> + Clone + Send + Sync + 'static,
+        C: (Fn(S, GrpcClientStream<I>, RequestParts, RS) -> F) + Clone + Send + Sync + 'static,
+        F: Future<Output = Result<OS, TwirpError>> + Send + 'static,
+        OS: Stream<Item = Result<O, TwirpError>> + Send + 'static,
+    >(
+        self,
+        path: &str,
+        codec: CO,
+        callback: C,
+    ) -> Self {
+        self.route_streaming_codec(path, move || codec.clone(), callback)
+    }
+
+    fn route_streaming_codec<
+        I: Send + 'static,
+        O: Send + 'static,
+        CO: tonic::codec::Codec<Encode = O, Decode = I> + Send + 'static,
+        C: (Fn(S, GrpcClientStream<I>, RequestParts, RS) -> F) + Clone + Send + Sync + 'static,
+        F: Future<Output = Result<OS, TwirpError>> + Send + 'static,
+        OS: Stream<Item = Result<O, TwirpError>> + Send + 'static,
+    >(
+        mut self,
+        path: &str,
+        make_codec: impl (Fn() -> CO) + Clone + Send + Sync + 'static,
+        callback: C,
+    ) -> Self {
+        let service = self.service.clone();
+        self.paths.push(path.to_string());
+        self.router = self.router.route(
+            path,
+            post(
+                move |HandlerState(state, _): HandlerState<RS>, request: Request| async move {
+                    let method = grpc_service_with_state(service, callback, state);
+                    let mut grpc = tonic::server::Grpc::new(make_codec());
+                    grpc.streaming(method, request).await
+                },
+            ),
+        );
+        self
+    }
+
+    /// Adds the routes of `other` (e.g. built in another module) to this router.
+    ///
+    /// Only the routes and the layers already applied to them are taken from `other`,
+    /// the options (e.g. [`with_timeout`](Self::with_timeout)) are the ones of this router.
+    ///
+    /// # Panics
+    ///
+    /// If both routers have a route with the same path, like [`Router::merge`].
+    pub fn merge(mut self, other: GrpcRouter<S, RS>) -> Self {
+        self.router = self.router.merge(other.router);
+        self.paths.extend(other.paths);
+        self
+    }
+
+    /// Returns the paths of the registered routes, including the ones added by [`merge`](Self::merge)
+    /// and by options like [`with_health_check`](Self::with_health_check), in registration order.
+    pub fn registered_paths(&self) -> Vec<String> {
+        self.paths.clone()
+    }
+
+    /// Applies `layer` to the routes registered so far, like [`Router::layer`].
+    ///
+    /// The routes registered after this call are not affected.
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<Route> + Clone + Send + Sync + 'static,
+        L::Service: Service<Request> + Clone + Send + Sync + 'static,
+        <L::Service as Service<Request>>::Response: IntoResponse + 'static,
+        <L::Service as Service<Request>>::Error: Into<Infallible> + 'static,
+        <L::Service as Service<Request>>::Future: Send + 'static,
+    {
+        self.router = self.router.layer(layer);
+        self
+    }
+
+    pub fn build(self) -> Router<RS> {
+        let mut router = self.router;
+        if let Some(timeout) = self.timeout {
+            router = router.layer(middleware::from_fn_with_state(timeout, grpc_timeout));
+        }
+        router = router.layer(middleware::from_fn(grpc_deadline));
+        if let Some(allowlist) = self.method_allowlist {
+            router = router.layer(middleware::from_fn_with_state(
+                allowlist,
+                check_grpc_method_allowlist,
+            ));
+        }
+        if let Some(limit) = self.concurrency_limit {
+            router = router.layer(middleware::from_fn_with_state(
+                Arc::new(Semaphore::new(limit)),
+                limit_grpc_concurrency,
+            ));
+        }
+        if let Some(allowed_subjects) = self.channel_credentials {
+            router = router.layer(middleware::from_fn_with_state(
+                allowed_subjects,
+                check_channel_credentials,
+            ));
+        }
+        #[cfg(feature = "validate")]
+        if self.validation {
+            router = router.layer(Extension(GrpcRequestValidation));
+        }
+        if let Some(checker) = self.field_constraint_checker {
+            router = router.layer(Extension(GrpcFieldConstraints(checker)));
+        }
+        #[cfg(feature = "compression")]
+        if self.response_compression {
+            // The default predicate already excludes gRPC responses
+            router = router.layer(CompressionLayer::new());
+        }
+        if let Some(metadata_router) = self.metadata_router {
+            // Layers run after routing so we need to wrap the router to change the request path
+            let inner = router;
+            // Built on the first request, the handlers get the state from the request extensions
+            let routed = Arc::new(OnceLock::new());
+            router =
+                Router::new().fallback(move |State(state): State<RS>, mut request: Request| {
+                    let inner = inner.clone();
+                    let routed = routed.clone();
+                    let metadata_router = metadata_router.clone();
+                    async move {
+                        let metadata =
+                            tonic::metadata::MetadataMap::from_headers(request.headers().clone());
+                        let path = metadata_router(&metadata);
+                        match replace_uri_path(request.uri(), &path) {
+                            Ok(uri) => *request.uri_mut() = uri,
+                            Err(e) => {
+                                error!(
+                                    "The metadata router returned an invalid path {path:?}: {e}"
+                                );
+                                return tonic::Status::internal("Invalid routed path").into_http();
+                            }
+                        }
+                        let routed: &Router =
+                            routed.get_or_init(|| inner.with_state(state.clone()));
+                        request.extensions_mut().insert(RequestState(state));
+                        let response = routed.clone().call(request).await;
+                        response.unwrap_or_else(|e| match e {})
+                    }
+                });
+        }
+        router
+    }
+}
+
+#[cfg(feature = "grpc")]
+fn replace_uri_path(uri: &Uri, path: &str) -> Result<Uri, axum::http::Error> {
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path.parse()?);
+    Ok(Uri::from_parts(parts)?)
+}
+
+#[cfg(feature = "grpc")]
+async fn check_grpc_method_allowlist(
+    State(allowlist): State<Arc<HashSet<String>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path();
+    if allowlist.contains(path) {
+        return next.run(request).await;
+    }
+    tonic::Status::unimplemented(format!("{path} is not available")).into_http()
+}
+
+#[cfg(all(feature = "grpc", feature = "serve"))]
+impl<S: Clone + Send + Sync + 'static> GrpcRouter<S> {
+    /// Serves the router on `listener` until `signal` resolves.
+    ///
+    /// When `signal` resolves, no new connection is accepted but in-flight requests are still served.
+    /// If `drain_timeout` is set, the requests still running after it are aborted.
+    pub async fn serve_with_graceful_shutdown(
+        self,
+        listener: TcpListener,
+        signal: impl Future<Output = ()>,
+        drain_timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        let connection_settings = self.connection_settings;
+        crate::serve::serve_with_graceful_shutdown(
+            listener,
+            self.build(),
+            signal,
+            drain_timeout,
+            connection_settings,
+        )
+        .await
+    }
+}
+
+/// Marker added to the request extensions when [`TwirpRouter::with_validation`] is enabled
+#[cfg(feature = "validate")]
+#[derive(Clone, Copy)]
+struct TwirpRequestValidation;
+
+/// Marker added to the request extensions when [`GrpcRouter::with_validation`] is enabled
+#[cfg(all(feature = "grpc", feature = "validate"))]
+#[derive(Clone, Copy)]
+struct GrpcRequestValidation;
+
+#[cfg(all(feature = "grpc", feature = "validate"))]
+fn validate_grpc_message<T: ReflectMessage>(message: &T) -> Result<(), TwirpError> {
+    prost_reflect_validate::validate(message)
+        .map_err(|e| TwirpError::invalid_argument(e.to_string()))
+}
+
+/// Checks the field constraints of a gRPC request message.
+///
+/// Set with [`GrpcRouter::with_field_constraint_checker`].
+/// The checker gets the message as a [`DynamicMessage`] so it can look at the field options of the descriptor.
+///
+/// ```
+/// use prost_reflect::{DynamicMessage, MessageDescriptor};
+/// use std::sync::Arc;
+/// use twurst_server::codegen::{FieldConstraintChecker, GrpcRouter};
+///
+/// /// Rejects the messages with an empty `name` field
+/// struct NonEmptyName;
+///
+/// impl FieldConstraintChecker for NonEmptyName {
+///     fn check(
+///         &self,
+///         descriptor: &MessageDescriptor,
+///         message: &DynamicMessage,
+///     ) -> Result<(), Vec<String>> {
+///         match descriptor.get_field_by_name("name") {
+///             Some(field) if !message.has_field(&field) => Err(vec!["name must be set".into()]),
+///             _ => Ok(()),
+///         }
+///     }
+/// }
+///
+/// let _router = GrpcRouter::<_, ()>::new(()).with_field_constraint_checker(Arc::new(NonEmptyName));
+/// ```
+#[cfg(feature = "grpc")]
+pub trait FieldConstraintChecker: Send + Sync {
+    /// Returns the list of the violated constraints if the message is not valid
+    fn check(
+        &self,
+        descriptor: &MessageDescriptor,
+        message: &DynamicMessage,
+    ) -> Result<(), Vec<String>>;
+}
+
+/// Checker added to the request extensions by [`GrpcRouter::with_field_constraint_checker`]
+#[cfg(feature = "grpc")]
+#[derive(Clone)]
+struct GrpcFieldConstraints(Arc<dyn FieldConstraintChecker>);
+
+/// Validates the request message if enabled by [`GrpcRouter::with_validation`]
+/// and checks it with the [`FieldConstraintChecker`] if any
+#[cfg(feature = "grpc")]
+fn check_grpc_message<T: ReflectMessage>(
+    extensions: &Extensions,
+    message: &T,
+) -> Result<(), TwirpError> {
+    #[cfg(feature = "validate")]
+    if extensions.get::<GrpcRequestValidation>().is_some() {
+        validate_grpc_message(message)?;
+    }
+    if let Some(GrpcFieldConstraints(checker)) = extensions.get() {
+        checker
+            .check(&message.descriptor(), &message.transcode_to_dynamic())
+            .map_err(|violations| TwirpError::invalid_argument(violations.join("; ")))?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "grpc")]
+struct GrpcService<S, C> {
+    service: S,
+    callback: C,
+}
+
+/// Builds a [`GrpcService`] whose callback gets the router `state` in addition to the request
+#[cfg(feature = "grpc")]
+fn grpc_service_with_state<S, I, RS: Clone + Send + 'static, F>(
+    service: S,
+    callback: impl (Fn(S, I, RequestParts, RS) -> F) + Clone + Send + 'static,
+    state: RS,
+) -> GrpcService<S, impl (Fn(S, I, RequestParts) -> F) + Clone + Send + 'static> {
+    GrpcService {
+        service,
+        callback: move |service, request, parts| callback(service, request, parts, state.clone()),
+    }
+}
+
+/// Callback of the methods returning a single message
+#[cfg(feature = "grpc")]
+trait GrpcUnaryCallback<S, I> {
+    type Response;
+    /// Future of the handler, polled in place by [`GrpcUnaryFuture`] instead of being boxed
+    type UnboxedFuture: Future<Output = Result<Self::Response, TwirpError>> + Send + 'static;
+
+    fn call(&self, service: S, request: I, parts: RequestParts) -> Self::UnboxedFuture;
+}
+
+#[cfg(feature = "grpc")]
+impl<
+    S,
+    I,
+    O,
+    C: Fn(S, I, RequestParts) -> F,
+    F: Future<Output = Result<O, TwirpError>> + Send + 'static,
+> GrpcUnaryCallback<S, I> for C
+{
+    type Response = O;
+    type UnboxedFuture = F;
+
+    #[inline]
+    fn call(&self, service: S, request: I, parts: RequestParts) -> F {
+        self(service, request, parts)
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl<
+    S: Clone + Send + Sync + 'static,
+    I: Send + 'static,
+    O: Send + 'static,
+    C: GrpcUnaryCallback<S, I, Response = O> + Clone + Send + 'static,
+> tonic::server::UnaryService<I> for GrpcService<S, C>
+{
+    type Response = O;
+    type Future = GrpcUnaryFuture<C::UnboxedFuture>;
+
+    fn call(&mut self, request: tonic::Request<I>) -> Self::Future {
+        let (request, parts) = grpc_to_twirp_request(request);
+        GrpcUnaryFuture {
+            future: self.callback.call(self.service.clone(), request, parts),
+        }
+    }
+}
+
+/// Unary service passing the [`tonic::Request`] as is to the callback
+#[cfg(feature = "grpc")]
+struct GrpcTonicRequestService<S, C> {
+    service: S,
+    callback: C,
+}
+
+#[cfg(feature = "grpc")]
+impl<
+    S: Clone + Send + Sync + 'static,
+    I: ReflectMessage + Default + 'static,
+    O: ReflectMessage + 'static,
+    C: (Fn(S, tonic::Request<I>) -> F) + Clone + Send + 'static,
+    F: Future<Output = Result<O, TwirpError>> + Send + 'static,
+> tonic::server::UnaryService<I> for GrpcTonicRequestService<S, C>
+{
+    type Response = O;
+    type Future = GrpcUnaryFuture<F>;
+
+    fn call(&mut self, request: tonic::Request<I>) -> Self::Future {
+        GrpcUnaryFuture {
+            future: (self.callback)(self.service.clone(), request),
+        }
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl<
+    S: Clone + Send + Sync + 'static,
+    I: Send + 'static,
+    O: Send + 'static,
+    C: (Fn(S, I, RequestParts) -> F) + Clone + Send + 'static,
+    F: Future<Output = Result<OS, TwirpError>> + Send + 'static,
+    OS: Stream<Item = Result<O, TwirpError>> + Send + 'static,
+> tonic::server::ServerStreamingService<I> for GrpcService<S, C>
+{
+    type Response = O;
+    type ResponseStream = Pin<Box<dyn Stream<Item = Result<O, tonic::Status>> + Send>>;
+    type Future = TonicResponseFuture<Self::ResponseStream>;
+
+    fn call(&mut self, request: tonic::Request<I>) -> Self::Future {
+        let (request, parts) = grpc_to_twirp_request(request);
+        let result_future = (self.callback)(self.service.clone(), request, parts);
+        Box::pin(async move {
+            Ok(tonic::Response::new(
+                Box::pin(result_future.await?.map(|item| Ok(item?))) as Self::ResponseStream,
+            ))
+        })
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl<
+    S: Clone + Send + Sync + 'static,
+    I: Send + 'static,
+    O: Send + 'static,
+    C: GrpcUnaryCallback<S, GrpcClientStream<I>, Response = O> + Clone + Send + 'static,
+> tonic::server::ClientStreamingService<I> for GrpcService<S, C>
+{
+    type Response = O;
+    type Future = GrpcUnaryFuture<C::UnboxedFuture>;
+
+    fn call(&mut self, request: tonic::Request<tonic::Streaming<I>>) -> Self::Future {
+        let (request, parts) = grpc_to_twirp_request(request);
+        let request = GrpcClientStream::new(request);
+        GrpcUnaryFuture {
+            future: self.callback.call(self.service.clone(), request, parts),
+        }
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl<
+    S: Clone + Send + Sync + 'static,
+    I: Send + 'static,
+    O: Send + 'static,
+    C: (Fn(S, GrpcClientStream<I>, RequestParts) -> F) + Clone + Send + 'static,
+    F: Future<Output = Result<OS, TwirpError>> + Send + 'static,
+    OS: Stream<Item = Result<O, TwirpError>> + Send + 'static,
+> tonic::server::StreamingService<I> for GrpcService<S, C>
+{
+    type Response = O;
+    type ResponseStream = Pin<Box<dyn Stream<Item = Result<O, tonic::Status>> + Send>>;
+    type Future = TonicResponseFuture<Self::ResponseStream>;
+
+    fn call(&mut self, request: tonic::Request<tonic::Streaming<I>>) -> Self::Future {
+        let (request, parts) = grpc_to_twirp_request(request);
+        let request = GrpcClientStream::new(request);
+        let result_future = (self.callback)(self.service.clone(), request, parts);
+        Box::pin(async move {
+            Ok(tonic::Response::new(
+                Box::pin(result_future.await?.map(|item| Ok(item?))) as Self::ResponseStream,
+            ))
+        })
+    }
+}
+
+#[cfg(feature = "grpc")]
+pin_project! {
+    /// Future of the handlers returning a single message.
+    ///
+    /// It wraps the handler future without boxing it to avoid an allocation per call.
+    struct GrpcUnaryFuture<F> {
+        #[pin]
+        future: F,
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl<O, F: Future<Output = Result<O, TwirpError>>> Future for GrpcUnaryFuture<F> {
+    type Output = Result<tonic::Response<O>, tonic::Status>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.project()
+            .future
+            .poll(cx)
+            .map(|result| Ok(tonic::Response::new(result?)))
+    }
+}
+
+#[cfg(feature = "grpc")]
+type TonicResponseFuture<R> =
+    Pin<Box<dyn Future<Output = Result<tonic::Response<R>, tonic::Status>> + Send + 'static>>;
+
+#[cfg(feature = "grpc")]
+fn grpc_to_twirp_request<T>(request: tonic::Request<T>) -> (T, RequestParts) {
+    let (metadata, extensions, request) = request.into_parts();
+    let mut request_builder = Request::builder().method(Method::POST);
+    *request_builder.headers_mut().unwrap() = metadata.into_headers();
+    *request_builder.extensions_mut().unwrap() = extensions;
+    let (parts, ()) = request_builder.body(()).unwrap().into_parts();
+    (request, parts)
+}
+
+#[cfg(feature = "grpc")]
+pub struct GrpcClientStream<O> {
+    inner: GrpcClientStreamInner<O>,
+}
+
+#[cfg(feature = "grpc")]
+#[allow(clippy::large_enum_variant)] // We do not want to box the common case
+enum GrpcClientStreamInner<O> {
+    Tonic(tonic::Streaming<O>),
+    Boxed(Pin<Box<dyn Stream<Item = Result<O, TwirpError>> + Send>>),
+}
+
+#[cfg(feature = "grpc")]
+impl<O> GrpcClientStream<O> {
+    fn new(stream: tonic::Streaming<O>) -> Self {
+        Self {
+            inner: GrpcClientStreamInner::Tonic(stream),
+        }
+    }
+
+    /// Reads all the messages of the stream, failing on the first error.
+    pub async fn try_collect(self) -> Result<Vec<O>, TwirpError> {
+        self.try_collect_limit(usize::MAX).await
+    }
+
+    /// Reads all the messages of the stream like [`try_collect`](Self::try_collect)
+    /// but fails with a `resource_exhausted` error if there are more than `max` messages.
+    pub async fn try_collect_limit(mut self, max: usize) -> Result<Vec<O>, TwirpError> {
+        let mut messages = Vec::new();
+        while let Some(message) = self.next().await {
+            if messages.len() == max {
+                return Err(TwirpError::resource_exhausted(
+                    "Too many streaming messages",
+                ));
+            }
+            messages.push(message?);
+        }
+        Ok(messages)
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl<O: Into<Bytes> + Send> GrpcClientStream<O> {
+    /// Concatenates the bytes of all the messages (e.g. `google.protobuf.BytesValue`)
+    /// into an [`AsyncRead`] that can be used with the `tokio::io` utilities.
+    ///
+    /// The messages are read when needed, the stream errors are returned as [`io::Error`]
+    /// wrapping the [`TwirpError`].
+    pub fn into_async_read(self) -> impl AsyncRead + Send + Unpin {
+        ClientStreamReader {
+            stream: self,
+            current: Bytes::new(),
+        }
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl<O: Send + 'static> GrpcClientStream<O> {
+    /// Fails with a `deadline_exceeded` error if the next message is not received within `timeout`
+    /// (counted from this call for the first message), then ends the stream.
+    ///
+    /// Unlike [`GrpcRouter::with_timeout`], it only limits the time between two messages.
+    pub fn with_message_timeout(self, timeout: Duration) -> Self {
+        Self {
+            inner: GrpcClientStreamInner::Boxed(Box::pin(MessageTimeout {
+                inner: self,
+                sleep: tokio::time::sleep(timeout),
+                timeout,
+                expired: false,
+            })),
+        }
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl<O: ReflectMessage + 'static> GrpcClientStream<O> {
+    /// Checks each message like [`check_grpc_message`] does
+    fn checked(self, extensions: &Extensions) -> Self {
+        let mut checks = Extensions::new();
+        #[cfg(feature = "validate")]
+        if let Some(validation) = extensions.get::<GrpcRequestValidation>() {
+            checks.insert(*validation);
+        }
+        if let Some(checker) = extensions.get::<GrpcFieldConstraints>() {
+            checks.insert(checker.clone());
+        }
+        if checks.is_empty() {
+            return self;
+        }
+        Self {
+            inner: GrpcClientStreamInner::Boxed(Box::pin(self.map(move |message| {
+                let message = message?;
+                check_grpc_message(&checks, &message)?;
+                Ok(message)
+            }))),
+        }
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl<O: Clone + Send + 'static> GrpcClientStream<O> {
+    /// Splits the stream into two streams that both yield every item of this stream.
+    ///
+    /// Both streams must consume an item before the next one is read from this stream.
+    /// If one of the two streams is dropped, the other one continues alone.
+    pub fn tee(self) -> (Self, Self) {
+        let [first, second] = self.split();
+        (first, second)
+    }
+
+    /// Splits the stream into `N` streams that all yield every item of this stream.
+    ///
+    /// See [`split_n`](Self::split_n).
+    pub fn split<const N: usize>(self) -> [Self; N] {
+        let Ok(branches) = self.split_n(N).try_into() else {
+            unreachable!("split_n(N) returns N streams")
+        };
+        branches
+    }
+
+    /// Splits the stream into `count` streams that all yield every item of this stream.
+    ///
+    /// Each item is buffered until all the streams have consumed it,
+    /// so all the streams must consume an item before the next one is read from this stream.
+    /// If some streams are dropped, the other ones continue without them.
+    pub fn split_n(self, count: usize) -> Vec<Self> {
+        let state = Arc::new(Mutex::new(SplitState {
+            source: self,
+            current: None,
+            pending: vec![false; count],
+            alive: vec![true; count],
+            wakers: vec![None; count],
+            done: false,
+        }));
+        (0..count)
+            .map(|index| Self {
+                inner: GrpcClientStreamInner::Boxed(Box::pin(SplitBranch {
+                    state: state.clone(),
+                    index,
+                })),
+            })
+            .collect()
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl<O> Stream for GrpcClientStream<O> {
+    type Item = Result<O, TwirpError>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<O, TwirpError>>> {
+        match &mut self.inner {
+            GrpcClientStreamInner::Tonic(stream) => Pin::new(stream)
+                .poll_next(cx)
+                .map(|opt| opt.map(|r| Ok(r?))),
+            GrpcClientStreamInner::Boxed(stream) => stream.as_mut().poll_next(cx),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match &self.inner {
+            GrpcClientStreamInner::Tonic(stream) => stream.size_hint(),
+            GrpcClientStreamInner::Boxed(stream) => stream.size_hint(),
+        }
+    }
+}
+
+/// Reader returned by [`GrpcClientStream::into_async_read`]
+#[cfg(feature = "grpc")]
+struct ClientStreamReader<O> {
+    stream: GrpcClientStream<O>,
+    /// Remaining bytes of the last received message
+    current: Bytes,
+}
+
+#[cfg(feature = "grpc")]
+impl<O: Into<Bytes>> AsyncRead for ClientStreamReader<O> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.current.is_empty() {
+            match ready!(Pin::new(&mut self.stream).poll_next(cx)) {
+                Some(Ok(message)) => self.current = message.into(),
+                Some(Err(e)) => return Poll::Ready(Err(io::Error::other(e))),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let len = self.current.len().min(buf.remaining());
+        buf.put_slice(&self.current.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "grpc")]
+pin_project! {
+    /// Stream returned by [`GrpcClientStream::with_message_timeout`]
+    struct MessageTimeout<S> {
+        #[pin]
+        inner: S,
+        #[pin]
+        sleep: tokio::time::Sleep,
+        timeout: Duration,
+        expired: bool,
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl<O, S: Stream<Item = Result<O, TwirpError>>> Stream for MessageTimeout<S> {
+    type Item = Result<O, TwirpError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        if *this.expired {
+            return Poll::Ready(None);
+        }
+        if let Poll::Ready(item) = this.inner.poll_next(cx) {
+            this.sleep
+                .as_mut()
+                .reset(tokio::time::Instant::now() + *this.timeout);
+            return Poll::Ready(item);
+        }
+        if this.sleep.poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        *this.expired = true;
+        Poll::Ready(Some(Err(TwirpError::deadline_exceeded(
+            "Message not received in time",
+        ))))
+    }
+}
+
+/// State shared between the streams returned by [`GrpcClientStream::split_n`]
+#[cfg(feature = "grpc")]
+struct SplitState<O> {
+    source: GrpcClientStream<O>,
+    /// The last item read from the source if some branches have not consumed it yet
+    current: Option<Result<O, TwirpError>>,
+    /// The branches that still have to consume `current`
+    pending: Vec<bool>,
+    /// The branches that have not been dropped yet
+    alive: Vec<bool>,
+    wakers: Vec<Option<Waker>>,
+    done: bool,
+}
+
+#[cfg(feature = "grpc")]
+impl<O> SplitState<O> {
+    fn wake_others(&mut self, index: usize) {
+        for (i, waker) in self.wakers.iter_mut().enumerate() {
+            if i != index {
+                if let Some(waker) = waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "grpc")]
+struct SplitBranch<O> {
+    state: Arc<Mutex<SplitState<O>>>,
+    index: usize,
+}
+
+#[cfg(feature = "grpc")]
+impl<O: Clone> Stream for SplitBranch<O> {
+    type Item = Result<O, TwirpError>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<O, TwirpError>>> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = &mut *state;
+        if state.current.is_some() {
+            if !state.pending[self.index] {
+                // We wait for the other branches to consume the current item
+                state.wakers[self.index] = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            state.pending[self.index] = false;
+            let item = if state.pending.contains(&true) {
+                state.current.clone()
+            } else {
+                // We are the last consumer, the other branches can pull the next item
+                state.wake_others(self.index);
+                state.current.take()
+            };
+            return Poll::Ready(item);
+        }
+        if state.done {
+            return Poll::Ready(None);
+        }
+        match Pin::new(&mut state.source).poll_next(cx) {
+            Poll::Ready(Some(item)) => {
+                for (i, pending) in state.pending.iter_mut().enumerate() {
+                    *pending = i != self.index && state.alive[i];
+                }
+                if state.pending.contains(&true) {
+                    state.current = Some(item.clone());
+                    state.wake_others(self.index);
+                }
+                Poll::Ready(Some(item))
+            }
+            Poll::Ready(None) => {
+                state.done = true;
+                state.wake_others(self.index);
+                Poll::Ready(None)
+            }
+            Poll::Pending => {
+                state.wakers[self.index] = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl<O> Drop for SplitBranch<O> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.alive[self.index] = false;
+        state.wakers[self.index] = None;
+        if state.pending[self.index] {
+            state.pending[self.index] = false;
+            if !state.pending.contains(&true) {
+                state.current = None;
+                state.wake_others(self.index);
+            }
+        } else if state.current.is_none() && !state.done {
+            // This branch may hold the source waker, another branch has to poll the source again
+            state.wake_others(self.index);
+        }
+    }
+}
+
+pub async fn twirp_error_from_response(response: impl IntoResponse) -> TwirpError {
+    let (parts, body) = response.into_response().into_parts();
+    let body = match body.collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) => {
+            error!(
+                "Failed to load the body of the HTTP payload when building a TwirpError from a generic HTTP response: {e}"
+            );
+            return TwirpError::wrap(
+                TwirpErrorCode::Internal,
+                "Failed to map an internal error",
+                e,
+            );
+        }
+    };
+    Response::from_parts(parts, body).into()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::ContentSecurityPolicy;
+    #[cfg(feature = "validate")]
+    use crate::MockTwirpService;
+    #[cfg(feature = "baggage")]
+    use crate::OtelBaggage;
+    use crate::Principal;
+    use crate::twirp_fallback;
+    #[cfg(feature = "grpc")]
+    use axum::http::uri::PathAndQuery;
+    use axum::http::{HeaderName, Method, Request};
+    use http_body_util::BodyExt;
+    use prost::Message;
+    #[cfg(feature = "grpc")]
+    use prost_reflect::bytes::{Buf, BufMut};
+    #[cfg(feature = "serve")]
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    #[cfg(feature = "serve")]
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    #[cfg(feature = "serve")]
+    use tokio::net::TcpStream;
+    #[cfg(any(feature = "prometheus", feature = "serve"))]
+    use tokio::sync::Notify;
+    #[cfg(feature = "serve")]
+    use tokio::sync::oneshot;
+    #[cfg(feature = "grpc")]
+    use tonic::Code;
+    #[cfg(feature = "grpc")]
+    use tonic::client::Grpc;
+    #[cfg(feature = "grpc")]
+    use tonic_prost::ProstCodec;
+    use tower::ServiceExt;
+    use tower_service::Service;
+
+    const FILE_DESCRIPTOR_SET_BYTES: &[u8] = &[
+        10, 107, 10, 21, 101, 120, 97, 109, 112, 108, 101, 95, 115, 101, 114, 118, 105, 99, 101,
+        46, 112, 114, 111, 116, 111, 18, 7, 112, 97, 99, 107, 97, 103, 101, 34, 11, 10, 9, 77, 121,
+        77, 101, 115, 115, 97, 103, 101, 74, 52, 10, 6, 18, 4, 0, 0, 5, 1, 10, 8, 10, 1, 12, 18, 3,
+        0, 0, 18, 10, 8, 10, 1, 2, 18, 3, 2, 0, 16, 10, 10, 10, 2, 4, 0, 18, 4, 4, 0, 5, 1, 10, 10,
+        10, 3, 4, 0, 1, 18, 3, 4, 8, 17, 98, 6, 112, 114, 111, 116, 111, 51,
+    ];
+
+    #[derive(Message, ReflectMessage, PartialEq, Clone)]
+    #[prost_reflect(
+        file_descriptor_set_bytes = "crate::codegen::tests::FILE_DESCRIPTOR_SET_BYTES",
+        message_name = "package.MyMessage"
+    )]
+    pub struct MyMessage {}
+
+    #[tokio::test]
+    async fn test_bad_route() {
+        let router = TwirpRouter::new(()).build().fallback(twirp_fallback);
+        let response = router
+            .into_service()
+            .call(Request::new(Body::empty()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            b"{\"code\":\"bad_route\",\"msg\":\"/ is not a supported Twirp method\"}".as_slice()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_with_fallback() {
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .with_fallback()
+            .build();
+        let response = router
+            .oneshot(json_request("/package.MyService/OtherMethod"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            b"{\"code\":\"bad_route\",\"msg\":\"/package.MyService/OtherMethod is not a supported Twirp method\"}".as_slice()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_no_content_type() {
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .build();
+        let response = router
+            .into_service()
+            .call(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/package.MyService/MyMethod")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            b"{\"code\":\"malformed\",\"msg\":\"No content-type header\"}".as_slice()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ok_binary() {
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .build();
+        let response = router
+            .into_service()
+            .call(
+                Request::builder()
+                    .method(Method::POST)
+                    .header(CONTENT_TYPE, APPLICATION_PROTOBUF)
+                    .uri("/package.MyService/MyMethod")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            [].as_slice()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bad_binary() {
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .build();
+        let response = router
+            .into_service()
+            .call(
+                Request::builder()
+                    .method(Method::POST)
+                    .header(CONTENT_TYPE, APPLICATION_PROTOBUF)
+                    .uri("/package.MyService/MyMethod")
+                    .body(Body::from(b"1234".to_vec()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            b"{\"code\":\"malformed\",\"msg\":\"Invalid binary protobuf request: failed to decode Protobuf message: buffer underflow\"}".as_slice()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ok_json() {
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .build();
+        let response = router
+            .into_service()
+            .call(
+                Request::builder()
+                    .method(Method::POST)
+                    .header(CONTENT_TYPE, APPLICATION_JSON)
+                    .uri("/package.MyService/MyMethod")
+                    .body(Body::from(b"{}".to_vec()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            b"{}".as_slice()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bad_json() {
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .build();
+        let response = router
+            .into_service()
+            .call(
+                Request::builder()
+                    .method(Method::POST)
+                    .header(CONTENT_TYPE, APPLICATION_JSON)
+                    .uri("/package.MyService/MyMethod")
+                    .body(Body::from(b"foo".to_vec()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            b"{\"code\":\"malformed\",\"msg\":\"Invalid JSON protobuf request: expected ident at line 1 column 2\"}".as_slice()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_content_type_parameters() {
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .build();
+        for (content_type, body, status) in [
+            ("application/json; charset=utf-8", "{}", StatusCode::OK),
+            ("Application/JSON;charset=UTF-8", "{}", StatusCode::OK),
+            ("application/protobuf; charset=binary", "", StatusCode::OK),
+            ("text/plain", "{}", StatusCode::BAD_REQUEST),
+            ("text/plain; charset=utf-8", "{}", StatusCode::BAD_REQUEST),
+        ] {
+            let response = router
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method(Method::POST)
+                        .header(CONTENT_TYPE, content_type)
+                        .uri("/package.MyService/MyMethod")
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{content_type}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bad_content_type() {
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .build();
+        let response = router
+            .into_service()
+            .call(
+                Request::builder()
+                    .method(Method::POST)
+                    .header(CONTENT_TYPE, "foo/bar")
+                    .uri("/package.MyService/MyMethod")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            b"{\"code\":\"malformed\",\"msg\":\"Unsupported content type: foo/bar\"}".as_slice()
+        );
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_request() {
+        let router = GrpcRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .build();
+        let path = PathAndQuery::from_static("/package.MyService/MyMethod");
+        let response: MyMessage = Grpc::new(router)
+            .unary(
+                tonic::Request::new(MyMessage {}),
+                path,
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response, MyMessage {})
+    }
+
+    #[cfg(feature = "validate")]
+    static VALIDATED_DESCRIPTOR_POOL: std::sync::LazyLock<prost_reflect::DescriptorPool> =
+        std::sync::LazyLock::new(|| {
+            let mut pool = prost_validate_types::DESCRIPTOR_POOL.clone();
+            let file = DynamicMessage::parse_text_format(
+                pool.get_message_by_name("google.protobuf.FileDescriptorProto")
+                    .unwrap(),
+                r#"
+                name: "validated.proto"
+                package: "package"
+                dependency: "validate.proto"
+                message_type {
+                    name: "ValidatedMessage"
+                    field {
+                        name: "name"
+                        number: 1
+                        label: LABEL_OPTIONAL
+                        type: TYPE_STRING
+                        json_name: "name"
+                        options { [validate.rules] { string { min_len: 1 } } }
+                    }
+                }
+                syntax: "proto3"
+                "#,
+            )
+            .unwrap();
+            pool.decode_file_descriptor_proto(file.encode_to_vec().as_slice())
+                .unwrap();
+            pool
+        });
+
+    #[cfg(feature = "validate")]
+    #[derive(Message, ReflectMessage, PartialEq, Clone)]
+    #[prost_reflect(
+        descriptor_pool = "VALIDATED_DESCRIPTOR_POOL",
+        message_name = "package.ValidatedMessage"
+    )]
+    pub struct ValidatedMessage {
+        #[prost(string, tag = "1")]
+        pub name: String,
+    }
+
+    #[cfg(feature = "validate")]
+    #[tokio::test]
+    async fn test_validation() {
+        let service = MockTwirpService::new(TwirpRouter::new(()).with_validation().route(
+            "/package.MyService/MyMethod",
+            |(), request: ValidatedMessage, _, _| async move { Ok(request) },
+        ));
+        let request = ValidatedMessage { name: "foo".into() };
+        assert_eq!(
+            service
+                .call("/package.MyService/MyMethod", request.clone())
+                .await,
+            Ok(request)
+        );
+        let error = service
+            .call::<_, ValidatedMessage>("/package.MyService/MyMethod", ValidatedMessage::default())
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), TwirpErrorCode::Malformed);
+    }
+
+    #[cfg(all(feature = "grpc", feature = "validate"))]
+    #[tokio::test]
+    async fn test_grpc_validation() {
+        let router = GrpcRouter::new(())
+            .with_validation()
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: ValidatedMessage, _, _| async move { Ok(request) },
+            )
+            .build();
+        let path = PathAndQuery::from_static("/package.MyService/MyMethod");
+        let mut grpc = Grpc::new(router);
+        let request = ValidatedMessage { name: "foo".into() };
+        let response: ValidatedMessage = grpc
+            .unary(
+                tonic::Request::new(request.clone()),
+                path.clone(),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response, request);
+        let status = grpc
+            .unary::<_, ValidatedMessage, _>(
+                tonic::Request::new(ValidatedMessage::default()),
+                path,
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    #[cfg(all(feature = "grpc", feature = "validate"))]
+    #[tokio::test]
+    async fn test_grpc_client_streaming_validation() {
+        let router = GrpcRouter::new(())
+            .with_validation()
+            .route_client_streaming(
+                "/package.MyService/MyMethod",
+                |(), mut request: GrpcClientStream<ValidatedMessage>, _, _| async move {
+                    while request.next().await.transpose()?.is_some() {}
+                    Ok(ValidatedMessage::default())
+                },
+            )
+            .build();
+        let path = PathAndQuery::from_static("/package.MyService/MyMethod");
+        let status = Grpc::new(router)
+            .client_streaming::<_, _, ValidatedMessage, _>(
+                tonic::Request::new(tokio_stream::iter([
+                    ValidatedMessage { name: "foo".into() },
+                    ValidatedMessage::default(),
+                ])),
+                path,
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_field_constraint_checker() {
+        use prost_reflect::prost_types::Timestamp;
+
+        struct PositiveTimestamp;
+
+        impl FieldConstraintChecker for PositiveTimestamp {
+            fn check(
+                &self,
+                descriptor: &MessageDescriptor,
+                message: &DynamicMessage,
+            ) -> Result<(), Vec<String>> {
+                let violations = descriptor
+                    .fields()
+                    .filter(|field| {
+                        let value = message.get_field(field);
+                        value
+                            .as_i64()
+                            .or(value.as_i32().map(i64::from))
+                            .is_some_and(|value| value < 0)
+                    })
+                    .map(|field| format!("{} must not be negative", field.name()))
+                    .collect::<Vec<_>>();
+                if violations.is_empty() {
+                    Ok(())
+                } else {
+                    Err(violations)
+                }
+            }
+        }
+
+        let router = GrpcRouter::new(())
+            .with_field_constraint_checker(Arc::new(PositiveTimestamp))
+            .route(
+                "/package.MyService/Unary",
+                |(), request: Timestamp, _, _| async move { Ok(request) },
+            )
+            .route_client_streaming(
+                "/package.MyService/ClientStreaming",
+                |(), mut request: GrpcClientStream<Timestamp>, _, _| async move {
+                    while request.next().await.transpose()?.is_some() {}
+                    Ok(Timestamp::default())
+                },
+            )
+            .build();
+        let mut grpc = Grpc::new(router);
+        let valid = Timestamp {
+            seconds: 1,
+            nanos: 2,
+        };
+        let invalid = Timestamp {
+            seconds: -1,
+            nanos: -2,
+        };
+
+        let response = grpc
+            .unary::<_, Timestamp, _>(
+                tonic::Request::new(valid),
+                PathAndQuery::from_static("/package.MyService/Unary"),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.into_inner(), valid);
+        let status = grpc
+            .unary::<_, Timestamp, _>(
+                tonic::Request::new(invalid),
+                PathAndQuery::from_static("/package.MyService/Unary"),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(
+            status.message(),
+            "seconds must not be negative; nanos must not be negative"
+        );
+
+        let status = grpc
+            .client_streaming::<_, _, Timestamp, _>(
+                tonic::Request::new(tokio_stream::iter([valid, invalid])),
+                PathAndQuery::from_static("/package.MyService/ClientStreaming"),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_merge() {
+        let first = GrpcRouter::new(()).route(
+            "/package.MyService/First",
+            |(), request: MyMessage, _, _| async move { Ok(request) },
+        );
+        let second = GrpcRouter::new(()).route(
+            "/package.MyService/Second",
+            |(), request: MyMessage, _, _| async move { Ok(request) },
+        );
+        let router = first.merge(second);
+        assert_eq!(
+            router.registered_paths(),
+            ["/package.MyService/First", "/package.MyService/Second"]
+        );
+        let mut grpc = Grpc::new(router.build());
+        for path in ["/package.MyService/First", "/package.MyService/Second"] {
+            grpc.ready().await.unwrap();
+            grpc.unary::<_, MyMessage, _>(
+                tonic::Request::new(MyMessage {}),
+                PathAndQuery::from_static(path),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap();
+        }
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_route_with_shadow() {
+        use prost_reflect::prost_types::Timestamp;
+
+        struct PositiveSeconds;
+
+        impl FieldConstraintChecker for PositiveSeconds {
+            fn check(
+                &self,
+                _: &MessageDescriptor,
+                message: &DynamicMessage,
+            ) -> Result<(), Vec<String>> {
+                match message.get_field_by_name("seconds") {
+                    Some(seconds) if seconds.as_i64().is_some_and(|seconds| seconds < 0) => {
+                        Err(vec!["seconds must not be negative".into()])
+                    }
+                    _ => Ok(()),
+                }
+            }
+        }
+
+        let (shadow_sender, mut shadow_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let router = GrpcRouter::new(())
+            .with_field_constraint_checker(Arc::new(PositiveSeconds))
+            .route_with_shadow(
+                "/package.MyService/MyMethod",
+                |(), request: Timestamp, _, _| async move { Ok(request) },
+                move |(), request: Timestamp, _, _| {
+                    let shadow_sender = shadow_sender.clone();
+                    async move {
+                        shadow_sender.send(request).unwrap();
+                        Err::<Timestamp, _>(TwirpError::internal("shadow failure"))
+                    }
+                },
+            )
+            .build();
+        let mut grpc = Grpc::new(router);
+        let invalid = Timestamp {
+            seconds: -1,
+            nanos: 2,
+        };
+        let status = grpc
+            .unary::<_, Timestamp, _>(
+                tonic::Request::new(invalid),
+                PathAndQuery::from_static("/package.MyService/MyMethod"),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        let request = Timestamp {
+            seconds: 1,
+            nanos: 2,
+        };
+        let response = grpc
+            .unary::<_, Timestamp, _>(
+                tonic::Request::new(request),
+                PathAndQuery::from_static("/package.MyService/MyMethod"),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.into_inner(), request);
+        // The invalid request has not been sent to the shadow
+        assert_eq!(shadow_receiver.recv().await, Some(request));
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn test_grpc_response_compression() {
+        let router = GrpcRouter::new(())
+            .route_with_codec(
+                "/package.MyService/MyMethod",
+                StringCodec,
+                |(), _: String, _, _| async move { Ok("a".repeat(1000)) },
+            )
+            .with_response_compression()
+            .build();
+        let response = router
+            .into_service()
+            .call(
+                Request::builder()
+                    .method(Method::POST)
+                    .header(CONTENT_TYPE, "application/grpc")
+                    .header("accept-encoding", "gzip")
+                    .uri("/package.MyService/MyMethod")
+                    .body(Body::from(vec![0, 0, 0, 0, 0]))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get("content-encoding"), None);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[5..], "a".repeat(1000).as_bytes());
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_request_with_error() {
+        let router = GrpcRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), _: MyMessage, _, _| async move {
+                    Err::<MyMessage, _>(TwirpError::not_found("foo not found"))
+                },
+            )
+            .build();
+        let path = PathAndQuery::from_static("/package.MyService/MyMethod");
+        let status = Grpc::new(router)
+            .unary::<_, MyMessage, _>(
+                tonic::Request::new(MyMessage {}),
+                path,
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+        assert_eq!(status.message(), "foo not found");
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_router_state() {
+        let router = GrpcRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: String, _, prefix: String| async move {
+                    Ok(format!("{prefix} {request}"))
+                },
+            )
+            .route_client_streaming(
+                "/package.MyService/Count",
+                |(), request: GrpcClientStream<String>, _, prefix: String| async move {
+                    Ok(format!(
+                        "{prefix} {}",
+                        request.collect::<Vec<_>>().await.len()
+                    ))
+                },
+            )
+            .build()
+            .with_state("Hello".to_string());
+        let mut client = Grpc::new(router);
+        client.ready().await.unwrap();
+        let response = client
+            .unary(
+                tonic::Request::new("world".to_string()),
+                PathAndQuery::from_static("/package.MyService/MyMethod"),
+                ProstCodec::<String, String>::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.into_inner(), "Hello world");
+        client.ready().await.unwrap();
+        let response = client
+            .client_streaming(
+                tonic::Request::new(tokio_stream::iter(vec!["a".to_string(); 3])),
+                PathAndQuery::from_static("/package.MyService/Count"),
+                ProstCodec::<String, String>::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.into_inner(), "Hello 3");
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_timeout() {
+        let router = GrpcRouter::new(())
+            .route("/package.MyService/MyMethod", |(), _: MyMessage, _, _| {
+                std::future::pending::<Result<MyMessage, TwirpError>>()
+            })
+            .with_timeout(Duration::from_millis(10))
+            .build();
+        let response = router
+            .into_service()
+            .call(
+                Request::builder()
+                    .method(Method::POST)
+                    .header(CONTENT_TYPE, "application/grpc")
+                    .uri("/package.MyService/MyMethod")
+                    .body(Body::from(vec![0, 0, 0, 0, 0]))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers().get("grpc-status"),
+            Some(&HeaderValue::from_static("4"))
+        );
+        assert_eq!(
+            response.headers().get("grpc-message"),
+            Some(&HeaderValue::from_static("RPC%20timed%20out"))
+        );
+        assert_eq!(
+            response.headers().get("grpc-timeout"),
+            Some(&HeaderValue::from_static("10000000n"))
+        );
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_deadline() {
+        use crate::GrpcDeadline;
+
+        let router = GrpcRouter::new(())
+            .route(
+                "/package.MyService/Slow",
+                |(), _: MyMessage, _, _| async move {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    Ok(MyMessage {})
+                },
+            )
+            .route(
+                "/package.MyService/Deadline",
+                |(), _: MyMessage, parts: RequestParts, _| async move {
+                    let deadline = parts.extensions.get::<GrpcDeadline>().unwrap();
+                    assert!(deadline.remaining() <= Duration::from_secs(10));
+                    Ok(MyMessage {})
+                },
+            )
+            .build();
+        let mut grpc = Grpc::new(router);
+
+        let mut request = tonic::Request::new(MyMessage {});
+        request
+            .metadata_mut()
+            .insert("grpc-timeout", "1m".parse().unwrap());
+        grpc.ready().await.unwrap();
+        let status = grpc
+            .unary::<_, MyMessage, _>(
+                request,
+                PathAndQuery::from_static("/package.MyService/Slow"),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::DeadlineExceeded);
+
+        let mut request = tonic::Request::new(MyMessage {});
+        request.set_timeout(Duration::from_secs(10));
+        grpc.ready().await.unwrap();
+        grpc.unary::<_, MyMessage, _>(
+            request,
+            PathAndQuery::from_static("/package.MyService/Deadline"),
+            ProstCodec::default(),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_server_streaming_dropped_on_disconnect() {
+        struct DropGuard(Arc<std::sync::atomic::AtomicBool>);
+
+        impl Drop for DropGuard {
+            fn drop(&mut self) {
+                self.0.store(true, std::sync::atomic::Ordering::Relaxed);
+            }
+        }
+
+        let dropped = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let router = GrpcRouter::new(())
+            .route_server_streaming("/package.MyService/MyMethod", {
+                let dropped = dropped.clone();
+                move |(), _: MyMessage, _, _| {
+                    let guard = DropGuard(dropped.clone());
+                    async move {
+                        Ok(tokio_stream::iter(std::iter::repeat(())).map(move |()| {
+                            let _guard = &guard;
+                            Ok(MyMessage {})
+                        }))
+                    }
+                }
+            })
+            .build();
+        let response = router
+            .into_service()
+            .call(
+                Request::builder()
+                    .method(Method::POST)
+                    .header(CONTENT_TYPE, "application/grpc")
+                    .uri("/package.MyService/MyMethod")
+                    .body(Body::from(vec![0, 0, 0, 0, 0]))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let mut body = response.into_body();
+        let frame = body.frame().await.unwrap().unwrap();
+        // Empty messages, possibly several in the same frame
+        assert!(frame.into_data().unwrap().starts_with(&[0, 0, 0, 0, 0]));
+        assert!(!dropped.load(std::sync::atomic::Ordering::Relaxed));
+
+        // The client disconnection drops the response body
+        drop(body);
+        assert!(dropped.load(std::sync::atomic::Ordering::Relaxed));
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_route_with_trailers() {
+        let router = GrpcRouter::new(())
+            .route_with_trailers(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move {
+                    let mut trailers = tonic::metadata::MetadataMap::new();
+                    trailers.insert("x-custom-key", "value".parse().unwrap());
+                    Ok((request, trailers))
+                },
+            )
+            .build();
+        let response = router
+            .into_service()
+            .call(
+                Request::builder()
+                    .method(Method::POST)
+                    .header(CONTENT_TYPE, "application/grpc")
+                    .uri("/package.MyService/MyMethod")
+                    .body(Body::from(vec![0, 0, 0, 0, 0]))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap();
+        let trailers = body.trailers().unwrap();
+        assert_eq!(trailers.get("grpc-status").unwrap(), "0");
+        assert_eq!(trailers.get("x-custom-key").unwrap(), "value");
+        assert_eq!(body.to_bytes(), [0, 0, 0, 0, 0].as_slice());
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_client_stream_message_timeout() {
+        let router = GrpcRouter::new(())
+            .route_client_streaming(
+                "/package.MyService/MyMethod",
+                |(), request: GrpcClientStream<MyMessage>, _, _| async move {
+                    let mut request = request.with_message_timeout(Duration::from_millis(10));
+                    let mut count = 0;
+                    while request.next().await.transpose()?.is_some() {
+                        count += 1;
+                    }
+                    Err::<MyMessage, _>(TwirpError::internal(format!(
+                        "{count} messages received before the end of the stream"
+                    )))
+                },
+            )
+            .build();
+        // The client sends a message then stalls
+        let request = tokio_stream::iter([MyMessage {}]).chain(tokio_stream::pending());
+        let status = Grpc::new(router)
+            .client_streaming::<_, _, MyMessage, _>(
+                tonic::Request::new(request),
+                PathAndQuery::from_static("/package.MyService/MyMethod"),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::DeadlineExceeded);
+        assert_eq!(status.message(), "Message not received in time");
+    }
+
+    #[cfg(feature = "grpc")]
+    fn test_client_stream(
+        messages: impl IntoIterator<Item = Result<u32, TwirpError>, IntoIter: Send + 'static>,
+    ) -> GrpcClientStream<u32> {
+        GrpcClientStream {
+            inner: GrpcClientStreamInner::Boxed(Box::pin(tokio_stream::iter(messages))),
+        }
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_client_stream_try_collect() {
+        assert_eq!(
+            test_client_stream([Ok(1), Ok(2), Ok(3)])
+                .try_collect()
+                .await
+                .unwrap(),
+            [1, 2, 3]
+        );
+        assert_eq!(
+            test_client_stream([Ok(1), Err(TwirpError::malformed("Bad")), Ok(3)])
+                .try_collect()
+                .await
+                .unwrap_err(),
+            TwirpError::malformed("Bad")
+        );
+        assert_eq!(
+            test_client_stream([Ok(1), Ok(2), Ok(3)])
+                .try_collect_limit(3)
+                .await
+                .unwrap(),
+            [1, 2, 3]
+        );
+        assert_eq!(
+            test_client_stream([Ok(1), Ok(2), Ok(3)])
+                .try_collect_limit(2)
+                .await
+                .unwrap_err(),
+            TwirpError::resource_exhausted("Too many streaming messages")
+        );
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_client_stream_into_async_read() {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+
+        let stream = GrpcClientStream {
+            inner: GrpcClientStreamInner::Boxed(Box::pin(tokio_stream::iter([
+                Ok(b"first li".to_vec()),
+                Ok(Vec::new()),
+                Ok(b"ne\nsecond line\n".to_vec()),
+            ]))),
+        };
+        let mut lines = BufReader::new(stream.into_async_read()).lines();
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "first line");
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "second line");
+        assert_eq!(lines.next_line().await.unwrap(), None);
+
+        let stream = GrpcClientStream {
+            inner: GrpcClientStreamInner::Boxed(Box::pin(tokio_stream::iter([
+                Ok(Bytes::from("data")),
+                Err(TwirpError::malformed("Bad")),
+            ]))),
+        };
+        let mut content = Vec::new();
+        let error = stream
+            .into_async_read()
+            .read_to_end(&mut content)
+            .await
+            .unwrap_err();
+        assert_eq!(content, b"data");
+        assert_eq!(
+            error
+                .into_inner()
+                .unwrap()
+                .downcast::<TwirpError>()
+                .unwrap(),
+            Box::new(TwirpError::malformed("Bad"))
+        );
+    }
+
+    /// Codec sending UTF-8 strings as is
+    #[cfg(feature = "grpc")]
+    #[derive(Clone, Default)]
+    struct StringCodec;
+
+    #[cfg(feature = "grpc")]
+    impl tonic::codec::Codec for StringCodec {
+        type Encode = String;
+        type Decode = String;
+        type Encoder = Self;
+        type Decoder = Self;
+
+        fn encoder(&mut self) -> Self {
+            Self
+        }
+
+        fn decoder(&mut self) -> Self {
+            Self
+        }
+    }
+
+    #[cfg(feature = "grpc")]
+    impl tonic::codec::Encoder for StringCodec {
+        type Item = String;
+        type Error = tonic::Status;
+
+        fn encode(
+            &mut self,
+            item: String,
+            dst: &mut tonic::codec::EncodeBuf<'_>,
+        ) -> Result<(), tonic::Status> {
+            dst.put_slice(item.as_bytes());
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "grpc")]
+    impl tonic::codec::Decoder for StringCodec {
+        type Item = String;
+        type Error = tonic::Status;
+
+        fn decode(
+            &mut self,
+            src: &mut tonic::codec::DecodeBuf<'_>,
+        ) -> Result<Option<String>, tonic::Status> {
+            let bytes = src.copy_to_bytes(src.remaining());
+            String::from_utf8(bytes.into())
+                .map(Some)
+                .map_err(|e| tonic::Status::invalid_argument(e.to_string()))
+        }
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_request_with_codec() {
+        let router = GrpcRouter::new(())
+            .route_with_codec(
+                "/package.MyService/MyMethod",
+                StringCodec,
+                |(), request: String, _, _| async move { Ok(format!("Hello {request}")) },
+            )
+            .build();
+        let path = PathAndQuery::from_static("/package.MyService/MyMethod");
+        let response = Grpc::new(router)
+            .unary(tonic::Request::new("world".to_string()), path, StringCodec)
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response, "Hello world");
+    }
+
+    /// Codec delegating to [`ProstCodec`] while counting the encoders and decoders it creates
+    #[cfg(feature = "grpc")]
+    #[derive(Clone, Default)]
+    struct PassthroughCodec {
+        codec: ProstCodec<MyMessage, MyMessage>,
+        created: Arc<AtomicUsize>,
+    }
+
+    #[cfg(feature = "grpc")]
+    impl tonic::codec::Codec for PassthroughCodec {
+        type Encode = MyMessage;
+        type Decode = MyMessage;
+        type Encoder = <ProstCodec<MyMessage, MyMessage> as tonic::codec::Codec>::Encoder;
+        type Decoder = <ProstCodec<MyMessage, MyMessage> as tonic::codec::Codec>::Decoder;
+
+        fn encoder(&mut self) -> Self::Encoder {
+            self.created.fetch_add(1, Ordering::Relaxed);
+            self.codec.encoder()
+        }
+
+        fn decoder(&mut self) -> Self::Decoder {
+            self.created.fetch_add(1, Ordering::Relaxed);
+            self.codec.decoder()
+        }
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_streaming_with_codec() {
+        let codec = PassthroughCodec::default();
+        let router = GrpcRouter::new(())
+            .route_server_streaming_with_codec(
+                "/package.MyService/ServerStreaming",
+                codec.clone(),
+                |(), request: MyMessage, _, _| async move {
+                    Ok(tokio_stream::iter([Ok(request.clone()), Ok(request)]))
+                },
+            )
+            .route_client_streaming_with_codec(
+                "/package.MyService/ClientStreaming",
+                codec.clone(),
+                |(), request: GrpcClientStream<MyMessage>, _, _| async move {
+                    assert_eq!(request.try_collect().await?.len(), 2);
+                    Ok(MyMessage {})
+                },
+            )
+            .route_streaming_with_codec(
+                "/package.MyService/Streaming",
+                codec.clone(),
+                |(), request: GrpcClientStream<MyMessage>, _, _| async move { Ok(request) },
+            )
+            .build();
+        let mut client = Grpc::new(router);
+
+        client.ready().await.unwrap();
+        let responses = client
+            .server_streaming(
+                tonic::Request::new(MyMessage {}),
+                PathAndQuery::from_static("/package.MyService/ServerStreaming"),
+                ProstCodec::<MyMessage, MyMessage>::default(),
+            )
+            .await
+            .unwrap()
+            .into_inner()
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(responses, [MyMessage {}, MyMessage {}]);
+
+        client.ready().await.unwrap();
+        let response = client
+            .client_streaming(
+                tonic::Request::new(tokio_stream::iter([MyMessage {}, MyMessage {}])),
+                PathAndQuery::from_static("/package.MyService/ClientStreaming"),
+                ProstCodec::<MyMessage, MyMessage>::default(),
+            )
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response, MyMessage {});
+
+        client.ready().await.unwrap();
+        let responses = client
+            .streaming(
+                tonic::Request::new(tokio_stream::iter([MyMessage {}, MyMessage {}])),
+                PathAndQuery::from_static("/package.MyService/Streaming"),
+                ProstCodec::<MyMessage, MyMessage>::default(),
+            )
+            .await
+            .unwrap()
+            .into_inner()
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(responses, [MyMessage {}, MyMessage {}]);
+
+        // Each call creates an encoder and a decoder
+        assert_eq!(codec.created.load(Ordering::Relaxed), 6);
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_metadata_router() {
+        let shard_route = |router: GrpcRouter<()>, shard: &'static str| {
+            router.route_with_codec(
+                &format!("/package.MyService/Shard{shard}"),
+                StringCodec,
+                move |(), request: String, _, _| async move { Ok(format!("{request} {shard}")) },
+            )
+        };
+        let router = shard_route(shard_route(GrpcRouter::new(()), "0"), "1")
+            .with_metadata_router(|metadata| {
+                let shard = metadata
+                    .get("x-shard")
+                    .and_then(|shard| shard.to_str().ok())
+                    .unwrap_or("0");
+                format!("/package.MyService/Shard{shard}")
+            })
+            .build();
+        let mut grpc = Grpc::new(router);
+        for (shard, expected) in [(None, "shard 0"), (Some("1"), "shard 1")] {
+            let mut request = tonic::Request::new("shard".to_string());
+            if let Some(shard) = shard {
+                request
+                    .metadata_mut()
+                    .insert("x-shard", shard.parse().unwrap());
+            }
+            grpc.ready().await.unwrap();
+            let response = grpc
+                .unary(
+                    request,
+                    PathAndQuery::from_static("/package.MyService/MyMethod"),
+                    StringCodec,
+                )
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response, expected);
+        }
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_metadata_router_state() {
+        let router: Router<&'static str> = GrpcRouter::new(())
+            .route_with_codec(
+                "/package.MyService/Routed",
+                StringCodec,
+                |(), _: String, _, state: &'static str| async move { Ok(state.to_string()) },
+            )
+            .with_metadata_router(|_| "/package.MyService/Routed".into())
+            .build();
+        // The routes are built once but each router gets its own state
+        for state in ["first", "second"] {
+            let mut grpc = Grpc::new(router.clone().with_state::<()>(state));
+            grpc.ready().await.unwrap();
+            let response = grpc
+                .unary(
+                    tonic::Request::new(String::new()),
+                    PathAndQuery::from_static("/package.MyService/MyMethod"),
+                    StringCodec,
+                )
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response, state);
+        }
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_tonic_request() {
+        let router = GrpcRouter::new(())
+            .route_tonic_request(
+                "/package.MyService/MyMethod",
+                |(), request: tonic::Request<MyMessage>, state: &'static str| async move {
+                    if request
+                        .metadata()
+                        .get("x-foo")
+                        .is_none_or(|foo| foo != state)
+                    {
+                        return Err(TwirpError::invalid_argument("x-foo metadata is missing"));
+                    }
+                    Ok(request.into_inner())
+                },
+            )
+            .build()
+            .with_state("bar");
+        let path = PathAndQuery::from_static("/package.MyService/MyMethod");
+        let mut request = tonic::Request::new(MyMessage {});
+        request
+            .metadata_mut()
+            .insert("x-foo", "bar".parse().unwrap());
+        let response: MyMessage = Grpc::new(router)
+            .unary(request, path, ProstCodec::default())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response, MyMessage {})
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_client_streaming_request() {
+        let router = GrpcRouter::new(())
+            .route_client_streaming(
+                "/package.MyService/MyMethod",
+                |(), mut request: GrpcClientStream<MyMessage>, _, _| async move {
+                    let mut count = 0;
+                    while request.next().await.transpose()?.is_some() {
+                        count += 1;
+                    }
+                    if count == 2 {
+                        Ok(MyMessage {})
+                    } else {
+                        Err(TwirpError::invalid_argument("Expecting 2 messages"))
+                    }
+                },
+            )
+            .build();
+        let path = PathAndQuery::from_static("/package.MyService/MyMethod");
+        let response: MyMessage = Grpc::new(router)
+            .client_streaming(
+                tonic::Request::new(tokio_stream::iter([MyMessage {}, MyMessage {}])),
+                path,
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response, MyMessage {})
+    }
+
+    #[cfg(feature = "serve")]
+    async fn send_raw_json_request(address: std::net::SocketAddr) -> String {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"POST /package.MyService/MyMethod HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n{}")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[cfg(feature = "serve")]
+    #[tokio::test]
+    async fn test_graceful_shutdown_drains_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let notifies = Arc::new((Notify::new(), Notify::new()));
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
+        let server = tokio::spawn(
+            TwirpRouter::new(notifies.clone())
+                .route(
+                    "/package.MyService/MyMethod",
+                    |notifies: Arc<(Notify, Notify)>, request: MyMessage, _, _| async move {
+                        notifies.0.notify_one();
+                        notifies.1.notified().await;
+                        Ok(request)
+                    },
+                )
+                .serve_with_graceful_shutdown(
+                    listener,
+                    async move {
+                        shutdown_receiver.await.unwrap();
+                    },
+                    None,
+                ),
+        );
+        let request = tokio::spawn(send_raw_json_request(address));
+        notifies.0.notified().await; // The request is in-flight
+        shutdown_sender.send(()).unwrap();
+        notifies.1.notify_one(); // We let the request finish
+        let response = request.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("{}"), "{response}");
+        server.await.unwrap().unwrap();
+    }
+
+    #[cfg(feature = "serve")]
+    #[tokio::test]
+    async fn test_graceful_shutdown_drain_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let started = Arc::new(Notify::new());
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
+        let server = tokio::spawn(
+            TwirpRouter::new(started.clone())
+                .route(
+                    "/package.MyService/MyMethod",
+                    |started: Arc<Notify>, _: MyMessage, _, _| async move {
+                        started.notify_one();
+                        std::future::pending::<Result<MyMessage, TwirpError>>().await
+                    },
+                )
+                .serve_with_graceful_shutdown(
+                    listener,
+                    async move {
+                        shutdown_receiver.await.unwrap();
+                    },
+                    Some(Duration::from_millis(10)),
+                ),
+        );
+        let request = tokio::spawn(send_raw_json_request(address));
+        started.notified().await;
+        shutdown_sender.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        // The connection has been aborted without response
+        assert_eq!(request.await.unwrap(), "");
+    }
+
+    #[cfg(all(feature = "grpc", feature = "serve"))]
+    #[tokio::test]
+    async fn test_grpc_http2_settings() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
+        let server = tokio::spawn(
+            GrpcRouter::new(())
+                .with_max_concurrent_streams(7)
+                .with_flow_control(1 << 20, 1 << 16)
+                .serve_with_graceful_shutdown(
+                    listener,
+                    async move {
+                        shutdown_receiver.await.unwrap();
+                    },
+                    None,
+                ),
+        );
+
+        // HTTP/2 connection preface followed by an empty SETTINGS frame
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0")
+            .await
+            .unwrap();
+        // The server first frame is its SETTINGS frame
+        let mut header = [0; 9];
+        stream.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[3], 0x04, "not a SETTINGS frame");
+        let mut payload = vec![0; usize::from(header[1]) << 8 | usize::from(header[2])];
+        stream.read_exact(&mut payload).await.unwrap();
+        let setting = |id: u8| {
+            payload.chunks_exact(6).find_map(|setting| {
+                (setting[..2] == [0, id])
+                    .then(|| u32::from_be_bytes(setting[2..].try_into().unwrap()))
+            })
+        };
+        assert_eq!(setting(0x03), Some(7), "SETTINGS_MAX_CONCURRENT_STREAMS");
+        assert_eq!(setting(0x04), Some(1 << 20), "SETTINGS_INITIAL_WINDOW_SIZE");
+        assert_eq!(setting(0x05), Some(1 << 16), "SETTINGS_MAX_FRAME_SIZE");
+
+        drop(stream);
+        shutdown_sender.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[cfg(all(feature = "grpc", feature = "serve"))]
+    #[test]
+    #[should_panic(expected = "must be between 16384 and 16777215")]
+    fn test_grpc_invalid_max_frame_size() {
+        let _ = GrpcRouter::<()>::new(()).with_flow_control(1 << 20, 1 << 24);
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_method_allowlist() {
+        let router = GrpcRouter::new(())
+            .route(
+                "/package.MyService/Allowed",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .route(
+                "/package.MyService/Denied",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .with_method_allowlist(HashSet::from(["/package.MyService/Allowed".into()]))
+            .build();
+        let call = |path: &'static str| {
+            let router = router.clone();
+            async move {
+                let mut client = Grpc::new(router);
+                client.ready().await.unwrap();
+                client
+                    .unary(
+                        tonic::Request::new(MyMessage {}),
+                        PathAndQuery::from_static(path),
+                        ProstCodec::<MyMessage, MyMessage>::default(),
+                    )
+                    .await
+                    .map(|r| r.into_inner())
+                    .map_err(|s| s.code())
+            }
+        };
+        assert_eq!(call("/package.MyService/Allowed").await, Ok(MyMessage {}));
+        assert_eq!(
+            call("/package.MyService/Denied").await,
+            Err(Code::Unimplemented)
+        );
+    }
+
+    #[cfg(feature = "grpc")]
+    async fn call_client_streaming(router: Router, count: usize) -> Result<MyMessage, Code> {
+        let path = PathAndQuery::from_static("/package.MyService/MyMethod");
+        Grpc::new(router)
+            .client_streaming(
+                tonic::Request::new(tokio_stream::iter(vec![MyMessage {}; count])),
+                path,
+                ProstCodec::default(),
+            )
+            .await
+            .map(|r| r.into_inner())
+            .map_err(|s| s.code())
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_client_stream_tee() {
+        let router = GrpcRouter::new(())
+            .route_client_streaming(
+                "/package.MyService/MyMethod",
+                |(), request: GrpcClientStream<MyMessage>, _, _| async move {
+                    let (first, second) = request.tee();
+                    let (first, second) = tokio::join!(
+                        first.collect::<Result<Vec<_>, _>>(),
+                        second.collect::<Result<Vec<_>, _>>()
+                    );
+                    if first?.len() == 3 && second?.len() == 3 {
+                        Ok(MyMessage {})
+                    } else {
+                        Err(TwirpError::invalid_argument(
+                            "Both streams must see 3 messages",
+                        ))
+                    }
+                },
+            )
+            .build();
+        assert_eq!(call_client_streaming(router, 3).await, Ok(MyMessage {}));
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_client_stream_split_n() {
+        let router = GrpcRouter::new(())
+            .route_client_streaming(
+                "/package.MyService/MyMethod",
+                |(), request: GrpcClientStream<MyMessage>, _, _| async move {
+                    let mut branches = request.split_n(4);
+                    drop(branches.pop());
+                    // The branches must be consumed concurrently
+                    let tasks = branches
+                        .into_iter()
+                        .map(|branch| tokio::spawn(branch.collect::<Result<Vec<_>, _>>()))
+                        .collect::<Vec<_>>();
+                    for task in tasks {
+                        let messages = task
+                            .await
+                            .map_err(|e| TwirpError::internal(e.to_string()))?;
+                        if messages?.len() != 5 {
+                            return Err(TwirpError::invalid_argument(
+                                "All streams must see 5 messages",
+                            ));
+                        }
+                    }
+                    Ok(MyMessage {})
+                },
+            )
+            .build();
+        assert_eq!(call_client_streaming(router, 5).await, Ok(MyMessage {}));
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_client_stream_tee_dropped_branch() {
+        let router = GrpcRouter::new(())
+            .route_client_streaming(
+                "/package.MyService/MyMethod",
+                |(), request: GrpcClientStream<MyMessage>, _, _| async move {
+                    let (first, second) = request.tee();
+                    drop(second);
+                    if first.collect::<Result<Vec<_>, _>>().await?.len() == 3 {
+                        Ok(MyMessage {})
+                    } else {
+                        Err(TwirpError::invalid_argument(
+                            "The stream must see 3 messages",
+                        ))
+                    }
+                },
+            )
+            .build();
+        assert_eq!(call_client_streaming(router, 3).await, Ok(MyMessage {}));
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_client_stream_tee_dropped_while_parked() {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let (mut first, mut second) = GrpcClientStream {
+            inner: GrpcClientStreamInner::Boxed(Box::pin(
+                tokio_stream::wrappers::UnboundedReceiverStream::new(receiver),
+            )),
+        }
+        .tee();
+        // The first branch parks on the source
+        let first = tokio::spawn(async move { first.next().await });
+        tokio::task::yield_now().await;
+        // The second branch takes over the source waker and is dropped
+        assert!(
+            tokio::time::timeout(Duration::ZERO, second.next())
+                .await
+                .is_err()
+        );
+        drop(second);
+        sender.send(Ok(MyMessage {})).unwrap();
+        let message = tokio::time::timeout(Duration::from_secs(1), first)
+            .await
+            .expect("The first branch must be woken up")
+            .unwrap();
+        assert!(matches!(message, Some(Ok(MyMessage {}))));
+    }
+
+    #[tokio::test]
+    async fn test_method_validation() {
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .with_method_validation()
+            .build();
+        for uri in ["/package.MyService/MyMethod", "/package.MyService/Unknown"] {
+            let response = router
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method(Method::PUT)
+                        .header(CONTENT_TYPE, APPLICATION_JSON)
+                        .uri(uri)
+                        .body(Body::from(b"{}".to_vec()))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+            assert_eq!(response.headers().get(ALLOW).unwrap(), "POST");
+            assert_eq!(
+                response.into_body().collect().await.unwrap().to_bytes(),
+                b"{\"code\":\"bad_route\",\"msg\":\"Only POST method is allowed\"}".as_slice()
+            );
+        }
+    }
+
+    #[cfg(any(feature = "trace", feature = "trace-context"))]
+    #[test]
+    fn test_rpc_service_and_method() {
+        assert_eq!(
+            rpc_service_and_method("/package.MyService/MyMethod"),
+            ("package.MyService", "MyMethod")
+        );
+        assert_eq!(
+            rpc_service_and_method("/twirp/package.MyService/MyMethod"),
+            ("package.MyService", "MyMethod")
+        );
+        assert_eq!(rpc_service_and_method("/"), ("", ""));
+    }
+
+    #[tokio::test]
+    async fn test_route_idempotent() {
+        use prost_reflect::prost_types::Timestamp;
+
+        let router = TwirpRouter::new(())
+            .route_idempotent(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .route_idempotent(
+                "/package.MyService/Timestamp",
+                |(), request: Timestamp, _, _| async move { Ok(request) },
+            )
+            .with_method_validation()
+            .build();
+        let get = |uri: &str, accept: &str| {
+            router.clone().oneshot(
+                Request::get(uri)
+                    .header(ACCEPT, accept)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = get("/package.MyService/MyMethod?request=", "*/*")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            "{}"
+        );
+
+        let timestamp = Timestamp {
+            seconds: 10,
+            nanos: 0,
+        };
+        let uri = format!(
+            "/package.MyService/Timestamp?twirp_request={}",
+            BASE64_URL_SAFE_NO_PAD.encode(timestamp.encode_to_vec())
+        );
+        let response = get(&uri, "application/protobuf").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "application/protobuf"
+        );
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            timestamp.encode_to_vec()
+        );
+
+        let response = get("/package.MyService/MyMethod", "*/*").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = router
+            .oneshot(json_request("/package.MyService/MyMethod"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_route_idempotent_method_not_allowed() {
+        for method_validation in [false, true] {
+            let mut router = TwirpRouter::new(()).route_idempotent(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            );
+            if method_validation {
+                router = router.with_method_validation();
+            }
+            let response = router
+                .build()
+                .oneshot(
+                    Request::builder()
+                        .method(Method::PUT)
+                        .uri("/package.MyService/MyMethod")
+                        .header(CONTENT_TYPE, APPLICATION_JSON)
+                        .body(Body::from("{}"))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+            assert_eq!(response.headers().get(ALLOW).unwrap(), "GET, POST");
+            assert_eq!(
+                response.into_body().collect().await.unwrap().to_bytes(),
+                b"{\"code\":\"bad_route\",\"msg\":\"Only GET and POST methods are allowed\"}"
+                    .as_slice()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_method_not_allowed() {
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .build();
+        let response = router
+            .oneshot(
+                Request::get("/package.MyService/MyMethod")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+        assert_eq!(response.headers().get(ALLOW).unwrap(), "POST");
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            b"{\"code\":\"bad_route\",\"msg\":\"Only POST method is allowed\"}".as_slice()
+        );
+    }
+
+    #[cfg(feature = "baggage")]
+    #[tokio::test]
+    async fn test_baggage_propagation() {
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, parts: RequestParts, _| async move {
+                    let expected =
+                        OtelBaggage([("userId".into(), "alice".into())].into_iter().collect());
+                    if parts.extensions.get::<OtelBaggage>() != Some(&expected)
+                        || OtelBaggage::current() != Some(expected)
+                    {
+                        return Err(TwirpError::invalid_argument("Unexpected baggage"));
+                    }
+                    Ok(request)
+                },
+            )
+            .with_baggage_propagation()
+            .build();
+        let response = router
+            .into_service()
+            .call(
+                Request::builder()
+                    .method(Method::POST)
+                    .header(CONTENT_TYPE, APPLICATION_JSON)
+                    .header("baggage", "userId=alice")
+                    .uri("/package.MyService/MyMethod")
+                    .body(Body::from(b"{}".to_vec()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_early_data() {
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/GetFoo",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .route(
+                "/package.MyService/SetFoo",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .with_early_data(EarlyDataPolicy::AllowIdempotent(vec![
+                "/package.MyService/GetFoo".into(),
+            ]))
+            .build();
+        for (path, early_data, expected_status) in [
+            ("/package.MyService/GetFoo", true, StatusCode::OK),
+            ("/package.MyService/SetFoo", true, StatusCode::TOO_EARLY),
+            ("/package.MyService/SetFoo", false, StatusCode::OK),
+        ] {
+            let mut request = Request::builder()
+                .method(Method::POST)
+                .header(CONTENT_TYPE, APPLICATION_JSON)
+                .uri(path);
+            if early_data {
+                request = request.header("early-data", "1");
+            }
+            let response = router
+                .clone()
+                .into_service()
+                .call(request.body(Body::from(b"{}".to_vec())).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_arc_state() {
+        struct AppState {
+            greeting: String,
+        }
+
+        let state = Arc::new(AppState {
+            greeting: "hello".into(),
+        });
+        let router = |path: &str| -> Router {
+            let shared = state.clone();
+            TwirpRouter::with_arc_state((), state.clone())
+                .route(
+                    path,
+                    move |(), request: MyMessage, _, state: Arc<AppState>| {
+                        // The state is not cloned and is shared by both routers
+                        let shared = Arc::ptr_eq(&state, &shared);
+                        async move {
+                            if !shared || state.greeting != "hello" {
+                                return Err(TwirpError::internal("Unexpected state"));
+                            }
+                            Ok(request)
+                        }
+                    },
+                )
+                .build_with_state()
+        };
+        let router = router("/package.MyService/First").merge(router("/package.MyService/Second"));
+        for path in ["/package.MyService/First", "/package.MyService/Second"] {
+            let response = router.clone().oneshot(json_request(path)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_state_factory() {
+        // The state does not need a default value
+        #[derive(Clone)]
+        struct Connection(usize);
+
+        let created = Arc::new(AtomicUsize::new(0));
+        let router: Router = TwirpRouter::new_with_async_state_factory((), {
+            let created = created.clone();
+            move || {
+                let id = created.fetch_add(1, Ordering::Relaxed);
+                async move {
+                    if id == 2 {
+                        return Err(TwirpError::unavailable("No connection available"));
+                    }
+                    Ok(Connection(id))
+                }
+            }
+        })
+        .route(
+            "/package.MyService/MyMethod",
+            |(), _: MyMessage, _, Connection(id)| async move {
+                Err::<MyMessage, _>(TwirpError::not_found(id.to_string()))
+            },
+        )
+        .build_with_state_factory();
+        let call = || async {
+            let response = router
+                .clone()
+                .oneshot(json_request("/package.MyService/MyMethod"))
+                .await
+                .unwrap();
+            let (parts, body) = response.into_parts();
+            let body = body.collect().await.unwrap().to_bytes();
+            TwirpError::from(Response::from_parts(parts, body))
+        };
+        // Each request gets its own state
+        assert_eq!(call().await, TwirpError::not_found("0"));
+        assert_eq!(call().await, TwirpError::not_found("1"));
+        assert_eq!(
+            call().await,
+            TwirpError::internal("Failed to create request state")
+        );
+        assert_eq!(created.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limit() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let router = TwirpRouter::new(())
+            .route_with_concurrency_limit("/package.MyService/MyMethod", 2, {
+                let running = running.clone();
+                move |(), request: MyMessage, _, _| {
+                    let running = running.clone();
+                    async move {
+                        if running.fetch_add(1, Ordering::SeqCst) >= 2 {
+                            return Err(TwirpError::internal("Too many concurrent requests"));
+                        }
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                        running.fetch_sub(1, Ordering::SeqCst);
+                        Ok(request)
+                    }
+                }
+            })
+            .build();
+        let calls = (0..6)
+            .map(|_| {
+                tokio::spawn(
+                    router
+                        .clone()
+                        .into_service()
+                        .oneshot(json_request("/package.MyService/MyMethod")),
+                )
+            })
+            .collect::<Vec<_>>();
+        for call in calls {
+            assert_eq!(call.await.unwrap().unwrap().status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_router_concurrency_limit() {
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    Ok(request)
+                },
+            )
+            .with_concurrency_limit(2)
+            .build();
+        let calls = (0..3)
+            .map(|_| {
+                tokio::spawn(
+                    router
+                        .clone()
+                        .oneshot(json_request("/package.MyService/MyMethod")),
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut statuses = Vec::new();
+        for call in calls {
+            statuses.push(call.await.unwrap().unwrap().status());
+        }
+        statuses.sort();
+        assert_eq!(
+            statuses,
+            [
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS
+            ]
+        );
+        // The permits are released
+        let response = router
+            .oneshot(json_request("/package.MyService/MyMethod"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_concurrency_limit() {
+        let router = GrpcRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    Ok(request)
+                },
+            )
+            .with_concurrency_limit(2)
+            .build();
+        let calls = (0..3)
+            .map(|_| {
+                let router = router.clone();
+                tokio::spawn(async move {
+                    Grpc::new(router)
+                        .unary(
+                            tonic::Request::new(MyMessage {}),
+                            PathAndQuery::from_static("/package.MyService/MyMethod"),
+                            ProstCodec::<MyMessage, MyMessage>::default(),
+                        )
+                        .await
+                        .map(|r| r.into_inner())
+                        .map_err(|s| (s.code(), s.message().to_string()))
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut results = Vec::new();
+        for call in calls {
+            results.push(call.await.unwrap());
+        }
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 2);
+        assert!(results.contains(&Err((Code::ResourceExhausted, "Server overloaded".into()))));
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[tokio::test]
+    async fn test_concurrency_metrics() {
+        let registry = prometheus::Registry::new();
+        let started = Arc::new(Notify::new());
+        let release = Arc::new(Notify::new());
+        let router = TwirpRouter::new(())
+            .route_with_concurrency_limit("/package.MyService/MyMethod", 1, {
+                let started = started.clone();
+                let release = release.clone();
+                move |(), request: MyMessage, _, _| {
+                    let started = started.clone();
+                    let release = release.clone();
+                    async move {
+                        started.notify_one();
+                        release.notified().await;
+                        Ok(request)
+                    }
+                }
+            })
+            .with_concurrency_metrics(registry.clone())
+            .unwrap()
+            .build();
+        // The metrics can only be registered once
+        assert!(
+            TwirpRouter::<_, ()>::new(())
+                .with_concurrency_metrics(registry.clone())
+                .is_err()
+        );
+        let call = || {
+            router
+                .clone()
+                .into_service()
+                .oneshot(json_request("/package.MyService/MyMethod"))
+        };
+
+        // The first request runs, the second one is queued and the third one is abandoned while queued
+        let first = tokio::spawn(call());
+        started.notified().await;
+        let second = tokio::spawn(call());
+        assert!(
+            tokio::time::timeout(Duration::from_millis(20), call())
+                .await
+                .is_err()
+        );
+        release.notify_one();
+        assert_eq!(first.await.unwrap().unwrap().status(), StatusCode::OK);
+        started.notified().await;
+        release.notify_one();
+        assert_eq!(second.await.unwrap().unwrap().status(), StatusCode::OK);
+
+        let metrics = prometheus::TextEncoder::new()
+            .encode_to_string(&registry.gather())
+            .unwrap();
+        assert!(metrics.contains(
+            "twirp_concurrency_queue_duration_seconds_count{route=\"/package.MyService/MyMethod\"} 2"
+        ));
+        assert!(
+            metrics.contains(
+                "twirp_concurrency_rejected_total{route=\"/package.MyService/MyMethod\"} 1"
+            )
+        );
+    }
+
+    fn json_request(path: &str) -> Request<Body> {
+        Request::builder()
+            .method(Method::POST)
+            .header(CONTENT_TYPE, APPLICATION_JSON)
+            .uri(path)
+            .body(Body::from(b"{}".to_vec()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_merge() {
+        let first = TwirpRouter::new(()).route(
+            "/package.MyService/First",
+            |(), request: MyMessage, _, _| async move { Ok(request) },
+        );
+        let second = TwirpRouter::new(()).route(
+            "/package.MyService/Second",
+            |(), request: MyMessage, _, _| async move { Ok(request) },
+        );
+        let router = first.merge(second).with_route_listing();
+        assert_eq!(
+            router.registered_paths(),
+            ["/package.MyService/First", "/package.MyService/Second"]
+        );
+        let router = router.build();
+        for path in ["/package.MyService/First", "/package.MyService/Second"] {
+            let response = router.clone().oneshot(json_request(path)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{path}");
+        }
+        let response = router
+            .oneshot(
+                Request::get("/.well-known/twirp-routes")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            r#"[{"enabled":true,"method":"POST","path":"/package.MyService/First"},{"enabled":true,"method":"POST","path":"/package.MyService/Second"}]"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prefix_and_nest() {
+        let route = |router: TwirpRouter<()>| {
+            router.route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+        };
+        let router = route(TwirpRouter::new(()).prefix("/twirp"))
+            .nest("/api", route(TwirpRouter::new(())))
+            .with_route_listing()
+            .build();
+        for (path, status) in [
+            ("/twirp/package.MyService/MyMethod", StatusCode::OK),
+            ("/twirp/api/package.MyService/MyMethod", StatusCode::OK),
+            ("/package.MyService/MyMethod", StatusCode::NOT_FOUND),
+        ] {
+            let response = router.clone().oneshot(json_request(path)).await.unwrap();
+            assert_eq!(response.status(), status, "{path}");
+        }
+        let response = router
+            .oneshot(
+                Request::get("/.well-known/twirp-routes")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            r#"[{"enabled":true,"method":"POST","path":"/twirp/package.MyService/MyMethod"},{"enabled":true,"method":"POST","path":"/twirp/api/package.MyService/MyMethod"}]"#
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "must start with '/' and not end with '/'")]
+    fn test_invalid_prefix() {
+        let _ = TwirpRouter::<()>::new(()).prefix("/twirp/");
+    }
+
+    #[tokio::test]
+    async fn test_max_header_count() {
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .with_max_header_count(2)
+            .build();
+
+        let mut request = json_request("/package.MyService/MyMethod");
+        request
+            .headers_mut()
+            .insert("x-custom", HeaderValue::from_static("1"));
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let mut request = json_request("/package.MyService/MyMethod");
+        for name in ["x-custom-1", "x-custom-2"] {
+            request
+                .headers_mut()
+                .insert(name, HeaderValue::from_static("1"));
+        }
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            b"{\"code\":\"malformed\",\"msg\":\"Too many request headers\"}".as_slice()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_body_limit() {
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/Default",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .route_with_limit(
+                "/package.MyService/Custom",
+                20,
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .with_body_limit(10)
+            .build();
+        let call = |path: &'static str, size: usize| {
+            let mut request = json_request(path);
+            *request.body_mut() = Body::from(format!("{{}}{}", " ".repeat(size - 2)));
+            router.clone().into_service().oneshot(request)
+        };
+        for (path, limit) in [
+            ("/package.MyService/Default", 10),
+            ("/package.MyService/Custom", 20),
+        ] {
+            assert_eq!(call(path, limit).await.unwrap().status(), StatusCode::OK);
+            let response = call(path, limit + 1).await.unwrap();
+            assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(
+                response.into_body().collect().await.unwrap().to_bytes(),
+                b"{\"code\":\"resource_exhausted\",\"msg\":\"Request body too large\"}".as_slice()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let router = TwirpRouter::new(())
+            .route("/package.MyService/Default", |(), _: MyMessage, _, _| {
+                std::future::pending::<Result<MyMessage, TwirpError>>()
+            })
+            .route_with_timeout(
+                "/package.MyService/Longer",
+                Duration::from_secs(3600),
+                |(), request: MyMessage, _, _| async move {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    Ok(request)
+                },
+            )
+            .route_with_timeout(
+                "/package.MyService/Zero",
+                Duration::ZERO,
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .with_timeout(Duration::from_millis(10))
+            .build();
+        let call = |path: &'static str| router.clone().into_service().oneshot(json_request(path));
+        for path in ["/package.MyService/Default", "/package.MyService/Zero"] {
+            let response = call(path).await.unwrap();
+            assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+            assert_eq!(
+                response.into_body().collect().await.unwrap().to_bytes(),
+                b"{\"code\":\"deadline_exceeded\",\"msg\":\"RPC timed out\"}".as_slice()
             );
         }
-    };
-    Response::from_parts(parts, body).into()
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::twirp_fallback;
-    #[cfg(feature = "grpc")]
-    use axum::http::uri::PathAndQuery;
-    use axum::http::{Method, Request, StatusCode};
-    use http_body_util::BodyExt;
-    use prost::Message;
-    #[cfg(feature = "grpc")]
-    use tonic::Code;
-    #[cfg(feature = "grpc")]
-    use tonic::client::Grpc;
-    #[cfg(feature = "grpc")]
-    use tonic_prost::ProstCodec;
-    use tower_service::Service;
-
-    const FILE_DESCRIPTOR_SET_BYTES: &[u8] = &[
-        10, 107, 10, 21, 101, 120, 97, 109, 112, 108, 101, 95, 115, 101, 114, 118, 105, 99, 101,
-        46, 112, 114, 111, 116, 111, 18, 7, 112, 97, 99, 107, 97, 103, 101, 34, 11, 10, 9, 77, 121,
-        77, 101, 115, 115, 97, 103, 101, 74, 52, 10, 6, 18, 4, 0, 0, 5, 1, 10, 8, 10, 1, 12, 18, 3,
-        0, 0, 18, 10, 8, 10, 1, 2, 18, 3, 2, 0, 16, 10, 10, 10, 2, 4, 0, 18, 4, 4, 0, 5, 1, 10, 10,
-        10, 3, 4, 0, 1, 18, 3, 4, 8, 17, 98, 6, 112, 114, 111, 116, 111, 51,
-    ];
+        assert_eq!(
+            call("/package.MyService/Longer").await.unwrap().status(),
+            StatusCode::OK
+        );
+    }
 
-    #[derive(Message, ReflectMessage, PartialEq)]
-    #[prost_reflect(
-        file_descriptor_set_bytes = "crate::codegen::tests::FILE_DESCRIPTOR_SET_BYTES",
-        message_name = "package.MyMessage"
-    )]
-    pub struct MyMessage {}
+    #[test]
+    fn test_registered_paths() {
+        let router = TwirpRouter::<(), ()>::new(())
+            .route(
+                "/package.MyService/First",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .route_idempotent(
+                "/package.MyService/Second",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .route_streaming("/package.MyService/Third")
+            .with_health_check("/healthz", || true);
+        assert_eq!(
+            router.registered_paths(),
+            [
+                "/package.MyService/First",
+                "/package.MyService/Second",
+                "/package.MyService/Third"
+            ]
+        );
+    }
 
-    #[tokio::test]
-    async fn test_bad_route() {
-        let router = TwirpRouter::new(()).build().fallback(twirp_fallback);
-        let response = router
-            .into_service()
-            .call(Request::new(Body::empty()))
-            .await
-            .unwrap();
-        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    #[cfg(feature = "grpc")]
+    #[test]
+    fn test_grpc_registered_paths() {
+        let router = GrpcRouter::<(), ()>::new(())
+            .route(
+                "/package.MyService/First",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .route_server_streaming(
+                "/package.MyService/Second",
+                |(), request: MyMessage, _, _| async move { Ok(tokio_stream::iter([Ok(request)])) },
+            )
+            .route_client_streaming(
+                "/package.MyService/Third",
+                |(), _: GrpcClientStream<MyMessage>, _, _| async move { Ok(MyMessage {}) },
+            );
         assert_eq!(
-            response.into_body().collect().await.unwrap().to_bytes(),
-            b"{\"code\":\"bad_route\",\"msg\":\"/ is not a supported Twirp method\"}".as_slice()
+            router.registered_paths(),
+            [
+                "/package.MyService/First",
+                "/package.MyService/Second",
+                "/package.MyService/Third"
+            ]
         );
     }
 
     #[tokio::test]
-    async fn test_no_content_type() {
+    async fn test_route_listing() {
         let router = TwirpRouter::new(())
             .route(
                 "/package.MyService/MyMethod",
                 |(), request: MyMessage, _, _| async move { Ok(request) },
             )
+            .route_streaming("/package.MyService/MyStreamingMethod")
+            .with_route_listing()
+            .with_method_validation()
             .build();
         let response = router
             .into_service()
-            .call(
+            .oneshot(
                 Request::builder()
-                    .method(Method::POST)
-                    .uri("/package.MyService/MyMethod")
+                    .uri("/.well-known/twirp-routes")
                     .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
-        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.status(), StatusCode::OK);
         assert_eq!(
-            response.into_body().collect().await.unwrap().to_bytes(),
-            b"{\"code\":\"malformed\",\"msg\":\"No content-type header\"}".as_slice()
+            response.headers().get(CONTENT_TYPE),
+            Some(&APPLICATION_JSON)
+        );
+        let listing: serde_json::Value =
+            serde_json::from_slice(&response.into_body().collect().await.unwrap().to_bytes())
+                .unwrap();
+        assert_eq!(
+            listing,
+            serde_json::json!([
+                {"method": "POST", "path": "/package.MyService/MyMethod", "enabled": true},
+                {"method": "POST", "path": "/package.MyService/MyStreamingMethod", "enabled": false},
+            ])
         );
     }
 
     #[tokio::test]
-    async fn test_ok_binary() {
+    async fn test_schema_registry() {
+        let pool = DescriptorPool::decode(FILE_DESCRIPTOR_SET_BYTES).unwrap();
         let router = TwirpRouter::new(())
-            .route(
-                "/package.MyService/MyMethod",
-                |(), request: MyMessage, _, _| async move { Ok(request) },
-            )
+            .with_schema_registry(pool.clone())
+            .with_method_validation()
             .build();
+
         let response = router
-            .into_service()
-            .call(
+            .clone()
+            .oneshot(
                 Request::builder()
-                    .method(Method::POST)
-                    .header(CONTENT_TYPE, APPLICATION_PROTOBUF)
-                    .uri("/package.MyService/MyMethod")
+                    .uri("/.well-known/proto")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE),
+            Some(&APPLICATION_JSON)
+        );
+        let listing: serde_json::Value =
+            serde_json::from_slice(&response.into_body().collect().await.unwrap().to_bytes())
+                .unwrap();
+        assert_eq!(listing, serde_json::json!(["example_service.proto"]));
+
+        let response = router
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/.well-known/proto/example_service.proto")
                     .body(Body::empty())
                     .unwrap(),
             )
@@ -562,162 +5199,493 @@ mod tests {
             .unwrap();
         assert_eq!(response.status(), StatusCode::OK);
         assert_eq!(
+            response.headers().get(CONTENT_TYPE),
+            Some(&APPLICATION_PROTOBUF)
+        );
+        let file = prost_reflect::prost_types::FileDescriptorProto::decode(
             response.into_body().collect().await.unwrap().to_bytes(),
-            [].as_slice()
+        )
+        .unwrap();
+        assert_eq!(
+            &file,
+            pool.get_file_by_name("example_service.proto")
+                .unwrap()
+                .file_descriptor_proto()
         );
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/.well-known/proto/unknown.proto")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    async fn add_test_header(mut response: Response) -> Response {
+        response
+            .headers_mut()
+            .insert("x-test", HeaderValue::from_static("layer"));
+        response
     }
 
     #[tokio::test]
-    async fn test_bad_binary() {
+    async fn test_layer() {
         let router = TwirpRouter::new(())
             .route(
                 "/package.MyService/MyMethod",
                 |(), request: MyMessage, _, _| async move { Ok(request) },
             )
+            .layer(middleware::map_response(add_test_header))
             .build();
         let response = router
             .into_service()
-            .call(
-                Request::builder()
-                    .method(Method::POST)
-                    .header(CONTENT_TYPE, APPLICATION_PROTOBUF)
-                    .uri("/package.MyService/MyMethod")
-                    .body(Body::from(b"1234".to_vec()))
-                    .unwrap(),
-            )
+            .oneshot(json_request("/package.MyService/MyMethod"))
             .await
             .unwrap();
-        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.status(), StatusCode::OK);
         assert_eq!(
-            response.into_body().collect().await.unwrap().to_bytes(),
-            b"{\"code\":\"malformed\",\"msg\":\"Invalid binary protobuf request: failed to decode Protobuf message: buffer underflow\"}".as_slice()
+            response.headers().get("x-test"),
+            Some(&HeaderValue::from_static("layer"))
         );
     }
 
+    #[cfg(feature = "grpc")]
     #[tokio::test]
-    async fn test_ok_json() {
-        let router = TwirpRouter::new(())
+    async fn test_grpc_layer() {
+        let router = GrpcRouter::new(())
             .route(
                 "/package.MyService/MyMethod",
                 |(), request: MyMessage, _, _| async move { Ok(request) },
             )
+            .layer(middleware::map_response(add_test_header))
             .build();
         let response = router
             .into_service()
-            .call(
+            .oneshot(
                 Request::builder()
                     .method(Method::POST)
-                    .header(CONTENT_TYPE, APPLICATION_JSON)
+                    .header(CONTENT_TYPE, "application/grpc")
                     .uri("/package.MyService/MyMethod")
-                    .body(Body::from(b"{}".to_vec()))
+                    .body(Body::from(vec![0, 0, 0, 0, 0]))
                     .unwrap(),
             )
             .await
             .unwrap();
         assert_eq!(response.status(), StatusCode::OK);
         assert_eq!(
-            response.into_body().collect().await.unwrap().to_bytes(),
-            b"{}".as_slice()
+            response.headers().get("x-test"),
+            Some(&HeaderValue::from_static("layer"))
         );
     }
 
+    #[cfg(feature = "compression")]
     #[tokio::test]
-    async fn test_bad_json() {
+    async fn test_response_compression_quality_values() {
         let router = TwirpRouter::new(())
             .route(
                 "/package.MyService/MyMethod",
-                |(), request: MyMessage, _, _| async move { Ok(request) },
+                |(), _: MyMessage, _, _| async move {
+                    Err::<MyMessage, _>(TwirpError::not_found("x".repeat(100)))
+                },
             )
+            .with_response_compression()
             .build();
-        let response = router
-            .into_service()
-            .call(
+        for (accept_encoding, expected) in [
+            ("gzip", Some("gzip")),
+            ("gzip, deflate", Some("gzip")),
+            ("gzip;q=0.8, deflate;q=1.0", Some("deflate")),
+            ("deflate;q=0.5, gzip", Some("gzip")),
+            ("gzip;q=0, deflate;q=0.1", Some("deflate")),
+            ("br;q=1.0, gzip;q=0.2", Some("gzip")),
+            ("gzip;q=0", None),
+            ("identity", None),
+        ] {
+            let mut request = json_request("/package.MyService/MyMethod");
+            request
+                .headers_mut()
+                .insert("accept-encoding", HeaderValue::from_static(accept_encoding));
+            let response = router
+                .clone()
+                .into_service()
+                .oneshot(request)
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            assert_eq!(
+                response
+                    .headers()
+                    .get("content-encoding")
+                    .map(|v| v.to_str().unwrap()),
+                expected,
+                "{accept_encoding}"
+            );
+        }
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn test_request_decompression() {
+        use flate2::Compression;
+        use flate2::read::GzDecoder;
+        use flate2::write::GzEncoder;
+        use std::io::{Read, Write};
+
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: String, _, _| async move { Ok(request) },
+            )
+            .with_body_limit(200)
+            .with_request_decompression()
+            .with_response_compression()
+            .build();
+        let call = |body: Vec<u8>| {
+            router.clone().into_service().oneshot(
                 Request::builder()
                     .method(Method::POST)
-                    .header(CONTENT_TYPE, APPLICATION_JSON)
                     .uri("/package.MyService/MyMethod")
-                    .body(Body::from(b"foo".to_vec()))
+                    .header(CONTENT_TYPE, "application/protobuf")
+                    .header("content-encoding", "gzip")
+                    .header("accept-encoding", "gzip")
+                    .body(Body::from(body))
                     .unwrap(),
             )
-            .await
-            .unwrap();
+        };
+        let gzip = |message: &String| {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&message.encode_to_vec()).unwrap();
+            encoder.finish().unwrap()
+        };
+        let gunzip = async |response: Response| {
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let mut decoded = Vec::new();
+            GzDecoder::new(&body[..]).read_to_end(&mut decoded).unwrap();
+            decoded
+        };
+
+        let message = "a".repeat(100);
+        let response = call(gzip(&message)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get("content-encoding"),
+            Some(&HeaderValue::from_static("gzip"))
+        );
+        let body = gunzip(response).await;
+        assert_eq!(String::decode(&body[..]).unwrap(), message);
+
+        // The limit applies to the decompressed body
+        let response = call(gzip(&"a".repeat(300))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let response = call(b"not gzip".to_vec()).await.unwrap();
         assert_eq!(response.status(), StatusCode::BAD_REQUEST);
         assert_eq!(
-            response.into_body().collect().await.unwrap().to_bytes(),
-            b"{\"code\":\"malformed\",\"msg\":\"Invalid JSON protobuf request: expected ident at line 1 column 2\"}".as_slice()
+            gunzip(response).await,
+            b"{\"code\":\"malformed\",\"msg\":\"Failed to decompress the request body\"}"
         );
     }
 
+    #[cfg(feature = "compression")]
     #[tokio::test]
-    async fn test_bad_content_type() {
+    async fn test_request_decompression_algorithms() {
+        use flate2::Compression;
+        use flate2::write::{GzEncoder, ZlibEncoder};
+        use std::io::Write;
+
         let router = TwirpRouter::new(())
             .route(
                 "/package.MyService/MyMethod",
-                |(), request: MyMessage, _, _| async move { Ok(request) },
+                |(), request: String, _, _| async move { Ok(request) },
             )
+            .with_body_limit(1000)
+            .with_request_decompression()
             .build();
-        let response = router
-            .into_service()
-            .call(
+        let call = |encoding: &'static str, body: Vec<u8>| {
+            router.clone().oneshot(
                 Request::builder()
                     .method(Method::POST)
-                    .header(CONTENT_TYPE, "foo/bar")
                     .uri("/package.MyService/MyMethod")
-                    .body(Body::empty())
+                    .header(CONTENT_TYPE, "application/protobuf")
+                    .header("content-encoding", encoding)
+                    .body(Body::from(body))
                     .unwrap(),
             )
+        };
+        let gzip = |data: &[u8]| {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+        let deflate = |data: &[u8]| {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+
+        let message = "a".repeat(100).encode_to_vec();
+        for (encoding, body) in [("gzip", gzip(&message)), ("deflate", deflate(&message))] {
+            let response = call(encoding, body).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{encoding}");
+        }
+
+        // A few kilobytes expanding to 10 MB
+        let bomb = vec![0; 10 * 1024 * 1024];
+        for (encoding, body) in [("gzip", gzip(&bomb)), ("deflate", deflate(&bomb))] {
+            assert!(body.len() < 20 * 1024);
+            let response = call(encoding, body).await.unwrap();
+            assert_eq!(
+                response.status(),
+                StatusCode::TOO_MANY_REQUESTS,
+                "{encoding}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_headers() {
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .with_response_headers(HeaderMap::from_iter([
+                (
+                    HeaderName::from_static("x-content-type-options"),
+                    HeaderValue::from_static("nosniff"),
+                ),
+                (CONTENT_TYPE, HeaderValue::from_static("text/plain")),
+            ]))
+            .with_response_headers(HeaderMap::from_iter([(
+                HeaderName::from_static("x-frame-options"),
+                HeaderValue::from_static("DENY"),
+            )]))
+            .build();
+        let response = router
+            .into_service()
+            .oneshot(json_request("/package.MyService/MyMethod"))
             .await
             .unwrap();
-        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.status(), StatusCode::OK);
         assert_eq!(
-            response.into_body().collect().await.unwrap().to_bytes(),
-            b"{\"code\":\"malformed\",\"msg\":\"Unsupported content type: foo/bar\"}".as_slice()
+            response.headers().get("x-content-type-options"),
+            Some(&HeaderValue::from_static("nosniff"))
+        );
+        assert_eq!(
+            response.headers().get("x-frame-options"),
+            Some(&HeaderValue::from_static("DENY"))
+        );
+        // The headers set by the handler take precedence
+        assert_eq!(
+            response
+                .headers()
+                .get_all(CONTENT_TYPE)
+                .iter()
+                .collect::<Vec<_>>(),
+            [APPLICATION_JSON]
         );
     }
 
-    #[cfg(feature = "grpc")]
     #[tokio::test]
-    async fn test_grpc_request() {
-        let router = GrpcRouter::new(())
+    async fn test_content_security_policy() {
+        let router = TwirpRouter::new(())
             .route(
                 "/package.MyService/MyMethod",
-                |(), request: MyMessage, _| async move { Ok(request) },
+                |(), request: MyMessage, _, _| async move { Ok(request) },
             )
+            .with_content_security_policy("default-src 'self'")
+            .with_content_security_policy(&ContentSecurityPolicy::default_twirp())
             .build();
-        let path = PathAndQuery::from_static("/package.MyService/MyMethod");
-        let response: MyMessage = Grpc::new(router)
-            .unary(
-                tonic::Request::new(MyMessage {}),
-                path,
-                ProstCodec::default(),
+        for path in ["/package.MyService/MyMethod", "/package.MyService/Unknown"] {
+            let response = router.clone().oneshot(json_request(path)).await.unwrap();
+            assert_eq!(
+                response
+                    .headers()
+                    .get_all(CONTENT_SECURITY_POLICY)
+                    .iter()
+                    .collect::<Vec<_>>(),
+                [ContentSecurityPolicy::default_twirp().as_str()]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accept_header() {
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .build();
+        for (content_type, accept, expected) in [
+            (
+                APPLICATION_PROTOBUF,
+                Some(APPLICATION_JSON),
+                APPLICATION_JSON,
+            ),
+            (
+                APPLICATION_JSON,
+                Some(APPLICATION_PROTOBUF),
+                APPLICATION_PROTOBUF,
+            ),
+            (
+                APPLICATION_PROTOBUF,
+                Some(HeaderValue::from_static("*/*")),
+                APPLICATION_PROTOBUF,
+            ),
+            (APPLICATION_JSON, None, APPLICATION_JSON),
+            (
+                APPLICATION_PROTOBUF,
+                Some(HeaderValue::from_static("Application/JSON; charset=utf-8")),
+                APPLICATION_JSON,
+            ),
+            (
+                APPLICATION_PROTOBUF,
+                Some(HeaderValue::from_static(
+                    "text/html, application/json;q=0.5, application/protobuf;q=0.8, */*;q=0.1",
+                )),
+                APPLICATION_PROTOBUF,
+            ),
+            (
+                APPLICATION_JSON,
+                Some(HeaderValue::from_static(
+                    "application/protobuf;q=0, text/plain",
+                )),
+                APPLICATION_JSON,
+            ),
+        ] {
+            let mut request = Request::builder()
+                .method(Method::POST)
+                .uri("/package.MyService/MyMethod")
+                .header(CONTENT_TYPE, content_type.clone());
+            if let Some(accept) = accept {
+                request = request.header(ACCEPT, accept);
+            }
+            let body = if content_type == APPLICATION_JSON {
+                Body::from("{}")
+            } else {
+                Body::empty()
+            };
+            let response = router
+                .clone()
+                .into_service()
+                .oneshot(request.body(body).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), expected);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            if expected == APPLICATION_JSON {
+                assert_eq!(body, "{}");
+            } else {
+                assert!(body.is_empty());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_json_options() {
+        use prost_reflect::prost_types::Field;
+        use prost_reflect::prost_types::field::Kind;
+
+        let call = async |options: TwirpJsonOptions| {
+            let router = TwirpRouter::new(())
+                .route(
+                    "/package.MyService/MyMethod",
+                    |(), _: MyMessage, _, _| async move {
+                        Ok(Field {
+                            kind: Kind::TypeBytes.into(),
+                            json_name: "myField".into(),
+                            default_value: "+/8=".into(),
+                            ..Default::default()
+                        })
+                    },
+                )
+                .with_json_options(options)
+                .build();
+            let response = router
+                .into_service()
+                .oneshot(json_request("/package.MyService/MyMethod"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            serde_json::from_slice::<serde_json::Value>(
+                &response.into_body().collect().await.unwrap().to_bytes(),
             )
-            .await
             .unwrap()
-            .into_inner();
-        assert_eq!(response, MyMessage {})
+        };
+
+        assert_eq!(
+            call(TwirpJsonOptions::default()).await,
+            serde_json::json!({"kind": "TYPE_BYTES", "jsonName": "myField", "defaultValue": "+/8="})
+        );
+        assert_eq!(
+            call(TwirpJsonOptions::default().with_emit_default_values()).await,
+            serde_json::json!({
+                "kind": "TYPE_BYTES",
+                "cardinality": "CARDINALITY_UNKNOWN",
+                "number": 0,
+                "name": "",
+                "typeUrl": "",
+                "oneofIndex": 0,
+                "packed": false,
+                "options": [],
+                "jsonName": "myField",
+                "defaultValue": "+/8=",
+            })
+        );
+        assert_eq!(
+            call(TwirpJsonOptions::default().with_proto_field_names()).await,
+            serde_json::json!({"kind": "TYPE_BYTES", "json_name": "myField", "default_value": "+/8="})
+        );
+        assert_eq!(
+            call(TwirpJsonOptions::default().with_enum_numbers()).await,
+            serde_json::json!({"kind": 12, "jsonName": "myField", "defaultValue": "+/8="})
+        );
     }
 
-    #[cfg(feature = "grpc")]
     #[tokio::test]
-    async fn test_grpc_request_with_error() {
-        let router = GrpcRouter::new(())
+    async fn test_api_key_auth() {
+        let valid_keys = Arc::new(RwLock::new(HashSet::from(["foo".to_string()])));
+        let router = TwirpRouter::new(())
             .route(
                 "/package.MyService/MyMethod",
-                |(), _: MyMessage, _| async move {
-                    Err::<MyMessage, _>(TwirpError::not_found("foo not found"))
+                |(), request: MyMessage, parts: RequestParts, _| async move {
+                    // The principal must not leak the key
+                    let Some(Principal(principal)) = parts.extensions.get() else {
+                        return Err(TwirpError::internal("No principal"));
+                    };
+                    if !principal.starts_with("api-key-") || principal.contains("foo") {
+                        return Err(TwirpError::internal("The principal is the key"));
+                    }
+                    Ok(request)
                 },
             )
+            .with_shared_api_key_auth("x-api-key", valid_keys.clone())
             .build();
-        let path = PathAndQuery::from_static("/package.MyService/MyMethod");
-        let status = Grpc::new(router)
-            .unary::<_, MyMessage, _>(
-                tonic::Request::new(MyMessage {}),
-                path,
-                ProstCodec::default(),
-            )
-            .await
-            .unwrap_err();
-        assert_eq!(status.code(), Code::NotFound);
-        assert_eq!(status.message(), "foo not found");
+        let call = |key: Option<&'static str>| {
+            let mut request = json_request("/package.MyService/MyMethod");
+            if let Some(key) = key {
+                request
+                    .headers_mut()
+                    .insert("x-api-key", HeaderValue::from_static(key));
+            }
+            router.clone().into_service().oneshot(request)
+        };
+        assert_eq!(call(Some("foo")).await.unwrap().status(), StatusCode::OK);
+        let response = call(Some("bar")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            b"{\"code\":\"unauthenticated\",\"msg\":\"Invalid API key\"}".as_slice()
+        );
+        assert_eq!(call(None).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+
+        // Hot reload
+        valid_keys.write().unwrap().insert("bar".into());
+        assert_eq!(call(Some("bar")).await.unwrap().status(), StatusCode::OK);
     }
 }
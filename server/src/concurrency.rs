@@ -0,0 +1,126 @@
+use crate::TwirpError;
+use axum::extract::{Request, State};
+use axum::http::Extensions;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+#[cfg(feature = "prometheus")]
+use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry};
+use std::sync::Arc;
+#[cfg(feature = "prometheus")]
+use std::time::Instant;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Rejects the request if all the permits of `semaphore` are taken
+/// (limit set with `TwirpRouter::with_concurrency_limit`)
+pub(crate) async fn limit_concurrency(
+    State(semaphore): State<Arc<Semaphore>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match semaphore.try_acquire() {
+        Ok(_permit) => next.run(request).await,
+        Err(_) => server_overloaded().into_response(),
+    }
+}
+
+/// Rejects the request if all the permits of `semaphore` are taken
+/// (limit set with `GrpcRouter::with_concurrency_limit`)
+#[cfg(feature = "grpc")]
+pub(crate) async fn limit_grpc_concurrency(
+    State(semaphore): State<Arc<Semaphore>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match semaphore.try_acquire() {
+        Ok(_permit) => next.run(request).await,
+        Err(_) => tonic::Status::from(server_overloaded()).into_http(),
+    }
+}
+
+fn server_overloaded() -> TwirpError {
+    TwirpError::resource_exhausted("Server overloaded")
+}
+
+/// Waits for a permit to run the handler of `route`, recording the metrics if enabled.
+#[cfg_attr(not(feature = "prometheus"), allow(unused_variables))]
+pub(crate) async fn acquire_permit<'a>(
+    semaphore: &'a Semaphore,
+    route: &str,
+    extensions: &Extensions,
+) -> Result<SemaphorePermit<'a>, TwirpError> {
+    #[cfg(feature = "prometheus")]
+    let mut guard = extensions
+        .get::<ConcurrencyMetrics>()
+        .map(|metrics| QueueGuard {
+            metrics,
+            route,
+            start: Instant::now(),
+            acquired: false,
+        });
+    let permit = semaphore
+        .acquire()
+        .await
+        .map_err(|_| TwirpError::unavailable("The service is shutting down"))?;
+    #[cfg(feature = "prometheus")]
+    if let Some(guard) = &mut guard {
+        guard.acquired = true;
+        guard
+            .metrics
+            .queue_duration
+            .with_label_values(&[route])
+            .observe(guard.start.elapsed().as_secs_f64());
+    }
+    Ok(permit)
+}
+
+/// Metrics of the routes with a concurrency limit
+#[cfg(feature = "prometheus")]
+#[derive(Clone)]
+pub(crate) struct ConcurrencyMetrics {
+    queue_duration: HistogramVec,
+    rejected: IntCounterVec,
+}
+
+#[cfg(feature = "prometheus")]
+impl ConcurrencyMetrics {
+    pub(crate) fn register(registry: &Registry) -> prometheus::Result<Self> {
+        let queue_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "twirp_concurrency_queue_duration_seconds",
+                "Time spent by the requests waiting for the route concurrency limit",
+            ),
+            &["route"],
+        )?;
+        let rejected = IntCounterVec::new(
+            Opts::new(
+                "twirp_concurrency_rejected_total",
+                "Requests abandoned while waiting for the route concurrency limit",
+            ),
+            &["route"],
+        )?;
+        registry.register(Box::new(queue_duration.clone()))?;
+        registry.register(Box::new(rejected.clone()))?;
+        Ok(Self {
+            queue_duration,
+            rejected,
+        })
+    }
+}
+
+/// Counts the request as rejected if it is dropped before getting a permit
+#[cfg(feature = "prometheus")]
+struct QueueGuard<'a> {
+    metrics: &'a ConcurrencyMetrics,
+    route: &'a str,
+    start: Instant,
+    acquired: bool,
+}
+
+#[cfg(feature = "prometheus")]
+impl Drop for QueueGuard<'_> {
+    fn drop(&mut self) {
+        if !self.acquired {
+            self.metrics.rejected.with_label_values(&[self.route]).inc();
+        }
+    }
+}
@@ -0,0 +1,474 @@
+use crate::codegen::{RequestParts, json_decode, json_encode};
+use crate::{TwirpError, TwirpErrorCode, TwirpJsonOptions};
+use axum::RequestExt;
+use axum::Router;
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use http_body_util::BodyExt;
+use prost_reflect::ReflectMessage;
+use prost_reflect::bytes::{BufMut, Bytes, BytesMut};
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio_stream::Stream;
+
+const APPLICATION_PROTO: HeaderValue = HeaderValue::from_static("application/proto");
+const APPLICATION_JSON: HeaderValue = HeaderValue::from_static("application/json");
+const APPLICATION_CONNECT_PROTO: HeaderValue =
+    HeaderValue::from_static("application/connect+proto");
+const APPLICATION_CONNECT_JSON: HeaderValue = HeaderValue::from_static("application/connect+json");
+
+/// Flag of the envelope holding the end of a stream
+const END_STREAM_FLAG: u8 = 0x02;
+/// Flag of the envelopes holding a compressed message
+const COMPRESSED_FLAG: u8 = 0x01;
+
+/// Router serving the [Connect protocol](https://connectrpc.com/docs/protocol)
+///
+/// It has the same API as [`TwirpRouter`](crate::codegen::TwirpRouter):
+/// unary calls use plain `application/proto` or `application/json` bodies
+/// and server streaming calls use enveloped `application/connect+proto` or `application/connect+json` messages.
+///
+/// ```
+/// use prost_reflect::prost_types::Timestamp;
+/// use twurst_server::ConnectRouter;
+///
+/// let _router: axum::Router = ConnectRouter::new(())
+///     .route("/package.MyService/MyMethod", |(), request: Timestamp, _, _| async move {
+///         Ok(request)
+///     })
+///     .build();
+/// ```
+pub struct ConnectRouter<S, RS = ()> {
+    router: Router<RS>,
+    service: S,
+}
+
+impl<S: Clone + Send + Sync + 'static, RS: Clone + Send + Sync + 'static> ConnectRouter<S, RS> {
+    pub fn new(service: S) -> Self {
+        Self {
+            router: Router::new(),
+            service,
+        }
+    }
+
+    /// Adds a unary method
+    pub fn route<
+        I: ReflectMessage + Default,
+        O: ReflectMessage,
+        F: Future<Output = Result<O, TwirpError>> + Send,
+    >(
+        mut self,
+        path: &str,
+        call: impl (Fn(S, I, RequestParts, RS) -> F) + Clone + Send + Sync + 'static,
+    ) -> Self {
+        let service = self.service.clone();
+        self.router = self.router.route(
+            path,
+            post(
+                move |State(state): State<RS>, request: Request| async move {
+                    let (parts, body) = request.with_limited_body().into_parts();
+                    let Some(codec) = Codec::from_headers(&parts.headers, false) else {
+                        return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+                    };
+                    let result = async {
+                        let request = codec.decode(&read_body(body).await?)?;
+                        let response = call(service, request, parts, state).await?;
+                        codec.encode(&response)
+                    };
+                    match result.await {
+                        Ok(body) => {
+                            ([(CONTENT_TYPE, codec.content_type(false))], body).into_response()
+                        }
+                        Err(error) => unary_error_response(&error),
+                    }
+                },
+            ),
+        );
+        self
+    }
+
+    /// Adds a server streaming method
+    pub fn route_server_streaming<
+        I: ReflectMessage + Default,
+        O: ReflectMessage + 'static,
+        F: Future<Output = Result<OS, TwirpError>> + Send,
+        OS: Stream<Item = Result<O, TwirpError>> + Send + 'static,
+    >(
+        mut self,
+        path: &str,
+        call: impl (Fn(S, I, RequestParts, RS) -> F) + Clone + Send + Sync + 'static,
+    ) -> Self {
+        let service = self.service.clone();
+        self.router = self.router.route(
+            path,
+            post(
+                move |State(state): State<RS>, request: Request| async move {
+                    let (parts, body) = request.with_limited_body().into_parts();
+                    let Some(codec) = Codec::from_headers(&parts.headers, true) else {
+                        return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+                    };
+                    let result = async {
+                        let request = codec.decode(&read_single_envelope(body).await?)?;
+                        call(service, request, parts, state).await
+                    };
+                    let stream = ConnectResponseStream {
+                        inner: match result.await {
+                            Ok(stream) => Some(Box::pin(stream)),
+                            Err(error) => {
+                                return streaming_response(
+                                    codec,
+                                    Body::from(end_stream_envelope(Some(&error))),
+                                );
+                            }
+                        },
+                        codec,
+                    };
+                    streaming_response(codec, Body::from_stream(stream))
+                },
+            ),
+        );
+        self
+    }
+
+    pub fn build(self) -> Router<RS> {
+        self.router
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Codec {
+    Protobuf,
+    Json,
+}
+
+impl Codec {
+    /// The codec of the content type, `None` if it is not supported
+    fn from_headers(headers: &HeaderMap, streaming: bool) -> Option<Self> {
+        let content_type = headers.get(CONTENT_TYPE)?;
+        if *content_type == Self::Protobuf.content_type(streaming) {
+            Some(Self::Protobuf)
+        } else if *content_type == Self::Json.content_type(streaming) {
+            Some(Self::Json)
+        } else {
+            None
+        }
+    }
+
+    fn content_type(self, streaming: bool) -> HeaderValue {
+        match (self, streaming) {
+            (Self::Protobuf, false) => APPLICATION_PROTO,
+            (Self::Json, false) => APPLICATION_JSON,
+            (Self::Protobuf, true) => APPLICATION_CONNECT_PROTO,
+            (Self::Json, true) => APPLICATION_CONNECT_JSON,
+        }
+    }
+
+    fn decode<T: ReflectMessage + Default>(self, body: &[u8]) -> Result<T, TwirpError> {
+        match self {
+            Self::Protobuf => T::decode(body).map_err(|e| {
+                TwirpError::wrap(
+                    TwirpErrorCode::Malformed,
+                    format!("Invalid binary protobuf request: {e}"),
+                    e,
+                )
+            }),
+            Self::Json => json_decode(body),
+        }
+    }
+
+    fn encode<T: ReflectMessage>(self, message: &T) -> Result<Bytes, TwirpError> {
+        match self {
+            Self::Protobuf => Ok(message.encode_to_vec().into()),
+            Self::Json => json_encode(message, TwirpJsonOptions::default()),
+        }
+    }
+}
+
+async fn read_body(body: Body) -> Result<Bytes, TwirpError> {
+    Ok(body
+        .collect()
+        .await
+        .map_err(|e| {
+            TwirpError::wrap(
+                TwirpErrorCode::Internal,
+                "Failed to read the request body",
+                e,
+            )
+        })?
+        .to_bytes())
+}
+
+/// Reads the single message of a server streaming request
+async fn read_single_envelope(body: Body) -> Result<Bytes, TwirpError> {
+    let mut body = read_body(body).await?;
+    if body.len() < 5 {
+        return Err(TwirpError::malformed("Truncated envelope"));
+    }
+    let flags = body[0];
+    let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+    if flags & COMPRESSED_FLAG != 0 {
+        return Err(TwirpError::unimplemented(
+            "Compressed messages are not supported",
+        ));
+    }
+    if body.len() != 5 + len {
+        return Err(TwirpError::malformed(
+            "The request must contain a single enveloped message",
+        ));
+    }
+    Ok(body.split_off(5))
+}
+
+fn envelope(flags: u8, message: &[u8]) -> Bytes {
+    let mut buffer = BytesMut::with_capacity(5 + message.len());
+    buffer.put_u8(flags);
+    buffer.put_u32(message.len() as u32);
+    buffer.put_slice(message);
+    buffer.freeze()
+}
+
+fn end_stream_envelope(error: Option<&TwirpError>) -> Bytes {
+    let end_stream = match error {
+        Some(error) => serde_json::json!({ "error": error_json(error) }),
+        None => serde_json::json!({}),
+    };
+    envelope(END_STREAM_FLAG, end_stream.to_string().as_bytes())
+}
+
+fn streaming_response(codec: Codec, body: Body) -> Response {
+    ([(CONTENT_TYPE, codec.content_type(true))], body).into_response()
+}
+
+fn unary_error_response(error: &TwirpError) -> Response {
+    (
+        connect_status(error.code()),
+        [(CONTENT_TYPE, APPLICATION_JSON)],
+        error_json(error).to_string(),
+    )
+        .into_response()
+}
+
+fn error_json(error: &TwirpError) -> serde_json::Value {
+    serde_json::json!({
+        "code": connect_code(error.code()),
+        "message": error.message(),
+    })
+}
+
+/// The [Connect error code](https://connectrpc.com/docs/protocol#error-codes) of a Twirp error code
+fn connect_code(code: TwirpErrorCode) -> &'static str {
+    match code {
+        TwirpErrorCode::Canceled => "canceled",
+        TwirpErrorCode::Unknown => "unknown",
+        TwirpErrorCode::InvalidArgument | TwirpErrorCode::Malformed => "invalid_argument",
+        TwirpErrorCode::DeadlineExceeded => "deadline_exceeded",
+        TwirpErrorCode::NotFound | TwirpErrorCode::BadRoute => "not_found",
+        TwirpErrorCode::AlreadyExists => "already_exists",
+        TwirpErrorCode::PermissionDenied => "permission_denied",
+        TwirpErrorCode::Unauthenticated => "unauthenticated",
+        TwirpErrorCode::ResourceExhausted => "resource_exhausted",
+        TwirpErrorCode::FailedPrecondition => "failed_precondition",
+        TwirpErrorCode::Aborted => "aborted",
+        TwirpErrorCode::OutOfRange => "out_of_range",
+        TwirpErrorCode::Unimplemented => "unimplemented",
+        TwirpErrorCode::Internal => "internal",
+        TwirpErrorCode::Unavailable => "unavailable",
+        TwirpErrorCode::Dataloss => "data_loss",
+    }
+}
+
+fn connect_status(code: TwirpErrorCode) -> StatusCode {
+    match code {
+        TwirpErrorCode::Canceled => StatusCode::from_u16(499).unwrap(),
+        TwirpErrorCode::InvalidArgument
+        | TwirpErrorCode::Malformed
+        | TwirpErrorCode::FailedPrecondition
+        | TwirpErrorCode::OutOfRange => StatusCode::BAD_REQUEST,
+        TwirpErrorCode::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        TwirpErrorCode::NotFound | TwirpErrorCode::BadRoute => StatusCode::NOT_FOUND,
+        TwirpErrorCode::AlreadyExists | TwirpErrorCode::Aborted => StatusCode::CONFLICT,
+        TwirpErrorCode::PermissionDenied => StatusCode::FORBIDDEN,
+        TwirpErrorCode::Unauthenticated => StatusCode::UNAUTHORIZED,
+        TwirpErrorCode::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        TwirpErrorCode::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+        TwirpErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        TwirpErrorCode::Unknown | TwirpErrorCode::Internal | TwirpErrorCode::Dataloss => {
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Envelopes the messages of the response stream and adds the end of stream message
+struct ConnectResponseStream<OS> {
+    inner: Option<Pin<Box<OS>>>,
+    codec: Codec,
+}
+
+impl<O: ReflectMessage, OS: Stream<Item = Result<O, TwirpError>>> Stream
+    for ConnectResponseStream<OS>
+{
+    type Item = Result<Bytes, Infallible>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let Some(inner) = &mut self.inner else {
+            return Poll::Ready(None);
+        };
+        let result = match inner.as_mut().poll_next(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(result) => result,
+        };
+        let message = match result {
+            Some(Ok(message)) => match self.codec.encode(&message) {
+                Ok(message) => return Poll::Ready(Some(Ok(envelope(0, &message)))),
+                Err(error) => Some(error),
+            },
+            Some(Err(error)) => Some(error),
+            None => None,
+        };
+        self.inner = None;
+        Poll::Ready(Some(Ok(end_stream_envelope(message.as_ref()))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::tests::MyMessage;
+    use axum::http::Method;
+    use tower::ServiceExt;
+
+    fn router() -> Router {
+        ConnectRouter::new(())
+            .route(
+                "/package.MyService/Unary",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .route(
+                "/package.MyService/Failing",
+                |(), _: MyMessage, _, _| async move {
+                    Err::<MyMessage, _>(TwirpError::not_found("foo not found"))
+                },
+            )
+            .route_server_streaming(
+                "/package.MyService/Streaming",
+                |(), request: MyMessage, _, _| async move {
+                    Ok(tokio_stream::iter([
+                        Ok(request.clone()),
+                        Ok(request),
+                        Err(TwirpError::aborted("stop")),
+                    ]))
+                },
+            )
+            .build()
+    }
+
+    async fn call(path: &str, content_type: &'static str, body: Vec<u8>) -> Response {
+        router()
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri(path)
+                    .header(CONTENT_TYPE, content_type)
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+    }
+
+    async fn body(response: Response) -> Bytes {
+        response.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    #[tokio::test]
+    async fn test_unary() {
+        for (content_type, request) in [("application/proto", ""), ("application/json", "{}")] {
+            let response = call(
+                "/package.MyService/Unary",
+                content_type,
+                request.as_bytes().to_vec(),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), content_type);
+            assert_eq!(body(response).await, request.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unary_error() {
+        let response = call(
+            "/package.MyService/Failing",
+            "application/json",
+            b"{}".into(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            body(response).await,
+            b"{\"code\":\"not_found\",\"message\":\"foo not found\"}".as_slice()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server_streaming() {
+        let response = call(
+            "/package.MyService/Streaming",
+            "application/connect+json",
+            envelope(0, b"{}").to_vec(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "application/connect+json"
+        );
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&envelope(0, b"{}"));
+        expected.extend_from_slice(&envelope(0, b"{}"));
+        expected.extend_from_slice(&envelope(
+            END_STREAM_FLAG,
+            b"{\"error\":{\"code\":\"aborted\",\"message\":\"stop\"}}",
+        ));
+        assert_eq!(body(response).await, expected);
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_content_type() {
+        for path in ["/package.MyService/Unary", "/package.MyService/Streaming"] {
+            let response = call(path, "application/protobuf", Vec::new()).await;
+            assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_streaming_bad_envelope() {
+        let response = call(
+            "/package.MyService/Streaming",
+            "application/connect+proto",
+            vec![0, 0, 0],
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            body(response).await,
+            envelope(
+                END_STREAM_FLAG,
+                b"{\"error\":{\"code\":\"invalid_argument\",\"message\":\"Truncated envelope\"}}"
+            )
+        );
+    }
+
+    #[test]
+    fn test_connect_code() {
+        assert_eq!(connect_code(TwirpErrorCode::Dataloss), "data_loss");
+        assert_eq!(connect_code(TwirpErrorCode::Malformed), "invalid_argument");
+        assert_eq!(connect_code(TwirpErrorCode::Canceled), "canceled");
+    }
+}
@@ -0,0 +1,257 @@
+use crate::TwirpError;
+use axum::extract::{Request, State};
+use axum::http::header::{
+    ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD, CONTENT_TYPE, ORIGIN, VARY,
+};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// [CORS](https://developer.mozilla.org/en-US/docs/Web/HTTP/CORS) configuration of a `TwirpRouter`.
+///
+/// By default, no origin is allowed, only the `POST` method is allowed
+/// and only the `Content-Type` header (to send `application/json` or `application/protobuf`) is allowed.
+///
+/// ```
+/// use std::time::Duration;
+/// use twurst_server::CorsConfig;
+///
+/// let _config = CorsConfig::default()
+///     .with_allowed_origin("https://example.com")
+///     .with_allowed_header("authorization".parse().unwrap())
+///     .with_max_age(Duration::from_secs(3600));
+/// ```
+#[derive(Clone, Debug)]
+pub struct CorsConfig {
+    allowed_origins: Vec<String>,
+    allowed_methods: Vec<Method>,
+    allowed_headers: Vec<HeaderName>,
+    max_age: Option<Duration>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec![Method::POST],
+            allowed_headers: vec![CONTENT_TYPE],
+            max_age: None,
+        }
+    }
+}
+
+impl CorsConfig {
+    /// Allows an origin (e.g. `https://example.com`), or all origins if `origin` is `*`.
+    pub fn with_allowed_origin(mut self, origin: impl Into<String>) -> Self {
+        self.allowed_origins.push(origin.into());
+        self
+    }
+
+    /// Allows a method in addition to `POST`.
+    pub fn with_allowed_method(mut self, method: Method) -> Self {
+        if !self.allowed_methods.contains(&method) {
+            self.allowed_methods.push(method);
+        }
+        self
+    }
+
+    /// Allows a request header in addition to `Content-Type`.
+    pub fn with_allowed_header(mut self, header: HeaderName) -> Self {
+        if !self.allowed_headers.contains(&header) {
+            self.allowed_headers.push(header);
+        }
+        self
+    }
+
+    /// Sets how long the browsers might cache the preflight responses.
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// The `Access-Control-Allow-Origin` value for `origin` if it is allowed
+    fn allow_origin(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        if self.allowed_origins.iter().any(|o| o == "*") {
+            Some(HeaderValue::from_static("*"))
+        } else if self.allowed_origins.iter().any(|o| o == origin) {
+            Some(origin.clone())
+        } else {
+            None
+        }
+    }
+
+    fn preflight_headers(&self, headers: &mut HeaderMap) {
+        let methods = self
+            .allowed_methods
+            .iter()
+            .map(Method::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let allowed_headers = self
+            .allowed_headers
+            .iter()
+            .map(HeaderName::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+        if let Ok(methods) = HeaderValue::try_from(methods) {
+            headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+        if let Ok(allowed_headers) = HeaderValue::try_from(allowed_headers) {
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers);
+        }
+        if let Some(max_age) = self.max_age {
+            headers.insert(ACCESS_CONTROL_MAX_AGE, max_age.as_secs().into());
+        }
+    }
+}
+
+pub(crate) async fn apply_cors(
+    State(config): State<Arc<CorsConfig>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(origin) = request.headers().get(ORIGIN).cloned() else {
+        // Not a cross-origin request
+        return next.run(request).await;
+    };
+    let Some(allow_origin) = config.allow_origin(&origin) else {
+        return TwirpError::permission_denied(format!(
+            "Origin {} is not allowed",
+            String::from_utf8_lossy(origin.as_bytes())
+        ))
+        .into_response();
+    };
+    let is_preflight = request.method() == Method::OPTIONS
+        && request
+            .headers()
+            .contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+    let mut response = if is_preflight {
+        let mut response = StatusCode::NO_CONTENT.into_response();
+        config.preflight_headers(response.headers_mut());
+        response
+    } else {
+        next.run(request).await
+    };
+    response
+        .headers_mut()
+        .insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+    response
+        .headers_mut()
+        .append(VARY, HeaderValue::from_static("origin"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::TwirpRouter;
+    use crate::codegen::tests::MyMessage;
+    use axum::body::Body;
+    use axum::routing::Router;
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    fn router() -> Router {
+        TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .with_cors(
+                CorsConfig::default()
+                    .with_allowed_origin("https://example.com")
+                    .with_allowed_header(HeaderName::from_static("authorization"))
+                    .with_max_age(Duration::from_secs(600)),
+            )
+            .build()
+    }
+
+    #[tokio::test]
+    async fn test_preflight() {
+        let response = router()
+            .oneshot(
+                Request::builder()
+                    .method(Method::OPTIONS)
+                    .uri("/package.MyService/MyMethod")
+                    .header(ORIGIN, "https://example.com")
+                    .header(ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                    .header("access-control-request-headers", "content-type")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let headers = response.headers();
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_ORIGIN],
+            HeaderValue::from_static("https://example.com")
+        );
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_METHODS], "POST");
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_HEADERS],
+            "content-type, authorization"
+        );
+        assert_eq!(headers[ACCESS_CONTROL_MAX_AGE], "600");
+        assert_eq!(headers[VARY], "origin");
+    }
+
+    #[tokio::test]
+    async fn test_cors_request() {
+        let call = |origin: Option<&'static str>| {
+            let mut request = Request::builder()
+                .method(Method::POST)
+                .uri("/package.MyService/MyMethod")
+                .header(CONTENT_TYPE, "application/json");
+            if let Some(origin) = origin {
+                request = request.header(ORIGIN, origin);
+            }
+            router().oneshot(request.body(Body::from("{}")).unwrap())
+        };
+
+        let response = call(Some("https://example.com")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://example.com"
+        );
+
+        let response = call(None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        let response = call(Some("https://evil.com")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            "{\"code\":\"permission_denied\",\"msg\":\"Origin https://evil.com is not allowed\"}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_any_origin() {
+        let response = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .with_cors(CorsConfig::default().with_allowed_origin("*"))
+            .build()
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/package.MyService/MyMethod")
+                    .header(CONTENT_TYPE, "application/json")
+                    .header(ORIGIN, "https://example.org")
+                    .body(Body::from("{}"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    }
+}
@@ -0,0 +1,21 @@
+/// Helpers to build a [`Content-Security-Policy`](https://developer.mozilla.org/en-US/docs/Web/HTTP/CSP)
+/// for `TwirpRouter::with_content_security_policy`.
+#[derive(Clone, Copy, Debug)]
+pub struct ContentSecurityPolicy;
+
+impl ContentSecurityPolicy {
+    /// A strict policy for API endpoints: the responses are data, never documents,
+    /// so they are not allowed to load any resource, to be framed or to be the target of a form.
+    ///
+    /// ```
+    /// use twurst_server::ContentSecurityPolicy;
+    ///
+    /// assert_eq!(
+    ///     ContentSecurityPolicy::default_twirp(),
+    ///     "default-src 'none'; base-uri 'none'; form-action 'none'; frame-ancestors 'none'"
+    /// );
+    /// ```
+    pub fn default_twirp() -> String {
+        "default-src 'none'; base-uri 'none'; form-action 'none'; frame-ancestors 'none'".into()
+    }
+}
@@ -0,0 +1,546 @@
+use crate::api_key::X_API_KEY;
+use crate::codegen::collect_request_body;
+use crate::{Principal, TwirpError};
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::{Extensions, HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use http_body_util::BodyExt;
+use prost_reflect::bytes::Bytes;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::{Future, ready};
+use std::pin::{Pin, pin};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::Notify;
+use tokio::time::{Instant, timeout};
+
+/// Future returned by the [`DedupStore`] methods
+pub type DedupFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Store of the requests seen by a router with `TwirpRouter::with_content_dedup` or by an
+/// [`IdempotencyLayer`](crate::IdempotencyLayer) and of their responses.
+///
+/// The requests are identified by a SHA-256 hash: of their path, query and raw body for `with_content_dedup`
+/// and of their path and `Idempotency-Key` header for the `IdempotencyLayer`.
+/// The hash also covers the caller of the request (its API key principal or credentials) so that two callers sending
+/// the same request never get the response stored for each other.
+///
+/// The methods are async so that the store can be backed by a shared database (e.g. Redis)
+/// without blocking the server threads.
+pub trait DedupStore: Send + Sync {
+    /// Claims `hash` for `ttl` and returns `true` if it was new or its claim expired,
+    /// `false` if the request is a duplicate.
+    ///
+    /// The claim expires after `ttl` so that a request is not blocked forever
+    /// if the server handling its first copy stopped without completing it.
+    fn check_and_set(&self, hash: [u8; 32], ttl: Duration) -> DedupFuture<'_, bool>;
+
+    /// Stores the successful `response` of the request identified by `hash` for `window`.
+    fn set_response(
+        &self,
+        hash: [u8; 32],
+        response: DedupResponse,
+        window: Duration,
+    ) -> DedupFuture<'_, ()>;
+
+    /// Returns the response stored for `hash`, `None` if the request is still being handled.
+    fn get_response(&self, hash: [u8; 32]) -> DedupFuture<'_, Option<DedupResponse>>;
+
+    /// Forgets `hash` so that the request can be retried, e.g. because it failed.
+    fn remove(&self, hash: [u8; 32]) -> DedupFuture<'_, ()>;
+}
+
+/// Response returned to the duplicates of a request
+#[derive(Clone, Debug)]
+pub struct DedupResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl IntoResponse for DedupResponse {
+    fn into_response(self) -> Response {
+        (self.status, self.headers, self.body).into_response()
+    }
+}
+
+/// In-memory [`DedupStore`] for a single server instance.
+///
+/// The expired responses and claims are removed when new requests are checked.
+#[derive(Default)]
+pub struct InMemoryDedupStore {
+    entries: Mutex<HashMap<[u8; 32], StoredEntry>>,
+}
+
+struct StoredEntry {
+    /// `None` while the request is being handled
+    response: Option<DedupResponse>,
+    expires_at: Instant,
+}
+
+impl InMemoryDedupStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn insert(&self, hash: [u8; 32], response: Option<DedupResponse>, ttl: Duration) {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(
+                hash,
+                StoredEntry {
+                    response,
+                    expires_at: Instant::now() + ttl,
+                },
+            );
+    }
+}
+
+impl DedupStore for InMemoryDedupStore {
+    fn check_and_set(&self, hash: [u8; 32], ttl: Duration) -> DedupFuture<'_, bool> {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        entries.retain(|_, entry| entry.expires_at > now);
+        let is_new = !entries.contains_key(&hash);
+        if is_new {
+            entries.insert(
+                hash,
+                StoredEntry {
+                    response: None,
+                    expires_at: now + ttl,
+                },
+            );
+        }
+        Box::pin(ready(is_new))
+    }
+
+    fn set_response(
+        &self,
+        hash: [u8; 32],
+        response: DedupResponse,
+        window: Duration,
+    ) -> DedupFuture<'_, ()> {
+        self.insert(hash, Some(response), window);
+        Box::pin(ready(()))
+    }
+
+    fn get_response(&self, hash: [u8; 32]) -> DedupFuture<'_, Option<DedupResponse>> {
+        let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let response = entries
+            .get(&hash)
+            .filter(|entry| entry.expires_at > Instant::now())
+            .and_then(|entry| entry.response.clone());
+        Box::pin(ready(response))
+    }
+
+    fn remove(&self, hash: [u8; 32]) -> DedupFuture<'_, ()> {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&hash);
+        Box::pin(ready(()))
+    }
+}
+
+/// Configuration set with `TwirpRouter::with_content_dedup`
+#[derive(Clone)]
+pub(crate) struct ContentDedup {
+    window: Duration,
+    store: Arc<dyn DedupStore>,
+    body_limit: Option<usize>,
+    /// Notified when a request is done so that its in-flight duplicates can get its response
+    completed: Arc<Notify>,
+}
+
+impl ContentDedup {
+    pub(crate) fn new(
+        window: Duration,
+        store: Arc<dyn DedupStore>,
+        body_limit: Option<usize>,
+    ) -> Self {
+        Self {
+            window,
+            store,
+            body_limit,
+            completed: Arc::new(Notify::new()),
+        }
+    }
+}
+
+pub(crate) async fn deduplicate_content(
+    State(dedup): State<ContentDedup>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let (parts, body) = match collect_request_body(request, dedup.body_limit).await {
+        Ok(request) => request,
+        Err(e) => return e.into_response(),
+    };
+    let hash: [u8; 32] = with_caller(Sha256::new(), &parts.extensions, &parts.headers)
+        .chain_update(parts.uri.path_and_query().map_or("", |p| p.as_str()))
+        .chain_update([0])
+        .chain_update(&body)
+        .finalize()
+        .into();
+    let request = Request::from_parts(parts, Body::from(body));
+    run_deduplicated(
+        dedup.store,
+        hash,
+        dedup.window,
+        Some(dedup.completed),
+        async { Ok::<_, Infallible>(next.run(request).await) },
+    )
+    .await
+    .unwrap_or_else(|e| match e {})
+}
+
+/// Adds the identity of the caller of a request to `hasher`.
+///
+/// It is the [`Principal`] resolved by an [`ApiKeyLayer`](crate::ApiKeyLayer) if any,
+/// the `Authorization` and `X-Api-Key` headers otherwise.
+/// Requests without any of them are all considered to come from the same anonymous caller.
+pub(crate) fn with_caller(
+    mut hasher: Sha256,
+    extensions: &Extensions,
+    headers: &HeaderMap,
+) -> Sha256 {
+    if let Some(Principal(principal)) = extensions.get::<Principal>() {
+        return hasher
+            .chain_update("principal")
+            .chain_update([0])
+            .chain_update(principal)
+            .chain_update([0]);
+    }
+    for name in [AUTHORIZATION, X_API_KEY] {
+        for value in headers.get_all(&name) {
+            hasher = hasher
+                .chain_update(name.as_str())
+                .chain_update([0])
+                .chain_update(value.as_bytes())
+                .chain_update([0]);
+        }
+    }
+    hasher
+}
+
+/// How often a request waiting for a duplicate checks the store
+/// if the duplicate is handled by another server instance
+const IN_FLIGHT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a request is claimed while it is being handled.
+///
+/// After it, the claim of a server that stopped without completing the request expires and the request can be retried.
+pub(crate) const IN_FLIGHT_TTL: Duration = Duration::from_secs(60);
+
+/// How long a duplicate waits for the request being handled before failing with an `aborted` error
+pub(crate) const IN_FLIGHT_WAIT: Duration = Duration::from_secs(30);
+
+/// Returns the stored response of the request identified by `hash` or calls `handle` and stores its successful response.
+///
+/// The duplicates of a request still being handled wait for it for at most [`IN_FLIGHT_WAIT`] if `completed` is set,
+/// they fail with an `aborted` error otherwise or after it.
+/// `completed` is notified when a request is done so that the waiting duplicates can check the store again.
+pub(crate) async fn run_deduplicated<E>(
+    store: Arc<dyn DedupStore>,
+    hash: [u8; 32],
+    window: Duration,
+    completed: Option<Arc<Notify>>,
+    handle: impl Future<Output = Result<Response, E>>,
+) -> Result<Response, E> {
+    let deadline = Instant::now() + IN_FLIGHT_WAIT;
+    loop {
+        // We register before checking the store to not miss a notification sent in between
+        let notified = completed.as_deref().map(Notify::notified);
+        let mut notified = pin!(notified);
+        if let Some(notified) = notified.as_mut().as_pin_mut() {
+            notified.enable();
+        }
+        if store.check_and_set(hash, IN_FLIGHT_TTL).await {
+            break;
+        }
+        if let Some(response) = store.get_response(hash).await {
+            return Ok(response.into_response());
+        }
+        let now = Instant::now();
+        let Some(notified) = notified.as_pin_mut().filter(|_| now < deadline) else {
+            return Ok(
+                TwirpError::aborted("The same request is already being handled").into_response(),
+            );
+        };
+        let _ = timeout(IN_FLIGHT_POLL_INTERVAL.min(deadline - now), notified).await;
+    }
+    let mut claim = Claim {
+        store: store.clone(),
+        hash,
+        completed,
+        handled: false,
+    };
+    let response = handle.await?;
+    if !response.status().is_success() {
+        return Ok(response);
+    }
+    let (parts, body) = response.into_parts();
+    let body = match body.collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) => {
+            return Ok(
+                TwirpError::internal(format!("Failed to read the response body: {e}"))
+                    .into_response(),
+            );
+        }
+    };
+    store
+        .set_response(
+            hash,
+            DedupResponse {
+                status: parts.status,
+                headers: parts.headers.clone(),
+                body: body.clone(),
+            },
+            window,
+        )
+        .await;
+    claim.handled = true;
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+/// Request being handled by [`run_deduplicated`]
+///
+/// It is forgotten if it is not handled successfully (e.g. it failed or was cancelled) so that it can be retried.
+struct Claim {
+    store: Arc<dyn DedupStore>,
+    hash: [u8; 32],
+    completed: Option<Arc<Notify>>,
+    handled: bool,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        let completed = self.completed.take();
+        if self.handled {
+            if let Some(completed) = completed {
+                completed.notify_waiters();
+            }
+            return;
+        }
+        // Without a runtime (e.g. during the shutdown) the claim is left to expire
+        let Ok(runtime) = Handle::try_current() else {
+            return;
+        };
+        let store = self.store.clone();
+        let hash = self.hash;
+        runtime.spawn(async move {
+            store.remove(hash).await;
+            if let Some(completed) = completed {
+                completed.notify_waiters();
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::TwirpRouter;
+    use crate::codegen::tests::MyMessage;
+    use axum::http::Method;
+    use axum::http::header::CONTENT_TYPE;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_content_dedup() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handler_calls = calls.clone();
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                move |(), request: MyMessage, _, _| {
+                    handler_calls.fetch_add(1, Ordering::Relaxed);
+                    async move { Ok(request) }
+                },
+            )
+            .route(
+                "/package.MyService/Failing",
+                |(), _: MyMessage, _, _| async move {
+                    Err::<MyMessage, _>(TwirpError::unavailable("Try again"))
+                },
+            )
+            .with_content_dedup(Duration::from_secs(60), Arc::new(InMemoryDedupStore::new()))
+            .build();
+        let call = |path: &'static str, body: &'static str| {
+            let router = router.clone();
+            async move {
+                let response = router
+                    .oneshot(
+                        Request::builder()
+                            .method(Method::POST)
+                            .uri(path)
+                            .header(CONTENT_TYPE, "application/json")
+                            .body(Body::from(body))
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                let status = response.status();
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                (status, body)
+            }
+        };
+
+        for _ in 0..2 {
+            assert_eq!(
+                call("/package.MyService/MyMethod", "{}").await,
+                (StatusCode::OK, Bytes::from("{}"))
+            );
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        // Another body is a new request
+        call("/package.MyService/MyMethod", "{ }").await;
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        // Failed requests are not deduplicated
+        for _ in 0..2 {
+            assert_eq!(
+                call("/package.MyService/Failing", "{}").await.0,
+                StatusCode::SERVICE_UNAVAILABLE
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_content_dedup_callers() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handler_calls = calls.clone();
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                move |(), request: MyMessage, _, _| {
+                    let calls = handler_calls.clone();
+                    async move {
+                        calls.fetch_add(1, Ordering::Relaxed);
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                        Ok(request)
+                    }
+                },
+            )
+            .with_content_dedup(Duration::from_secs(60), Arc::new(InMemoryDedupStore::new()))
+            .build();
+        let call = |authorization: &'static str| {
+            let router = router.clone();
+            async move {
+                let response = router
+                    .oneshot(
+                        Request::builder()
+                            .method(Method::POST)
+                            .uri("/package.MyService/MyMethod")
+                            .header(CONTENT_TYPE, "application/json")
+                            .header(AUTHORIZATION, authorization)
+                            .body(Body::from("{}"))
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                let status = response.status();
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                (status, body)
+            }
+        };
+
+        // The in-flight duplicate waits for the response of the first request
+        let (first, second) = tokio::join!(call("Bearer a"), call("Bearer a"));
+        assert_eq!(first, (StatusCode::OK, Bytes::from("{}")));
+        assert_eq!(second, first);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        // Another caller does not get the stored response
+        call("Bearer b").await;
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_in_memory_dedup_store_expiration() {
+        let store = InMemoryDedupStore::new();
+        assert!(store.check_and_set([1; 32], Duration::from_secs(1)).await);
+        assert!(!store.check_and_set([1; 32], Duration::from_secs(1)).await);
+        assert!(store.get_response([1; 32]).await.is_none());
+        store
+            .set_response(
+                [1; 32],
+                DedupResponse {
+                    status: StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: Bytes::from("ok"),
+                },
+                Duration::from_millis(10),
+            )
+            .await;
+        assert_eq!(store.get_response([1; 32]).await.unwrap().body, "ok");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(store.get_response([1; 32]).await.is_none());
+        assert!(store.check_and_set([1; 32], Duration::from_secs(1)).await);
+        // The claims expire too
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(store.check_and_set([1; 32], Duration::from_secs(1)).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dedup_abandoned_claim() {
+        let store: Arc<dyn DedupStore> = Arc::new(InMemoryDedupStore::new());
+        let completed = Arc::new(Notify::new());
+        let run = || {
+            run_deduplicated(
+                store.clone(),
+                [1; 32],
+                Duration::from_secs(3600),
+                Some(completed.clone()),
+                async { Ok::<_, Infallible>("ok".into_response()) },
+            )
+        };
+
+        // A first holder dropped without completing is forgotten
+        let never_completed = run_deduplicated(
+            store.clone(),
+            [1; 32],
+            Duration::from_secs(3600),
+            Some(completed.clone()),
+            std::future::pending::<Result<Response, Infallible>>(),
+        );
+        assert!(
+            timeout(Duration::from_secs(1), never_completed)
+                .await
+                .is_err()
+        );
+        tokio::task::yield_now().await;
+        assert_eq!(run().await.unwrap().status(), StatusCode::OK);
+
+        // A first holder that stopped without releasing its claim (e.g. another crashed instance)
+        // makes the duplicates fail after a while and then expires
+        assert!(store.check_and_set([2; 32], IN_FLIGHT_TTL).await);
+        let start = Instant::now();
+        let run = || {
+            run_deduplicated(
+                store.clone(),
+                [2; 32],
+                Duration::from_secs(3600),
+                Some(completed.clone()),
+                async { Ok::<_, Infallible>("ok".into_response()) },
+            )
+        };
+        let response = run().await.unwrap();
+        assert_eq!(start.elapsed(), IN_FLIGHT_WAIT);
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            b"{\"code\":\"aborted\",\"msg\":\"The same request is already being handled\"}"
+                .as_slice()
+        );
+        tokio::time::sleep(IN_FLIGHT_TTL - IN_FLIGHT_WAIT).await;
+        assert_eq!(run().await.unwrap().status(), StatusCode::OK);
+    }
+}
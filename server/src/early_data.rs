@@ -0,0 +1,66 @@
+use crate::TwirpError;
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::sync::Arc;
+
+const EARLY_DATA: &str = "early-data";
+
+/// What to do with the requests sent in TLS 1.3 0-RTT early data.
+///
+/// Early data can be replayed by an attacker so only idempotent methods should accept it.
+/// The requests are detected using the `Early-Data: 1` header that the TLS terminator must set
+/// following [RFC 8470](https://www.rfc-editor.org/rfc/rfc8470).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum EarlyDataPolicy {
+    /// Rejects all the requests sent in early data with a `425 Too Early` status
+    #[default]
+    Reject,
+    /// Accepts the requests sent in early data only on the given paths (e.g. `/package.MyService/GetFoo`)
+    AllowIdempotent(Vec<String>),
+}
+
+impl EarlyDataPolicy {
+    fn allows(&self, path: &str) -> bool {
+        match self {
+            Self::Reject => false,
+            Self::AllowIdempotent(paths) => paths.iter().any(|p| p == path),
+        }
+    }
+}
+
+pub(crate) async fn check_early_data(
+    State(policy): State<Arc<EarlyDataPolicy>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let is_early_data = request
+        .headers()
+        .get(EARLY_DATA)
+        .is_some_and(|value| value == "1");
+    if !is_early_data || policy.allows(request.uri().path()) {
+        return next.run(request).await;
+    }
+    // The client is expected to retry once the TLS handshake is complete
+    let mut response = TwirpError::unavailable(format!(
+        "{} is not allowed in TLS early data",
+        request.uri().path()
+    ))
+    .into_response();
+    *response.status_mut() = StatusCode::TOO_EARLY;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allows() {
+        assert!(!EarlyDataPolicy::Reject.allows("/package.MyService/MyMethod"));
+        let policy = EarlyDataPolicy::AllowIdempotent(vec!["/package.MyService/MyMethod".into()]);
+        assert!(policy.allows("/package.MyService/MyMethod"));
+        assert!(!policy.allows("/package.MyService/OtherMethod"));
+    }
+}
@@ -0,0 +1,137 @@
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use std::sync::Arc;
+
+/// Health check function set with `TwirpRouter::with_health_check` or `GrpcRouter::with_health_check`
+pub(crate) type HealthCheck = Arc<dyn Fn() -> bool + Send + Sync>;
+
+pub(crate) fn health_check_response(check: &HealthCheck) -> Response {
+    if check() {
+        (StatusCode::OK, "ok").into_response()
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "unavailable").into_response()
+    }
+}
+
+/// Path of the `Check` method of the [gRPC health checking protocol](https://github.com/grpc/grpc/blob/master/doc/health-checking.md)
+#[cfg(feature = "grpc")]
+pub(crate) const GRPC_HEALTH_CHECK_PATH: &str = "/grpc.health.v1.Health/Check";
+
+/// `grpc.health.v1.HealthCheckRequest`
+#[cfg(feature = "grpc")]
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct HealthCheckRequest {
+    #[prost(string, tag = "1")]
+    pub service: String,
+}
+
+/// `grpc.health.v1.HealthCheckResponse`
+#[cfg(feature = "grpc")]
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct HealthCheckResponse {
+    #[prost(enumeration = "ServingStatus", tag = "1")]
+    pub status: i32,
+}
+
+/// `grpc.health.v1.HealthCheckResponse.ServingStatus`
+#[cfg(feature = "grpc")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub(crate) enum ServingStatus {
+    Unknown = 0,
+    Serving = 1,
+    NotServing = 2,
+}
+
+#[cfg(feature = "grpc")]
+pub(crate) fn grpc_health_check_response(check: &HealthCheck) -> HealthCheckResponse {
+    let status = if check() {
+        ServingStatus::Serving
+    } else {
+        ServingStatus::NotServing
+    };
+    HealthCheckResponse {
+        status: status.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::TwirpRouter;
+    use axum::body::Body;
+    use axum::http::{Method, Request};
+    use http_body_util::BodyExt;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_health_check() {
+        let healthy = Arc::new(AtomicBool::new(true));
+        let router = TwirpRouter::new(())
+            .with_method_validation()
+            .with_health_check("/healthz", {
+                let healthy = healthy.clone();
+                move || healthy.load(Ordering::Relaxed)
+            })
+            .build();
+        let call = || {
+            router.clone().oneshot(
+                Request::builder()
+                    .method(Method::GET)
+                    .uri("/healthz")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = call().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            "ok"
+        );
+
+        healthy.store(false, Ordering::Relaxed);
+        let response = call().await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_health_check() {
+        use crate::codegen::GrpcRouter;
+        use axum::http::uri::PathAndQuery;
+        use tonic::client::Grpc;
+        use tonic_prost::ProstCodec;
+
+        let healthy = Arc::new(AtomicBool::new(true));
+        let router = GrpcRouter::new(())
+            .with_health_check({
+                let healthy = healthy.clone();
+                move || healthy.load(Ordering::Relaxed)
+            })
+            .build();
+        let call = || {
+            let router = router.clone();
+            async move {
+                let mut client = Grpc::new(router);
+                client.ready().await.unwrap();
+                client
+                    .unary(
+                        tonic::Request::new(HealthCheckRequest::default()),
+                        PathAndQuery::from_static(GRPC_HEALTH_CHECK_PATH),
+                        ProstCodec::<HealthCheckRequest, HealthCheckResponse>::default(),
+                    )
+                    .await
+                    .unwrap()
+                    .into_inner()
+                    .status()
+            }
+        };
+
+        assert_eq!(call().await, ServingStatus::Serving);
+        healthy.store(false, Ordering::Relaxed);
+        assert_eq!(call().await, ServingStatus::NotServing);
+    }
+}
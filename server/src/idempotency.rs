@@ -0,0 +1,270 @@
+use crate::InMemoryDedupStore;
+use crate::dedup::{run_deduplicated, with_caller};
+use axum::extract::Request;
+use axum::http::HeaderName;
+use axum::response::Response;
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tower_layer::Layer;
+use tower_service::Service;
+
+const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// Store of the responses of the requests seen by an [`IdempotencyLayer`].
+///
+/// It is the [`DedupStore`](crate::DedupStore) also used by `TwirpRouter::with_content_dedup`,
+/// so a single store implementation (e.g. on top of Redis) can back both.
+pub use crate::dedup::DedupStore as IdempotencyStore;
+
+/// In-memory [`IdempotencyStore`] for a single server instance
+pub type HashMapIdempotencyStore = InMemoryDedupStore;
+
+/// [`Layer`] deduplicating the requests with the same `Idempotency-Key` header.
+///
+/// The successful responses are stored in an [`IdempotencyStore`] for `ttl`
+/// and returned again when the same caller sends a request with the same key on the same path.
+/// The caller is the [`Principal`](crate::Principal) set by an [`ApiKeyLayer`](crate::ApiKeyLayer) added before
+/// this layer if any, the `Authorization` and `X-Api-Key` headers otherwise,
+/// so two callers picking the same key never get the response stored for each other.
+/// A request received while another one with the same key is being handled waits for it to finish,
+/// for at most 30 seconds before failing with an `aborted` error.
+/// Failed requests are forgotten so that they can be retried.
+/// Requests without an `Idempotency-Key` header are passed through.
+///
+/// Use [`HashMapIdempotencyStore`] for a single server instance
+/// or implement [`IdempotencyStore`] on top of a shared database like Redis to deduplicate requests across instances.
+///
+/// It can be added to a router with `TwirpRouter::layer`:
+/// ```
+/// use std::sync::Arc;
+/// use std::time::Duration;
+/// use twurst_server::codegen::TwirpRouter;
+/// use twurst_server::{HashMapIdempotencyStore, IdempotencyLayer};
+///
+/// let _router: axum::Router = TwirpRouter::new(())
+///     .layer(IdempotencyLayer::new(
+///         Arc::new(HashMapIdempotencyStore::new()),
+///         Duration::from_secs(3600),
+///     ))
+///     .build();
+/// ```
+#[derive(Clone)]
+pub struct IdempotencyLayer {
+    store: Arc<dyn IdempotencyStore>,
+    ttl: Duration,
+    completed: Arc<Notify>,
+}
+
+impl IdempotencyLayer {
+    pub fn new(store: Arc<dyn IdempotencyStore>, ttl: Duration) -> Self {
+        Self {
+            store,
+            ttl,
+            completed: Arc::new(Notify::new()),
+        }
+    }
+}
+
+impl<S> Layer<S> for IdempotencyLayer {
+    type Service = IdempotencyService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        IdempotencyService {
+            inner,
+            store: self.store.clone(),
+            ttl: self.ttl,
+            completed: self.completed.clone(),
+        }
+    }
+}
+
+/// Service built by [`IdempotencyLayer`]
+#[derive(Clone)]
+pub struct IdempotencyService<S> {
+    inner: S,
+    store: Arc<dyn IdempotencyStore>,
+    ttl: Duration,
+    completed: Arc<Notify>,
+}
+
+impl<S> Service<Request> for IdempotencyService<S>
+where
+    S: Service<Request, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Send,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let Some(key) = request.headers().get(IDEMPOTENCY_KEY) else {
+            return Box::pin(self.inner.call(request));
+        };
+        // The key name keeps the hashes apart from the ones of `TwirpRouter::with_content_dedup`
+        let hash: [u8; 32] = with_caller(Sha256::new(), request.extensions(), request.headers())
+            .chain_update(IDEMPOTENCY_KEY.as_str())
+            .chain_update([0])
+            .chain_update(request.uri().path())
+            .chain_update([0])
+            .chain_update(key.as_bytes())
+            .finalize()
+            .into();
+        // The ready service is the one to call, we keep the clone for the next calls
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let store = self.store.clone();
+        let ttl = self.ttl;
+        let completed = self.completed.clone();
+        Box::pin(async move {
+            run_deduplicated(store, hash, ttl, Some(completed), inner.call(request)).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TwirpError;
+    use crate::codegen::TwirpRouter;
+    use crate::codegen::tests::MyMessage;
+    use axum::Router;
+    use axum::body::Body;
+    use axum::http::header::CONTENT_TYPE;
+    use axum::http::{Method, StatusCode};
+    use http_body_util::BodyExt;
+    use prost_reflect::bytes::Bytes;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tower::ServiceExt;
+
+    fn router(calls: Arc<AtomicUsize>) -> Router {
+        let failing_calls = calls.clone();
+        TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                move |(), request: MyMessage, _, _| {
+                    let calls = calls.clone();
+                    async move {
+                        calls.fetch_add(1, Ordering::Relaxed);
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                        Ok(request)
+                    }
+                },
+            )
+            .route(
+                "/package.MyService/Failing",
+                move |(), _: MyMessage, _, _| {
+                    failing_calls.fetch_add(1, Ordering::Relaxed);
+                    async move { Err::<MyMessage, _>(TwirpError::unavailable("Try again")) }
+                },
+            )
+            .layer(IdempotencyLayer::new(
+                Arc::new(HashMapIdempotencyStore::new()),
+                Duration::from_secs(60),
+            ))
+            .build()
+    }
+
+    async fn call(
+        router: &Router,
+        path: &str,
+        key: Option<&str>,
+        body: &'static str,
+    ) -> (StatusCode, Bytes) {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(path)
+            .header(CONTENT_TYPE, "application/json");
+        if let Some(key) = key {
+            request = request.header(IDEMPOTENCY_KEY, key);
+        }
+        let response = router
+            .clone()
+            .oneshot(request.body(Body::from(body)).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, body)
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let router = router(calls.clone());
+        let path = "/package.MyService/MyMethod";
+
+        for _ in 0..2 {
+            assert_eq!(
+                call(&router, path, Some("a"), "{}").await,
+                (StatusCode::OK, Bytes::from("{}"))
+            );
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        // Another key or no key is a new request
+        call(&router, path, Some("b"), "{}").await;
+        call(&router, path, None, "{}").await;
+        call(&router, path, None, "{}").await;
+        assert_eq!(calls.load(Ordering::Relaxed), 4);
+
+        // Failed requests are not stored
+        for _ in 0..2 {
+            assert_eq!(
+                call(&router, "/package.MyService/Failing", Some("a"), "{}")
+                    .await
+                    .0,
+                StatusCode::SERVICE_UNAVAILABLE
+            );
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 6);
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key_in_flight() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let router = router(calls.clone());
+        let path = "/package.MyService/MyMethod";
+
+        let (first, second) = tokio::join!(
+            call(&router, path, Some("a"), "{}"),
+            call(&router, path, Some("a"), "{}")
+        );
+        assert_eq!(first, (StatusCode::OK, Bytes::from("{}")));
+        assert_eq!(second, first);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key_callers() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let router = router(calls.clone());
+        let call = |api_key: &'static str| {
+            router.clone().oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/package.MyService/MyMethod")
+                    .header(CONTENT_TYPE, "application/json")
+                    .header(IDEMPOTENCY_KEY, "a")
+                    .header("x-api-key", api_key)
+                    .body(Body::from("{}"))
+                    .unwrap(),
+            )
+        };
+
+        for api_key in ["key-1", "key-1", "key-2"] {
+            assert_eq!(call(api_key).await.unwrap().status(), StatusCode::OK);
+        }
+        // The same key sent by another caller is a new request
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+}
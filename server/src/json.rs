@@ -0,0 +1,393 @@
+use base64::Engine;
+use base64::prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD};
+use prost_reflect::{DynamicMessage, Kind, MapKey, ReflectMessage, SerializeOptions, Value};
+use serde_json::Value as JsonValue;
+
+/// Options of the Twirp JSON encoding
+///
+/// They only affect the encoding: decoding always accepts both the JSON and the proto field names,
+/// both the enum names and numbers, and both standard and URL-safe Base64 for `bytes` fields.
+///
+/// ```
+/// use twurst_server::TwirpJsonOptions;
+///
+/// let options = TwirpJsonOptions::default()
+///     .with_emit_default_values()
+///     .with_proto_field_names();
+/// assert!(options.emit_default_values && options.preserve_proto_field_names);
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TwirpJsonOptions {
+    /// Encodes the `bytes` fields with URL-safe Base64 without padding (`-` and `_` instead of `+` and `/`)
+    /// instead of standard Base64 with padding.
+    ///
+    /// Note that `bytes` fields nested in a `google.protobuf.Any` are not affected.
+    pub base64_url_safe: bool,
+    /// Outputs the fields set to their default value (e.g. `0` or `""`) instead of omitting them.
+    pub emit_default_values: bool,
+    /// Uses the field names of the `.proto` file (e.g. `my_field`) instead of their JSON names (e.g. `myField`).
+    pub preserve_proto_field_names: bool,
+    /// Outputs the enum values as integers instead of their names.
+    pub use_enum_numbers: bool,
+    /// Outputs the fields in the order of their field numbers and the map entries in the order of their keys.
+    ///
+    /// The same message is then always encoded to the same JSON (e.g. to compute ETags).
+    pub preserve_field_order: bool,
+}
+
+impl TwirpJsonOptions {
+    /// Sets [`base64_url_safe`](Self::base64_url_safe)
+    pub fn with_base64_url_safe(mut self) -> Self {
+        self.base64_url_safe = true;
+        self
+    }
+
+    /// Sets [`emit_default_values`](Self::emit_default_values)
+    pub fn with_emit_default_values(mut self) -> Self {
+        self.emit_default_values = true;
+        self
+    }
+
+    /// Sets [`preserve_proto_field_names`](Self::preserve_proto_field_names)
+    pub fn with_proto_field_names(mut self) -> Self {
+        self.preserve_proto_field_names = true;
+        self
+    }
+
+    /// Sets [`use_enum_numbers`](Self::use_enum_numbers)
+    pub fn with_enum_numbers(mut self) -> Self {
+        self.use_enum_numbers = true;
+        self
+    }
+
+    /// Sets [`preserve_field_order`](Self::preserve_field_order)
+    pub fn with_field_order(mut self) -> Self {
+        self.preserve_field_order = true;
+        self
+    }
+
+    pub(crate) fn serialize_options(&self) -> SerializeOptions {
+        SerializeOptions::new()
+            .skip_default_fields(!self.emit_default_values)
+            .use_proto_field_name(self.preserve_proto_field_names)
+            .use_enum_numbers(self.use_enum_numbers)
+    }
+}
+
+/// Rewrites the `bytes` fields of `json`, the JSON serialization of `message`, to URL-safe Base64
+///
+/// `proto_field_names` must be set if `json` uses the field names of the `.proto` file.
+pub(crate) fn make_bytes_url_safe(
+    message: &DynamicMessage,
+    json: &mut JsonValue,
+    proto_field_names: bool,
+) {
+    let descriptor = message.descriptor();
+    if descriptor.full_name() == "google.protobuf.BytesValue" {
+        make_string_url_safe(json);
+        return;
+    }
+    if descriptor.package_name() == "google.protobuf" {
+        // The other well-known types do not contain bytes or have a custom JSON encoding
+        return;
+    }
+    let JsonValue::Object(object) = json else {
+        return;
+    };
+    for (field, value) in message.fields() {
+        let name = if proto_field_names {
+            field.name()
+        } else {
+            field.json_name()
+        };
+        let Some(json) = object.get_mut(name) else {
+            continue;
+        };
+        match value {
+            Value::List(values) => {
+                if let JsonValue::Array(array) = json {
+                    for (value, json) in values.iter().zip(array) {
+                        make_value_url_safe(value, json, proto_field_names);
+                    }
+                }
+            }
+            Value::Map(values) => {
+                let Kind::Message(entry) = field.kind() else {
+                    continue;
+                };
+                if !matches!(
+                    entry.map_entry_value_field().kind(),
+                    Kind::Bytes | Kind::Message(_)
+                ) {
+                    continue;
+                }
+                if let JsonValue::Object(object) = json {
+                    for (key, value) in values {
+                        if let Some(json) = object.get_mut(&map_key_to_string(key)) {
+                            make_value_url_safe(value, json, proto_field_names);
+                        }
+                    }
+                }
+            }
+            value => make_value_url_safe(value, json, proto_field_names),
+        }
+    }
+}
+
+/// Writes `json`, the JSON serialization of `message`, with the fields in field number order
+/// and the map entries in key order
+///
+/// `proto_field_names` must be set if `json` uses the field names of the `.proto` file.
+pub(crate) fn write_in_field_order(
+    message: &DynamicMessage,
+    json: &JsonValue,
+    proto_field_names: bool,
+    out: &mut Vec<u8>,
+) -> serde_json::Result<()> {
+    let JsonValue::Object(object) = json else {
+        return serde_json::to_writer(out, json);
+    };
+    if message.descriptor().package_name() == "google.protobuf" {
+        // The well-known types have a custom JSON encoding
+        return serde_json::to_writer(out, json);
+    }
+    out.push(b'{');
+    let mut written = 0;
+    // `fields` iterates in field number order
+    for field in message.descriptor().fields() {
+        let name = if proto_field_names {
+            field.name()
+        } else {
+            field.json_name()
+        };
+        let Some(json) = object.get(name) else {
+            continue;
+        };
+        if written > 0 {
+            out.push(b',');
+        }
+        serde_json::to_writer(&mut *out, name)?;
+        out.push(b':');
+        write_value_in_field_order(&message.get_field(&field), json, proto_field_names, out)?;
+        written += 1;
+    }
+    if written < object.len() {
+        // Extension fields
+        let mut others = object
+            .iter()
+            .filter(|(key, _)| {
+                message.descriptor().fields().all(|field| {
+                    let name = if proto_field_names {
+                        field.name()
+                    } else {
+                        field.json_name()
+                    };
+                    name != key.as_str()
+                })
+            })
+            .collect::<Vec<_>>();
+        others.sort_by_key(|(key, _)| *key);
+        for (key, json) in others {
+            if written > 0 {
+                out.push(b',');
+            }
+            serde_json::to_writer(&mut *out, key)?;
+            out.push(b':');
+            serde_json::to_writer(&mut *out, json)?;
+            written += 1;
+        }
+    }
+    out.push(b'}');
+    Ok(())
+}
+
+fn write_value_in_field_order(
+    value: &Value,
+    json: &JsonValue,
+    proto_field_names: bool,
+    out: &mut Vec<u8>,
+) -> serde_json::Result<()> {
+    match (value, json) {
+        (Value::Message(message), json) => {
+            write_in_field_order(message, json, proto_field_names, out)
+        }
+        (Value::List(values), JsonValue::Array(array)) => {
+            out.push(b'[');
+            for (i, (value, json)) in values.iter().zip(array).enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                write_value_in_field_order(value, json, proto_field_names, out)?;
+            }
+            out.push(b']');
+            Ok(())
+        }
+        (Value::Map(values), JsonValue::Object(object)) => {
+            let mut entries = values
+                .iter()
+                .filter_map(|(key, value)| {
+                    let key = map_key_to_string(key);
+                    let json = object.get(&key)?;
+                    Some((key, value, json))
+                })
+                .collect::<Vec<_>>();
+            entries.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
+            out.push(b'{');
+            for (i, (key, value, json)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                serde_json::to_writer(&mut *out, &key)?;
+                out.push(b':');
+                write_value_in_field_order(value, json, proto_field_names, out)?;
+            }
+            out.push(b'}');
+            Ok(())
+        }
+        (_, json) => serde_json::to_writer(out, json),
+    }
+}
+
+fn make_value_url_safe(value: &Value, json: &mut JsonValue, proto_field_names: bool) {
+    match value {
+        Value::Bytes(_) => make_string_url_safe(json),
+        Value::Message(message) => make_bytes_url_safe(message, json, proto_field_names),
+        _ => (),
+    }
+}
+
+fn make_string_url_safe(json: &mut JsonValue) {
+    if let JsonValue::String(value) = json {
+        if let Ok(bytes) = BASE64_STANDARD.decode(value.as_bytes()) {
+            *value = BASE64_URL_SAFE_NO_PAD.encode(bytes);
+        }
+    }
+}
+
+fn map_key_to_string(key: &MapKey) -> String {
+    match key {
+        MapKey::Bool(v) => v.to_string(),
+        MapKey::I32(v) => v.to_string(),
+        MapKey::I64(v) => v.to_string(),
+        MapKey::U32(v) => v.to_string(),
+        MapKey::U64(v) => v.to_string(),
+        MapKey::String(v) => v.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost_reflect::prost_types::field_descriptor_proto::{Label, Type};
+    use prost_reflect::prost_types::{
+        DescriptorProto, FieldDescriptorProto, FileDescriptorProto, MessageOptions,
+    };
+    use prost_reflect::{DescriptorPool, MessageDescriptor};
+    use serde::Serialize;
+    use serde_json::json;
+
+    fn field(name: &str, number: i32, label: Label, r#type: Type) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            name: Some(name.into()),
+            json_name: Some(name.into()),
+            number: Some(number),
+            label: Some(label.into()),
+            r#type: Some(r#type.into()),
+            ..Default::default()
+        }
+    }
+
+    fn descriptor() -> MessageDescriptor {
+        let mut map_field = field("map", 4, Label::Repeated, Type::Message);
+        map_field.type_name = Some(".package.BytesMessage.MapEntry".into());
+        let mut child_field = field("child", 5, Label::Optional, Type::Message);
+        child_field.type_name = Some(".package.BytesMessage".into());
+        let pool = DescriptorPool::from_file_descriptor_set(
+            prost_reflect::prost_types::FileDescriptorSet {
+                file: vec![FileDescriptorProto {
+                    name: Some("bytes.proto".into()),
+                    package: Some("package".into()),
+                    message_type: vec![DescriptorProto {
+                        name: Some("BytesMessage".into()),
+                        field: vec![
+                            field("single", 1, Label::Optional, Type::Bytes),
+                            field("list", 2, Label::Repeated, Type::Bytes),
+                            field("text", 3, Label::Optional, Type::String),
+                            map_field,
+                            child_field,
+                        ],
+                        nested_type: vec![DescriptorProto {
+                            name: Some("MapEntry".into()),
+                            field: vec![
+                                field("key", 1, Label::Optional, Type::String),
+                                field("value", 2, Label::Optional, Type::Bytes),
+                            ],
+                            options: Some(MessageOptions {
+                                map_entry: Some(true),
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        }],
+                        ..Default::default()
+                    }],
+                    syntax: Some("proto3".into()),
+                    ..Default::default()
+                }],
+            },
+        )
+        .unwrap();
+        pool.get_message_by_name("package.BytesMessage").unwrap()
+    }
+
+    #[test]
+    fn test_make_bytes_url_safe() {
+        let descriptor = descriptor();
+        let message = DynamicMessage::deserialize(
+            descriptor,
+            json!({
+                "single": "+/8=",
+                "list": ["+/8=", "-_8"],
+                "text": "+/8=",
+                "map": {"k": "+/8="},
+                "child": {"single": "+/8="},
+            }),
+        )
+        .unwrap();
+        let mut json = message.serialize(serde_json::value::Serializer).unwrap();
+        make_bytes_url_safe(&message, &mut json, false);
+        assert_eq!(
+            json,
+            json!({
+                "single": "-_8",
+                "list": ["-_8", "-_8"],
+                "text": "+/8=",
+                "map": {"k": "-_8"},
+                "child": {"single": "-_8"},
+            })
+        );
+    }
+
+    #[test]
+    fn test_write_in_field_order() {
+        let encode = || {
+            let message = DynamicMessage::deserialize(
+                descriptor(),
+                json!({
+                    "child": {"text": "c", "single": "AA=="},
+                    "map": {"z": "", "b": "", "m": "", "a": ""},
+                    "text": "t",
+                    "single": "AA==",
+                }),
+            )
+            .unwrap();
+            let json = message.serialize(serde_json::value::Serializer).unwrap();
+            let mut out = Vec::new();
+            write_in_field_order(&message, &json, false, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let expected = r#"{"single":"AA==","text":"t","map":{"a":"","b":"","m":"","z":""},"child":{"single":"AA==","text":"c"}}"#;
+        for _ in 0..10 {
+            assert_eq!(encode(), expected);
+        }
+    }
+}
@@ -6,14 +6,92 @@
 )]
 #![cfg_attr(docsrs, feature(doc_auto_cfg))]
 
+mod api_key;
+#[cfg(feature = "auth")]
+mod auth;
+#[cfg(feature = "baggage")]
+mod baggage;
+mod body_log;
+#[cfg(feature = "catch-panic")]
+mod catch_panic;
 #[doc(hidden)]
 pub mod codegen;
+mod concurrency;
+#[cfg(feature = "connect")]
+mod connect;
+mod cors;
+mod csp;
+#[cfg(feature = "dedup")]
+mod dedup;
+mod early_data;
+mod health;
+#[cfg(feature = "dedup")]
+mod idempotency;
+mod json;
+#[cfg(feature = "logging")]
+mod logging;
+#[cfg(feature = "prometheus")]
+mod metrics;
+mod mock;
+#[cfg(feature = "grpc")]
+mod mtls;
+#[cfg(feature = "grpc")]
+mod reflection;
+#[cfg(feature = "request-id")]
+mod request_id;
+mod schema;
+#[cfg(feature = "serve")]
+mod serve;
+mod timeout;
+#[cfg(feature = "trace")]
+mod trace;
+#[cfg(feature = "trace-context")]
+mod trace_context;
 
+pub use api_key::{
+    ApiKeyLayer, ApiKeyService, AsyncKeyStore, InMemoryKeyStore, KeyStore, Principal,
+};
+#[cfg(feature = "auth")]
+pub use auth::{TwirpAuthorization, TwirpAuthorizationLayer};
 use axum::http::Uri;
 use axum::response::IntoResponse;
+#[cfg(feature = "baggage")]
+pub use baggage::{BaggagePropagation, BaggagePropagationLayer, OtelBaggage};
+#[cfg(feature = "catch-panic")]
+pub use catch_panic::{PanicRecoveryLayer, TwirpPanicHandler};
+#[cfg(feature = "connect")]
+pub use connect::ConnectRouter;
+pub use cors::CorsConfig;
+pub use csp::ContentSecurityPolicy;
+#[cfg(feature = "dedup")]
+pub use dedup::{DedupFuture, DedupResponse, DedupStore, InMemoryDedupStore};
+pub use early_data::EarlyDataPolicy;
+#[cfg(feature = "dedup")]
+pub use idempotency::{
+    HashMapIdempotencyStore, IdempotencyLayer, IdempotencyService, IdempotencyStore,
+};
+pub use json::TwirpJsonOptions;
+#[cfg(feature = "logging")]
+pub use logging::{RequestLoggingFuture, RequestLoggingLayer, RequestLoggingService};
+#[cfg(feature = "prometheus")]
+pub use metrics::{MetricsFuture, MetricsLayer, MetricsService, metrics_router};
+pub use mock::MockTwirpService;
+#[cfg(feature = "grpc")]
+pub use mtls::PeerCertificates;
+#[cfg(feature = "request-id")]
+pub use request_id::{RequestId, RequestIdFuture, RequestIdLayer, RequestIdService};
+#[cfg(feature = "grpc")]
+pub use timeout::GrpcDeadline;
+#[cfg(feature = "trace")]
+pub use trace::{TwirpErrorOnFailure, TwirpErrorOnResponse, TwirpMakeSpan};
+#[cfg(feature = "trace-context")]
+pub use trace_context::{TraceContext, TracingFuture, TracingLayer, TracingService};
 pub use twurst_error::{TwirpError, TwirpErrorCode};
 
 /// Fallback method to be used with a Twirp router
+///
+/// Prefer [`TwirpRouter::with_fallback`](codegen::TwirpRouter::with_fallback) when building the router,
+/// this function is for routers built without it, e.g. when merging several of them.
 pub async fn twirp_fallback(uri: Uri) -> impl IntoResponse {
     TwirpError::new(
         TwirpErrorCode::BadRoute,
@@ -0,0 +1,232 @@
+use axum::body::HttpBody;
+use axum::http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use axum::http::{HeaderMap, Request, Response};
+use pin_project_lite::pin_project;
+use std::collections::HashSet;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+use std::time::Instant;
+use tower_layer::Layer;
+use tower_service::Service;
+use tracing::info;
+
+/// [`Layer`] logging each request at the `INFO` level when its response is returned.
+///
+/// The event has the structured fields `method` (the request path, e.g. `/package.MyService/MyMethod`),
+/// `content_type`, `status`, `duration_ms`, `request_size`, `response_size` and `headers`.
+/// The sizes are only set if they are known before streaming the body.
+/// The values of the `skip_headers` headers (e.g. `authorization`) are redacted.
+///
+/// It can be added to a router with `TwirpRouter::layer` or `GrpcRouter::layer`:
+///
+/// ```
+/// use twurst_server::RequestLoggingLayer;
+/// use twurst_server::codegen::TwirpRouter;
+///
+/// let router = TwirpRouter::new(())
+///     .layer(RequestLoggingLayer::new(vec!["authorization".into()]))
+///     .build();
+/// # let _: axum::Router = router;
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct RequestLoggingLayer {
+    skip_headers: Arc<HashSet<String>>,
+}
+
+impl RequestLoggingLayer {
+    pub fn new(skip_headers: Vec<String>) -> Self {
+        Self {
+            skip_headers: Arc::new(
+                skip_headers
+                    .into_iter()
+                    .map(|name| name.to_ascii_lowercase())
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl<S> Layer<S> for RequestLoggingLayer {
+    type Service = RequestLoggingService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestLoggingService {
+            inner,
+            skip_headers: self.skip_headers.clone(),
+        }
+    }
+}
+
+/// Service built by [`RequestLoggingLayer`]
+#[derive(Clone, Debug)]
+pub struct RequestLoggingService<S> {
+    inner: S,
+    skip_headers: Arc<HashSet<String>>,
+}
+
+impl<S: Service<Request<B>, Response = Response<RB>>, B: HttpBody, RB: HttpBody> Service<Request<B>>
+    for RequestLoggingService<S>
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = RequestLoggingFuture<S::Future>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        let request_size = request.body().size_hint().exact().or_else(|| {
+            request
+                .headers()
+                .get(CONTENT_LENGTH)?
+                .to_str()
+                .ok()?
+                .parse()
+                .ok()
+        });
+        RequestLoggingFuture {
+            method: request.uri().path().to_string(),
+            headers: request.headers().clone(),
+            skip_headers: self.skip_headers.clone(),
+            request_size,
+            start: Instant::now(),
+            inner: self.inner.call(request),
+        }
+    }
+}
+
+pin_project! {
+    /// Future returned by [`RequestLoggingService`]
+    pub struct RequestLoggingFuture<F> {
+        #[pin]
+        inner: F,
+        method: String,
+        headers: HeaderMap,
+        skip_headers: Arc<HashSet<String>>,
+        request_size: Option<u64>,
+        start: Instant,
+    }
+}
+
+impl<F: Future<Output = Result<Response<B>, E>>, B: HttpBody, E> Future
+    for RequestLoggingFuture<F>
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let response = ready!(this.inner.poll(cx))?;
+        info!(
+            method = %this.method,
+            content_type = this.headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()),
+            status = response.status().as_u16(),
+            duration_ms = this.start.elapsed().as_millis() as u64,
+            request_size = *this.request_size,
+            response_size = response.body().size_hint().exact(),
+            headers = ?RedactedHeaders {
+                headers: this.headers,
+                skip_headers: this.skip_headers,
+            },
+            "Request completed"
+        );
+        Poll::Ready(Ok(response))
+    }
+}
+
+struct RedactedHeaders<'a> {
+    headers: &'a HeaderMap,
+    skip_headers: &'a HashSet<String>,
+}
+
+impl fmt::Debug for RedactedHeaders<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.headers.iter().map(|(name, value)| {
+                let value = if self.skip_headers.contains(name.as_str()) {
+                    "[redacted]"
+                } else {
+                    value.to_str().unwrap_or("[binary]")
+                };
+                (name.as_str(), value)
+            }))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::TwirpRouter;
+    use crate::codegen::tests::MyMessage;
+    use axum::body::Body;
+    use axum::http::Method;
+    use std::io;
+    use std::sync::Mutex;
+    use tower::ServiceExt;
+
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_logging() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let _guard = tracing::subscriber::set_default(
+            tracing_subscriber::fmt()
+                .with_ansi(false)
+                .with_writer(move || writer.clone())
+                .finish(),
+        );
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .layer(RequestLoggingLayer::new(vec!["Authorization".into()]))
+            .build();
+        router
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/package.MyService/MyMethod")
+                    .header(CONTENT_TYPE, "application/json")
+                    .header("authorization", "Bearer secret")
+                    .body(Body::from("{}"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        for expected in [
+            " INFO ",
+            "Request completed",
+            "method=/package.MyService/MyMethod",
+            "content_type=\"application/json\"",
+            "status=200",
+            "duration_ms=",
+            "request_size=2",
+            "response_size=2",
+            "\"authorization\": \"[redacted]\"",
+        ] {
+            assert!(logs.contains(expected), "{expected} not in {logs}");
+        }
+        assert!(!logs.contains("secret"), "{logs}");
+    }
+}
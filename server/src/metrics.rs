@@ -0,0 +1,306 @@
+use crate::TwirpError;
+use axum::Router;
+use axum::body::HttpBody;
+use axum::extract::MatchedPath;
+use axum::http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use axum::http::{HeaderValue, Request, Response, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use pin_project_lite::pin_project;
+use prometheus::{
+    HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder, exponential_buckets,
+};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::Instant;
+use tower_layer::Layer;
+use tower_service::Service;
+use tracing::error;
+
+/// [`Layer`] recording [Prometheus](https://prometheus.io/) metrics of the RPCs:
+/// - `twirp_server_requests_total{method, code}` counter where `code` is the Twirp error code or `ok`,
+/// - `twirp_server_request_duration_seconds{method}` histogram,
+/// - `twirp_server_request_size_bytes{method}` and `twirp_server_response_size_bytes{method}` histograms,
+///   only recorded if the size is known before streaming the body (e.g. not for gRPC streams).
+///
+/// `method` is the path of the matched route (e.g. `/package.MyService/MyMethod`), `unmatched` for the requests
+/// without a route so that unknown paths do not create new series.
+/// It can be added to a router with `TwirpRouter::layer` or `GrpcRouter::layer`
+/// and the metrics served with [`metrics_router`].
+///
+/// ```
+/// use prometheus::Registry;
+/// use twurst_server::codegen::TwirpRouter;
+/// use twurst_server::{MetricsLayer, metrics_router};
+///
+/// let registry = Registry::new();
+/// let router = TwirpRouter::new(())
+///     .layer(MetricsLayer::new(&registry).unwrap())
+///     .build()
+///     .merge(metrics_router(registry));
+/// # let _: axum::Router = router;
+/// ```
+#[derive(Clone)]
+pub struct MetricsLayer {
+    metrics: RpcMetrics,
+}
+
+impl MetricsLayer {
+    /// Registers the metrics in `registry`.
+    ///
+    /// Fails if they are already registered, use the same layer for all the routers sharing a registry.
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        Ok(Self {
+            metrics: RpcMetrics::register(registry)?,
+        })
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsService {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+/// `method` label of the requests that did not match any route
+const UNMATCHED_METHOD: &str = "unmatched";
+
+#[derive(Clone)]
+struct RpcMetrics {
+    requests: IntCounterVec,
+    duration: HistogramVec,
+    request_size: HistogramVec,
+    response_size: HistogramVec,
+}
+
+impl RpcMetrics {
+    fn register(registry: &Registry) -> prometheus::Result<Self> {
+        let requests = IntCounterVec::new(
+            Opts::new("twirp_server_requests_total", "Number of RPCs handled"),
+            &["method", "code"],
+        )?;
+        let duration = HistogramVec::new(
+            HistogramOpts::new(
+                "twirp_server_request_duration_seconds",
+                "Time spent handling the RPCs until the response is returned",
+            ),
+            &["method"],
+        )?;
+        let size_buckets = exponential_buckets(64., 4., 10)?;
+        let request_size = HistogramVec::new(
+            HistogramOpts::new(
+                "twirp_server_request_size_bytes",
+                "Size of the RPC request bodies",
+            )
+            .buckets(size_buckets.clone()),
+            &["method"],
+        )?;
+        let response_size = HistogramVec::new(
+            HistogramOpts::new(
+                "twirp_server_response_size_bytes",
+                "Size of the RPC response bodies",
+            )
+            .buckets(size_buckets),
+            &["method"],
+        )?;
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(duration.clone()))?;
+        registry.register(Box::new(request_size.clone()))?;
+        registry.register(Box::new(response_size.clone()))?;
+        Ok(Self {
+            requests,
+            duration,
+            request_size,
+            response_size,
+        })
+    }
+}
+
+/// Service built by [`MetricsLayer`]
+#[derive(Clone)]
+pub struct MetricsService<S> {
+    inner: S,
+    metrics: RpcMetrics,
+}
+
+impl<S: Service<Request<B>, Response = Response<RB>>, B: HttpBody, RB: HttpBody> Service<Request<B>>
+    for MetricsService<S>
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = MetricsFuture<S::Future>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        let method = request
+            .extensions()
+            .get::<MatchedPath>()
+            .map_or(UNMATCHED_METHOD, MatchedPath::as_str)
+            .to_string();
+        let request_size = request.body().size_hint().exact().or_else(|| {
+            request
+                .headers()
+                .get(CONTENT_LENGTH)?
+                .to_str()
+                .ok()?
+                .parse()
+                .ok()
+        });
+        if let Some(size) = request_size {
+            self.metrics
+                .request_size
+                .with_label_values(&[&method])
+                .observe(size as f64);
+        }
+        MetricsFuture {
+            inner: self.inner.call(request),
+            metrics: self.metrics.clone(),
+            method,
+            start: Instant::now(),
+        }
+    }
+}
+
+pin_project! {
+    /// Future returned by [`MetricsService`]
+    pub struct MetricsFuture<F> {
+        #[pin]
+        inner: F,
+        metrics: RpcMetrics,
+        method: String,
+        start: Instant,
+    }
+}
+
+impl<F: Future<Output = Result<Response<B>, E>>, B: HttpBody, E> Future for MetricsFuture<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let response = ready!(this.inner.poll(cx))?;
+        let method = [this.method.as_str()];
+        this.metrics
+            .duration
+            .with_label_values(&method)
+            .observe(this.start.elapsed().as_secs_f64());
+        let code = match response.extensions().get::<TwirpError>() {
+            Some(error) => error.code().to_string(),
+            None => "ok".into(),
+        };
+        this.metrics
+            .requests
+            .with_label_values(&[this.method.as_str(), &code])
+            .inc();
+        if let Some(size) = response.body().size_hint().exact() {
+            this.metrics
+                .response_size
+                .with_label_values(&method)
+                .observe(size as f64);
+        }
+        Poll::Ready(Ok(response))
+    }
+}
+
+/// Router with a single `GET /metrics` endpoint serving the metrics of `registry`
+/// in the Prometheus text format.
+pub fn metrics_router<S: Clone + Send + Sync + 'static>(registry: Registry) -> Router<S> {
+    Router::new().route(
+        "/metrics",
+        get(move || async move {
+            match TextEncoder::new().encode_to_string(&registry.gather()) {
+                Ok(text) => (
+                    [(
+                        CONTENT_TYPE,
+                        HeaderValue::from_static(prometheus::TEXT_FORMAT),
+                    )],
+                    text,
+                )
+                    .into_response(),
+                Err(e) => {
+                    error!("Failed to encode the Prometheus metrics: {e}");
+                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                }
+            }
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::TwirpRouter;
+    use crate::codegen::tests::MyMessage;
+    use axum::body::Body;
+    use axum::http::Method;
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let registry = Registry::new();
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .route(
+                "/package.MyService/Failing",
+                |(), _: MyMessage, _, _| async move {
+                    Err::<MyMessage, _>(TwirpError::not_found("Not found"))
+                },
+            )
+            .layer(MetricsLayer::new(&registry).unwrap())
+            .build();
+        for path in [
+            "/package.MyService/MyMethod",
+            "/package.MyService/MyMethod",
+            "/package.MyService/Failing",
+            "/package.MyService/Unknown1",
+            "/package.MyService/Unknown2",
+        ] {
+            router
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method(Method::POST)
+                        .uri(path)
+                        .header(CONTENT_TYPE, "application/json")
+                        .body(Body::from("{}"))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+        }
+
+        // Served separately so that the unknown paths go to the fallback wrapped by the layer
+        let response = metrics_router::<()>(registry)
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let metrics = std::str::from_utf8(&body).unwrap();
+        for expected in [
+            "twirp_server_requests_total{code=\"ok\",method=\"/package.MyService/MyMethod\"} 2",
+            "twirp_server_requests_total{code=\"not_found\",method=\"/package.MyService/Failing\"} 1",
+            "twirp_server_request_duration_seconds_count{method=\"/package.MyService/MyMethod\"} 2",
+            "twirp_server_request_size_bytes_sum{method=\"/package.MyService/MyMethod\"} 4",
+            "twirp_server_response_size_bytes_sum{method=\"/package.MyService/MyMethod\"} 4",
+            // The unknown paths share a single series
+            "twirp_server_request_duration_seconds_count{method=\"unmatched\"} 2",
+        ] {
+            assert!(metrics.contains(expected), "{expected} not in {metrics}");
+        }
+        assert!(!metrics.contains("Unknown"), "{metrics}");
+    }
+}
@@ -0,0 +1,135 @@
+use crate::TwirpError;
+use crate::codegen::TwirpRouter;
+use axum::Router;
+use axum::body::Body;
+use axum::extract::Request;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{Method, Response};
+use http_body_util::BodyExt;
+use prost_reflect::ReflectMessage;
+use tower_service::Service;
+
+/// Calls the routes of a [`TwirpRouter`] in-process, without an HTTP server, e.g. to unit test handlers.
+///
+/// The messages are encoded in binary protobuf and go through the whole router,
+/// including its layers and options.
+///
+/// ```
+/// use twurst_server::MockTwirpService;
+/// use twurst_server::codegen::TwirpRouter;
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let service = MockTwirpService::new(TwirpRouter::new(()).route(
+///     "/package.MyService/Shout",
+///     |(), request: String, _, _| async move { Ok(request.to_uppercase()) },
+/// ));
+/// let response: String = service
+///     .call("/package.MyService/Shout", "hello".to_string())
+///     .await
+///     .unwrap();
+/// assert_eq!(response, "HELLO");
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct MockTwirpService {
+    router: Router,
+}
+
+impl MockTwirpService {
+    pub fn new<S: Clone + Send + Sync + 'static>(router: TwirpRouter<S, ()>) -> Self {
+        Self {
+            router: router.build(),
+        }
+    }
+
+    /// Calls the route at `path` (e.g. `/package.MyService/MyMethod`) with `request`.
+    ///
+    /// The errors returned by the router, including the ones of its layers, are parsed back into [`TwirpError`].
+    pub async fn call<I: ReflectMessage, O: ReflectMessage + Default>(
+        &self,
+        path: &str,
+        request: I,
+    ) -> Result<O, TwirpError> {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(path)
+            .header(CONTENT_TYPE, "application/protobuf")
+            .body(Body::from(request.encode_to_vec()))
+            .map_err(|e| TwirpError::internal(format!("Invalid request: {e}")))?;
+        let Ok(response) = self.router.clone().call(request).await;
+        let (parts, body) = response.into_parts();
+        let body = body
+            .collect()
+            .await
+            .map_err(|e| TwirpError::internal(format!("Failed to read the response body: {e}")))?
+            .to_bytes();
+        if !parts.status.is_success() {
+            return Err(Response::from_parts(parts, body).into());
+        }
+        O::decode(body)
+            .map_err(|e| TwirpError::internal(format!("Invalid binary protobuf response: {e}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::tests::MyMessage;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_mock_twirp_service() {
+        let service = MockTwirpService::new(
+            TwirpRouter::new(())
+                .route(
+                    "/package.MyService/MyMethod",
+                    |(), request: MyMessage, _, _| async move { Ok(request) },
+                )
+                .route(
+                    "/package.MyService/Failing",
+                    |(), _: MyMessage, _, _| async move {
+                        Err::<MyMessage, _>(TwirpError::not_found("Nothing here"))
+                    },
+                ),
+        );
+        assert_eq!(
+            service
+                .call::<_, MyMessage>("/package.MyService/MyMethod", MyMessage {})
+                .await,
+            Ok(MyMessage {})
+        );
+        assert_eq!(
+            service
+                .call::<_, MyMessage>("/package.MyService/Failing", MyMessage {})
+                .await,
+            Err(TwirpError::not_found("Nothing here"))
+        );
+        // Without fallback, the error is built from the empty 404 response
+        assert_eq!(
+            service
+                .call::<_, MyMessage>("/package.MyService/Unknown", MyMessage {})
+                .await
+                .unwrap_err()
+                .code(),
+            crate::TwirpErrorCode::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mock_twirp_service_timeout() {
+        let service = MockTwirpService::new(
+            TwirpRouter::new(())
+                .route("/package.MyService/Pending", |(), _: MyMessage, _, _| {
+                    std::future::pending::<Result<MyMessage, TwirpError>>()
+                })
+                .with_timeout(Duration::from_millis(10)),
+        );
+        assert_eq!(
+            service
+                .call::<_, MyMessage>("/package.MyService/Pending", MyMessage {})
+                .await,
+            Err(TwirpError::deadline_exceeded("RPC timed out"))
+        );
+    }
+}
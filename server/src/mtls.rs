@@ -0,0 +1,223 @@
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use prost_reflect::bytes::Bytes;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// DER-encoded X.509 certificates sent by the client during the TLS handshake, leaf certificate first.
+///
+/// The TLS acceptor must insert it in the request extensions,
+/// e.g. with an [`Extension`](axum::Extension) layer per connection,
+/// for `GrpcRouter::with_channel_credentials` to validate the client.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerCertificates(pub Vec<Bytes>);
+
+pub(crate) async fn check_channel_credentials(
+    State(allowed_subjects): State<Arc<HashSet<String>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(leaf) = request
+        .extensions()
+        .get::<PeerCertificates>()
+        .and_then(|certificates| certificates.0.first())
+    else {
+        return tonic::Status::unauthenticated("No client certificate").into_http();
+    };
+    let Some(subjects) = certificate_subjects(leaf) else {
+        return tonic::Status::unauthenticated("Invalid client certificate").into_http();
+    };
+    if !subjects
+        .iter()
+        .any(|subject| allowed_subjects.contains(subject))
+    {
+        return tonic::Status::permission_denied("The client certificate is not allowed")
+            .into_http();
+    }
+    next.run(request).await
+}
+
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_OID: u8 = 0x06;
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_VERSION: u8 = 0xA0;
+const TAG_EXTENSIONS: u8 = 0xA3;
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1D, 0x11];
+/// `rfc822Name`, `dNSName` and `uniformResourceIdentifier` general names
+const SAN_STRING_TAGS: [u8; 3] = [0x81, 0x82, 0x86];
+
+/// Returns the subject common names and the e-mail, DNS and URI subject alternative names
+/// of a DER-encoded X.509 certificate
+fn certificate_subjects(certificate: &[u8]) -> Option<Vec<String>> {
+    let mut certificate = read_expected(&mut &*certificate, TAG_SEQUENCE)?;
+    let mut tbs_certificate = read_expected(&mut certificate, TAG_SEQUENCE)?;
+    if tbs_certificate.first() == Some(&TAG_VERSION) {
+        read_tlv(&mut tbs_certificate)?;
+    }
+    // Serial number, signature algorithm, issuer and validity
+    for _ in 0..4 {
+        read_tlv(&mut tbs_certificate)?;
+    }
+    let mut subjects = Vec::new();
+    let mut name = read_expected(&mut tbs_certificate, TAG_SEQUENCE)?;
+    while !name.is_empty() {
+        let mut relative_name = read_expected(&mut name, TAG_SET)?;
+        while !relative_name.is_empty() {
+            let mut attribute = read_expected(&mut relative_name, TAG_SEQUENCE)?;
+            if read_expected(&mut attribute, TAG_OID)? == OID_COMMON_NAME {
+                let (_, value) = read_tlv(&mut attribute)?;
+                subjects.push(String::from_utf8(value.to_vec()).ok()?);
+            }
+        }
+    }
+    // Subject public key info
+    read_tlv(&mut tbs_certificate)?;
+    while !tbs_certificate.is_empty() {
+        let (tag, mut value) = read_tlv(&mut tbs_certificate)?;
+        if tag != TAG_EXTENSIONS {
+            continue; // Unique identifiers
+        }
+        let mut extensions = read_expected(&mut value, TAG_SEQUENCE)?;
+        while !extensions.is_empty() {
+            let mut extension = read_expected(&mut extensions, TAG_SEQUENCE)?;
+            if read_expected(&mut extension, TAG_OID)? != OID_SUBJECT_ALT_NAME {
+                continue;
+            }
+            if extension.first() == Some(&TAG_BOOLEAN) {
+                read_tlv(&mut extension)?;
+            }
+            let mut value = read_expected(&mut extension, TAG_OCTET_STRING)?;
+            let mut general_names = read_expected(&mut value, TAG_SEQUENCE)?;
+            while !general_names.is_empty() {
+                let (tag, value) = read_tlv(&mut general_names)?;
+                if SAN_STRING_TAGS.contains(&tag) {
+                    subjects.push(String::from_utf8(value.to_vec()).ok()?);
+                }
+            }
+        }
+    }
+    Some(subjects)
+}
+
+fn read_expected<'a>(input: &mut &'a [u8], expected_tag: u8) -> Option<&'a [u8]> {
+    let (tag, value) = read_tlv(input)?;
+    (tag == expected_tag).then_some(value)
+}
+
+/// Reads a DER tag-length-value, only supporting single byte tags
+fn read_tlv<'a>(input: &mut &'a [u8]) -> Option<(u8, &'a [u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&length, mut rest) = rest.split_first()?;
+    let length = if length < 0x80 {
+        usize::from(length)
+    } else {
+        let (length_bytes, after_length) = rest.split_at_checked(usize::from(length & 0x7F))?;
+        if length_bytes.is_empty() || length_bytes.len() > 4 {
+            return None;
+        }
+        rest = after_length;
+        length_bytes
+            .iter()
+            .fold(0, |length, byte| (length << 8) | usize::from(*byte))
+    };
+    let (value, rest) = rest.split_at_checked(length)?;
+    *input = rest;
+    Some((tag, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::GrpcRouter;
+    use crate::codegen::tests::MyMessage;
+    use axum::Extension;
+    use axum::http::uri::PathAndQuery;
+    use base64::Engine;
+    use base64::prelude::BASE64_STANDARD;
+    use tonic::Code;
+    use tonic::client::Grpc;
+    use tonic_prost::ProstCodec;
+
+    /// Self-signed certificate with the `client.example.com` common name
+    /// and the `client.internal` and `spiffe://example.com/client` alternative names
+    const CLIENT_CERTIFICATE: &str = concat!(
+        "MIIBsDCCAWKgAwIBAgIUYSTZJnHTfIygbckTFTrzyj0KUC8wBQYDK2VwMC8xEDAOBgNVBAoMB0V4YW1wbGUxGzAZ",
+        "BgNVBAMMEmNsaWVudC5leGFtcGxlLmNvbTAgFw0yNjEwMTUxMzE2MTVaGA8yMTI2MDkyMTEzMTYxNVowLzEQMA4G",
+        "A1UECgwHRXhhbXBsZTEbMBkGA1UEAwwSY2xpZW50LmV4YW1wbGUuY29tMCowBQYDK2VwAyEAGYT7Lk/Z7/1qq/Oy",
+        "Zf/GJjBHPE+zpDtcjGZQUuSIWySjgY0wgYowHQYDVR0OBBYEFPy+Oyt4S7a2cVWI/NnVRqeKMkALMB8GA1UdIwQY",
+        "MBaAFPy+Oyt4S7a2cVWI/NnVRqeKMkALMA8GA1UdEwEB/wQFMAMBAf8wNwYDVR0RBDAwLoIPY2xpZW50LmludGVy",
+        "bmFshhtzcGlmZmU6Ly9leGFtcGxlLmNvbS9jbGllbnQwBQYDK2VwA0EAZQc6aurUWvhpo+QePQqdmf9wgPx4nGy3",
+        "8kTQhFwMAR88VNa2xYf2Rb8K95iMTAi2yATgID++g6z/PNpsmzXpCw==",
+    );
+
+    fn client_certificate() -> Bytes {
+        BASE64_STANDARD.decode(CLIENT_CERTIFICATE).unwrap().into()
+    }
+
+    #[test]
+    fn test_certificate_subjects() {
+        assert_eq!(
+            certificate_subjects(&client_certificate()).unwrap(),
+            [
+                "client.example.com",
+                "client.internal",
+                "spiffe://example.com/client"
+            ]
+        );
+        assert_eq!(certificate_subjects(b"not a certificate"), None);
+    }
+
+    #[tokio::test]
+    async fn test_channel_credentials() {
+        let call = |allowed_subjects: Vec<String>, certificates: Option<PeerCertificates>| async move {
+            let mut router = GrpcRouter::new(())
+                .route(
+                    "/package.MyService/MyMethod",
+                    |(), request: MyMessage, _, _| async move { Ok(request) },
+                )
+                .with_channel_credentials(allowed_subjects)
+                .build();
+            if let Some(certificates) = certificates {
+                router = router.layer(Extension(certificates));
+            }
+            Grpc::new(router)
+                .unary(
+                    tonic::Request::new(MyMessage {}),
+                    PathAndQuery::from_static("/package.MyService/MyMethod"),
+                    ProstCodec::<MyMessage, MyMessage>::default(),
+                )
+                .await
+                .map(|r| r.into_inner())
+                .map_err(|s| s.code())
+        };
+        let certificates = PeerCertificates(vec![client_certificate()]);
+        assert_eq!(
+            call(
+                vec!["spiffe://example.com/client".into()],
+                Some(certificates.clone())
+            )
+            .await,
+            Ok(MyMessage {})
+        );
+        assert_eq!(
+            call(
+                vec!["client.example.com".into()],
+                Some(certificates.clone())
+            )
+            .await,
+            Ok(MyMessage {})
+        );
+        assert_eq!(
+            call(vec!["other.example.com".into()], Some(certificates)).await,
+            Err(Code::PermissionDenied)
+        );
+        assert_eq!(
+            call(vec!["client.example.com".into()], None).await,
+            Err(Code::Unauthenticated)
+        );
+    }
+}
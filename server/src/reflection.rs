@@ -0,0 +1,461 @@
+use prost_reflect::{DescriptorPool, FileDescriptor};
+use std::collections::HashSet;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio_stream::{Stream, StreamExt};
+
+/// Path of the bidirectional streaming method of the [gRPC server reflection](https://github.com/grpc/grpc/blob/master/doc/server-reflection.md) v1 service
+pub(crate) const GRPC_REFLECTION_PATH: &str =
+    "/grpc.reflection.v1.ServerReflection/ServerReflectionInfo";
+
+/// Path of the same method in the `v1alpha` version of the service, still used by some tools.
+///
+/// The messages are the same as the v1 ones.
+pub(crate) const GRPC_REFLECTION_V1ALPHA_PATH: &str =
+    "/grpc.reflection.v1alpha.ServerReflection/ServerReflectionInfo";
+
+/// `grpc.reflection.v1.ServerReflectionRequest`
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct ServerReflectionRequest {
+    #[prost(string, tag = "1")]
+    pub host: String,
+    #[prost(oneof = "MessageRequest", tags = "3, 4, 5, 6, 7")]
+    pub message_request: Option<MessageRequest>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub(crate) enum MessageRequest {
+    #[prost(string, tag = "3")]
+    FileByFilename(String),
+    #[prost(string, tag = "4")]
+    FileContainingSymbol(String),
+    #[prost(message, tag = "5")]
+    FileContainingExtension(ExtensionRequest),
+    #[prost(string, tag = "6")]
+    AllExtensionNumbersOfType(String),
+    #[prost(string, tag = "7")]
+    ListServices(String),
+}
+
+/// `grpc.reflection.v1.ExtensionRequest`
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct ExtensionRequest {
+    #[prost(string, tag = "1")]
+    pub containing_type: String,
+    #[prost(int32, tag = "2")]
+    pub extension_number: i32,
+}
+
+/// `grpc.reflection.v1.ServerReflectionResponse`
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct ServerReflectionResponse {
+    #[prost(string, tag = "1")]
+    pub valid_host: String,
+    #[prost(message, optional, tag = "2")]
+    pub original_request: Option<ServerReflectionRequest>,
+    #[prost(oneof = "MessageResponse", tags = "4, 5, 6, 7")]
+    pub message_response: Option<MessageResponse>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+#[allow(clippy::enum_variant_names)] // Named like the oneof fields of the proto definition
+pub(crate) enum MessageResponse {
+    #[prost(message, tag = "4")]
+    FileDescriptorResponse(FileDescriptorResponse),
+    #[prost(message, tag = "5")]
+    AllExtensionNumbersResponse(ExtensionNumberResponse),
+    #[prost(message, tag = "6")]
+    ListServicesResponse(ListServiceResponse),
+    #[prost(message, tag = "7")]
+    ErrorResponse(ErrorResponse),
+}
+
+/// `grpc.reflection.v1.FileDescriptorResponse`
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct FileDescriptorResponse {
+    #[prost(bytes = "vec", repeated, tag = "1")]
+    pub file_descriptor_proto: Vec<Vec<u8>>,
+}
+
+/// `grpc.reflection.v1.ExtensionNumberResponse`
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct ExtensionNumberResponse {
+    #[prost(string, tag = "1")]
+    pub base_type_name: String,
+    #[prost(int32, repeated, tag = "2")]
+    pub extension_number: Vec<i32>,
+}
+
+/// `grpc.reflection.v1.ListServiceResponse`
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct ListServiceResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub service: Vec<ServiceResponse>,
+}
+
+/// `grpc.reflection.v1.ServiceResponse`
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct ServiceResponse {
+    #[prost(string, tag = "1")]
+    pub name: String,
+}
+
+/// `grpc.reflection.v1.ErrorResponse`
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct ErrorResponse {
+    #[prost(int32, tag = "1")]
+    pub error_code: i32,
+    #[prost(string, tag = "2")]
+    pub error_message: String,
+}
+
+/// Server reflection service answering from the descriptors given to `GrpcRouter::with_reflection`
+#[derive(Clone)]
+pub(crate) struct ReflectionService {
+    pool: Arc<DescriptorPool>,
+}
+
+impl ReflectionService {
+    pub(crate) fn new(pool: DescriptorPool) -> Self {
+        Self {
+            pool: Arc::new(pool),
+        }
+    }
+
+    fn respond(&self, request: ServerReflectionRequest) -> ServerReflectionResponse {
+        let message_response = match &request.message_request {
+            Some(MessageRequest::FileByFilename(name)) => self
+                .pool
+                .get_file_by_name(name)
+                .map(file_descriptor_response)
+                .unwrap_or_else(|| not_found(format!("File {name} not found"))),
+            Some(MessageRequest::FileContainingSymbol(symbol)) => self
+                .file_containing_symbol(symbol)
+                .map(file_descriptor_response)
+                .unwrap_or_else(|| not_found(format!("Symbol {symbol} not found"))),
+            Some(MessageRequest::FileContainingExtension(extension)) => self
+                .pool
+                .get_message_by_name(&extension.containing_type)
+                .and_then(|message| {
+                    message
+                        .extensions()
+                        .find(|e| i32::try_from(e.number()) == Ok(extension.extension_number))
+                })
+                .map(|extension| file_descriptor_response(extension.parent_file()))
+                .unwrap_or_else(|| {
+                    not_found(format!(
+                        "Extension {} of {} not found",
+                        extension.extension_number, extension.containing_type
+                    ))
+                }),
+            Some(MessageRequest::AllExtensionNumbersOfType(name)) => {
+                match self.pool.get_message_by_name(name) {
+                    Some(message) => {
+                        MessageResponse::AllExtensionNumbersResponse(ExtensionNumberResponse {
+                            base_type_name: message.full_name().into(),
+                            extension_number: message
+                                .extensions()
+                                .filter_map(|e| e.number().try_into().ok())
+                                .collect(),
+                        })
+                    }
+                    None => not_found(format!("Type {name} not found")),
+                }
+            }
+            Some(MessageRequest::ListServices(_)) => {
+                MessageResponse::ListServicesResponse(ListServiceResponse {
+                    service: self
+                        .pool
+                        .services()
+                        .map(|service| ServiceResponse {
+                            name: service.full_name().into(),
+                        })
+                        .collect(),
+                })
+            }
+            None => MessageResponse::ErrorResponse(ErrorResponse {
+                error_code: tonic::Code::InvalidArgument.into(),
+                error_message: "Empty reflection request".into(),
+            }),
+        };
+        ServerReflectionResponse {
+            valid_host: request.host.clone(),
+            original_request: Some(request),
+            message_response: Some(message_response),
+        }
+    }
+
+    fn file_containing_symbol(&self, symbol: &str) -> Option<FileDescriptor> {
+        if let Some(service) = self.pool.get_service_by_name(symbol) {
+            return Some(service.parent_file());
+        }
+        if let Some(message) = self.pool.get_message_by_name(symbol) {
+            return Some(message.parent_file());
+        }
+        if let Some(enum_) = self.pool.get_enum_by_name(symbol) {
+            return Some(enum_.parent_file());
+        }
+        if let Some(extension) = self.pool.get_extension_by_name(symbol) {
+            return Some(extension.parent_file());
+        }
+        // Methods and fields are named after their parent
+        let (parent, name) = symbol.rsplit_once('.')?;
+        if let Some(service) = self.pool.get_service_by_name(parent) {
+            return service
+                .methods()
+                .any(|method| method.name() == name)
+                .then(|| service.parent_file());
+        }
+        let message = self.pool.get_message_by_name(parent)?;
+        message
+            .get_field_by_name(name)
+            .map(|_| message.parent_file())
+    }
+}
+
+impl tonic::server::StreamingService<ServerReflectionRequest> for ReflectionService {
+    type Response = ServerReflectionResponse;
+    type ResponseStream =
+        Pin<Box<dyn Stream<Item = Result<ServerReflectionResponse, tonic::Status>> + Send>>;
+    type Future = Pin<
+        Box<
+            dyn Future<Output = Result<tonic::Response<Self::ResponseStream>, tonic::Status>>
+                + Send,
+        >,
+    >;
+
+    fn call(
+        &mut self,
+        request: tonic::Request<tonic::Streaming<ServerReflectionRequest>>,
+    ) -> Self::Future {
+        let service = self.clone();
+        let responses = request
+            .into_inner()
+            .map(move |request| Ok(service.respond(request?)));
+        Box::pin(async move { Ok(tonic::Response::new(Box::pin(responses) as _)) })
+    }
+}
+
+/// Returns the file and all its transitive dependencies, as expected by the reflection clients
+fn file_descriptor_response(file: FileDescriptor) -> MessageResponse {
+    let mut seen = HashSet::new();
+    let mut to_visit = vec![file];
+    let mut file_descriptor_proto = Vec::new();
+    while let Some(file) = to_visit.pop() {
+        if seen.insert(file.name().to_string()) {
+            file_descriptor_proto.push(file.encode_to_vec());
+            to_visit.extend(file.dependencies());
+        }
+    }
+    MessageResponse::FileDescriptorResponse(FileDescriptorResponse {
+        file_descriptor_proto,
+    })
+}
+
+fn not_found(message: String) -> MessageResponse {
+    MessageResponse::ErrorResponse(ErrorResponse {
+        error_code: tonic::Code::NotFound.into(),
+        error_message: message,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::GrpcRouter;
+    use crate::codegen::tests::MyMessage;
+    use axum::http::uri::PathAndQuery;
+    use prost::Message;
+    use prost_reflect::prost_types::{
+        DescriptorProto, FileDescriptorProto, FileDescriptorSet, MethodDescriptorProto,
+        ServiceDescriptorProto,
+    };
+    use tonic::client::Grpc;
+    use tonic_prost::ProstCodec;
+
+    fn file_descriptor_set() -> Vec<u8> {
+        FileDescriptorSet {
+            file: vec![
+                FileDescriptorProto {
+                    name: Some("message.proto".into()),
+                    package: Some("package".into()),
+                    message_type: vec![DescriptorProto {
+                        name: Some("MyMessage".into()),
+                        ..Default::default()
+                    }],
+                    syntax: Some("proto3".into()),
+                    ..Default::default()
+                },
+                FileDescriptorProto {
+                    name: Some("service.proto".into()),
+                    package: Some("package".into()),
+                    dependency: vec!["message.proto".into()],
+                    service: vec![ServiceDescriptorProto {
+                        name: Some("MyService".into()),
+                        method: vec![MethodDescriptorProto {
+                            name: Some("MyMethod".into()),
+                            input_type: Some(".package.MyMessage".into()),
+                            output_type: Some(".package.MyMessage".into()),
+                            ..Default::default()
+                        }],
+                        ..Default::default()
+                    }],
+                    syntax: Some("proto3".into()),
+                    ..Default::default()
+                },
+            ],
+        }
+        .encode_to_vec()
+    }
+
+    async fn reflect(requests: Vec<MessageRequest>) -> Vec<MessageResponse> {
+        let router = GrpcRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, _, _| async move { Ok(request) },
+            )
+            .with_reflection(&[&file_descriptor_set()])
+            .unwrap()
+            .build();
+        reflect_on(router, GRPC_REFLECTION_PATH, requests).await
+    }
+
+    async fn reflect_on(
+        router: axum::Router,
+        path: &'static str,
+        requests: Vec<MessageRequest>,
+    ) -> Vec<MessageResponse> {
+        let mut client = Grpc::new(router);
+        client.ready().await.unwrap();
+        let requests = requests
+            .into_iter()
+            .map(|message_request| ServerReflectionRequest {
+                host: String::new(),
+                message_request: Some(message_request),
+            })
+            .collect::<Vec<_>>();
+        client
+            .streaming(
+                tonic::Request::new(tokio_stream::iter(requests)),
+                PathAndQuery::from_static(path),
+                ProstCodec::<ServerReflectionRequest, ServerReflectionResponse>::default(),
+            )
+            .await
+            .unwrap()
+            .into_inner()
+            .map(|response| response.unwrap().message_response.unwrap())
+            .collect()
+            .await
+    }
+
+    fn file_names(response: &MessageResponse) -> Vec<String> {
+        let MessageResponse::FileDescriptorResponse(response) = response else {
+            panic!("Not a file descriptor response");
+        };
+        response
+            .file_descriptor_proto
+            .iter()
+            .map(|file| {
+                FileDescriptorProto::decode(file.as_slice())
+                    .unwrap()
+                    .name
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_list_services() {
+        let responses = reflect(vec![MessageRequest::ListServices(String::new())]).await;
+        assert_eq!(
+            responses,
+            [MessageResponse::ListServicesResponse(ListServiceResponse {
+                service: vec![ServiceResponse {
+                    name: "package.MyService".into()
+                }]
+            })]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reflection_v1alpha() {
+        let file_descriptor_set = file_descriptor_set();
+        let pool = DescriptorPool::decode(file_descriptor_set.as_slice()).unwrap();
+        let routers = [
+            GrpcRouter::new(())
+                .with_reflection_v1alpha(pool.clone())
+                .build(),
+            GrpcRouter::new(())
+                .with_reflection(&[&file_descriptor_set])
+                .unwrap()
+                .with_reflection_v1alpha(pool)
+                .build(),
+        ];
+        for (router, path) in routers.into_iter().flat_map(|router| {
+            [GRPC_REFLECTION_V1ALPHA_PATH, GRPC_REFLECTION_PATH].map(|path| (router.clone(), path))
+        }) {
+            let responses = reflect_on(
+                router,
+                path,
+                vec![
+                    MessageRequest::ListServices(String::new()),
+                    MessageRequest::FileByFilename("service.proto".into()),
+                ],
+            )
+            .await;
+            assert_eq!(
+                responses[0],
+                MessageResponse::ListServicesResponse(ListServiceResponse {
+                    service: vec![ServiceResponse {
+                        name: "package.MyService".into()
+                    }]
+                })
+            );
+            assert_eq!(
+                file_names(&responses[1]),
+                ["service.proto", "message.proto"]
+            );
+        }
+    }
+
+    #[test]
+    fn test_reflection_invalid_file_descriptor_set() {
+        assert!(
+            GrpcRouter::<_, ()>::new(())
+                .with_reflection(&[b"\xff"])
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_file_requests() {
+        let responses = reflect(vec![
+            MessageRequest::FileContainingSymbol("package.MyService".into()),
+            MessageRequest::FileContainingSymbol("package.MyService.MyMethod".into()),
+            MessageRequest::FileContainingSymbol("package.MyMessage".into()),
+            MessageRequest::FileByFilename("service.proto".into()),
+            MessageRequest::FileContainingSymbol("package.Unknown".into()),
+        ])
+        .await;
+        assert_eq!(
+            file_names(&responses[0]),
+            ["service.proto", "message.proto"]
+        );
+        assert_eq!(
+            file_names(&responses[1]),
+            ["service.proto", "message.proto"]
+        );
+        assert_eq!(file_names(&responses[2]), ["message.proto"]);
+        assert_eq!(
+            file_names(&responses[3]),
+            ["service.proto", "message.proto"]
+        );
+        assert_eq!(
+            responses[4],
+            MessageResponse::ErrorResponse(ErrorResponse {
+                error_code: tonic::Code::NotFound.into(),
+                error_message: "Symbol package.Unknown not found".into(),
+            })
+        );
+    }
+}
@@ -0,0 +1,193 @@
+use axum::http::{HeaderName, HeaderValue, Request, Response};
+use pin_project_lite::pin_project;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tower_layer::Layer;
+use tower_service::Service;
+use uuid::Uuid;
+
+const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+const X_TRACE_ID: HeaderName = HeaderName::from_static("x-trace-id");
+
+/// Identifier of the request set by [`RequestIdLayer`].
+///
+/// It is available in the request extensions, so handlers can get it from their `RequestParts`:
+///
+/// ```
+/// use twurst_server::RequestId;
+/// use twurst_server::codegen::RequestParts;
+///
+/// fn log_request_id(parts: &RequestParts) {
+///     if let Some(request_id) = parts.extensions.get::<RequestId>() {
+///         println!("Handling request {request_id}");
+///     }
+/// }
+/// # let (mut parts, ()) = axum::http::Request::new(()).into_parts();
+/// # parts.extensions.insert(RequestId::generate());
+/// # log_request_id(&parts);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RequestId(pub HeaderValue);
+
+impl RequestId {
+    /// Generates a new random (UUID v4) identifier
+    pub fn generate() -> Self {
+        Self(
+            HeaderValue::from_str(&Uuid::new_v4().to_string())
+                .expect("UUIDs are valid header values"),
+        )
+    }
+
+    pub fn as_str(&self) -> &str {
+        // We only build request ids from UUIDs or from valid header strings
+        self.0.to_str().unwrap_or_default()
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// [`Layer`] identifying each request with a [`RequestId`].
+///
+/// The identifier is read from the `X-Request-Id` or the `X-Trace-Id` request header
+/// or generated as a random UUID if there is none.
+/// It is added to the request extensions and returned in the `X-Request-Id` response header.
+///
+/// It can be added to a router with `TwirpRouter::layer`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RequestIdLayer;
+
+impl<S> Layer<S> for RequestIdLayer {
+    type Service = RequestIdService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestIdService { inner }
+    }
+}
+
+/// Service built by [`RequestIdLayer`]
+#[derive(Clone, Debug)]
+pub struct RequestIdService<S> {
+    inner: S,
+}
+
+impl<S: Service<Request<B>, Response = Response<RB>>, B, RB> Service<Request<B>>
+    for RequestIdService<S>
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = RequestIdFuture<S::Future>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<B>) -> Self::Future {
+        let headers = request.headers();
+        let request_id = headers
+            .get(X_REQUEST_ID)
+            .or_else(|| headers.get(X_TRACE_ID))
+            .filter(|value| value.to_str().is_ok())
+            .map_or_else(RequestId::generate, |value| RequestId(value.clone()));
+        request.extensions_mut().insert(request_id.clone());
+        RequestIdFuture {
+            inner: self.inner.call(request),
+            request_id: Some(request_id),
+        }
+    }
+}
+
+pin_project! {
+    /// Future returned by [`RequestIdService`]
+    pub struct RequestIdFuture<F> {
+        #[pin]
+        inner: F,
+        request_id: Option<RequestId>,
+    }
+}
+
+impl<F: Future<Output = Result<Response<B>, E>>, B, E> Future for RequestIdFuture<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let mut response = ready!(this.inner.poll(cx))?;
+        if let Some(request_id) = this.request_id.take() {
+            response.headers_mut().insert(X_REQUEST_ID, request_id.0);
+        }
+        Poll::Ready(Ok(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TwirpError;
+    use crate::codegen::RequestParts;
+    use crate::codegen::TwirpRouter;
+    use crate::codegen::tests::MyMessage;
+    use axum::body::Body;
+    use axum::http::header::CONTENT_TYPE;
+    use axum::http::{Method, StatusCode};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    async fn call(headers: &[(HeaderName, &'static str)]) -> (Option<HeaderValue>, RequestId) {
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), request: MyMessage, parts: RequestParts, _| async move {
+                    // We check that the id seen by the handler is the one returned
+                    match parts.extensions.get::<RequestId>() {
+                        Some(id) => Err::<MyMessage, _>(TwirpError::not_found(id.to_string())),
+                        None => Ok(request),
+                    }
+                },
+            )
+            .layer(RequestIdLayer)
+            .build();
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri("/package.MyService/MyMethod")
+            .header(CONTENT_TYPE, "application/json");
+        for (name, value) in headers {
+            request = request.header(name, *value);
+        }
+        let response = router
+            .oneshot(request.body(Body::from("{}")).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let header = response.headers().get(X_REQUEST_ID).cloned();
+        let (parts, body) = response.into_parts();
+        let body = body.collect().await.unwrap().to_bytes();
+        let error = TwirpError::from(Response::from_parts(parts, body));
+        (header, RequestId(error.message().parse().unwrap()))
+    }
+
+    #[tokio::test]
+    async fn test_generated_request_id() {
+        let (header, seen) = call(&[]).await;
+        assert_eq!(header, Some(seen.0.clone()));
+        assert!(Uuid::parse_str(seen.as_str()).is_ok());
+        let (_, other) = call(&[]).await;
+        assert_ne!(seen, other);
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_request_id() {
+        let (header, seen) = call(&[(X_REQUEST_ID, "my-request")]).await;
+        assert_eq!(header, Some(HeaderValue::from_static("my-request")));
+        assert_eq!(seen.as_str(), "my-request");
+
+        let (header, seen) = call(&[(X_TRACE_ID, "my-trace")]).await;
+        assert_eq!(header, Some(HeaderValue::from_static("my-trace")));
+        assert_eq!(seen.as_str(), "my-trace");
+    }
+}
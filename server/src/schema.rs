@@ -0,0 +1,61 @@
+use crate::TwirpError;
+use axum::Router;
+use axum::extract::Path;
+use axum::http::HeaderValue;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use prost_reflect::DescriptorPool;
+use prost_reflect::bytes::Bytes;
+use prost_reflect::prost::Message;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Router serving the files of `pool` set with `TwirpRouter::with_schema_registry`:
+/// - `GET /.well-known/proto` returns the file names as a JSON array,
+/// - `GET /.well-known/proto/<file name>` returns the encoded `google.protobuf.FileDescriptorProto` of the file.
+pub(crate) fn schema_registry_router<S: Clone + Send + Sync + 'static>(
+    pool: &DescriptorPool,
+) -> Router<S> {
+    let files = Arc::new(
+        pool.files()
+            .map(|file| {
+                (
+                    file.name().to_string(),
+                    Bytes::from(file.file_descriptor_proto().encode_to_vec()),
+                )
+            })
+            .collect::<BTreeMap<_, _>>(),
+    );
+    let listing = Bytes::from(serde_json::Value::from_iter(files.keys().cloned()).to_string());
+    Router::new()
+        .route(
+            "/.well-known/proto",
+            get(move || async move {
+                (
+                    [(CONTENT_TYPE, HeaderValue::from_static("application/json"))],
+                    listing,
+                )
+            }),
+        )
+        .route(
+            "/.well-known/proto/{*file_name}",
+            get(move |Path(file_name): Path<String>| async move {
+                file_response(&files, &file_name)
+            }),
+        )
+}
+
+fn file_response(files: &BTreeMap<String, Bytes>, file_name: &str) -> Response {
+    match files.get(file_name) {
+        Some(file) => (
+            [(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/protobuf"),
+            )],
+            file.clone(),
+        )
+            .into_response(),
+        None => TwirpError::not_found(format!("No proto file named {file_name}")).into_response(),
+    }
+}
@@ -0,0 +1,233 @@
+use axum::Router;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::server::graceful::GracefulShutdown;
+use hyper_util::service::TowerToHyperService;
+use std::future::Future;
+use std::io;
+use std::pin::{Pin, pin};
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+use tokio::time::{Instant, Sleep, sleep, timeout};
+use tracing::{debug, error, warn};
+
+/// Settings of the served connections
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct ConnectionSettings {
+    /// HTTP/2 `SETTINGS_MAX_CONCURRENT_STREAMS`
+    pub(crate) max_concurrent_streams: Option<u32>,
+    /// HTTP/2 flow control window of the streams and of the connections
+    pub(crate) initial_window_size: Option<u32>,
+    /// HTTP/2 `SETTINGS_MAX_FRAME_SIZE`
+    pub(crate) max_frame_size: Option<u32>,
+    /// Closes the connections without any byte sent or received during this duration
+    pub(crate) idle_timeout: Option<Duration>,
+}
+
+/// Serves `router` on `listener` until `signal` resolves.
+///
+/// Once `signal` resolves, no new connection is accepted and the already accepted ones are asked to close
+/// after their in-flight requests. If `drain_timeout` is set, the remaining connections are aborted after it.
+pub(crate) async fn serve_with_graceful_shutdown(
+    listener: TcpListener,
+    router: Router,
+    signal: impl Future<Output = ()>,
+    drain_timeout: Option<Duration>,
+    connection_settings: ConnectionSettings,
+) -> io::Result<()> {
+    let mut builder = Builder::new(TokioExecutor::new());
+    if let Some(max) = connection_settings.max_concurrent_streams {
+        builder.http2().max_concurrent_streams(max);
+    }
+    if let Some(size) = connection_settings.initial_window_size {
+        builder
+            .http2()
+            .initial_stream_window_size(size)
+            .initial_connection_window_size(size);
+    }
+    if let Some(size) = connection_settings.max_frame_size {
+        builder.http2().max_frame_size(size);
+    }
+    let graceful = GracefulShutdown::new();
+    let mut connections = JoinSet::new();
+    let mut signal = pin!(signal);
+    loop {
+        let stream = tokio::select! {
+            () = &mut signal => break,
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            result = listener.accept() => match result {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    // Errors like "too many open files" are usually transient
+                    error!("Failed to accept a connection: {e}");
+                    sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            }
+        };
+        let connection = builder
+            .serve_connection_with_upgrades(
+                TokioIo::new(IdleTimeout::new(stream, connection_settings.idle_timeout)),
+                TowerToHyperService::new(router.clone()),
+            )
+            .into_owned();
+        let connection = graceful.watch(connection);
+        connections.spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("Error while serving a connection: {e}");
+            }
+        });
+    }
+    drop(listener);
+
+    // We notify the connections to stop and wait for them to finish
+    let shutdown = graceful.shutdown();
+    if let Some(drain_timeout) = drain_timeout {
+        if timeout(drain_timeout, shutdown).await.is_err() {
+            warn!(
+                "{} connection(s) still open after the drain timeout, aborting them",
+                connections.len()
+            );
+        }
+    } else {
+        shutdown.await;
+    }
+    connections.shutdown().await;
+    Ok(())
+}
+
+/// I/O failing with a [`io::ErrorKind::TimedOut`] error if nothing is read or written during `timeout`
+struct IdleTimeout<T> {
+    inner: T,
+    timeout: Option<(Duration, Pin<Box<Sleep>>)>,
+}
+
+impl<T> IdleTimeout<T> {
+    fn new(inner: T, timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            timeout: timeout.map(|timeout| (timeout, Box::pin(sleep(timeout)))),
+        }
+    }
+
+    fn on_activity(&mut self) {
+        if let Some((timeout, deadline)) = &mut self.timeout {
+            deadline.as_mut().reset(Instant::now() + *timeout);
+        }
+    }
+
+    fn poll_deadline<R>(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<R>> {
+        let Some((_, deadline)) = &mut self.timeout else {
+            return Poll::Pending;
+        };
+        ready!(deadline.as_mut().poll(cx));
+        Poll::Ready(Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "connection idle timeout",
+        )))
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for IdleTimeout<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                self.on_activity();
+                Poll::Ready(result)
+            }
+            Poll::Pending => self.poll_deadline(cx),
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match Pin::new(&mut self.inner).poll_write(cx, buf) {
+            Poll::Ready(result) => {
+                self.on_activity();
+                Poll::Ready(result)
+            }
+            Poll::Pending => self.poll_deadline(cx),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match Pin::new(&mut self.inner).poll_write_vectored(cx, bufs) {
+            Poll::Ready(result) => {
+                self.on_activity();
+                Poll::Ready(result)
+            }
+            Poll::Pending => self.poll_deadline(cx),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_timeout() {
+        let (client, server) = duplex(64);
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        let mut server = IdleTimeout::new(server, Some(Duration::from_secs(10)));
+        let mut buffer = [0; 4];
+
+        // Activity in both directions keeps the connection alive
+        for _ in 0..3 {
+            tokio::time::advance(Duration::from_secs(6)).await;
+            client_write.write_all(b"ping").await.unwrap();
+            server.read_exact(&mut buffer).await.unwrap();
+            tokio::time::advance(Duration::from_secs(6)).await;
+            server.write_all(b"pong").await.unwrap();
+            client_read.read_exact(&mut buffer).await.unwrap();
+        }
+
+        // Nothing for 10s
+        let start = Instant::now();
+        let error = server.read(&mut buffer).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_no_idle_timeout() {
+        let (mut client, server) = duplex(64);
+        let mut server = IdleTimeout::new(server, None);
+        let read = tokio::spawn(async move {
+            let mut buffer = [0; 4];
+            server.read_exact(&mut buffer).await.map(|_| buffer)
+        });
+        tokio::time::advance(Duration::from_secs(3600)).await;
+        client.write_all(b"ping").await.unwrap();
+        assert_eq!(&read.await.unwrap().unwrap(), b"ping");
+    }
+}
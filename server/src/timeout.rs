@@ -0,0 +1,163 @@
+use crate::TwirpError;
+#[cfg(feature = "grpc")]
+use axum::extract::{Request, State};
+#[cfg(feature = "grpc")]
+use axum::http::HeaderValue;
+#[cfg(feature = "grpc")]
+use axum::middleware::Next;
+#[cfg(feature = "grpc")]
+use axum::response::Response;
+use std::future::Future;
+use std::time::Duration;
+#[cfg(feature = "grpc")]
+use std::time::Instant;
+
+/// Runs `future`, failing with a `deadline_exceeded` error if it takes more than `timeout`.
+///
+/// A zero timeout fails immediately.
+pub(crate) async fn run_with_timeout<T>(
+    timeout: Duration,
+    future: impl Future<Output = T>,
+) -> Result<T, TwirpError> {
+    if timeout.is_zero() {
+        return Err(TwirpError::deadline_exceeded("RPC timed out"));
+    }
+    tokio::time::timeout(timeout, future)
+        .await
+        .map_err(|_| TwirpError::deadline_exceeded("RPC timed out"))
+}
+
+/// Middleware applying [`GrpcRouter::with_timeout`](crate::codegen::GrpcRouter::with_timeout)
+#[cfg(feature = "grpc")]
+pub(crate) async fn grpc_timeout(
+    State(timeout): State<Duration>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match run_with_timeout(timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(error) => {
+            let mut response = tonic::Status::from(error).into_http::<axum::body::Body>();
+            if let Ok(value) = HeaderValue::try_from(format_grpc_timeout(timeout)) {
+                response.headers_mut().insert("grpc-timeout", value);
+            }
+            response
+        }
+    }
+}
+
+/// Deadline of a gRPC call set by the client in the `grpc-timeout` header.
+///
+/// It is available in the [`RequestParts`](crate::codegen::RequestParts) extensions
+/// of the `GrpcRouter` handlers, e.g. to set the timeout of the downstream calls.
+/// The calls still running after it fail with a `DEADLINE_EXCEEDED` status.
+#[cfg(feature = "grpc")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct GrpcDeadline(pub Instant);
+
+#[cfg(feature = "grpc")]
+impl GrpcDeadline {
+    /// Time left before the deadline, zero if it is already passed
+    pub fn remaining(&self) -> Duration {
+        self.0.saturating_duration_since(Instant::now())
+    }
+}
+
+/// Middleware enforcing the `grpc-timeout` request header
+#[cfg(feature = "grpc")]
+pub(crate) async fn grpc_deadline(mut request: Request, next: Next) -> Response {
+    let Some(timeout) = request
+        .headers()
+        .get("grpc-timeout")
+        .and_then(|value| parse_grpc_timeout(value.to_str().ok()?))
+    else {
+        return next.run(request).await;
+    };
+    request
+        .extensions_mut()
+        .insert(GrpcDeadline(Instant::now() + timeout));
+    match run_with_timeout(timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(error) => tonic::Status::from(error).into_http(),
+    }
+}
+
+/// Parses the gRPC `grpc-timeout` header syntax (at most 8 digits and a unit)
+#[cfg(feature = "grpc")]
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    let unit_start = value.len().checked_sub(1)?;
+    let (value, unit) = value.split_at_checked(unit_start)?;
+    if value.is_empty() || value.len() > 8 || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let value = value.parse().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(value * 3600),
+        "M" => Duration::from_secs(value * 60),
+        "S" => Duration::from_secs(value),
+        "m" => Duration::from_millis(value),
+        "u" => Duration::from_micros(value),
+        "n" => Duration::from_nanos(value),
+        _ => return None,
+    })
+}
+
+/// Formats `timeout` following the gRPC `grpc-timeout` header syntax (at most 8 digits and a unit)
+#[cfg(feature = "grpc")]
+fn format_grpc_timeout(timeout: Duration) -> String {
+    const MAX: u128 = 99_999_999;
+    let nanos = timeout.as_nanos();
+    for (unit, nanos_per_unit) in [
+        ('n', 1),
+        ('u', 1_000),
+        ('m', 1_000_000),
+        ('S', 1_000_000_000),
+        ('M', 60_000_000_000),
+    ] {
+        let value = nanos.div_ceil(nanos_per_unit);
+        if value <= MAX {
+            return format!("{value}{unit}");
+        }
+    }
+    format!("{}H", nanos.div_ceil(3_600_000_000_000).min(MAX))
+}
+
+#[cfg(all(test, feature = "grpc"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_grpc_timeout() {
+        assert_eq!(parse_grpc_timeout("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_grpc_timeout("3M"), Some(Duration::from_secs(180)));
+        assert_eq!(parse_grpc_timeout("4S"), Some(Duration::from_secs(4)));
+        assert_eq!(parse_grpc_timeout("1000m"), Some(Duration::from_secs(1)));
+        assert_eq!(parse_grpc_timeout("5u"), Some(Duration::from_micros(5)));
+        assert_eq!(
+            parse_grpc_timeout("99999999n"),
+            Some(Duration::from_nanos(99_999_999))
+        );
+        for invalid in ["", "m", "10", "10s", "-1m", "+1m", "123456789m", "1é"] {
+            assert_eq!(parse_grpc_timeout(invalid), None, "{invalid}");
+        }
+        for duration in [Duration::from_millis(1), Duration::from_secs(86_400)] {
+            assert_eq!(
+                parse_grpc_timeout(&format_grpc_timeout(duration)),
+                Some(duration)
+            );
+        }
+    }
+
+    #[test]
+    fn test_format_grpc_timeout() {
+        assert_eq!(format_grpc_timeout(Duration::ZERO), "0n");
+        assert_eq!(format_grpc_timeout(Duration::from_micros(1)), "1000n");
+        assert_eq!(format_grpc_timeout(Duration::from_millis(500)), "500000u");
+        assert_eq!(format_grpc_timeout(Duration::from_secs(30)), "30000000u");
+        assert_eq!(
+            format_grpc_timeout(Duration::from_secs(86_400)),
+            "86400000m"
+        );
+        assert_eq!(format_grpc_timeout(Duration::MAX), "99999999H");
+    }
+}
@@ -0,0 +1,86 @@
+use crate::TwirpError;
+use crate::codegen::rpc_service_and_method;
+use axum::http::{Request, Response};
+use std::fmt::Display;
+use std::time::Duration;
+use tower_http::trace::{MakeSpan, OnFailure, OnResponse};
+use tracing::field::{self, Empty};
+use tracing::{Span, debug, error, info, info_span, warn};
+use twurst_error::Severity;
+
+/// [`MakeSpan`] for [`TraceLayer`](tower_http::trace::TraceLayer) creating a span per Twirp request.
+///
+/// The span follows the [OpenTelemetry RPC conventions](https://opentelemetry.io/docs/specs/semconv/rpc/rpc-spans/)
+/// with the `rpc.system`, `rpc.service` and `rpc.method` attributes.
+/// It also has a `twirp.error_code` attribute filled by [`TwirpErrorOnResponse`].
+///
+/// ```
+/// use axum::Router;
+/// use tower_http::trace::TraceLayer;
+/// use twurst_server::{TwirpErrorOnFailure, TwirpErrorOnResponse, TwirpMakeSpan};
+///
+/// let _router: Router = Router::new().layer(
+///     TraceLayer::new_for_http()
+///         .make_span_with(TwirpMakeSpan)
+///         .on_response(TwirpErrorOnResponse)
+///         .on_failure(TwirpErrorOnFailure),
+/// );
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TwirpMakeSpan;
+
+impl<B> MakeSpan<B> for TwirpMakeSpan {
+    fn make_span(&mut self, request: &Request<B>) -> Span {
+        let (service, method) = rpc_service_and_method(request.uri().path());
+        info_span!(
+            "twirp request",
+            rpc.system = "twirp",
+            rpc.service = service,
+            rpc.method = method,
+            twirp.error_code = Empty,
+        )
+    }
+}
+
+/// [`OnResponse`] for [`TraceLayer`](tower_http::trace::TraceLayer) logging the [`TwirpError`] returned by the handlers.
+///
+/// The level is given by the [severity](crate::TwirpErrorCode::severity) of the error code,
+/// [`Severity::Critical`] errors are logged at the `ERROR` level with a `critical` field.
+///
+/// The error code is also recorded in the `twirp.error_code` attribute of the span built by [`TwirpMakeSpan`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TwirpErrorOnResponse;
+
+impl<B> OnResponse<B> for TwirpErrorOnResponse {
+    fn on_response(self, response: &Response<B>, latency: Duration, span: &Span) {
+        let latency = latency.as_millis();
+        let status = response.status().as_u16();
+        let Some(error) = response.extensions().get::<TwirpError>() else {
+            debug!(latency, status, "finished processing request");
+            return;
+        };
+        span.record("twirp.error_code", field::debug(error.code()));
+        let code = error.code();
+        let message = error.message();
+        match code.severity() {
+            Severity::Debug => debug!(latency, status, ?code, "{message}"),
+            Severity::Info => info!(latency, status, ?code, "{message}"),
+            Severity::Warn => warn!(latency, status, ?code, "{message}"),
+            Severity::Error => error!(latency, status, ?code, "{message}"),
+            Severity::Critical => error!(latency, status, ?code, critical = true, "{message}"),
+        }
+    }
+}
+
+/// [`OnFailure`] for [`TraceLayer`](tower_http::trace::TraceLayer) logging requests classified as failures.
+///
+/// Twirp errors are already logged by [`TwirpErrorOnResponse`], this logs the failure classification
+/// (e.g. the HTTP status code or a transport error).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TwirpErrorOnFailure;
+
+impl<F: Display> OnFailure<F> for TwirpErrorOnFailure {
+    fn on_failure(&mut self, failure: F, latency: Duration, _span: &Span) {
+        error!(latency = latency.as_millis(), "request failed: {failure}");
+    }
+}
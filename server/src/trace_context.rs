@@ -0,0 +1,436 @@
+use crate::TwirpError;
+use crate::codegen::rpc_service_and_method;
+use axum::body::HttpBody;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, Request, Response};
+use pin_project_lite::pin_project;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tower_layer::Layer;
+use tower_service::Service;
+use tracing::field::{self, Empty};
+use tracing::{Span, info_span};
+
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+#[cfg(feature = "b3")]
+const B3: &str = "b3";
+#[cfg(feature = "b3")]
+const X_B3_TRACE_ID: &str = "x-b3-traceid";
+#[cfg(feature = "b3")]
+const X_B3_SPAN_ID: &str = "x-b3-spanid";
+#[cfg(feature = "b3")]
+const X_B3_SAMPLED: &str = "x-b3-sampled";
+#[cfg(feature = "b3")]
+const X_B3_FLAGS: &str = "x-b3-flags";
+
+/// [W3C trace context](https://www.w3.org/TR/trace-context/) of the request.
+///
+/// It is extracted by [`TracingLayer`] and available in the request extensions:
+///
+/// ```
+/// use twurst_server::TraceContext;
+/// use twurst_server::codegen::RequestParts;
+///
+/// fn log_trace_id(parts: &RequestParts) {
+///     if let Some(context) = parts.extensions.get::<TraceContext>() {
+///         println!("Handling request of trace {}", context.trace_id);
+///     }
+/// }
+/// # let (parts, ()) = axum::http::Request::new(()).into_parts();
+/// # log_trace_id(&parts);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    /// Trace id as 32 lowercase hexadecimal characters
+    pub trace_id: String,
+    /// Id of the caller span as 16 lowercase hexadecimal characters
+    pub parent_id: String,
+    /// If the caller has recorded its span
+    pub sampled: bool,
+    /// Vendor-specific `tracestate` header value
+    pub trace_state: Option<String>,
+}
+
+impl TraceContext {
+    /// Extracts the trace context from the `traceparent` and `tracestate` headers
+    /// or, if the `b3` feature is enabled, from the `b3` or `X-B3-*` headers.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let Some(context) = headers
+            .get(TRACEPARENT)
+            .and_then(|value| Self::parse_traceparent(value.to_str().ok()?))
+        else {
+            #[cfg(feature = "b3")]
+            return Self::from_b3_headers(headers);
+            #[cfg(not(feature = "b3"))]
+            return None;
+        };
+        Some(Self {
+            trace_state: headers
+                .get(TRACESTATE)
+                .and_then(|value| Some(value.to_str().ok()?.to_string())),
+            ..context
+        })
+    }
+
+    /// Parses a `traceparent` header value (e.g. `00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01`)
+    pub fn parse_traceparent(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let parent_id = parts.next()?;
+        let flags = parts.next()?;
+        if !is_hex_id(version, 2) || version == "ff" || (version == "00" && parts.next().is_some())
+        {
+            return None;
+        }
+        if !is_hex_id(trace_id, 32) || !is_hex_id(parent_id, 16) || !is_hex_id(flags, 2) {
+            return None;
+        }
+        Some(Self {
+            trace_id: trace_id.into(),
+            parent_id: parent_id.into(),
+            sampled: u8::from_str_radix(flags, 16).ok()? & 1 == 1,
+            trace_state: None,
+        })
+    }
+
+    /// Extracts the trace context from the [B3](https://github.com/openzipkin/b3-propagation) `b3` single header
+    /// or from the `X-B3-TraceId`, `X-B3-SpanId` and `X-B3-Sampled` headers.
+    ///
+    /// 64 bits trace ids are left-padded with zeros to 128 bits.
+    #[cfg(feature = "b3")]
+    pub fn from_b3_headers(headers: &HeaderMap) -> Option<Self> {
+        let header = |name| headers.get(name)?.to_str().ok();
+        let (trace_id, span_id, sampled) = if let Some(b3) = header(B3) {
+            let mut parts = b3.trim().split('-');
+            (parts.next()?, parts.next()?, parts.next())
+        } else {
+            let sampled = if header(X_B3_FLAGS) == Some("1") {
+                Some("d")
+            } else {
+                header(X_B3_SAMPLED)
+            };
+            (header(X_B3_TRACE_ID)?, header(X_B3_SPAN_ID)?, sampled)
+        };
+        let trace_id = match trace_id.len() {
+            16 => format!("{:0>32}", trace_id.to_ascii_lowercase()),
+            _ => trace_id.to_ascii_lowercase(),
+        };
+        let parent_id = span_id.to_ascii_lowercase();
+        if !is_hex_id(&trace_id, 32) || !is_hex_id(&parent_id, 16) {
+            return None;
+        }
+        Some(Self {
+            trace_id,
+            parent_id,
+            sampled: matches!(sampled, Some("1" | "d" | "true")),
+            trace_state: None,
+        })
+    }
+
+    /// The `traceparent` header value of this context
+    pub fn to_traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{}",
+            self.trace_id,
+            self.parent_id,
+            if self.sampled { "01" } else { "00" }
+        )
+    }
+}
+
+/// If `value` is `len` lowercase hexadecimal characters, not all zeros
+fn is_hex_id(value: &str, len: usize) -> bool {
+    value.len() == len
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+        && (len == 2 || value.bytes().any(|b| b != b'0'))
+}
+
+/// [`Layer`] creating a [`tracing`] span per RPC, child of the caller trace.
+///
+/// The [`TraceContext`] is extracted from the request headers (see [`TraceContext::from_headers`])
+/// and added to the request extensions with the [`Span`] of the request
+/// (also available with [`Span::current`] in the handlers).
+///
+/// The span is named after the method path using the `otel.name` attribute
+/// and follows the [OpenTelemetry RPC conventions](https://opentelemetry.io/docs/specs/semconv/rpc/rpc-spans/).
+/// It has the `trace_id` and `parent_id` attributes of the caller trace context
+/// and, once the response is built, the `http.response.status_code`, `http.response.body.size` (if known),
+/// `twirp.error_code` (on Twirp errors) and `rpc.grpc.status_code` (for unary gRPC calls) attributes.
+///
+/// It can be added to a router with `TwirpRouter::layer` or `GrpcRouter::layer`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TracingLayer;
+
+impl<S> Layer<S> for TracingLayer {
+    type Service = TracingService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TracingService { inner }
+    }
+}
+
+/// Service built by [`TracingLayer`]
+#[derive(Clone, Debug)]
+pub struct TracingService<S> {
+    inner: S,
+}
+
+impl<S: Service<Request<B>, Response = Response<RB>>, B, RB: HttpBody> Service<Request<B>>
+    for TracingService<S>
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = TracingFuture<S::Future>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<B>) -> Self::Future {
+        let context = TraceContext::from_headers(request.headers());
+        let path = request.uri().path();
+        let (service, method) = rpc_service_and_method(path);
+        let is_grpc = request
+            .headers()
+            .get(CONTENT_TYPE)
+            .is_some_and(|value| value.as_bytes().starts_with(b"application/grpc"));
+        let span = info_span!(
+            "rpc",
+            otel.name = path,
+            otel.kind = "server",
+            rpc.system = if is_grpc { "grpc" } else { "twirp" },
+            rpc.service = service,
+            rpc.method = method,
+            trace_id = context.as_ref().map(|c| c.trace_id.as_str()),
+            parent_id = context.as_ref().map(|c| c.parent_id.as_str()),
+            http.response.status_code = Empty,
+            http.response.body.size = Empty,
+            twirp.error_code = Empty,
+            rpc.grpc.status_code = Empty,
+        );
+        if let Some(context) = context {
+            request.extensions_mut().insert(context);
+        }
+        request.extensions_mut().insert(span.clone());
+        let inner = {
+            let _entered = span.enter();
+            self.inner.call(request)
+        };
+        TracingFuture { inner, span }
+    }
+}
+
+pin_project! {
+    /// Future returned by [`TracingService`]
+    pub struct TracingFuture<F> {
+        #[pin]
+        inner: F,
+        span: Span,
+    }
+}
+
+impl<F: Future<Output = Result<Response<B>, E>>, B: HttpBody, E> Future for TracingFuture<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let _entered = this.span.enter();
+        let response = ready!(this.inner.poll(cx))?;
+        this.span
+            .record("http.response.status_code", response.status().as_u16());
+        if let Some(size) = response.body().size_hint().exact() {
+            this.span.record("http.response.body.size", size);
+        }
+        if let Some(error) = response.extensions().get::<TwirpError>() {
+            this.span
+                .record("twirp.error_code", field::display(error.code()));
+        }
+        if let Some(status) = response
+            .headers()
+            .get("grpc-status")
+            .and_then(|value| value.to_str().ok()?.parse::<i32>().ok())
+        {
+            this.span.record("rpc.grpc.status_code", status);
+        }
+        Poll::Ready(Ok(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::tests::MyMessage;
+    use crate::codegen::{RequestParts, TwirpRouter};
+    use axum::body::Body;
+    use axum::http::Method;
+    #[cfg(feature = "b3")]
+    use axum::http::{HeaderName, HeaderValue};
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+    use tower::ServiceExt;
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    /// Subscriber recording the fields of all the spans
+    #[derive(Clone, Default)]
+    struct RecordingSubscriber {
+        fields: Arc<Mutex<HashMap<String, String>>>,
+    }
+
+    impl field::Visit for &RecordingSubscriber {
+        fn record_str(&mut self, field: &field::Field, value: &str) {
+            self.fields
+                .lock()
+                .unwrap()
+                .insert(field.name().into(), value.into());
+        }
+
+        fn record_debug(&mut self, field: &field::Field, value: &dyn fmt::Debug) {
+            self.fields
+                .lock()
+                .unwrap()
+                .insert(field.name().into(), format!("{value:?}"));
+        }
+    }
+
+    impl Subscriber for RecordingSubscriber {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            span.record(&mut &*self);
+            Id::from_u64(1)
+        }
+
+        fn record(&self, _: &Id, values: &Record<'_>) {
+            values.record(&mut &*self);
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, _: &Event<'_>) {}
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn test_parse_traceparent() {
+        assert_eq!(
+            TraceContext::parse_traceparent(
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+            ),
+            Some(TraceContext {
+                trace_id: "0af7651916cd43dd8448eb211c80319c".into(),
+                parent_id: "b7ad6b7169203331".into(),
+                sampled: true,
+                trace_state: None,
+            })
+        );
+        for invalid in [
+            "",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+            "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0AF7651916CD43DD8448EB211C80319C-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra",
+        ] {
+            assert_eq!(TraceContext::parse_traceparent(invalid), None, "{invalid}");
+        }
+    }
+
+    #[cfg(feature = "b3")]
+    #[test]
+    fn test_b3_headers() {
+        let expected = TraceContext {
+            trace_id: "0000000000000000a3ce929d0e0e4736".into(),
+            parent_id: "00f067aa0ba902b7".into(),
+            sampled: true,
+            trace_state: None,
+        };
+        let headers = HeaderMap::from_iter([(
+            HeaderName::from_static(B3),
+            HeaderValue::from_static("a3ce929d0e0e4736-00f067aa0ba902b7-1"),
+        )]);
+        assert_eq!(TraceContext::from_headers(&headers), Some(expected.clone()));
+        let headers = HeaderMap::from_iter([
+            (
+                HeaderName::from_static(X_B3_TRACE_ID),
+                HeaderValue::from_static("A3CE929D0E0E4736"),
+            ),
+            (
+                HeaderName::from_static(X_B3_SPAN_ID),
+                HeaderValue::from_static("00f067aa0ba902b7"),
+            ),
+            (
+                HeaderName::from_static(X_B3_SAMPLED),
+                HeaderValue::from_static("1"),
+            ),
+        ]);
+        assert_eq!(TraceContext::from_headers(&headers), Some(expected));
+    }
+
+    #[tokio::test]
+    async fn test_tracing_layer() {
+        let subscriber = RecordingSubscriber::default();
+        let _guard = tracing::subscriber::set_default(subscriber.clone());
+        let router = TwirpRouter::new(())
+            .route(
+                "/package.MyService/MyMethod",
+                |(), _: MyMessage, parts: RequestParts, _| async move {
+                    let context = parts.extensions.get::<TraceContext>().unwrap();
+                    assert!(parts.extensions.get::<Span>().is_some());
+                    Err::<MyMessage, _>(TwirpError::not_found(context.to_traceparent()))
+                },
+            )
+            .layer(TracingLayer)
+            .build();
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/package.MyService/MyMethod")
+                    .header(CONTENT_TYPE, "application/json")
+                    .header(
+                        TRACEPARENT,
+                        "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+                    )
+                    .header(TRACESTATE, "vendor=value")
+                    .body(Body::from("{}"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), axum::http::StatusCode::NOT_FOUND);
+
+        let fields = subscriber.fields.lock().unwrap();
+        let field = |name: &str| fields.get(name).map(String::as_str);
+        assert_eq!(field("otel.name"), Some("/package.MyService/MyMethod"));
+        assert_eq!(field("rpc.system"), Some("twirp"));
+        assert_eq!(field("rpc.service"), Some("package.MyService"));
+        assert_eq!(field("rpc.method"), Some("MyMethod"));
+        assert_eq!(field("trace_id"), Some("0af7651916cd43dd8448eb211c80319c"));
+        assert_eq!(field("parent_id"), Some("b7ad6b7169203331"));
+        assert_eq!(field("http.response.status_code"), Some("404"));
+        assert_eq!(field("twirp.error_code"), Some("not_found"));
+        let expected_size = format!(
+            "{{\"code\":\"not_found\",\"msg\":\"{}\"}}",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+        )
+        .len()
+        .to_string();
+        assert_eq!(
+            field("http.response.body.size"),
+            Some(expected_size.as_str())
+        );
+    }
+}